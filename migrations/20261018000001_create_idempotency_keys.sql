@@ -0,0 +1,23 @@
+-- Idempotency keys for probe submission.
+-- A client sends an `Idempotency-Key` header with `POST /api/probes`; the first
+-- request claims the key (response still NULL while it is being dispatched) and
+-- stores the `SubmitProbesResponse` once the batches are in Kafka. A retry with
+-- the same key inside the retention window gets that stored response back instead
+-- of creating a second measurement.
+
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_hash VARCHAR(64) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    -- SHA-256 of the request body, so a reused key with a different payload is rejected
+    request_hash VARCHAR(64) NOT NULL,
+    measurement_id UUID,
+    -- Serialized SubmitProbesResponse; NULL while the original request is in flight
+    response TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (user_hash, idempotency_key)
+);
+
+-- Expired keys are looked up and overwritten by creation time
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_created_at
+ON idempotency_keys (created_at);
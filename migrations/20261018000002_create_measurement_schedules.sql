@@ -0,0 +1,39 @@
+-- Scheduled and recurring measurements.
+-- A schedule stores a probe submission spec that the gateway's background
+-- scheduler dispatches at `next_run_at`, then either advances by
+-- `interval_seconds` (recurring) or clears (one-shot). Each run produces a
+-- regular measurement, linked back to its schedule in measurement_schedule_runs.
+
+CREATE TABLE IF NOT EXISTS measurement_schedules (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_hash VARCHAR(64) NOT NULL,
+    name VARCHAR(255),
+    -- Serialized SubmitProbesRequest (agents metadata and probes)
+    spec TEXT NOT NULL,
+    -- NULL for a one-shot schedule
+    interval_seconds BIGINT,
+    -- NULL once a one-shot schedule has run
+    next_run_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_schedules_user_hash
+ON measurement_schedules (user_hash);
+
+-- Index for the scheduler's due-schedule scan
+CREATE INDEX IF NOT EXISTS idx_measurement_schedules_next_run_at
+ON measurement_schedules (next_run_at)
+WHERE next_run_at IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS measurement_schedule_runs (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    schedule_id UUID NOT NULL REFERENCES measurement_schedules (id) ON DELETE CASCADE,
+    -- The measurement created by this run; NULL if the run failed
+    measurement_id UUID,
+    error TEXT,
+    started_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_schedule_runs_schedule
+ON measurement_schedule_runs (schedule_id, started_at);
@@ -0,0 +1,18 @@
+-- Saved target lists.
+-- A target list is a named, per-user set of validated probe destinations that
+-- probe submissions and schedules can reference by ID instead of carrying the
+-- addresses inline.
+
+CREATE TABLE IF NOT EXISTS target_lists (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_hash VARCHAR(64) NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    -- Canonical destination addresses, one per line
+    targets TEXT NOT NULL,
+    target_count INTEGER NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_target_lists_user_hash
+ON target_lists (user_hash);
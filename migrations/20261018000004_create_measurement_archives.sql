@@ -0,0 +1,21 @@
+-- Archived probe sets.
+-- When probe archiving is enabled, the encoded Cap'n Proto batches of each
+-- measurement are compressed into a blob store; this table records where they
+-- are and what was submitted, for audit downloads and replays.
+
+CREATE TABLE IF NOT EXISTS measurement_archives (
+    measurement_id UUID PRIMARY KEY,
+    user_hash VARCHAR(64) NOT NULL,
+    blob_key VARCHAR(255) NOT NULL,
+    -- Serialized agent metadata (agent IDs and source IPs) of the submission
+    metadata TEXT NOT NULL,
+    probe_count INTEGER NOT NULL,
+    size_bytes BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_archives_user_hash
+ON measurement_archives (user_hash);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_archives_created_at
+ON measurement_archives (created_at);
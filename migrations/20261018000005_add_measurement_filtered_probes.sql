@@ -0,0 +1,27 @@
+-- Record how many submitted probes were filtered out before dispatch (e.g. by
+-- the destination blocklist). The count is per submission, so every agent row
+-- of a measurement carries the same value.
+
+ALTER TABLE measurement_tracking
+    ADD COLUMN IF NOT EXISTS filtered_probes INTEGER NOT NULL DEFAULT 0;
+
+-- Recreate the status view to expose the filtered count (appended last).
+DROP VIEW IF EXISTS measurement_status;
+CREATE VIEW measurement_status AS
+SELECT
+    measurement_id,
+    user_hash,
+    COUNT(*) as total_agents,
+    SUM(expected_probes) as total_expected_probes,
+    SUM(sent_probes) as total_sent_probes,
+    COUNT(*) FILTER (WHERE is_complete = TRUE) as completed_agents,
+    CASE
+        WHEN COUNT(*) FILTER (WHERE is_complete = TRUE OR cancelled = TRUE) = COUNT(*) THEN TRUE
+        ELSE FALSE
+    END as measurement_complete,
+    bool_or(cancelled) as measurement_cancelled,
+    MIN(created_at) as started_at,
+    MAX(updated_at) as last_updated,
+    MAX(filtered_probes)::BIGINT as filtered_probes
+FROM measurement_tracking
+GROUP BY measurement_id, user_hash;
@@ -0,0 +1,18 @@
+-- Multi-round measurements.
+-- A measurement opened explicitly stays open while rounds of probes are
+-- appended to it, and only ends (end_of_measurement sent to its agents) when
+-- it is closed. Per-agent progress stays in measurement_tracking, whose
+-- expected_probes grows with each round.
+
+CREATE TABLE IF NOT EXISTS multi_round_measurements (
+    measurement_id UUID PRIMARY KEY,
+    user_hash VARCHAR(64) NOT NULL,
+    -- Serialized agent metadata (agent IDs and source IPs) fixed at open
+    metadata TEXT NOT NULL,
+    rounds INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    closed_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS idx_multi_round_measurements_user_hash
+ON multi_round_measurements (user_hash);
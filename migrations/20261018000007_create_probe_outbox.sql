@@ -0,0 +1,47 @@
+-- Transactional outbox for probe batches.
+-- With the outbox enabled, a submission stores its encoded Kafka messages here
+-- in the same transaction as its measurement tracking, and a background relay
+-- publishes them. Rows are kept once sent (sent_at set).
+
+CREATE TABLE IF NOT EXISTS probe_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    measurement_id UUID NOT NULL,
+    message_key TEXT NOT NULL,
+    payload BYTEA NOT NULL,
+    -- JSON list of [key, value] Kafka header pairs
+    headers TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- Set while a relay is publishing the row
+    claimed_until TIMESTAMP WITH TIME ZONE,
+    sent_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS idx_probe_outbox_pending
+ON probe_outbox (measurement_id, id) WHERE sent_at IS NULL;
+
+-- Recreate the status view to expose the number of batches still queued
+-- (appended last).
+DROP VIEW IF EXISTS measurement_status;
+CREATE VIEW measurement_status AS
+SELECT
+    measurement_id,
+    user_hash,
+    COUNT(*) as total_agents,
+    SUM(expected_probes) as total_expected_probes,
+    SUM(sent_probes) as total_sent_probes,
+    COUNT(*) FILTER (WHERE is_complete = TRUE) as completed_agents,
+    CASE
+        WHEN COUNT(*) FILTER (WHERE is_complete = TRUE OR cancelled = TRUE) = COUNT(*) THEN TRUE
+        ELSE FALSE
+    END as measurement_complete,
+    bool_or(cancelled) as measurement_cancelled,
+    MIN(created_at) as started_at,
+    MAX(updated_at) as last_updated,
+    MAX(filtered_probes)::BIGINT as filtered_probes,
+    (SELECT COUNT(*) FROM probe_outbox o
+     WHERE o.measurement_id = measurement_tracking.measurement_id
+       AND o.sent_at IS NULL) as queued_batches
+FROM measurement_tracking
+GROUP BY measurement_id, user_hash;
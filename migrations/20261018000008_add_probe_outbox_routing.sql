@@ -0,0 +1,5 @@
+-- Destination of queued probe batches when they are routed per agent.
+-- NULL means the probes topic, partitioned by message key.
+
+ALTER TABLE probe_outbox ADD COLUMN IF NOT EXISTS kafka_topic TEXT;
+ALTER TABLE probe_outbox ADD COLUMN IF NOT EXISTS kafka_partition INTEGER;
@@ -0,0 +1,15 @@
+-- Cancellations published to agents. Cancelling a measurement adds a row per
+-- agent that had not finished; agents poll the rows addressed to them after a
+-- cursor (the row id) and acknowledge them once they have stopped probing.
+
+CREATE TABLE IF NOT EXISTS measurement_cancellations (
+    id BIGSERIAL PRIMARY KEY,
+    measurement_id UUID NOT NULL,
+    agent_id VARCHAR(255) NOT NULL,
+    requested_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    acknowledged_at TIMESTAMP WITH TIME ZONE,
+    UNIQUE(measurement_id, agent_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_cancellations_agent
+ON measurement_cancellations (agent_id, id);
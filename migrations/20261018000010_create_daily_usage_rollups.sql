@@ -0,0 +1,12 @@
+-- Daily usage rolled up from measurement_tracking. The retention job adds the
+-- usage of the measurements it purges here, by user and by the UTC day the
+-- measurement started, so usage history outlives the per-agent rows.
+
+CREATE TABLE IF NOT EXISTS daily_usage_rollups (
+    user_hash VARCHAR(64) NOT NULL,
+    day DATE NOT NULL,
+    measurements BIGINT NOT NULL DEFAULT 0,
+    expected_probes BIGINT NOT NULL DEFAULT 0,
+    sent_probes BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_hash, day)
+);
@@ -0,0 +1,12 @@
+-- Quota policies of the users whose quota differs from the gateway defaults.
+-- `windows` is the JSON list of the policy's windows, e.g.
+-- [{"period": "24h", "limit": 10000, "burst": 0}, {"period": "month", "limit": 200000, "burst": 0}].
+-- It replaces user_limits.probe_limit, which is only used for users without a
+-- row here.
+
+CREATE TABLE IF NOT EXISTS user_quotas (
+    user_hash VARCHAR(64) PRIMARY KEY,
+    windows TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,17 @@
+-- Probes held for submissions between their quota check and the creation of
+-- their measurement tracking. They count as usage, so that concurrent
+-- submissions of a user can't all pass the check. Rows are deleted when the
+-- dispatch ends; rows left over by an interrupted dispatch are dropped after
+-- a few minutes.
+
+CREATE TABLE IF NOT EXISTS quota_reservations (
+    id UUID PRIMARY KEY,
+    user_hash VARCHAR(64) NOT NULL,
+    probes BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_quota_reservations_user_hash
+ON quota_reservations (user_hash, created_at);
+CREATE INDEX IF NOT EXISTS idx_quota_reservations_created_at
+ON quota_reservations (created_at);
@@ -0,0 +1,10 @@
+-- Probes refunded to the user's quota: the probes an agent had not sent when
+-- the measurement was cancelled or the agent was lost. Probes the agent still
+-- reports as sent afterwards reduce the refund. Whether refunds lower the
+-- usage depends on the gateway's charging mode.
+
+ALTER TABLE measurement_tracking
+ADD COLUMN IF NOT EXISTS refunded_probes INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE daily_usage_rollups
+ADD COLUMN IF NOT EXISTS refunded_probes BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- The probes of a measurement are archived in segments: one per submission
+-- or round, stored under numbered blob keys next to the first. `probe_count`
+-- and `size_bytes` cover every segment.
+
+ALTER TABLE measurement_archives
+ADD COLUMN IF NOT EXISTS segments INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,183 @@
+-- Schema of the SQLite backend, equivalent to the PostgreSQL migrations up to
+-- 20261018000009. Differences from PostgreSQL:
+--   * UUIDs are 16-byte BLOBs and are generated by the gateway, not the database;
+--   * timestamps are RFC 3339 UTC TEXT and are always bound by the gateway, so
+--     that they compare in time order (there is no default, as CURRENT_TIMESTAMP
+--     uses a different format);
+--   * BOOLEAN columns hold 0 or 1, and BIGSERIAL columns are AUTOINCREMENT so
+--     that agent cursors never see a reused id;
+--   * updated_at columns are set by the queries, not by triggers.
+-- The unused probe_usage table and user_usage_stats view are not created.
+
+CREATE TABLE IF NOT EXISTS user_limits (
+    id BLOB PRIMARY KEY NOT NULL,
+    user_hash VARCHAR(64) NOT NULL UNIQUE,
+    probe_limit INTEGER NOT NULL DEFAULT 10000,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS user_id_mappings (
+    user_hash VARCHAR(64) PRIMARY KEY NOT NULL,
+    user_id INTEGER UNIQUE NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS measurement_tracking (
+    id BLOB PRIMARY KEY NOT NULL,
+    user_hash VARCHAR(64) NOT NULL,
+    measurement_id BLOB NOT NULL,
+    agent_id VARCHAR(255) NOT NULL,
+    expected_probes INTEGER NOT NULL DEFAULT 0,
+    sent_probes INTEGER NOT NULL DEFAULT 0,
+    is_complete BOOLEAN NOT NULL DEFAULT 0,
+    cancelled BOOLEAN NOT NULL DEFAULT 0,
+    filtered_probes INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+
+    UNIQUE(measurement_id, user_hash, agent_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_tracking_user_hash
+ON measurement_tracking (user_hash);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_tracking_agent_id
+ON measurement_tracking (agent_id);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_tracking_user_timestamp
+ON measurement_tracking (user_hash, created_at);
+
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_hash VARCHAR(64) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    request_hash VARCHAR(64) NOT NULL,
+    measurement_id BLOB,
+    response TEXT,
+    created_at TEXT NOT NULL,
+
+    PRIMARY KEY (user_hash, idempotency_key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_created_at
+ON idempotency_keys (created_at);
+
+CREATE TABLE IF NOT EXISTS measurement_schedules (
+    id BLOB PRIMARY KEY NOT NULL,
+    user_hash VARCHAR(64) NOT NULL,
+    name VARCHAR(255),
+    spec TEXT NOT NULL,
+    interval_seconds BIGINT,
+    next_run_at TEXT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_schedules_user_hash
+ON measurement_schedules (user_hash);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_schedules_next_run_at
+ON measurement_schedules (next_run_at)
+WHERE next_run_at IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS measurement_schedule_runs (
+    id BLOB PRIMARY KEY NOT NULL,
+    schedule_id BLOB NOT NULL REFERENCES measurement_schedules (id) ON DELETE CASCADE,
+    measurement_id BLOB,
+    error TEXT,
+    started_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_schedule_runs_schedule
+ON measurement_schedule_runs (schedule_id, started_at);
+
+CREATE TABLE IF NOT EXISTS target_lists (
+    id BLOB PRIMARY KEY NOT NULL,
+    user_hash VARCHAR(64) NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    targets TEXT NOT NULL,
+    target_count INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_target_lists_user_hash
+ON target_lists (user_hash);
+
+CREATE TABLE IF NOT EXISTS measurement_archives (
+    measurement_id BLOB PRIMARY KEY NOT NULL,
+    user_hash VARCHAR(64) NOT NULL,
+    blob_key VARCHAR(255) NOT NULL,
+    metadata TEXT NOT NULL,
+    probe_count INTEGER NOT NULL,
+    size_bytes BIGINT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_archives_user_hash
+ON measurement_archives (user_hash);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_archives_created_at
+ON measurement_archives (created_at);
+
+CREATE TABLE IF NOT EXISTS multi_round_measurements (
+    measurement_id BLOB PRIMARY KEY NOT NULL,
+    user_hash VARCHAR(64) NOT NULL,
+    metadata TEXT NOT NULL,
+    rounds INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    closed_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_multi_round_measurements_user_hash
+ON multi_round_measurements (user_hash);
+
+CREATE TABLE IF NOT EXISTS probe_outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    measurement_id BLOB NOT NULL,
+    message_key TEXT NOT NULL,
+    payload BLOB NOT NULL,
+    headers TEXT NOT NULL,
+    kafka_topic TEXT,
+    kafka_partition INTEGER,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TEXT NOT NULL,
+    claimed_until TEXT,
+    sent_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_probe_outbox_pending
+ON probe_outbox (measurement_id, id) WHERE sent_at IS NULL;
+
+CREATE TABLE IF NOT EXISTS measurement_cancellations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    measurement_id BLOB NOT NULL,
+    agent_id VARCHAR(255) NOT NULL,
+    requested_at TEXT NOT NULL,
+    acknowledged_at TEXT,
+    UNIQUE(measurement_id, agent_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_measurement_cancellations_agent
+ON measurement_cancellations (agent_id, id);
+
+-- Same columns as the PostgreSQL view
+CREATE VIEW IF NOT EXISTS measurement_status AS
+SELECT
+    measurement_id,
+    user_hash,
+    COUNT(*) as total_agents,
+    SUM(expected_probes) as total_expected_probes,
+    SUM(sent_probes) as total_sent_probes,
+    COUNT(*) FILTER (WHERE is_complete) as completed_agents,
+    COUNT(*) FILTER (WHERE is_complete OR cancelled) = COUNT(*) as measurement_complete,
+    MAX(cancelled) as measurement_cancelled,
+    MIN(created_at) as started_at,
+    MAX(updated_at) as last_updated,
+    MAX(filtered_probes) as filtered_probes,
+    (SELECT COUNT(*) FROM probe_outbox o
+     WHERE o.measurement_id = measurement_tracking.measurement_id
+       AND o.sent_at IS NULL) as queued_batches
+FROM measurement_tracking
+GROUP BY measurement_id, user_hash;
@@ -0,0 +1,10 @@
+-- Same as the PostgreSQL migration 20261018000010; days are 'YYYY-MM-DD' TEXT.
+
+CREATE TABLE IF NOT EXISTS daily_usage_rollups (
+    user_hash VARCHAR(64) NOT NULL,
+    day TEXT NOT NULL,
+    measurements BIGINT NOT NULL DEFAULT 0,
+    expected_probes BIGINT NOT NULL DEFAULT 0,
+    sent_probes BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_hash, day)
+);
@@ -0,0 +1,8 @@
+-- Same as the PostgreSQL migration 20261018000011.
+
+CREATE TABLE IF NOT EXISTS user_quotas (
+    user_hash VARCHAR(64) PRIMARY KEY NOT NULL,
+    windows TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
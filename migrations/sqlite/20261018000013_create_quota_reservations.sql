@@ -0,0 +1,13 @@
+-- Same as the PostgreSQL migration 20261018000012.
+
+CREATE TABLE IF NOT EXISTS quota_reservations (
+    id BLOB PRIMARY KEY NOT NULL,
+    user_hash VARCHAR(64) NOT NULL,
+    probes INTEGER NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_quota_reservations_user_hash
+ON quota_reservations (user_hash, created_at);
+CREATE INDEX IF NOT EXISTS idx_quota_reservations_created_at
+ON quota_reservations (created_at);
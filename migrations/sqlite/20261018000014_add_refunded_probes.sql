@@ -0,0 +1,7 @@
+-- Same as the PostgreSQL migration 20261018000013.
+
+ALTER TABLE measurement_tracking
+ADD COLUMN refunded_probes INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE daily_usage_rollups
+ADD COLUMN refunded_probes INTEGER NOT NULL DEFAULT 0;
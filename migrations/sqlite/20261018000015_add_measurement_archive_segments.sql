@@ -0,0 +1,4 @@
+-- Same as the PostgreSQL migration 20261018000014.
+
+ALTER TABLE measurement_archives
+ADD COLUMN segments INTEGER NOT NULL DEFAULT 1;
@@ -23,6 +23,25 @@ impl Agent {
             last_seen: Utc::now(),
         }
     }
+
+    /// Whether every configured instance supports the per-probe `option`
+    pub fn supports_probe_option(&self, option: &str) -> bool {
+        match &self.config {
+            Some(configs) if !configs.is_empty() => configs
+                .iter()
+                .all(|config| config.probe_options.iter().any(|o| o == option)),
+            _ => false,
+        }
+    }
+
+    /// Largest probe payload every configured instance can send, if limited
+    pub fn max_payload_size(&self) -> Option<u16> {
+        self.config
+            .iter()
+            .flatten()
+            .filter_map(|config| config.max_payload_size)
+            .min()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -60,6 +79,12 @@ pub struct AgentConfig {
     pub probing_rate: u64,
     #[serde(default = "default_caracat_rate_limiting_method")]
     pub rate_limiting_method: String,
+    /// Per-probe options this instance supports (payload_size, flow_label, probe_id, tcp_flags)
+    #[serde(default)]
+    pub probe_options: Vec<String>,
+    /// Largest probe payload this instance can send, in bytes
+    #[serde(default)]
+    pub max_payload_size: Option<u16>,
 }
 
 fn default_caracat_batch_size() -> u64 {
@@ -0,0 +1,247 @@
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::probe::{self, Probe};
+
+/// Storage backend for archived probe sets. Keys are relative, `/`-separated
+/// paths such as `measurements/<uuid>.capnp.gz`.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Blob store keeping each blob as a file under a root directory
+pub struct FilesystemBlobStore {
+    root: PathBuf,
+}
+
+impl FilesystemBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.is_empty()
+            || key
+                .split('/')
+                .any(|part| part.is_empty() || part == "." || part == "..")
+        {
+            return Err(anyhow!("Invalid blob key: {}", key));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FilesystemBlobStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write to a temporary file first so a crash never leaves a truncated blob
+        let tmp_path = path.with_extension(format!("tmp-{}", Uuid::new_v4()));
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Archive of the encoded probe batches sent for each measurement. The batches
+/// of a submission are concatenated (they are a plain stream of Cap'n Proto
+/// messages) and gzip-compressed into a blob: a segment of the measurement's
+/// archive. Multi-round measurements get a segment per round.
+#[derive(Clone)]
+pub struct ProbeArchive {
+    store: Arc<dyn BlobStore>,
+}
+
+impl ProbeArchive {
+    pub fn new(store: Arc<dyn BlobStore>) -> Self {
+        Self { store }
+    }
+
+    /// Archive backed by a directory on the local filesystem
+    pub fn filesystem(root: impl Into<PathBuf>) -> Self {
+        Self::new(Arc::new(FilesystemBlobStore::new(root)))
+    }
+
+    /// Blob key of a measurement's archived probes (its first segment)
+    pub fn key(measurement_id: Uuid) -> String {
+        format!("measurements/{}.capnp.gz", measurement_id)
+    }
+
+    /// Blob key of a segment of a measurement's archived probes, numbered from 0
+    pub fn segment_key(measurement_id: Uuid, segment: i32) -> String {
+        match segment {
+            0 => Self::key(measurement_id),
+            _ => format!("measurements/{}.{}.capnp.gz", measurement_id, segment),
+        }
+    }
+
+    /// Compress a submission's probe batches into a segment
+    pub fn compress(batches: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for batch in batches {
+            encoder.write_all(batch)?;
+        }
+        Ok(encoder.finish()?)
+    }
+
+    /// Store a compressed segment of a measurement's probes
+    pub async fn store_segment(
+        &self,
+        measurement_id: Uuid,
+        segment: i32,
+        compressed: Vec<u8>,
+    ) -> Result<()> {
+        self.store
+            .put(&Self::segment_key(measurement_id, segment), compressed)
+            .await
+    }
+
+    /// Load the first `segments` segments of a measurement, concatenated: a
+    /// gzip file with a member per segment. Segments that failed to be stored
+    /// are skipped; `None` if there is none.
+    pub async fn load(&self, measurement_id: Uuid, segments: i32) -> Result<Option<Vec<u8>>> {
+        let mut blob: Option<Vec<u8>> = None;
+        for segment in 0..segments {
+            if let Some(data) = self
+                .store
+                .get(&Self::segment_key(measurement_id, segment))
+                .await?
+            {
+                blob.get_or_insert_with(Vec::new).extend(data);
+            }
+        }
+        Ok(blob)
+    }
+
+    /// Load and decode the probes of the first `segments` segments of a measurement
+    pub async fn load_probes(
+        &self,
+        measurement_id: Uuid,
+        segments: i32,
+    ) -> Result<Option<Vec<Probe>>> {
+        let Some(compressed) = self.load(measurement_id, segments).await? else {
+            return Ok(None);
+        };
+
+        let mut encoded = Vec::new();
+        MultiGzDecoder::new(compressed.as_slice()).read_to_end(&mut encoded)?;
+        probe::decode_probes_batch(&encoded).map(Some)
+    }
+
+    /// Remove the segments of a measurement's archive
+    pub async fn delete(&self, measurement_id: Uuid, segments: i32) -> Result<()> {
+        for segment in 0..segments {
+            self.store
+                .delete(&Self::segment_key(measurement_id, segment))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_archive() -> (ProbeArchive, PathBuf) {
+        let root = std::env::temp_dir().join(format!("saimiris-archive-{}", Uuid::new_v4()));
+        (ProbeArchive::filesystem(&root), root)
+    }
+
+    #[tokio::test]
+    async fn test_archive_roundtrip() {
+        let (archive, root) = temp_archive();
+        let probes = vec![
+            json!(["2001:db8::1", 24000, 33434, 1, "udp"]),
+            json!(["2001:db8::2", 24000, 33434, 2, "icmpv6"]),
+            json!(["192.0.2.1", 24000, 80, 3, "tcp"]),
+        ];
+        // Small batch size so the probes span several batches
+        let batches = probe::deserialize_probes_batch(&probes, 64).unwrap();
+        assert!(batches.len() > 1);
+
+        let measurement_id = Uuid::new_v4();
+        let compressed = ProbeArchive::compress(&batches).unwrap();
+        assert!(!compressed.is_empty());
+        archive
+            .store_segment(measurement_id, 0, compressed)
+            .await
+            .unwrap();
+        assert!(root.join(ProbeArchive::key(measurement_id)).exists());
+
+        let decoded = archive
+            .load_probes(measurement_id, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        let decoded: Vec<_> = decoded.iter().map(Probe::to_json).collect();
+        assert_eq!(decoded, probes);
+
+        // A round is a segment of its own, decoded after the first
+        let round = vec![json!(["2001:db8::3", 24000, 33434, 4, "udp"])];
+        let batches = probe::deserialize_probes_batch(&round, 64).unwrap();
+        archive
+            .store_segment(measurement_id, 1, ProbeArchive::compress(&batches).unwrap())
+            .await
+            .unwrap();
+        let decoded = archive
+            .load_probes(measurement_id, 2)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.len(), 4);
+        assert_eq!(decoded[3].to_json(), round[0]);
+
+        // A segment that failed to be stored is skipped
+        let decoded = archive
+            .load_probes(measurement_id, 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.len(), 4);
+
+        archive.delete(measurement_id, 2).await.unwrap();
+        assert!(archive.load(measurement_id, 2).await.unwrap().is_none());
+
+        std::fs::remove_dir_all(root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_filesystem_store_rejects_escaping_keys() {
+        let (archive, root) = temp_archive();
+        assert!(archive.store.get("../etc/passwd").await.is_err());
+        assert!(archive.store.get("measurements//x").await.is_err());
+        assert!(archive.store.get("").await.is_err());
+        std::fs::remove_dir_all(root).ok();
+    }
+}
@@ -0,0 +1,452 @@
+use anyhow::{Result, anyhow};
+use ipnet::Ipv6Net;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+use uuid::Uuid;
+
+/// What to do with probes towards a blocked destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlocklistAction {
+    /// Refuse the whole submission
+    Reject,
+    /// Drop the blocked probes and send the rest
+    Filter,
+}
+
+impl FromStr for BlocklistAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(BlocklistAction::Reject),
+            "filter" => Ok(BlocklistAction::Filter),
+            _ => Err(format!("Invalid blocklist action: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: [Option<Box<TrieNode>>; 2],
+    // Set when a blocked prefix ends at this node
+    terminal: bool,
+}
+
+/// Binary trie of IPv6 prefixes. Lookups walk at most 128 nodes regardless of
+/// the number of prefixes.
+#[derive(Debug, Default)]
+pub struct PrefixTrie {
+    root: TrieNode,
+    len: usize,
+}
+
+fn bit(addr: u128, index: u8) -> usize {
+    ((addr >> (127 - index)) & 1) as usize
+}
+
+impl PrefixTrie {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a prefix. Returns false if it was already present.
+    pub fn insert(&mut self, prefix: Ipv6Net) -> bool {
+        let prefix = prefix.trunc();
+        let addr = u128::from(prefix.addr());
+        let mut node = &mut self.root;
+        for i in 0..prefix.prefix_len() {
+            node = node.children[bit(addr, i)].get_or_insert_with(Default::default);
+        }
+        if node.terminal {
+            return false;
+        }
+        node.terminal = true;
+        self.len += 1;
+        true
+    }
+
+    /// Remove a prefix. Returns false if it was not present. Nodes are left in
+    /// place, which is fine for operator-sized lists.
+    pub fn remove(&mut self, prefix: Ipv6Net) -> bool {
+        let prefix = prefix.trunc();
+        let addr = u128::from(prefix.addr());
+        let mut node = &mut self.root;
+        for i in 0..prefix.prefix_len() {
+            match node.children[bit(addr, i)].as_deref_mut() {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        if !node.terminal {
+            return false;
+        }
+        node.terminal = false;
+        self.len -= 1;
+        true
+    }
+
+    /// The shortest stored prefix containing `addr`, if any
+    pub fn lookup(&self, addr: Ipv6Addr) -> Option<Ipv6Net> {
+        let bits = u128::from(addr);
+        let mut node = &self.root;
+        for depth in 0..=128u8 {
+            if node.terminal {
+                let network = if depth == 0 {
+                    0
+                } else {
+                    bits & (u128::MAX << (128 - depth as u32))
+                };
+                return Ipv6Net::new(Ipv6Addr::from(network), depth).ok();
+            }
+            if depth == 128 {
+                break;
+            }
+            match node.children[bit(bits, depth)].as_deref() {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+        None
+    }
+
+    pub fn contains(&self, addr: Ipv6Addr) -> bool {
+        self.lookup(addr).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// All stored prefixes, in address order
+    pub fn prefixes(&self) -> Vec<Ipv6Net> {
+        fn walk(node: &TrieNode, addr: u128, depth: u8, out: &mut Vec<Ipv6Net>) {
+            if node.terminal
+                && let Ok(net) = Ipv6Net::new(Ipv6Addr::from(addr), depth)
+            {
+                out.push(net);
+            }
+            for (b, child) in node.children.iter().enumerate() {
+                if let Some(child) = child {
+                    let addr = addr | ((b as u128) << (127 - depth as u32));
+                    walk(child, addr, depth + 1, out);
+                }
+            }
+        }
+
+        let mut out = Vec::with_capacity(self.len);
+        walk(&self.root, 0, 0, &mut out);
+        out
+    }
+}
+
+/// Parse a blocklist entry: a prefix, or a single address (treated as a /128)
+pub fn parse_prefix(s: &str) -> Result<Ipv6Net> {
+    let s = s.trim();
+    if let Ok(net) = s.parse::<Ipv6Net>() {
+        return Ok(net.trunc());
+    }
+    match s.parse::<Ipv6Addr>() {
+        Ok(addr) => Ok(Ipv6Net::new(addr, 128)?),
+        Err(_) => Err(anyhow!("Invalid IPv6 prefix: {}", s)),
+    }
+}
+
+/// Parse a blocklist file: one prefix per line, `#` starts a comment
+pub fn parse_blocklist(contents: &str) -> Result<Vec<Ipv6Net>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let entry = line.split('#').next().unwrap_or("").trim();
+            (!entry.is_empty())
+                .then(|| parse_prefix(entry).map_err(|err| anyhow!("Line {}: {}", i + 1, err)))
+        })
+        .collect()
+}
+
+/// Operator-managed destination blocklist shared by all requests. When backed
+/// by a file, admin edits are written back to it so they survive restarts.
+#[derive(Clone)]
+pub struct Blocklist {
+    trie: Arc<RwLock<PrefixTrie>>,
+    path: Option<PathBuf>,
+    action: BlocklistAction,
+    // Held from an edit until the file is written, so the file follows the
+    // order of the edits
+    file_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Blocklist {
+    /// An empty, in-memory blocklist
+    pub fn new(action: BlocklistAction) -> Self {
+        Self {
+            trie: Arc::new(RwLock::new(PrefixTrie::new())),
+            path: None,
+            action,
+            file_lock: Default::default(),
+        }
+    }
+
+    /// A blocklist loaded from (and saved to) `path`. A missing file is treated
+    /// as an empty list and created on the first edit.
+    pub fn from_file(path: impl Into<PathBuf>, action: BlocklistAction) -> Result<Self> {
+        let path = path.into();
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let trie = load_trie(&path, &contents)?;
+        Ok(Self {
+            trie: Arc::new(RwLock::new(trie)),
+            path: Some(path),
+            action,
+            file_lock: Default::default(),
+        })
+    }
+
+    pub fn action(&self) -> BlocklistAction {
+        self.action
+    }
+
+    /// Re-read the backing file, replacing the in-memory list. Returns the
+    /// number of prefixes loaded.
+    pub async fn reload(&self) -> Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(self.len());
+        };
+
+        let _file = self.file_lock.lock().await;
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let trie = load_trie(path, &contents)?;
+        let len = trie.len();
+        *self.trie.write().unwrap() = trie;
+        Ok(len)
+    }
+
+    /// The blocked prefix containing `addr`, if any
+    pub fn lookup(&self, addr: Ipv6Addr) -> Option<Ipv6Net> {
+        self.trie.read().unwrap().lookup(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.trie.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn prefixes(&self) -> Vec<Ipv6Net> {
+        self.trie.read().unwrap().prefixes()
+    }
+
+    /// Add prefixes, returning how many were new
+    pub async fn add(&self, prefixes: &[Ipv6Net]) -> Result<usize> {
+        self.edit(|trie| prefixes.iter().filter(|p| trie.insert(**p)).count())
+            .await
+    }
+
+    /// Remove prefixes, returning how many were present
+    pub async fn remove(&self, prefixes: &[Ipv6Net]) -> Result<usize> {
+        self.edit(|trie| prefixes.iter().filter(|p| trie.remove(**p)).count())
+            .await
+    }
+
+    // Apply `edit`, which returns how many prefixes it changed, and write the
+    // resulting list back to the backing file
+    async fn edit(&self, edit: impl FnOnce(&mut PrefixTrie) -> usize) -> Result<usize> {
+        let _file = self.file_lock.lock().await;
+        let (changed, prefixes) = {
+            let mut trie = self.trie.write().unwrap();
+            let changed = edit(&mut trie);
+            (changed, trie.prefixes())
+        };
+        if changed > 0 {
+            self.save(&prefixes).await?;
+        }
+        Ok(changed)
+    }
+
+    // Write `prefixes` to the backing file, atomically. Callers hold the file
+    // lock.
+    async fn save(&self, prefixes: &[Ipv6Net]) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut contents = String::from("# Managed by saimiris-gateway\n");
+        for prefix in prefixes {
+            contents.push_str(&prefix.to_string());
+            contents.push('\n');
+        }
+
+        // Other gateways may share the file, so each save has its own
+        // temporary file
+        let tmp_path = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        tokio::fs::write(&tmp_path, contents).await?;
+        if let Err(err) = tokio::fs::rename(&tmp_path, path).await {
+            tokio::fs::remove_file(&tmp_path).await.ok();
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+// Parse the contents of the blocklist file at `path`
+fn load_trie(path: &Path, contents: &str) -> Result<PrefixTrie> {
+    let mut trie = PrefixTrie::new();
+    for prefix in parse_blocklist(contents)? {
+        trie.insert(prefix);
+    }
+    info!(
+        "Loaded {} blocked prefixes from {}",
+        trie.len(),
+        path.display()
+    );
+    Ok(trie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> Ipv6Net {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> Ipv6Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_prefix_trie_lookup() {
+        let mut trie = PrefixTrie::new();
+        assert!(trie.insert(net("2001:db8::/32")));
+        assert!(trie.insert(net("2001:db8:1::/48")));
+        assert!(trie.insert(net("2a00::1/128")));
+        assert!(!trie.insert(net("2001:db8::/32")));
+        assert_eq!(trie.len(), 3);
+
+        // The covering (shortest) prefix is reported
+        assert_eq!(
+            trie.lookup(addr("2001:db8:1::5")),
+            Some(net("2001:db8::/32"))
+        );
+        assert_eq!(
+            trie.lookup(addr("2001:db8:ffff::1")),
+            Some(net("2001:db8::/32"))
+        );
+        assert_eq!(trie.lookup(addr("2a00::1")), Some(net("2a00::1/128")));
+        assert!(!trie.contains(addr("2a00::2")));
+        assert!(!trie.contains(addr("2001:db9::1")));
+
+        assert!(trie.remove(net("2001:db8::/32")));
+        assert!(!trie.remove(net("2001:db8::/32")));
+        assert_eq!(
+            trie.lookup(addr("2001:db8:1::5")),
+            Some(net("2001:db8:1::/48"))
+        );
+        assert!(!trie.contains(addr("2001:db8:2::1")));
+
+        assert_eq!(
+            trie.prefixes(),
+            vec![net("2001:db8:1::/48"), net("2a00::1/128")]
+        );
+    }
+
+    #[test]
+    fn test_prefix_trie_default_route() {
+        let mut trie = PrefixTrie::new();
+        trie.insert(net("::/0"));
+        assert_eq!(trie.lookup(addr("2001:db8::1")), Some(net("::/0")));
+    }
+
+    #[test]
+    fn test_parse_blocklist() {
+        let contents =
+            "# opt-outs\n2001:db8::/32\n\n2001:db8:1::1  # single host\n2001:db8:2::1/48\n";
+        assert_eq!(
+            parse_blocklist(contents).unwrap(),
+            vec![
+                net("2001:db8::/32"),
+                net("2001:db8:1::1/128"),
+                net("2001:db8:2::/48")
+            ]
+        );
+
+        let err = parse_blocklist("2001:db8::/32\n192.0.2.0/24\n").unwrap_err();
+        assert!(err.to_string().contains("Line 2"));
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_file_roundtrip() {
+        let path =
+            std::env::temp_dir().join(format!("saimiris-blocklist-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "2001:db8::/32\n").unwrap();
+
+        let blocklist = Blocklist::from_file(&path, BlocklistAction::Filter).unwrap();
+        assert_eq!(blocklist.len(), 1);
+        assert_eq!(
+            blocklist
+                .add(&[net("2a00::/16"), net("2001:db8::/32")])
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(blocklist.remove(&[net("2001:db8::/32")]).await.unwrap(), 1);
+
+        // Edits are persisted and survive a reload
+        let reloaded = Blocklist::from_file(&path, BlocklistAction::Filter).unwrap();
+        assert_eq!(reloaded.prefixes(), vec![net("2a00::/16")]);
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_edits_are_all_saved() {
+        let path =
+            std::env::temp_dir().join(format!("saimiris-blocklist-{}", uuid::Uuid::new_v4()));
+        let blocklist = Blocklist::from_file(&path, BlocklistAction::Filter).unwrap();
+
+        let edits = (0..32u16).map(|i| {
+            let blocklist = blocklist.clone();
+            tokio::spawn(async move {
+                let prefix = Ipv6Net::new(Ipv6Addr::new(0x2001, 0xdb8, i, 0, 0, 0, 0, 0), 48);
+                blocklist.add(&[prefix.unwrap()]).await.unwrap()
+            })
+        });
+        for edit in edits {
+            assert_eq!(edit.await.unwrap(), 1);
+        }
+
+        // The file holds the final list, and no temporary file is left behind
+        let reloaded = Blocklist::from_file(&path, BlocklistAction::Filter).unwrap();
+        assert_eq!(reloaded.len(), 32);
+        assert_eq!(reloaded.prefixes(), blocklist.prefixes());
+        let leftovers = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.starts_with(&*path.file_name().unwrap().to_string_lossy())
+                    && name.ends_with(".tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -1,12 +1,24 @@
 use crate::hash_user_identifier;
-use chrono::{DateTime, Utc};
+use crate::kafka::OutboundMessage;
+use crate::quota::ChargingMode;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
 use sqlx::postgres::PgConnectOptions;
-use sqlx::{PgPool, Row};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use tracing::debug;
 use uuid::Uuid;
 
+#[cfg(test)]
+mod conformance;
+mod memory;
+mod postgres;
+mod sqlite;
+
+use memory::MemoryStorage;
+use postgres::PostgresStorage;
+use sqlite::SqliteStorage;
+pub use sqlite::is_sqlite_url;
+
 #[derive(Debug, Clone)]
 pub struct DatabaseConfig {
     pub database_url: String,
@@ -54,6 +66,10 @@ pub struct MeasurementTracking {
     pub sent_probes: i32,
     pub is_complete: bool,
     pub cancelled: bool,
+    pub filtered_probes: i32,
+    /// Unsent probes refunded to the user's quota when the measurement was
+    /// cancelled or the agent was lost
+    pub refunded_probes: i32,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -70,6 +86,213 @@ pub struct MeasurementStatus {
     pub measurement_cancelled: bool,
     pub started_at: DateTime<Utc>,
     pub last_updated: DateTime<Utc>,
+    pub filtered_probes: i64,
+    /// Probe batches not yet relayed from the outbox to Kafka
+    pub queued_batches: i64,
+}
+
+/// A claimed `Idempotency-Key` for a probe submission. `response` holds the
+/// serialized `SubmitProbesResponse` once the original request has completed,
+/// and is `None` while it is still being dispatched.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct IdempotencyRecord {
+    pub user_hash: String,
+    pub idempotency_key: String,
+    pub request_hash: String,
+    pub measurement_id: Option<Uuid>,
+    pub response: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A stored measurement spec fired by the background scheduler. `next_run_at`
+/// is `None` once a one-shot schedule has run.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct MeasurementSchedule {
+    pub id: Uuid,
+    pub user_hash: String,
+    pub name: Option<String>,
+    pub spec: String,
+    pub interval_seconds: Option<i64>,
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One firing of a schedule, with the measurement it created or why it failed
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ScheduleRun {
+    pub id: Uuid,
+    pub schedule_id: Uuid,
+    pub measurement_id: Option<Uuid>,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// A user's saved target list. The destinations themselves are loaded
+/// separately with `get_target_list_targets`, as lists can be large.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct TargetList {
+    pub id: Uuid,
+    pub user_hash: String,
+    pub name: String,
+    pub target_count: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Where a measurement's probe batches were archived, and the agent metadata
+/// (serialized `Vec<AgentMetadata>`) they were submitted with. Each round of a
+/// multi-round measurement is a segment of its own; `blob_key` is the key of
+/// the first.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct MeasurementArchive {
+    pub measurement_id: Uuid,
+    pub user_hash: String,
+    pub blob_key: String,
+    pub metadata: String,
+    pub probe_count: i32,
+    pub size_bytes: i64,
+    pub segments: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A measurement opened for rounds of probes, with the agent metadata
+/// (serialized `Vec<AgentMetadata>`) every round is sent to
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct MultiRoundMeasurement {
+    pub measurement_id: Uuid,
+    pub user_hash: String,
+    pub metadata: String,
+    pub rounds: i32,
+    pub created_at: DateTime<Utc>,
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+/// The tracking recorded for a dispatch of probes: a new measurement gets one
+/// row per agent, while a round of an open measurement adds to its rows
+#[derive(Debug, Clone)]
+pub struct DispatchTracking {
+    pub measurement_id: Uuid,
+    pub user_hash: String,
+    pub agent_ids: Vec<String>,
+    pub probes: i32,
+    pub new_measurement: bool,
+    pub filtered: i32,
+}
+
+/// A Kafka message kept in the outbox until the relay publishes it. `headers`
+/// is the JSON list of `[key, value]` header pairs.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub measurement_id: Uuid,
+    pub message_key: String,
+    pub payload: Vec<u8>,
+    pub headers: String,
+    /// Destination topic, when not the probes topic
+    pub kafka_topic: Option<String>,
+    pub kafka_partition: Option<i32>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub claimed_until: Option<DateTime<Utc>>,
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+/// A cancellation published to an agent. `id` is the cursor agents poll from.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct MeasurementCancellation {
+    pub id: i64,
+    pub measurement_id: Uuid,
+    pub agent_id: String,
+    pub requested_at: DateTime<Utc>,
+    /// Set once the agent has stopped sending the measurement's probes
+    pub acknowledged_at: Option<DateTime<Utc>>,
+}
+
+/// Measurements removed by a retention purge, or that a purge would remove
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgedMeasurements {
+    pub measurements: u64,
+    /// Per-agent tracking rows of the measurements
+    pub rows: u64,
+}
+
+/// A user's usage over one UTC day, rolled up from the tracking rows of
+/// measurements started that day once they are purged
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct DailyUsage {
+    pub user_hash: String,
+    pub day: NaiveDate,
+    pub measurements: i64,
+    pub expected_probes: i64,
+    pub sent_probes: i64,
+    pub refunded_probes: i64,
+}
+
+/// A user's quota policy, replacing the gateway defaults. `windows` is the
+/// JSON list of the policy's windows.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserQuota {
+    pub user_hash: String,
+    pub windows: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Probes a user was charged for since a point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageSince {
+    pub probes: i64,
+    /// Probes refunded in the period, whether the charging mode deducts
+    /// them or not
+    pub refunded: i64,
+    /// Oldest measurement tracked in the period, if any (rolled-up usage
+    /// has no time)
+    pub oldest: Option<DateTime<Utc>>,
+}
+
+/// Start of a quota window, and of the daily rollups counted in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsageWindowStart {
+    pub since: DateTime<Utc>,
+    pub rollup_since: NaiveDate,
+}
+
+impl UsageWindowStart {
+    /// Usage rolled up by the retention job counts by whole UTC days: a day
+    /// counts if it starts at or after `since`
+    pub fn new(since: DateTime<Utc>) -> Self {
+        let since_day = since.date_naive();
+        let rollup_since = if since == since_day.and_hms_opt(0, 0, 0).unwrap().and_utc() {
+            since_day
+        } else {
+            since_day.succ_opt().unwrap_or(since_day)
+        };
+        UsageWindowStart {
+            since,
+            rollup_since,
+        }
+    }
+}
+
+/// Probes held for a submission from its quota check until its measurement
+/// tracking exists (or its dispatch failed), so that concurrent submissions
+/// of a user can't all pass the check. They count as usage meanwhile.
+#[derive(Debug, Clone)]
+pub struct QuotaReservation {
+    pub id: Uuid,
+    pub user_hash: String,
+    pub probes: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Outcome of a quota reservation: the usage of each window before it, and
+/// the reservation if the usage admitted it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuotaCheck {
+    pub reservation: Option<Uuid>,
+    pub usage: Vec<UsageSince>,
 }
 
 /// A measurement's progress/terminal state, used for filtering.
@@ -78,6 +301,8 @@ pub enum MeasurementState {
     Complete,
     InProgress,
     Cancelled,
+    /// Probe batches still waiting in the outbox
+    Queued,
 }
 
 /// Which timestamp to order the measurement list by.
@@ -114,457 +339,574 @@ impl MeasurementListFilter {
     }
 }
 
-/// Does a measurement match one of the requested states?
-fn measurement_in_state(m: &MeasurementStatus, state: MeasurementState) -> bool {
-    match state {
-        MeasurementState::Complete => m.measurement_complete && !m.measurement_cancelled,
-        MeasurementState::InProgress => !m.measurement_complete,
-        MeasurementState::Cancelled => m.measurement_cancelled,
-    }
-}
-
-// Mock storage for testing
-#[derive(Debug, Clone)]
-pub(crate) struct MockStorage {
-    records: Arc<Mutex<Vec<ProbeUsageRecord>>>,
-    user_limits: Arc<Mutex<Vec<UserLimit>>>,
-    user_id_mappings: Arc<Mutex<HashMap<String, u32>>>,
-    measurement_tracking: Arc<Mutex<Vec<MeasurementTracking>>>,
-}
-
 const DEFAULT_PROBE_LIMIT: u32 = 10_000; // Default probe limit for users
 
-impl MockStorage {
-    fn new() -> Self {
-        Self {
-            records: Arc::new(Mutex::new(Vec::new())),
-            user_limits: Arc::new(Mutex::new(Vec::new())),
-            user_id_mappings: Arc::new(Mutex::new(HashMap::new())),
-            measurement_tracking: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
+/// Age after which a quota reservation is dropped, should its dispatch never
+/// have released it (e.g. the gateway stopped mid-dispatch)
+const QUOTA_RESERVATION_TTL_MINUTES: i64 = 10;
 
-    fn insert(&self, record: ProbeUsageRecord) {
-        let mut records = self.records.lock().unwrap();
-        records.push(record);
-    }
+/// A storage backend for the gateway's state: PostgreSQL, SQLite or memory.
+/// Methods behave as the `Database` methods of the same name, except that
+/// users are identified by their hash; the conformance suite checks that every
+/// backend gives the same answers.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Create or update the schema
+    async fn migrate(&self) -> Result<(), sqlx::Error>;
+
+    async fn ping(&self) -> Result<(), sqlx::Error>;
 
-    fn get_user_stats(
+    async fn get_user_usage_stats(
         &self,
         user_hash: &str,
         start_time: DateTime<Utc>,
         end_time: DateTime<Utc>,
-    ) -> UserUsageStats {
-        // Get stats from measurement tracking instead of probe records
-        let tracking = self.measurement_tracking.lock().unwrap();
-        let filtered_tracking: Vec<_> = tracking
-            .iter()
-            .filter(|t| {
-                t.user_hash == user_hash && t.created_at >= start_time && t.created_at <= end_time
-            })
-            .collect();
+    ) -> Result<UserUsageStats, sqlx::Error>;
 
-        // Group by measurement_id to count unique measurements
-        let mut measurements = std::collections::HashSet::new();
-        let mut total_probes = 0;
-        let mut last_submitted: Option<DateTime<Utc>> = None;
-
-        for tracking_entry in &filtered_tracking {
-            measurements.insert(&tracking_entry.measurement_id);
-            total_probes += tracking_entry.expected_probes as u32;
-            let updated_at = tracking_entry.updated_at;
-            last_submitted = Some(match last_submitted {
-                Some(existing) => existing.max(updated_at),
-                None => updated_at,
-            });
-        }
+    async fn get_recent_usage(&self, limit: i32) -> Result<Vec<ProbeUsageRecord>, sqlx::Error>;
 
-        // Get user limit from mock storage
-        let user_limits = self.user_limits.lock().unwrap();
-        let limit = user_limits
-            .iter()
-            .find(|ul| ul.user_hash == user_hash)
-            .map(|ul| ul.probe_limit)
-            .unwrap_or(DEFAULT_PROBE_LIMIT);
+    async fn set_user_limit(
+        &self,
+        user_hash: &str,
+        probe_limit: u32,
+    ) -> Result<UserLimit, sqlx::Error>;
 
-        UserUsageStats {
-            user_hash: user_hash.to_string(),
-            submission_count: measurements.len() as u32,
-            total_probes,
-            limit,
-            last_submitted,
-        }
-    }
+    async fn get_user_limit(&self, user_hash: &str) -> Result<Option<UserLimit>, sqlx::Error>;
 
-    fn set_user_limit(&self, user_hash: &str, probe_limit: u32) -> UserLimit {
-        let mut user_limits = self.user_limits.lock().unwrap();
-        let now = Utc::now();
+    async fn get_user_id_by_hash(&self, user_hash: &str) -> Result<Option<u32>, sqlx::Error>;
 
-        // Check if user limit already exists
-        if let Some(existing) = user_limits.iter_mut().find(|ul| ul.user_hash == user_hash) {
-            existing.probe_limit = probe_limit;
-            existing.updated_at = now;
-            existing.clone()
-        } else {
-            // Create new user limit
-            let new_limit = UserLimit {
-                id: Uuid::new_v4(),
-                user_hash: user_hash.to_string(),
-                probe_limit,
-                created_at: now,
-                updated_at: now,
-            };
-            user_limits.push(new_limit.clone());
-            new_limit
-        }
-    }
+    async fn create_user_id_mapping(
+        &self,
+        user_hash: &str,
+        user_id: u32,
+    ) -> Result<(), sqlx::Error>;
 
-    fn get_user_limit(&self, user_hash: &str) -> Option<UserLimit> {
-        let user_limits = self.user_limits.lock().unwrap();
-        user_limits
-            .iter()
-            .find(|ul| ul.user_hash == user_hash)
-            .cloned()
-    }
-
-    fn get_recent(&self, limit: i32) -> Vec<ProbeUsageRecord> {
-        // Get recent measurements from tracking data
-        let tracking = self.measurement_tracking.lock().unwrap();
-
-        // Group by measurement_id and sum probe counts
-        let mut measurement_map: std::collections::HashMap<(Uuid, String), (i32, DateTime<Utc>)> =
-            std::collections::HashMap::new();
-        for entry in tracking.iter() {
-            let key = (entry.measurement_id, entry.user_hash.clone());
-            measurement_map
-                .entry(key)
-                .and_modify(|(probe_count, timestamp)| {
-                    *probe_count += entry.sent_probes;
-                    *timestamp = (*timestamp).max(entry.updated_at);
-                })
-                .or_insert((entry.sent_probes, entry.updated_at));
-        }
+    async fn create_measurement_tracking(
+        &self,
+        user_hash: &str,
+        measurement_id: Uuid,
+        agent_id: &str,
+        expected_probes: i32,
+    ) -> Result<MeasurementTracking, sqlx::Error>;
 
-        // Convert to ProbeUsageRecord format
-        let mut records: Vec<_> = measurement_map
-            .into_iter()
-            .map(
-                |((measurement_id, user_hash), (probe_count, timestamp))| ProbeUsageRecord {
-                    id: measurement_id,
-                    user_hash,
-                    probe_count,
-                    timestamp,
-                },
-            )
-            .collect();
+    async fn update_measurement_probe_count(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_id: &str,
+        sent_probes: i32,
+        is_complete: bool,
+    ) -> Result<MeasurementTracking, sqlx::Error>;
 
-        records.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-        records.into_iter().take(limit as usize).collect()
-    }
-}
+    async fn advance_measurement_progress(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+        sent_probes: i32,
+        is_complete: bool,
+    ) -> Result<bool, sqlx::Error>;
 
-#[derive(Clone)]
-#[allow(private_interfaces)]
-pub enum DatabaseImpl {
-    Real(PgPool),
-    Mock(MockStorage),
-}
+    async fn add_measurement_expected_probes(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_id: &str,
+        probes: i32,
+    ) -> Result<bool, sqlx::Error>;
 
-#[derive(Clone)]
-pub struct Database {
-    impl_: DatabaseImpl,
-}
+    async fn add_measurement_filtered_probes(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        filtered_probes: i32,
+    ) -> Result<(), sqlx::Error>;
 
-/// Build Postgres connection options from a URL, working around a regression in
-/// sqlx 0.9: a bracketed IPv6 literal host (e.g. `postgresql://[2a06:de00:…]:5432/db`)
-/// is stored — and handed to the resolver — with the `[ ]` still attached, so
-/// getaddrinfo fails with "Name or service not known". sqlx 0.8 trimmed the brackets
-/// in `connect_tcp`; 0.9 dropped that, so we trim them here before connecting.
-/// See nxthdr/saimiris-gateway#55 (and the sister regression peerlab-gateway#97).
-fn connect_options(database_url: &str) -> Result<PgConnectOptions, sqlx::Error> {
-    let options: PgConnectOptions = database_url.parse()?;
-    let host = options.get_host();
-    let unbracketed = host.trim_matches(|c| c == '[' || c == ']');
-    if unbracketed.len() == host.len() {
-        Ok(options)
-    } else {
-        let unbracketed = unbracketed.to_string();
-        Ok(options.host(&unbracketed))
-    }
-}
+    async fn get_measurement_status(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementStatus>, sqlx::Error>;
 
-/// Render a log-safe description of the database target — host, port, and database
-/// name only — so the password embedded in the connection URL is never logged.
-/// Falls back to a placeholder if the URL can't be parsed.
-pub fn safe_database_target(database_url: &str) -> String {
-    let Ok(options) = connect_options(database_url) else {
-        return "<unparseable database url>".to_string();
-    };
-    let host = options.get_host();
-    let host = if host.contains(':') {
-        format!("[{host}]")
-    } else {
-        host.to_string()
-    };
-    match options.get_database() {
-        Some(db) => format!("{host}:{}/{db}", options.get_port()),
-        None => format!("{host}:{}", options.get_port()),
-    }
-}
+    async fn list_user_measurements(
+        &self,
+        user_hash: &str,
+        filter: &MeasurementListFilter,
+    ) -> Result<Vec<MeasurementStatus>, sqlx::Error>;
 
-impl Database {
-    pub async fn new(config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
-        let pool = PgPool::connect_with(connect_options(&config.database_url)?).await?;
-        Ok(Self {
-            impl_: DatabaseImpl::Real(pool),
-        })
-    }
+    async fn cancel_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<u64, sqlx::Error>;
 
-    /// Create a mock database for testing (doesn't require PostgreSQL)
-    pub fn new_mock() -> Self {
-        Self {
-            impl_: DatabaseImpl::Mock(MockStorage::new()),
-        }
-    }
+    async fn get_agent_cancellations(
+        &self,
+        agent_id: &str,
+        after: i64,
+        limit: i32,
+    ) -> Result<Vec<MeasurementCancellation>, sqlx::Error>;
 
-    pub async fn initialize(&self) -> Result<(), sqlx::Error> {
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                debug!("Running database migrations...");
-
-                // Run sqlx migrations
-                sqlx::migrate!("./migrations").run(pool).await?;
-
-                debug!("Database migrations completed successfully");
-                Ok(())
-            }
-            DatabaseImpl::Mock(_) => {
-                debug!("Mock database initialized (no-op)");
-                Ok(())
-            }
-        }
-    }
+    async fn get_measurement_cancellations(
+        &self,
+        measurement_id: Uuid,
+    ) -> Result<Vec<MeasurementCancellation>, sqlx::Error>;
 
-    /// Record probe usage by updating measurement tracking (replaces old probe_usage table)
-    /// This should be called when probes are submitted to initialize the measurement tracking
-    pub async fn record_probe_usage(
+    async fn acknowledge_cancellation(
         &self,
-        user_id: &str,
         measurement_id: Uuid,
-        probe_count: i32,
-    ) -> Result<ProbeUsageRecord, sqlx::Error> {
-        let user_hash = hash_user_identifier(user_id);
-        let timestamp = Utc::now();
+        agent_id: &str,
+    ) -> Result<Option<MeasurementCancellation>, sqlx::Error>;
 
-        // This method is now deprecated in favor of measurement tracking
-        // But we keep it for compatibility. It returns a synthetic record.
-        let record = ProbeUsageRecord {
-            id: measurement_id,
-            user_hash: user_hash.clone(),
-            probe_count,
-            timestamp,
-        };
+    async fn delete_measurement_tracking(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<u64, sqlx::Error>;
 
-        match &self.impl_ {
-            DatabaseImpl::Real(_pool) => {
-                // In the real implementation, this is handled by measurement tracking
-                // This method is kept for backward compatibility but doesn't do actual DB operations
-                debug!(
-                    "Probe usage recorded via measurement tracking: user_hash={}, probe_count={}, timestamp={}",
-                    user_hash, probe_count, timestamp
-                );
-            }
-            DatabaseImpl::Mock(storage) => {
-                storage.insert(record.clone());
-                debug!(
-                    "Mock recorded probe usage: user_hash={}, probe_count={}, timestamp={}",
-                    user_hash, probe_count, timestamp
-                );
-            }
-        }
+    async fn get_measurement_tracking(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Vec<MeasurementTracking>, sqlx::Error>;
 
-        Ok(record)
-    }
+    async fn get_measurement_tracking_by_agent(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+    ) -> Result<Option<MeasurementTracking>, sqlx::Error>;
 
-    pub async fn get_user_usage_stats(
+    async fn claim_idempotency_key(
         &self,
-        user_id: &str,
-        start_time: Option<DateTime<Utc>>,
-        end_time: Option<DateTime<Utc>>,
-    ) -> Result<UserUsageStats, sqlx::Error> {
-        let user_hash = hash_user_identifier(user_id);
+        user_hash: &str,
+        idempotency_key: &str,
+        request_hash: &str,
+        expired_before: DateTime<Utc>,
+        abandoned_before: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, sqlx::Error>;
 
-        // Default to today's usage (from start of day to now)
-        let now = Utc::now();
-        let today_start = now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
-        let start_time = start_time.unwrap_or(today_start);
-        let end_time = end_time.unwrap_or(now);
+    async fn complete_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+        measurement_id: Uuid,
+        response: &str,
+    ) -> Result<(), sqlx::Error>;
 
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                // First get the user's limit
-                let limit_row = sqlx::query(
-                    r#"
-                    SELECT probe_limit FROM user_limits WHERE user_hash = $1
-                    "#,
-                )
-                .bind(&user_hash)
-                .fetch_optional(pool)
-                .await?;
-
-                let limit = limit_row
-                    .map(|row| row.get::<i32, _>("probe_limit") as u32)
-                    .unwrap_or(DEFAULT_PROBE_LIMIT); // Default limit if not found
-
-                // Get usage stats from measurement tracking table
-                let row = sqlx::query(
-                    r#"
-                    SELECT
-                        COUNT(DISTINCT measurement_id) as submission_count,
-                        COALESCE(SUM(expected_probes), 0) as total_probes,
-                        MAX(updated_at) as last_submitted
-                    FROM measurement_tracking
-                    WHERE user_hash = $1
-                    AND created_at >= $2
-                    AND created_at <= $3
-                    "#,
-                )
-                .bind(&user_hash)
-                .bind(start_time)
-                .bind(end_time)
-                .fetch_one(pool)
-                .await?;
-
-                Ok(UserUsageStats {
-                    user_hash,
-                    submission_count: row.get::<i64, _>("submission_count") as u32,
-                    total_probes: row.get::<i64, _>("total_probes") as u32,
-                    limit,
-                    last_submitted: row.get::<Option<DateTime<Utc>>, _>("last_submitted"),
-                })
-            }
-            DatabaseImpl::Mock(storage) => {
-                let stats = storage.get_user_stats(&user_hash, start_time, end_time);
-                Ok(stats)
-            }
-        }
-    }
+    async fn release_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+    ) -> Result<(), sqlx::Error>;
 
-    pub async fn get_recent_usage(
+    async fn create_schedule(
         &self,
-        limit: Option<i32>,
-    ) -> Result<Vec<ProbeUsageRecord>, sqlx::Error> {
-        let limit = limit.unwrap_or(100);
-
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let rows = sqlx::query(
-                    r#"
-                    SELECT
-                        measurement_id as id,
-                        user_hash,
-                        SUM(sent_probes) as probe_count,
-                        MAX(updated_at) as timestamp
-                    FROM measurement_tracking
-                    GROUP BY measurement_id, user_hash
-                    ORDER BY timestamp DESC
-                    LIMIT $1
-                    "#,
-                )
-                .bind(limit)
-                .fetch_all(pool)
-                .await?;
-
-                let mut records = Vec::new();
-                for row in rows {
-                    records.push(ProbeUsageRecord {
-                        id: row.get("id"),
-                        user_hash: row.get("user_hash"),
-                        probe_count: row.get::<i64, _>("probe_count") as i32,
-                        timestamp: row.get("timestamp"),
-                    });
-                }
-
-                Ok(records)
-            }
-            DatabaseImpl::Mock(storage) => {
-                let records = storage.get_recent(limit);
-                Ok(records)
-            }
-        }
-    }
+        user_hash: &str,
+        name: Option<&str>,
+        spec: &str,
+        interval_seconds: Option<i64>,
+        next_run_at: DateTime<Utc>,
+    ) -> Result<MeasurementSchedule, sqlx::Error>;
 
-    /// Set a user's probe limit
-    pub async fn set_user_limit(
+    async fn list_user_schedules(
+        &self,
+        user_hash: &str,
+    ) -> Result<Vec<MeasurementSchedule>, sqlx::Error>;
+
+    async fn get_schedule(
+        &self,
+        schedule_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementSchedule>, sqlx::Error>;
+
+    async fn delete_schedule(
+        &self,
+        schedule_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error>;
+
+    async fn get_due_schedules(
+        &self,
+        now: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<Vec<MeasurementSchedule>, sqlx::Error>;
+
+    async fn advance_schedule(
+        &self,
+        schedule_id: Uuid,
+        expected_run_at: DateTime<Utc>,
+        next_run_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, sqlx::Error>;
+
+    async fn record_schedule_run(
+        &self,
+        schedule_id: Uuid,
+        measurement_id: Option<Uuid>,
+        error: Option<&str>,
+    ) -> Result<ScheduleRun, sqlx::Error>;
+
+    async fn list_schedule_runs(
+        &self,
+        schedule_id: Uuid,
+        limit: i32,
+    ) -> Result<Vec<ScheduleRun>, sqlx::Error>;
+
+    async fn create_target_list(
+        &self,
+        user_hash: &str,
+        name: &str,
+        targets: &[String],
+    ) -> Result<TargetList, sqlx::Error>;
+
+    async fn list_user_target_lists(&self, user_hash: &str)
+    -> Result<Vec<TargetList>, sqlx::Error>;
+
+    async fn get_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<TargetList>, sqlx::Error>;
+
+    async fn get_target_list_targets(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<Vec<String>>, sqlx::Error>;
+
+    async fn update_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+        name: Option<&str>,
+        targets: Option<&[String]>,
+    ) -> Result<Option<TargetList>, sqlx::Error>;
+
+    async fn delete_target_list(&self, list_id: Uuid, user_hash: &str)
+    -> Result<bool, sqlx::Error>;
+
+    async fn append_measurement_archive(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        blob_key: &str,
+        metadata: &str,
+        probe_count: i32,
+        size_bytes: i64,
+    ) -> Result<MeasurementArchive, sqlx::Error>;
+
+    async fn get_measurement_archive(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementArchive>, sqlx::Error>;
+
+    async fn create_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_ids: &[String],
+        metadata: &str,
+    ) -> Result<MultiRoundMeasurement, sqlx::Error>;
+
+    async fn get_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MultiRoundMeasurement>, sqlx::Error>;
+
+    async fn record_measurement_round(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<i32>, sqlx::Error>;
+
+    async fn close_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error>;
+
+    async fn record_dispatch(
+        &self,
+        tracking: &DispatchTracking,
+        messages: &[OutboundMessage],
+    ) -> Result<(), sqlx::Error>;
+
+    async fn revert_dispatch(
+        &self,
+        tracking: &DispatchTracking,
+        delivered: &[i32],
+    ) -> Result<(), sqlx::Error>;
+
+    async fn claim_outbox_messages(
+        &self,
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<Vec<OutboxMessage>, sqlx::Error>;
+
+    async fn mark_outbox_sent(&self, ids: &[i64]) -> Result<(), sqlx::Error>;
+
+    async fn record_outbox_failure(&self, ids: &[i64], error: &str) -> Result<(), sqlx::Error>;
+
+    async fn get_usage_since(
+        &self,
+        user_hash: &str,
+        since: DateTime<Utc>,
+        rollup_since: NaiveDate,
+        charging: ChargingMode,
+    ) -> Result<UsageSince, sqlx::Error>;
+
+    async fn reserve_quota(
+        &self,
+        reservation: &QuotaReservation,
+        windows: &[UsageWindowStart],
+        charging: ChargingMode,
+        stale_before: DateTime<Utc>,
+        admit: &(dyn for<'u> Fn(&'u [UsageSince]) -> bool + Send + Sync),
+    ) -> Result<QuotaCheck, sqlx::Error>;
+
+    async fn release_quota_reservation(&self, reservation_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn refund_lost_measurements(
+        &self,
+        idle_before: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error>;
+
+    async fn get_user_quota(&self, user_hash: &str) -> Result<Option<UserQuota>, sqlx::Error>;
+
+    async fn set_user_quota(
+        &self,
+        user_hash: &str,
+        windows: &str,
+    ) -> Result<UserQuota, sqlx::Error>;
+
+    async fn delete_user_quota(&self, user_hash: &str) -> Result<bool, sqlx::Error>;
+
+    async fn count_expired_measurements(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<PurgedMeasurements, sqlx::Error>;
+
+    async fn purge_expired_measurements(
+        &self,
+        before: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<PurgedMeasurements, sqlx::Error>;
+
+    async fn get_daily_usage(
+        &self,
+        user_hash: &str,
+        since: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, sqlx::Error>;
+
+    async fn count_expired_probe_usage(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error>;
+
+    async fn purge_expired_probe_usage(
+        &self,
+        before: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<u64, sqlx::Error>;
+}
+
+#[derive(Clone)]
+pub struct Database {
+    storage: Arc<dyn Storage>,
+}
+
+/// Build Postgres connection options from a URL, working around a regression in
+/// sqlx 0.9: a bracketed IPv6 literal host (e.g. `postgresql://[2a06:de00:…]:5432/db`)
+/// is stored — and handed to the resolver — with the `[ ]` still attached, so
+/// getaddrinfo fails with "Name or service not known". sqlx 0.8 trimmed the brackets
+/// in `connect_tcp`; 0.9 dropped that, so we trim them here before connecting.
+/// See nxthdr/saimiris-gateway#55 (and the sister regression peerlab-gateway#97).
+fn connect_options(database_url: &str) -> Result<PgConnectOptions, sqlx::Error> {
+    let options: PgConnectOptions = database_url.parse()?;
+    let host = options.get_host();
+    let unbracketed = host.trim_matches(|c| c == '[' || c == ']');
+    if unbracketed.len() == host.len() {
+        Ok(options)
+    } else {
+        let unbracketed = unbracketed.to_string();
+        Ok(options.host(&unbracketed))
+    }
+}
+
+/// Render a log-safe description of the database target — host, port, and database
+/// name only — so the password embedded in the connection URL is never logged.
+/// Falls back to a placeholder if the URL can't be parsed. A SQLite URL is a
+/// file path and carries no credentials, only its query parameters are dropped.
+pub fn safe_database_target(database_url: &str) -> String {
+    if is_sqlite_url(database_url) {
+        return database_url
+            .split('?')
+            .next()
+            .unwrap_or(database_url)
+            .to_string();
+    }
+    let Ok(options) = connect_options(database_url) else {
+        return "<unparseable database url>".to_string();
+    };
+    let host = options.get_host();
+    let host = if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    };
+    match options.get_database() {
+        Some(db) => format!("{host}:{}/{db}", options.get_port()),
+        None => format!("{host}:{}", options.get_port()),
+    }
+}
+
+/// Turn the violation of a user ID mapping's unique constraints into the
+/// `AlreadyExists` error callers retry on
+fn user_id_mapping_conflict(err: sqlx::Error) -> sqlx::Error {
+    let sqlx::Error::Database(db_err) = err else {
+        return err;
+    };
+    // Check if this is a constraint violation and provide better context
+    let error_message = db_err.message();
+    if error_message.contains("user_id_mappings_pkey") || error_message.contains("user_hash") {
+        // Primary key violation on user_hash - another request created this user
+        sqlx::Error::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "UNIQUE constraint failed: user_hash already exists",
+        ))
+    } else if error_message.contains("user_id") {
+        // Unique constraint violation on user_id - need to try different ID
+        sqlx::Error::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "UNIQUE constraint failed: user_id already exists",
+        ))
+    } else {
+        // Re-throw the original error for other database issues
+        sqlx::Error::Database(db_err)
+    }
+}
+
+impl Database {
+    /// Connect to the database, to SQLite for a `sqlite:` URL and to PostgreSQL
+    /// otherwise
+    pub async fn new(config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
+        if is_sqlite_url(&config.database_url) {
+            return Ok(Self::with_storage(
+                SqliteStorage::connect(&config.database_url).await?,
+            ));
+        }
+        Ok(Self::with_storage(
+            PostgresStorage::connect(&config.database_url).await?,
+        ))
+    }
+
+    /// Create a mock database for testing (doesn't require PostgreSQL)
+    pub fn new_mock() -> Self {
+        Self::with_storage(MemoryStorage::new())
+    }
+
+    /// Use the given storage backend
+    pub fn with_storage(storage: impl Storage + 'static) -> Self {
+        Self {
+            storage: Arc::new(storage),
+        }
+    }
+
+    pub async fn initialize(&self) -> Result<(), sqlx::Error> {
+        debug!("Running database migrations...");
+        self.storage.migrate().await?;
+        debug!("Database migrations completed successfully");
+        Ok(())
+    }
+
+    /// Check that the database answers queries
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        self.storage.ping().await
+    }
+
+    /// Record probe usage. Usage is derived from the measurement tracking, so
+    /// this stores nothing and returns a synthetic record; it is kept for
+    /// backward compatibility.
+    pub async fn record_probe_usage(
+        &self,
+        user_id: &str,
+        measurement_id: Uuid,
+        probe_count: i32,
+    ) -> Result<ProbeUsageRecord, sqlx::Error> {
+        let user_hash = hash_user_identifier(user_id);
+        self.record_probe_usage_by_hash(&user_hash, measurement_id, probe_count)
+            .await
+    }
+
+    /// Record probe usage of the user with hash `user_hash`, like
+    /// `record_probe_usage`
+    pub async fn record_probe_usage_by_hash(
+        &self,
+        user_hash: &str,
+        measurement_id: Uuid,
+        probe_count: i32,
+    ) -> Result<ProbeUsageRecord, sqlx::Error> {
+        let timestamp = Utc::now();
+
+        debug!(
+            "Probe usage recorded via measurement tracking: user_hash={}, probe_count={}, timestamp={}",
+            user_hash, probe_count, timestamp
+        );
+
+        Ok(ProbeUsageRecord {
+            id: measurement_id,
+            user_hash: user_hash.to_string(),
+            probe_count,
+            timestamp,
+        })
+    }
+
+    pub async fn get_user_usage_stats(
+        &self,
+        user_id: &str,
+        start_time: Option<DateTime<Utc>>,
+        end_time: Option<DateTime<Utc>>,
+    ) -> Result<UserUsageStats, sqlx::Error> {
+        let user_hash = hash_user_identifier(user_id);
+
+        // Default to today's usage (from start of day to now)
+        let now = Utc::now();
+        let today_start = now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let start_time = start_time.unwrap_or(today_start);
+        let end_time = end_time.unwrap_or(now);
+
+        self.storage
+            .get_user_usage_stats(&user_hash, start_time, end_time)
+            .await
+    }
+
+    pub async fn get_recent_usage(
+        &self,
+        limit: Option<i32>,
+    ) -> Result<Vec<ProbeUsageRecord>, sqlx::Error> {
+        self.storage.get_recent_usage(limit.unwrap_or(100)).await
+    }
+
+    /// Set a user's probe limit
+    pub async fn set_user_limit(
         &self,
         user_id: &str,
         probe_limit: u32,
     ) -> Result<UserLimit, sqlx::Error> {
         let user_hash = hash_user_identifier(user_id);
-
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let row = sqlx::query(
-                    r#"
-                    INSERT INTO user_limits (user_hash, probe_limit)
-                    VALUES ($1, $2)
-                    ON CONFLICT (user_hash)
-                    DO UPDATE SET
-                        probe_limit = EXCLUDED.probe_limit,
-                        updated_at = NOW()
-                    RETURNING id, user_hash, probe_limit, created_at, updated_at
-                    "#,
-                )
-                .bind(&user_hash)
-                .bind(probe_limit as i32)
-                .fetch_one(pool)
-                .await?;
-
-                Ok(UserLimit {
-                    id: row.get("id"),
-                    user_hash: row.get("user_hash"),
-                    probe_limit: row.get::<i32, _>("probe_limit") as u32,
-                    created_at: row.get("created_at"),
-                    updated_at: row.get("updated_at"),
-                })
-            }
-            DatabaseImpl::Mock(storage) => {
-                let limit = storage.set_user_limit(&user_hash, probe_limit);
-                Ok(limit)
-            }
-        }
+        self.storage.set_user_limit(&user_hash, probe_limit).await
     }
 
     /// Get a user's probe limit
     pub async fn get_user_limit(&self, user_id: &str) -> Result<Option<UserLimit>, sqlx::Error> {
         let user_hash = hash_user_identifier(user_id);
+        self.get_user_limit_by_hash(&user_hash).await
+    }
 
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let row = sqlx::query(
-                    r#"
-                    SELECT id, user_hash, probe_limit, created_at, updated_at
-                    FROM user_limits
-                    WHERE user_hash = $1
-                    "#,
-                )
-                .bind(&user_hash)
-                .fetch_optional(pool)
-                .await?;
-
-                Ok(row.map(|r| UserLimit {
-                    id: r.get("id"),
-                    user_hash: r.get("user_hash"),
-                    probe_limit: r.get::<i32, _>("probe_limit") as u32,
-                    created_at: r.get("created_at"),
-                    updated_at: r.get("updated_at"),
-                }))
-            }
-            DatabaseImpl::Mock(storage) => {
-                let limit = storage.get_user_limit(&user_hash);
-                Ok(limit)
-            }
-        }
+    /// Get the probe limit of the user with hash `user_hash`
+    pub async fn get_user_limit_by_hash(
+        &self,
+        user_hash: &str,
+    ) -> Result<Option<UserLimit>, sqlx::Error> {
+        self.storage.get_user_limit(user_hash).await
     }
 
     /// Check if a user can submit additional probes (daily rate limiting)
@@ -589,22 +931,91 @@ impl Database {
         Ok(stats.total_probes + additional_probes <= stats.limit)
     }
 
+    /// Probes a user was charged for since `since`, reservations included.
+    /// Usage rolled up by the retention job counts by whole UTC days: a day
+    /// counts if it starts at or after `since`.
+    pub async fn get_usage_since(
+        &self,
+        user_hash: &str,
+        since: DateTime<Utc>,
+        charging: ChargingMode,
+    ) -> Result<UsageSince, sqlx::Error> {
+        let start = UsageWindowStart::new(since);
+        self.storage
+            .get_usage_since(user_hash, start.since, start.rollup_since, charging)
+            .await
+    }
+
+    /// Reserve `probes` for a user if `admit` accepts the usage since each of
+    /// `windows`. The check and the reservation are atomic: concurrent
+    /// reservations of the user wait for each other. Reservations left over
+    /// for longer than the reservation TTL are dropped on the way.
+    pub async fn reserve_quota(
+        &self,
+        user_hash: &str,
+        probes: i64,
+        windows: &[DateTime<Utc>],
+        charging: ChargingMode,
+        admit: &(dyn for<'u> Fn(&'u [UsageSince]) -> bool + Send + Sync),
+    ) -> Result<QuotaCheck, sqlx::Error> {
+        let now = Utc::now();
+        let reservation = QuotaReservation {
+            id: Uuid::new_v4(),
+            user_hash: user_hash.to_string(),
+            probes,
+            created_at: now,
+        };
+        let windows: Vec<_> = windows
+            .iter()
+            .map(|&since| UsageWindowStart::new(since))
+            .collect();
+        let stale_before = now - chrono::Duration::minutes(QUOTA_RESERVATION_TTL_MINUTES);
+        self.storage
+            .reserve_quota(&reservation, &windows, charging, stale_before, admit)
+            .await
+    }
+
+    /// Release a quota reservation, once its probes are tracked or were not
+    /// dispatched. Returns false if it was already gone.
+    pub async fn release_quota_reservation(
+        &self,
+        reservation_id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        self.storage.release_quota_reservation(reservation_id).await
+    }
+
+    /// Refund the probes lost agents have not sent to the users of their
+    /// running measurements. An agent is lost when none of its tracking rows
+    /// was updated since `idle_before`. Returns the tracking rows refunded.
+    pub async fn refund_lost_measurements(
+        &self,
+        idle_before: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        self.storage.refund_lost_measurements(idle_before).await
+    }
+
+    /// Get a user's quota policy, if it differs from the defaults
+    pub async fn get_user_quota(&self, user_hash: &str) -> Result<Option<UserQuota>, sqlx::Error> {
+        self.storage.get_user_quota(user_hash).await
+    }
+
+    /// Set a user's quota policy (JSON list of windows)
+    pub async fn set_user_quota(
+        &self,
+        user_hash: &str,
+        windows: &str,
+    ) -> Result<UserQuota, sqlx::Error> {
+        self.storage.set_user_quota(user_hash, windows).await
+    }
+
+    /// Remove a user's quota policy, so that the defaults apply again
+    pub async fn delete_user_quota(&self, user_hash: &str) -> Result<bool, sqlx::Error> {
+        self.storage.delete_user_quota(user_hash).await
+    }
+
     /// Get user ID by user hash from the database
     pub async fn get_user_id_by_hash(&self, user_hash: &str) -> Result<Option<u32>, sqlx::Error> {
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let row = sqlx::query("SELECT user_id FROM user_id_mappings WHERE user_hash = $1")
-                    .bind(user_hash)
-                    .fetch_optional(pool)
-                    .await?;
-
-                Ok(row.map(|row| row.get::<i32, _>("user_id") as u32))
-            }
-            DatabaseImpl::Mock(storage) => {
-                let mappings = storage.user_id_mappings.lock().unwrap();
-                Ok(mappings.get(user_hash).copied())
-            }
-        }
+        self.storage.get_user_id_by_hash(user_hash).await
     }
 
     /// Create a new user ID mapping in the database
@@ -613,58 +1024,9 @@ impl Database {
         user_hash: &str,
         user_id: u32,
     ) -> Result<(), sqlx::Error> {
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                match sqlx::query("INSERT INTO user_id_mappings (user_hash, user_id, created_at) VALUES ($1, $2, $3)")
-                    .bind(user_hash)
-                    .bind(user_id as i32)
-                    .bind(chrono::Utc::now())
-                    .execute(pool)
-                    .await
-                {
-                    Ok(_) => Ok(()),
-                    Err(sqlx::Error::Database(db_err)) => {
-                        // Check if this is a constraint violation and provide better context
-                        let error_message = db_err.message();
-                        if error_message.contains("user_id_mappings_pkey") || error_message.contains("user_hash") {
-                            // Primary key violation on user_hash - another request created this user
-                            return Err(sqlx::Error::Io(std::io::Error::new(
-                                std::io::ErrorKind::AlreadyExists,
-                                "UNIQUE constraint failed: user_hash already exists",
-                            )));
-                        } else if error_message.contains("user_id") {
-                            // Unique constraint violation on user_id - need to try different ID
-                            return Err(sqlx::Error::Io(std::io::Error::new(
-                                std::io::ErrorKind::AlreadyExists,
-                                "UNIQUE constraint failed: user_id already exists",
-                            )));
-                        }
-                        // Re-throw the original error for other database issues
-                        Err(sqlx::Error::Database(db_err))
-                    }
-                    Err(e) => Err(e),
-                }
-            }
-            DatabaseImpl::Mock(storage) => {
-                let mut mappings = storage.user_id_mappings.lock().unwrap();
-                if mappings.contains_key(user_hash) {
-                    // Simulate user_hash primary key violation
-                    return Err(sqlx::Error::Io(std::io::Error::new(
-                        std::io::ErrorKind::AlreadyExists,
-                        "UNIQUE constraint failed: user_hash already exists",
-                    )));
-                }
-                if mappings.values().any(|&v| v == user_id) {
-                    // Simulate user_id unique constraint violation
-                    return Err(sqlx::Error::Io(std::io::Error::new(
-                        std::io::ErrorKind::AlreadyExists,
-                        "UNIQUE constraint failed: user_id already exists",
-                    )));
-                }
-                mappings.insert(user_hash.to_string(), user_id);
-                Ok(())
-            }
-        }
+        self.storage
+            .create_user_id_mapping(user_hash, user_id)
+            .await
     }
 
     /// Create a new measurement tracking entry
@@ -675,420 +1037,560 @@ impl Database {
         agent_id: &str,
         expected_probes: i32,
     ) -> Result<MeasurementTracking, sqlx::Error> {
-        let now = Utc::now();
+        self.storage
+            .create_measurement_tracking(user_hash, measurement_id, agent_id, expected_probes)
+            .await
+    }
 
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let record = sqlx::query_as::<_, MeasurementTracking>(
-                    r#"INSERT INTO measurement_tracking
-                       (user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, created_at, updated_at)
-                       VALUES ($1, $2, $3, $4, 0, false, $5, $5)
-                       RETURNING id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, cancelled, created_at, updated_at"#
-                )
-                .bind(user_hash)
-                .bind(measurement_id)
-                .bind(agent_id)
-                .bind(expected_probes)
-                .bind(now)
-                .fetch_one(pool)
-                .await?;
-
-                Ok(record)
-            }
-            DatabaseImpl::Mock(storage) => {
-                let record = MeasurementTracking {
-                    id: Uuid::new_v4(),
-                    user_hash: user_hash.to_string(),
-                    measurement_id,
-                    agent_id: agent_id.to_string(),
-                    expected_probes,
-                    sent_probes: 0,
-                    is_complete: false,
-                    cancelled: false,
-                    created_at: now,
-                    updated_at: now,
-                };
-
-                let mut tracking = storage.measurement_tracking.lock().unwrap();
-                tracking.push(record.clone());
-                Ok(record)
-            }
-        }
+    /// Update probe count for a measurement tracking entry. Probes sent after
+    /// a refund reduce it.
+    pub async fn update_measurement_probe_count(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_id: &str,
+        sent_probes: i32,
+        is_complete: bool,
+    ) -> Result<MeasurementTracking, sqlx::Error> {
+        self.storage
+            .update_measurement_probe_count(
+                measurement_id,
+                user_hash,
+                agent_id,
+                sent_probes,
+                is_complete,
+            )
+            .await
+    }
+
+    /// Move an agent's progress on a measurement forward, in a single update:
+    /// only if it adds sent probes, or completes the measurement with as many.
+    /// A completed measurement stays complete. Returns whether it moved.
+    pub async fn advance_measurement_progress(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+        sent_probes: i32,
+        is_complete: bool,
+    ) -> Result<bool, sqlx::Error> {
+        self.storage
+            .advance_measurement_progress(measurement_id, agent_id, sent_probes, is_complete)
+            .await
+    }
+
+    /// Add a round of probes to what an agent is expected to send for a measurement
+    pub async fn add_measurement_expected_probes(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_id: &str,
+        probes: i32,
+    ) -> Result<bool, sqlx::Error> {
+        self.storage
+            .add_measurement_expected_probes(measurement_id, user_hash, agent_id, probes)
+            .await
+    }
+
+    /// Add to the number of probes of a measurement filtered out before dispatch
+    pub async fn add_measurement_filtered_probes(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        filtered_probes: i32,
+    ) -> Result<(), sqlx::Error> {
+        self.storage
+            .add_measurement_filtered_probes(measurement_id, user_hash, filtered_probes)
+            .await
+    }
+
+    /// Get measurement status for a specific measurement
+    pub async fn get_measurement_status(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementStatus>, sqlx::Error> {
+        self.storage
+            .get_measurement_status(measurement_id, user_hash)
+            .await
+    }
+
+    /// List a user's measurements, applying the given filters (status, started-at
+    /// window, agent) and ordering. Filters are applied before the limit.
+    pub async fn list_user_measurements(
+        &self,
+        user_hash: &str,
+        filter: &MeasurementListFilter,
+    ) -> Result<Vec<MeasurementStatus>, sqlx::Error> {
+        self.storage.list_user_measurements(user_hash, filter).await
+    }
+
+    /// Cancel a user's measurement by marking its not-yet-complete agent rows
+    /// as cancelled, refunding their unsent probes. Returns the number of rows
+    /// changed (0 if the measurement is already terminal — every agent already
+    /// complete or cancelled).
+    pub async fn cancel_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        self.storage
+            .cancel_measurement(measurement_id, user_hash)
+            .await
+    }
+
+    /// Cancellations published to an agent after the `after` cursor, oldest
+    /// first
+    pub async fn get_agent_cancellations(
+        &self,
+        agent_id: &str,
+        after: i64,
+        limit: i32,
+    ) -> Result<Vec<MeasurementCancellation>, sqlx::Error> {
+        self.storage
+            .get_agent_cancellations(agent_id, after, limit)
+            .await
+    }
+
+    /// The cancellations published for a measurement
+    pub async fn get_measurement_cancellations(
+        &self,
+        measurement_id: Uuid,
+    ) -> Result<Vec<MeasurementCancellation>, sqlx::Error> {
+        self.storage
+            .get_measurement_cancellations(measurement_id)
+            .await
+    }
+
+    /// Record that an agent stopped a cancelled measurement. Acknowledging
+    /// again keeps the first acknowledgement. Returns `None` when no
+    /// cancellation was published to the agent.
+    pub async fn acknowledge_cancellation(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+    ) -> Result<Option<MeasurementCancellation>, sqlx::Error> {
+        self.storage
+            .acknowledge_cancellation(measurement_id, agent_id)
+            .await
+    }
+
+    /// Delete a measurement's tracking rows, for a measurement whose probes
+    /// were never delivered. Returns the number of rows deleted.
+    pub async fn delete_measurement_tracking(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        self.storage
+            .delete_measurement_tracking(measurement_id, user_hash)
+            .await
+    }
+
+    /// Get all measurement tracking entries for a measurement
+    pub async fn get_measurement_tracking(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Vec<MeasurementTracking>, sqlx::Error> {
+        self.storage
+            .get_measurement_tracking(measurement_id, user_hash)
+            .await
+    }
+
+    /// Get measurement tracking entry by measurement ID and agent ID (for agent updates)
+    pub async fn get_measurement_tracking_by_agent(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+    ) -> Result<Option<MeasurementTracking>, sqlx::Error> {
+        self.storage
+            .get_measurement_tracking_by_agent(measurement_id, agent_id)
+            .await
+    }
+
+    /// Claim an idempotency key for a user. Returns `None` if this call now owns
+    /// the key (it was unused, its previous use is older than `expired_before`,
+    /// or it was claimed before `abandoned_before` and never completed),
+    /// otherwise the existing record — completed or still in flight.
+    pub async fn claim_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+        request_hash: &str,
+        expired_before: DateTime<Utc>,
+        abandoned_before: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, sqlx::Error> {
+        self.storage
+            .claim_idempotency_key(
+                user_hash,
+                idempotency_key,
+                request_hash,
+                expired_before,
+                abandoned_before,
+            )
+            .await
+    }
+
+    /// Store the response of a completed request against its claimed idempotency key
+    pub async fn complete_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+        measurement_id: Uuid,
+        response: &str,
+    ) -> Result<(), sqlx::Error> {
+        self.storage
+            .complete_idempotency_key(user_hash, idempotency_key, measurement_id, response)
+            .await
+    }
+
+    /// Release an in-flight idempotency key after the request failed, so that a
+    /// retry with the same key is processed again. Completed keys are left alone.
+    pub async fn release_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+    ) -> Result<(), sqlx::Error> {
+        self.storage
+            .release_idempotency_key(user_hash, idempotency_key)
+            .await
+    }
+
+    /// Create a measurement schedule, first firing at `next_run_at`
+    pub async fn create_schedule(
+        &self,
+        user_hash: &str,
+        name: Option<&str>,
+        spec: &str,
+        interval_seconds: Option<i64>,
+        next_run_at: DateTime<Utc>,
+    ) -> Result<MeasurementSchedule, sqlx::Error> {
+        self.storage
+            .create_schedule(user_hash, name, spec, interval_seconds, next_run_at)
+            .await
+    }
+
+    /// List a user's schedules, newest first
+    pub async fn list_user_schedules(
+        &self,
+        user_hash: &str,
+    ) -> Result<Vec<MeasurementSchedule>, sqlx::Error> {
+        self.storage.list_user_schedules(user_hash).await
+    }
+
+    /// Get one of a user's schedules
+    pub async fn get_schedule(
+        &self,
+        schedule_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementSchedule>, sqlx::Error> {
+        self.storage.get_schedule(schedule_id, user_hash).await
+    }
+
+    /// Delete one of a user's schedules along with its run history. Returns
+    /// false if the schedule does not exist. Measurements already created by
+    /// the schedule are kept.
+    pub async fn delete_schedule(
+        &self,
+        schedule_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        self.storage.delete_schedule(schedule_id, user_hash).await
+    }
+
+    /// Schedules whose next run is at or before `now`, oldest first
+    pub async fn get_due_schedules(
+        &self,
+        now: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<Vec<MeasurementSchedule>, sqlx::Error> {
+        self.storage.get_due_schedules(now, limit).await
+    }
+
+    /// Move a due schedule to its next run (`None` retires a one-shot schedule).
+    /// The update only applies if the schedule is still at `expected_run_at`, so
+    /// when several gateways race for the same run exactly one gets `true`.
+    pub async fn advance_schedule(
+        &self,
+        schedule_id: Uuid,
+        expected_run_at: DateTime<Utc>,
+        next_run_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, sqlx::Error> {
+        self.storage
+            .advance_schedule(schedule_id, expected_run_at, next_run_at)
+            .await
+    }
+
+    /// Record the outcome of a schedule run
+    pub async fn record_schedule_run(
+        &self,
+        schedule_id: Uuid,
+        measurement_id: Option<Uuid>,
+        error: Option<&str>,
+    ) -> Result<ScheduleRun, sqlx::Error> {
+        self.storage
+            .record_schedule_run(schedule_id, measurement_id, error)
+            .await
+    }
+
+    /// Most recent runs of a schedule, newest first
+    pub async fn list_schedule_runs(
+        &self,
+        schedule_id: Uuid,
+        limit: i32,
+    ) -> Result<Vec<ScheduleRun>, sqlx::Error> {
+        self.storage.list_schedule_runs(schedule_id, limit).await
+    }
+
+    /// Create a target list from already validated destinations
+    pub async fn create_target_list(
+        &self,
+        user_hash: &str,
+        name: &str,
+        targets: &[String],
+    ) -> Result<TargetList, sqlx::Error> {
+        self.storage
+            .create_target_list(user_hash, name, targets)
+            .await
+    }
+
+    /// List a user's target lists, newest first
+    pub async fn list_user_target_lists(
+        &self,
+        user_hash: &str,
+    ) -> Result<Vec<TargetList>, sqlx::Error> {
+        self.storage.list_user_target_lists(user_hash).await
+    }
+
+    /// Get one of a user's target lists
+    pub async fn get_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<TargetList>, sqlx::Error> {
+        self.storage.get_target_list(list_id, user_hash).await
+    }
+
+    /// Get the destinations of one of a user's target lists
+    pub async fn get_target_list_targets(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<Vec<String>>, sqlx::Error> {
+        self.storage
+            .get_target_list_targets(list_id, user_hash)
+            .await
+    }
+
+    /// Rename a target list and/or replace its destinations. Returns `None` if
+    /// the list does not exist.
+    pub async fn update_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+        name: Option<&str>,
+        targets: Option<&[String]>,
+    ) -> Result<Option<TargetList>, sqlx::Error> {
+        self.storage
+            .update_target_list(list_id, user_hash, name, targets)
+            .await
+    }
+
+    /// Delete one of a user's target lists. Returns false if it does not exist.
+    pub async fn delete_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        self.storage.delete_target_list(list_id, user_hash).await
+    }
+
+    /// Record a segment of a measurement's archived probes: the first one
+    /// creates the archive record, later ones (rounds) add their probes and
+    /// size to it. The new segment is the last of the returned record's
+    /// `segments`.
+    pub async fn append_measurement_archive(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        blob_key: &str,
+        metadata: &str,
+        probe_count: i32,
+        size_bytes: i64,
+    ) -> Result<MeasurementArchive, sqlx::Error> {
+        self.storage
+            .append_measurement_archive(
+                measurement_id,
+                user_hash,
+                blob_key,
+                metadata,
+                probe_count,
+                size_bytes,
+            )
+            .await
+    }
+
+    /// Get the archive record of one of a user's measurements
+    pub async fn get_measurement_archive(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementArchive>, sqlx::Error> {
+        self.storage
+            .get_measurement_archive(measurement_id, user_hash)
+            .await
+    }
+
+    /// Open a measurement for rounds of probes, with the tracking of each of
+    /// its agents (nothing expected yet), in a single transaction
+    pub async fn create_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_ids: &[String],
+        metadata: &str,
+    ) -> Result<MultiRoundMeasurement, sqlx::Error> {
+        self.storage
+            .create_multi_round_measurement(measurement_id, user_hash, agent_ids, metadata)
+            .await
+    }
+
+    /// Get one of a user's multi-round measurements
+    pub async fn get_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MultiRoundMeasurement>, sqlx::Error> {
+        self.storage
+            .get_multi_round_measurement(measurement_id, user_hash)
+            .await
+    }
+
+    /// Count a round dispatched for an open measurement. Returns the round
+    /// number, or None if the measurement is not open.
+    pub async fn record_measurement_round(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<i32>, sqlx::Error> {
+        self.storage
+            .record_measurement_round(measurement_id, user_hash)
+            .await
+    }
+
+    /// Close an open multi-round measurement. Returns false if it was not open.
+    pub async fn close_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        self.storage
+            .close_multi_round_measurement(measurement_id, user_hash)
+            .await
+    }
+
+    /// Record the tracking of a dispatch and queue its messages in the outbox,
+    /// in a single transaction
+    pub async fn record_dispatch(
+        &self,
+        tracking: &DispatchTracking,
+        messages: &[OutboundMessage],
+    ) -> Result<(), sqlx::Error> {
+        self.storage.record_dispatch(tracking, messages).await
+    }
+
+    /// Undo the tracking of a dispatch whose probes were never delivered, in a
+    /// single transaction: a new measurement's rows are deleted, a round is
+    /// subtracted from its rows
+    pub async fn revert_dispatch(&self, tracking: &DispatchTracking) -> Result<(), sqlx::Error> {
+        let delivered = vec![0; tracking.agent_ids.len()];
+        self.storage.revert_dispatch(tracking, &delivered).await
+    }
+
+    /// Keep only the probes of a dispatch that reached the agents, in a single
+    /// transaction. `delivered` holds each agent's delivered probes, in the
+    /// order of `tracking.agent_ids`. The rows of a new measurement whose
+    /// agent got nothing are deleted, the others expect what they got.
+    pub async fn revert_undelivered(
+        &self,
+        tracking: &DispatchTracking,
+        delivered: &[i32],
+    ) -> Result<(), sqlx::Error> {
+        self.storage.revert_dispatch(tracking, delivered).await
     }
 
-    /// Update probe count for a measurement tracking entry
-    pub async fn update_measurement_probe_count(
+    /// Claim the pending outbox messages of up to `limit` measurements until
+    /// `lease_until`, oldest first. A measurement is only claimed when none of
+    /// its pending messages is held by another relay, so its batches stay in
+    /// order.
+    pub async fn claim_outbox_messages(
         &self,
-        measurement_id: Uuid,
-        user_hash: &str,
-        agent_id: &str,
-        sent_probes: i32,
-        is_complete: bool,
-    ) -> Result<MeasurementTracking, sqlx::Error> {
-        let now = Utc::now();
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<Vec<OutboxMessage>, sqlx::Error> {
+        self.storage
+            .claim_outbox_messages(now, lease_until, limit)
+            .await
+    }
 
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let record = sqlx::query_as::<_, MeasurementTracking>(
-                    r#"UPDATE measurement_tracking
-                       SET sent_probes = $4, is_complete = $5, updated_at = $6
-                       WHERE measurement_id = $1 AND user_hash = $2 AND agent_id = $3
-                       RETURNING id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, cancelled, created_at, updated_at"#
-                )
-                .bind(measurement_id)
-                .bind(user_hash)
-                .bind(agent_id)
-                .bind(sent_probes)
-                .bind(is_complete)
-                .bind(now)
-                .fetch_one(pool)
-                .await?;
-
-                Ok(record)
-            }
-            DatabaseImpl::Mock(storage) => {
-                let mut tracking = storage.measurement_tracking.lock().unwrap();
-
-                if let Some(record) = tracking.iter_mut().find(|t| {
-                    t.measurement_id == measurement_id
-                        && t.user_hash == user_hash
-                        && t.agent_id == agent_id
-                }) {
-                    record.sent_probes = sent_probes;
-                    record.is_complete = is_complete;
-                    record.updated_at = now;
-                    Ok(record.clone())
-                } else {
-                    Err(sqlx::Error::RowNotFound)
-                }
-            }
-        }
+    /// Mark outbox messages as published
+    pub async fn mark_outbox_sent(&self, ids: &[i64]) -> Result<(), sqlx::Error> {
+        self.storage.mark_outbox_sent(ids).await
     }
 
-    /// Get measurement status for a specific measurement
-    pub async fn get_measurement_status(
-        &self,
-        measurement_id: Uuid,
-        user_hash: &str,
-    ) -> Result<Option<MeasurementStatus>, sqlx::Error> {
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let status = sqlx::query_as::<_, MeasurementStatus>(
-                    r#"SELECT
-                       measurement_id,
-                       user_hash,
-                       total_agents,
-                       total_expected_probes,
-                       total_sent_probes,
-                       completed_agents,
-                       measurement_complete,
-                       measurement_cancelled,
-                       started_at,
-                       last_updated
-                       FROM measurement_status
-                       WHERE measurement_id = $1 AND user_hash = $2"#,
-                )
-                .bind(measurement_id)
-                .bind(user_hash)
-                .fetch_optional(pool)
-                .await?;
-
-                Ok(status)
-            }
-            DatabaseImpl::Mock(storage) => {
-                let tracking = storage.measurement_tracking.lock().unwrap();
-                let records: Vec<_> = tracking
-                    .iter()
-                    .filter(|t| t.measurement_id == measurement_id && t.user_hash == user_hash)
-                    .collect();
-
-                if records.is_empty() {
-                    return Ok(None);
-                }
-
-                let total_agents = records.len() as i64;
-                let total_expected_probes: i64 =
-                    records.iter().map(|r| r.expected_probes as i64).sum();
-                let total_sent_probes: i64 = records.iter().map(|r| r.sent_probes as i64).sum();
-                let completed_agents = records.iter().filter(|r| r.is_complete).count() as i64;
-                // Terminal when every agent is complete or cancelled (mirrors the view).
-                let terminal_agents =
-                    records.iter().filter(|r| r.is_complete || r.cancelled).count() as i64;
-                let measurement_complete = terminal_agents == total_agents;
-                let measurement_cancelled = records.iter().any(|r| r.cancelled);
-                let started_at = records.iter().map(|r| r.created_at).min().unwrap();
-                let last_updated = records.iter().map(|r| r.updated_at).max().unwrap();
-
-                Ok(Some(MeasurementStatus {
-                    measurement_id,
-                    user_hash: user_hash.to_string(),
-                    total_agents,
-                    total_expected_probes,
-                    total_sent_probes,
-                    completed_agents,
-                    measurement_complete,
-                    measurement_cancelled,
-                    started_at,
-                    last_updated,
-                }))
-            }
-        }
+    /// Record a failed attempt to publish outbox messages, releasing them for
+    /// a later retry
+    pub async fn record_outbox_failure(&self, ids: &[i64], error: &str) -> Result<(), sqlx::Error> {
+        self.storage.record_outbox_failure(ids, error).await
     }
 
-    /// List a user's measurements, applying the given filters (status, started-at
-    /// window, agent) and ordering. Filters are applied before the limit.
-    pub async fn list_user_measurements(
+    /// Count the measurements a purge would remove: those whose agents are all
+    /// complete or cancelled and that were last updated before `before`. Open
+    /// multi-round measurements and measurements with queued batches are kept.
+    pub async fn count_expired_measurements(
         &self,
-        user_hash: &str,
-        filter: &MeasurementListFilter,
-    ) -> Result<Vec<MeasurementStatus>, sqlx::Error> {
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let mut qb = sqlx::QueryBuilder::new(
-                    "SELECT measurement_id, user_hash, total_agents, total_expected_probes, \
-                     total_sent_probes, completed_agents, measurement_complete, \
-                     measurement_cancelled, started_at, last_updated \
-                     FROM measurement_status WHERE user_hash = ",
-                );
-                qb.push_bind(user_hash);
-
-                // status: OR of the requested states (constant predicates, no binds)
-                if !filter.status.is_empty() {
-                    qb.push(" AND (");
-                    {
-                        let mut sep = qb.separated(" OR ");
-                        for state in &filter.status {
-                            match state {
-                                MeasurementState::Complete => {
-                                    sep.push("(measurement_complete AND NOT measurement_cancelled)");
-                                }
-                                MeasurementState::InProgress => {
-                                    sep.push("(NOT measurement_complete)");
-                                }
-                                MeasurementState::Cancelled => {
-                                    sep.push("measurement_cancelled");
-                                }
-                            };
-                        }
-                    }
-                    qb.push(")");
-                }
-
-                if let Some(since) = filter.since {
-                    qb.push(" AND started_at >= ").push_bind(since);
-                }
-                if let Some(until) = filter.until {
-                    qb.push(" AND started_at <= ").push_bind(until);
-                }
-                if let Some(agent) = &filter.agent {
-                    qb.push(
-                        " AND EXISTS (SELECT 1 FROM measurement_tracking t \
-                         WHERE t.measurement_id = measurement_status.measurement_id \
-                         AND t.user_hash = ",
-                    )
-                    .push_bind(user_hash)
-                    .push(" AND t.agent_id = ")
-                    .push_bind(agent)
-                    .push(")");
-                }
-
-                // sort column is a fixed identifier from an enum, never raw input
-                let sort_col = match filter.sort {
-                    MeasurementSort::Started => "started_at",
-                    MeasurementSort::Updated => "last_updated",
-                };
-                qb.push(" ORDER BY ").push(sort_col);
-                qb.push(if filter.reverse { " ASC" } else { " DESC" });
-                qb.push(" LIMIT ").push_bind(filter.limit);
-
-                let measurements = qb
-                    .build_query_as::<MeasurementStatus>()
-                    .fetch_all(pool)
-                    .await?;
-                Ok(measurements)
-            }
-            DatabaseImpl::Mock(storage) => {
-                let tracking = storage.measurement_tracking.lock().unwrap();
-
-                // Aggregate the per-agent tracking rows into one status per
-                // measurement, mirroring the `measurement_status` SQL view.
-                let mut by_measurement: std::collections::HashMap<Uuid, Vec<&MeasurementTracking>> =
-                    std::collections::HashMap::new();
-                for t in tracking.iter().filter(|t| t.user_hash == user_hash) {
-                    by_measurement.entry(t.measurement_id).or_default().push(t);
-                }
-
-                let mut measurements: Vec<MeasurementStatus> = by_measurement
-                    .into_iter()
-                    // agent filter: keep only measurements that involve the agent
-                    .filter(|(_, records)| match &filter.agent {
-                        Some(a) => records.iter().any(|r| &r.agent_id == a),
-                        None => true,
-                    })
-                    .map(|(measurement_id, records)| {
-                        let total_agents = records.len() as i64;
-                        let completed_agents =
-                            records.iter().filter(|r| r.is_complete).count() as i64;
-                        let terminal_agents =
-                            records.iter().filter(|r| r.is_complete || r.cancelled).count() as i64;
-                        MeasurementStatus {
-                            measurement_id,
-                            user_hash: user_hash.to_string(),
-                            total_agents,
-                            total_expected_probes: records
-                                .iter()
-                                .map(|r| r.expected_probes as i64)
-                                .sum(),
-                            total_sent_probes: records.iter().map(|r| r.sent_probes as i64).sum(),
-                            completed_agents,
-                            measurement_complete: terminal_agents == total_agents,
-                            measurement_cancelled: records.iter().any(|r| r.cancelled),
-                            started_at: records.iter().map(|r| r.created_at).min().unwrap(),
-                            last_updated: records.iter().map(|r| r.updated_at).max().unwrap(),
-                        }
-                    })
-                    .collect();
-
-                // status filter (OR of requested states)
-                if !filter.status.is_empty() {
-                    measurements
-                        .retain(|m| filter.status.iter().any(|s| measurement_in_state(m, *s)));
-                }
-                // started-at window
-                if let Some(since) = filter.since {
-                    measurements.retain(|m| m.started_at >= since);
-                }
-                if let Some(until) = filter.until {
-                    measurements.retain(|m| m.started_at <= until);
-                }
-
-                measurements.sort_by(|a, b| {
-                    let ord = match filter.sort {
-                        MeasurementSort::Started => a.started_at.cmp(&b.started_at),
-                        MeasurementSort::Updated => a.last_updated.cmp(&b.last_updated),
-                    };
-                    if filter.reverse { ord } else { ord.reverse() }
-                });
-                measurements.truncate(filter.limit as usize);
-                Ok(measurements)
-            }
-        }
+        before: DateTime<Utc>,
+    ) -> Result<PurgedMeasurements, sqlx::Error> {
+        self.storage.count_expired_measurements(before).await
     }
 
-    /// Cancel a user's measurement by marking its not-yet-complete agent rows
-    /// as cancelled. Returns the number of rows changed (0 if the measurement is
-    /// already terminal — every agent already complete or cancelled).
-    pub async fn cancel_measurement(
+    /// Delete up to `limit` expired measurements (oldest first) with their
+    /// cancellations, adding their usage to the daily rollup in the same
+    /// transaction
+    pub async fn purge_expired_measurements(
         &self,
-        measurement_id: Uuid,
-        user_hash: &str,
-    ) -> Result<u64, sqlx::Error> {
-        let now = Utc::now();
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let result = sqlx::query(
-                    r#"UPDATE measurement_tracking
-                       SET cancelled = TRUE, updated_at = $3
-                       WHERE measurement_id = $1 AND user_hash = $2
-                         AND is_complete = FALSE AND cancelled = FALSE"#,
-                )
-                .bind(measurement_id)
-                .bind(user_hash)
-                .bind(now)
-                .execute(pool)
-                .await?;
-                Ok(result.rows_affected())
-            }
-            DatabaseImpl::Mock(storage) => {
-                let mut tracking = storage.measurement_tracking.lock().unwrap();
-                let mut affected = 0u64;
-                for record in tracking.iter_mut().filter(|t| {
-                    t.measurement_id == measurement_id
-                        && t.user_hash == user_hash
-                        && !t.is_complete
-                        && !t.cancelled
-                }) {
-                    record.cancelled = true;
-                    record.updated_at = now;
-                    affected += 1;
-                }
-                Ok(affected)
-            }
-        }
+        before: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<PurgedMeasurements, sqlx::Error> {
+        self.storage.purge_expired_measurements(before, limit).await
     }
 
-    /// Get all measurement tracking entries for a measurement
-    pub async fn get_measurement_tracking(
+    /// Rolled-up daily usage of a user from `since`, oldest day first
+    pub async fn get_daily_usage(
         &self,
-        measurement_id: Uuid,
         user_hash: &str,
-    ) -> Result<Vec<MeasurementTracking>, sqlx::Error> {
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let records = sqlx::query_as::<_, MeasurementTracking>(
-                    r#"SELECT id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, cancelled, created_at, updated_at
-                       FROM measurement_tracking
-                       WHERE measurement_id = $1 AND user_hash = $2
-                       ORDER BY created_at"#
-                )
-                .bind(measurement_id)
-                .bind(user_hash)
-                .fetch_all(pool)
-                .await?;
-
-                Ok(records)
-            }
-            DatabaseImpl::Mock(storage) => {
-                let tracking = storage.measurement_tracking.lock().unwrap();
-                let mut records: Vec<_> = tracking
-                    .iter()
-                    .filter(|t| t.measurement_id == measurement_id && t.user_hash == user_hash)
-                    .cloned()
-                    .collect();
-
-                records.sort_by(|a, b| a.created_at.cmp(&b.created_at));
-                Ok(records)
-            }
-        }
+        since: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, sqlx::Error> {
+        self.storage.get_daily_usage(user_hash, since).await
     }
 
-    /// Get measurement tracking entry by measurement ID and agent ID (for agent updates)
-    pub async fn get_measurement_tracking_by_agent(
+    /// Count the rows of the legacy `probe_usage` table recorded before
+    /// `before` (always 0 on backends without the table)
+    pub async fn count_expired_probe_usage(
         &self,
-        measurement_id: Uuid,
-        agent_id: &str,
-    ) -> Result<Option<MeasurementTracking>, sqlx::Error> {
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => {
-                let record = sqlx::query_as::<_, MeasurementTracking>(
-                    r#"SELECT id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, cancelled, created_at, updated_at
-                       FROM measurement_tracking
-                       WHERE measurement_id = $1 AND agent_id = $2"#
-                )
-                .bind(measurement_id)
-                .bind(agent_id)
-                .fetch_optional(pool)
-                .await?;
-
-                Ok(record)
-            }
-            DatabaseImpl::Mock(storage) => {
-                let tracking = storage.measurement_tracking.lock().unwrap();
-                let record = tracking
-                    .iter()
-                    .find(|t| t.measurement_id == measurement_id && t.agent_id == agent_id)
-                    .cloned();
-
-                Ok(record)
-            }
-        }
+        before: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        self.storage.count_expired_probe_usage(before).await
     }
 
-    pub fn get_pool(&self) -> Option<&PgPool> {
-        match &self.impl_ {
-            DatabaseImpl::Real(pool) => Some(pool),
-            DatabaseImpl::Mock(_) => None,
-        }
+    /// Delete up to `limit` expired rows of the legacy `probe_usage` table
+    pub async fn purge_expired_probe_usage(
+        &self,
+        before: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<u64, sqlx::Error> {
+        self.storage.purge_expired_probe_usage(before, limit).await
     }
 }
 
@@ -1136,6 +1638,15 @@ mod tests {
         assert_eq!(target, "db.example.com:5432/saimiris");
     }
 
+    #[test]
+    fn safe_database_target_handles_sqlite() {
+        assert_eq!(
+            safe_database_target("sqlite:///var/lib/saimiris/gateway.db?mode=rwc"),
+            "sqlite:///var/lib/saimiris/gateway.db"
+        );
+        assert_eq!(safe_database_target("sqlite::memory:"), "sqlite::memory:");
+    }
+
     #[test]
     fn test_hash_user_id() {
         let user_id = "test-user-123";
@@ -1152,726 +1663,4 @@ mod tests {
         let different_hash = hash_user_identifier("different-user");
         assert_ne!(hash1, different_hash);
     }
-
-    #[tokio::test]
-    async fn test_mock_database() {
-        let db = Database::new_mock();
-
-        // Initialize mock database
-        db.initialize().await.unwrap();
-
-        // Test recording probe usage via measurement tracking
-        let user_id = "test-user";
-        let probe_count = 5;
-        let measurement_id = Uuid::new_v4();
-        let agent_id = "test-agent";
-
-        // Create measurement tracking entry
-        let _tracking = db
-            .create_measurement_tracking(
-                &hash_user_identifier(user_id),
-                measurement_id,
-                agent_id,
-                5,
-            )
-            .await
-            .unwrap();
-
-        // Update measurement tracking with probe count
-        let _updated = db
-            .update_measurement_probe_count(
-                measurement_id,
-                &hash_user_identifier(user_id),
-                agent_id,
-                probe_count,
-                true,
-            )
-            .await
-            .unwrap();
-
-        // Test getting user stats
-        let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
-        assert_eq!(stats.submission_count, 1);
-        assert_eq!(stats.total_probes, 5);
-        assert!(
-            stats.last_submitted.is_some(),
-            "last_submitted should be set"
-        );
-
-        // Test getting recent usage
-        let recent = db.get_recent_usage(Some(10)).await.unwrap();
-        assert_eq!(recent.len(), 1);
-        assert_eq!(recent[0].probe_count, probe_count);
-    }
-
-    #[tokio::test]
-    async fn test_user_limits() {
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-
-        let user_id = "test-user";
-
-        // Test default limit (no limit set)
-        let limit = db.get_user_limit(user_id).await.unwrap();
-        assert!(limit.is_none());
-
-        // Test setting a user limit
-        let new_limit = db.set_user_limit(user_id, 5000).await.unwrap();
-        assert_eq!(new_limit.probe_limit, 5000);
-        assert_eq!(new_limit.user_hash, hash_user_identifier(user_id));
-
-        // Test getting the user limit
-        let retrieved_limit = db.get_user_limit(user_id).await.unwrap();
-        assert!(retrieved_limit.is_some());
-        let retrieved_limit = retrieved_limit.unwrap();
-        assert_eq!(retrieved_limit.probe_limit, 5000);
-        assert_eq!(retrieved_limit.user_hash, hash_user_identifier(user_id));
-
-        // Test updating the user limit
-        let updated_limit = db.set_user_limit(user_id, 8000).await.unwrap();
-        assert_eq!(updated_limit.probe_limit, 8000);
-        assert!(updated_limit.updated_at >= updated_limit.created_at);
-
-        // Test that stats include the custom limit
-        let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
-        assert_eq!(stats.limit, 8000);
-    }
-
-    #[tokio::test]
-    async fn test_rate_limiting() {
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-
-        let user_id = "test-user";
-
-        // Set a low limit for testing
-        db.set_user_limit(user_id, 100).await.unwrap();
-
-        // Test that user can submit probes within limit
-        let can_submit = db.can_user_submit_probes(user_id, 50, None).await.unwrap();
-        assert!(can_submit);
-
-        // Record some usage via measurement tracking
-        let measurement_id = Uuid::new_v4();
-        let agent_id = "test-agent";
-        let _tracking = db
-            .create_measurement_tracking(
-                &hash_user_identifier(user_id),
-                measurement_id,
-                agent_id,
-                60,
-            )
-            .await
-            .unwrap();
-        let _updated = db
-            .update_measurement_probe_count(
-                measurement_id,
-                &hash_user_identifier(user_id),
-                agent_id,
-                60,
-                true,
-            )
-            .await
-            .unwrap();
-
-        // Test that user can still submit more probes within limit
-        let can_submit = db.can_user_submit_probes(user_id, 30, None).await.unwrap();
-        assert!(can_submit);
-
-        // Test that user cannot exceed limit
-        let can_submit = db.can_user_submit_probes(user_id, 50, None).await.unwrap();
-        assert!(!can_submit);
-
-        // Test with exactly at limit
-        let can_submit = db.can_user_submit_probes(user_id, 40, None).await.unwrap();
-        assert!(can_submit);
-
-        // Test with one over limit
-        let can_submit = db.can_user_submit_probes(user_id, 41, None).await.unwrap();
-        assert!(!can_submit);
-    }
-
-    #[tokio::test]
-    async fn test_default_limit_behavior() {
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-
-        let user_id = "test-user";
-
-        // Test that default limit is used when no limit is set
-        let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
-        assert_eq!(stats.limit, DEFAULT_PROBE_LIMIT); // Default limit
-
-        // Test rate limiting with default limit
-        let can_submit = db
-            .can_user_submit_probes(user_id, 5000, None)
-            .await
-            .unwrap();
-        assert!(can_submit);
-
-        let can_submit = db
-            .can_user_submit_probes(user_id, 15000, None)
-            .await
-            .unwrap();
-        assert!(!can_submit);
-    }
-
-    #[tokio::test]
-    async fn test_daily_rate_limiting() {
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-
-        let user_id = "test-user";
-
-        // Set a limit for testing
-        db.set_user_limit(user_id, 100).await.unwrap();
-
-        // Test daily usage calculation
-        let today_start = Utc::now()
-            .date_naive()
-            .and_hms_opt(0, 0, 0)
-            .unwrap()
-            .and_utc();
-
-        // Get today's stats (should be zero initially)
-        let stats = db
-            .get_user_usage_stats(user_id, Some(today_start), None)
-            .await
-            .unwrap();
-        assert_eq!(stats.total_probes, 0);
-        assert_eq!(stats.limit, 100);
-
-        // Record some usage today via measurement tracking
-        let measurement_id = Uuid::new_v4();
-        let agent_id = "test-agent";
-        let _tracking = db
-            .create_measurement_tracking(
-                &hash_user_identifier(user_id),
-                measurement_id,
-                agent_id,
-                50,
-            )
-            .await
-            .unwrap();
-        let _updated = db
-            .update_measurement_probe_count(
-                measurement_id,
-                &hash_user_identifier(user_id),
-                agent_id,
-                50,
-                true,
-            )
-            .await
-            .unwrap();
-
-        // Test that today's usage is calculated correctly (use None for end_time to get current time)
-        let stats = db
-            .get_user_usage_stats(user_id, Some(today_start), None)
-            .await
-            .unwrap();
-        assert_eq!(stats.total_probes, 50);
-
-        // Test daily rate limiting
-        let can_submit = db.can_user_submit_probes(user_id, 30, None).await.unwrap();
-        assert!(can_submit); // 50 + 30 = 80 <= 100
-
-        let can_submit = db.can_user_submit_probes(user_id, 60, None).await.unwrap();
-        assert!(!can_submit); // 50 + 60 = 110 > 100
-
-        // Test default behavior (today's usage)
-        let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
-        assert_eq!(stats.total_probes, 50); // Should be today's usage
-    }
-
-    #[tokio::test]
-    async fn test_measurement_tracking() {
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-
-        let measurement_id = Uuid::new_v4();
-        let user_hash = "test_user_hash";
-        let agent1_id = "agent1";
-        let agent2_id = "agent2";
-
-        // Test creating measurement tracking for multiple agents
-        let tracking1 = db
-            .create_measurement_tracking(user_hash, measurement_id, agent1_id, 100)
-            .await
-            .unwrap();
-
-        let _tracking2 = db
-            .create_measurement_tracking(user_hash, measurement_id, agent2_id, 100)
-            .await
-            .unwrap();
-
-        assert_eq!(tracking1.measurement_id, measurement_id);
-        assert_eq!(tracking1.agent_id, agent1_id);
-        assert_eq!(tracking1.expected_probes, 100);
-        assert_eq!(tracking1.sent_probes, 0);
-        assert!(!tracking1.is_complete);
-
-        // Test updating probe count for agent1 (partial completion)
-        let updated_tracking1 = db
-            .update_measurement_probe_count(measurement_id, user_hash, agent1_id, 50, false)
-            .await
-            .unwrap();
-
-        assert_eq!(updated_tracking1.sent_probes, 50);
-        assert!(!updated_tracking1.is_complete);
-
-        // Test completing agent1
-        let completed_tracking1 = db
-            .update_measurement_probe_count(measurement_id, user_hash, agent1_id, 100, true)
-            .await
-            .unwrap();
-
-        assert_eq!(completed_tracking1.sent_probes, 100);
-        assert!(completed_tracking1.is_complete);
-
-        // Test getting measurement status (should show 1 of 2 agents complete)
-        let status = db
-            .get_measurement_status(measurement_id, user_hash)
-            .await
-            .unwrap()
-            .unwrap();
-
-        assert_eq!(status.total_agents, 2);
-        assert_eq!(status.completed_agents, 1);
-        assert_eq!(status.total_expected_probes, 200); // 2 agents * 100 each
-        assert_eq!(status.total_sent_probes, 100); // Only agent1 has sent probes
-        assert!(!status.measurement_complete); // Not all agents complete
-
-        // Complete agent2
-        db.update_measurement_probe_count(measurement_id, user_hash, agent2_id, 100, true)
-            .await
-            .unwrap();
-
-        // Test measurement is now complete
-        let final_status = db
-            .get_measurement_status(measurement_id, user_hash)
-            .await
-            .unwrap()
-            .unwrap();
-
-        assert_eq!(final_status.completed_agents, 2);
-        assert_eq!(final_status.total_sent_probes, 200);
-        assert!(final_status.measurement_complete);
-
-        // Test getting detailed tracking
-        let tracking_details = db
-            .get_measurement_tracking(measurement_id, user_hash)
-            .await
-            .unwrap();
-
-        assert_eq!(tracking_details.len(), 2);
-        for tracking in &tracking_details {
-            assert_eq!(tracking.sent_probes, 100);
-            assert!(tracking.is_complete);
-        }
-    }
-
-    #[tokio::test]
-    async fn test_list_user_measurements() {
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-
-        let user_hash = "test_user_hash";
-        let other_user_hash = "other_user_hash";
-
-        // First measurement: two agents, one complete.
-        let m1 = Uuid::new_v4();
-        db.create_measurement_tracking(user_hash, m1, "agent1", 100)
-            .await
-            .unwrap();
-        db.create_measurement_tracking(user_hash, m1, "agent2", 100)
-            .await
-            .unwrap();
-        db.update_measurement_probe_count(m1, user_hash, "agent1", 100, true)
-            .await
-            .unwrap();
-
-        // The Mock derives `last_updated` from `Utc::now()`, so a real (small)
-        // delay is needed to guarantee m2 sorts strictly before m1 — two
-        // consecutive in-memory ops can otherwise tie on the same instant and
-        // make the order of the pair non-deterministic.
-        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
-
-        // Second measurement (touched later, so it should sort first).
-        let m2 = Uuid::new_v4();
-        db.create_measurement_tracking(user_hash, m2, "agent1", 10)
-            .await
-            .unwrap();
-        db.update_measurement_probe_count(m2, user_hash, "agent1", 10, true)
-            .await
-            .unwrap();
-
-        // A different user's measurement must not leak into the list.
-        let m3 = Uuid::new_v4();
-        db.create_measurement_tracking(other_user_hash, m3, "agent1", 5)
-            .await
-            .unwrap();
-
-        let measurements = db
-            .list_user_measurements(user_hash, &MeasurementListFilter::with_limit(20))
-            .await
-            .unwrap();
-
-        // Only this user's two measurements, newest (m2) first.
-        assert_eq!(measurements.len(), 2);
-        assert_eq!(measurements[0].measurement_id, m2);
-        assert!(measurements[0].measurement_complete);
-        assert_eq!(measurements[1].measurement_id, m1);
-        assert_eq!(measurements[1].total_agents, 2);
-        assert_eq!(measurements[1].completed_agents, 1);
-        assert!(!measurements[1].measurement_complete);
-
-        // The limit caps the number of returned measurements.
-        let limited = db
-            .list_user_measurements(user_hash, &MeasurementListFilter::with_limit(1))
-            .await
-            .unwrap();
-        assert_eq!(limited.len(), 1);
-        assert_eq!(limited[0].measurement_id, m2);
-    }
-
-    #[tokio::test]
-    async fn test_list_user_measurements_filters() {
-        use std::collections::HashSet;
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-        let user = "u";
-
-        // complete, on agentA (created/updated first)
-        let m_complete = Uuid::new_v4();
-        db.create_measurement_tracking(user, m_complete, "agentA", 10)
-            .await
-            .unwrap();
-        db.update_measurement_probe_count(m_complete, user, "agentA", 10, true)
-            .await
-            .unwrap();
-        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
-
-        // in-progress, on agentB
-        let m_inprogress = Uuid::new_v4();
-        db.create_measurement_tracking(user, m_inprogress, "agentB", 10)
-            .await
-            .unwrap();
-        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
-
-        // cancelled, on agentB (touched last)
-        let m_cancelled = Uuid::new_v4();
-        db.create_measurement_tracking(user, m_cancelled, "agentB", 10)
-            .await
-            .unwrap();
-        db.cancel_measurement(m_cancelled, user).await.unwrap();
-
-        let base = MeasurementListFilter::with_limit(100);
-        let ids = |ms: Vec<MeasurementStatus>| -> HashSet<Uuid> {
-            ms.into_iter().map(|m| m.measurement_id).collect()
-        };
-        let with_status = |status: Vec<MeasurementState>| MeasurementListFilter {
-            status,
-            ..base.clone()
-        };
-
-        // single-state filters
-        let r = db
-            .list_user_measurements(user, &with_status(vec![MeasurementState::Complete]))
-            .await
-            .unwrap();
-        assert_eq!(ids(r), HashSet::from([m_complete]));
-        let r = db
-            .list_user_measurements(user, &with_status(vec![MeasurementState::InProgress]))
-            .await
-            .unwrap();
-        assert_eq!(ids(r), HashSet::from([m_inprogress]));
-        let r = db
-            .list_user_measurements(user, &with_status(vec![MeasurementState::Cancelled]))
-            .await
-            .unwrap();
-        assert_eq!(ids(r), HashSet::from([m_cancelled]));
-
-        // OR of multiple states
-        let r = db
-            .list_user_measurements(
-                user,
-                &with_status(vec![MeasurementState::Complete, MeasurementState::Cancelled]),
-            )
-            .await
-            .unwrap();
-        assert_eq!(ids(r), HashSet::from([m_complete, m_cancelled]));
-
-        // agent filter
-        let r = db
-            .list_user_measurements(
-                user,
-                &MeasurementListFilter {
-                    agent: Some("agentA".to_string()),
-                    ..base.clone()
-                },
-            )
-            .await
-            .unwrap();
-        assert_eq!(ids(r), HashSet::from([m_complete]));
-
-        // default order = newest last_updated first (m_cancelled touched last)
-        let r = db.list_user_measurements(user, &base).await.unwrap();
-        assert_eq!(r.first().unwrap().measurement_id, m_cancelled);
-
-        // sort by started ascending -> earliest started first (m_complete)
-        let by_started = db
-            .list_user_measurements(
-                user,
-                &MeasurementListFilter {
-                    sort: MeasurementSort::Started,
-                    reverse: true,
-                    ..base.clone()
-                },
-            )
-            .await
-            .unwrap();
-        assert_eq!(by_started[0].measurement_id, m_complete);
-
-        // started-at window (timestamps taken from the started-ascending order:
-        // [0] m_complete, [1] m_inprogress, [2] m_cancelled)
-        let t_inprogress = by_started[1].started_at;
-        let t_cancelled = by_started[2].started_at;
-
-        // since excludes anything started earlier
-        let r = db
-            .list_user_measurements(
-                user,
-                &MeasurementListFilter { since: Some(t_inprogress), ..base.clone() },
-            )
-            .await
-            .unwrap();
-        assert_eq!(ids(r), HashSet::from([m_inprogress, m_cancelled]));
-
-        // until excludes anything started later
-        let r = db
-            .list_user_measurements(
-                user,
-                &MeasurementListFilter { until: Some(t_inprogress), ..base.clone() },
-            )
-            .await
-            .unwrap();
-        assert_eq!(ids(r), HashSet::from([m_complete, m_inprogress]));
-
-        // inclusive boundary: since == until matches exactly that instant
-        let r = db
-            .list_user_measurements(
-                user,
-                &MeasurementListFilter {
-                    since: Some(t_inprogress),
-                    until: Some(t_inprogress),
-                    ..base.clone()
-                },
-            )
-            .await
-            .unwrap();
-        assert_eq!(ids(r), HashSet::from([m_inprogress]));
-
-        // since > until yields nothing
-        let r = db
-            .list_user_measurements(
-                user,
-                &MeasurementListFilter {
-                    since: Some(t_cancelled),
-                    until: Some(t_inprogress),
-                    ..base.clone()
-                },
-            )
-            .await
-            .unwrap();
-        assert!(r.is_empty());
-    }
-
-    #[tokio::test]
-    async fn test_cancel_measurement() {
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-        let user_hash = "test_user_hash";
-
-        // A stuck measurement: one agent that never completed.
-        let m = Uuid::new_v4();
-        db.create_measurement_tracking(user_hash, m, "agent1", 3000)
-            .await
-            .unwrap();
-
-        let before = db.get_measurement_status(m, user_hash).await.unwrap().unwrap();
-        assert!(!before.measurement_complete);
-        assert!(!before.measurement_cancelled);
-
-        // Cancel marks the unfinished agent row -> 1 row changed.
-        assert_eq!(db.cancel_measurement(m, user_hash).await.unwrap(), 1);
-
-        let after = db.get_measurement_status(m, user_hash).await.unwrap().unwrap();
-        assert!(after.measurement_complete); // terminal now
-        assert!(after.measurement_cancelled);
-        assert_eq!(after.completed_agents, 0); // cancelled is not "completed"
-
-        // Idempotent: a second cancel changes nothing.
-        assert_eq!(db.cancel_measurement(m, user_hash).await.unwrap(), 0);
-
-        // A different user cannot cancel someone else's measurement.
-        let other = Uuid::new_v4();
-        db.create_measurement_tracking(user_hash, other, "agent1", 10)
-            .await
-            .unwrap();
-        assert_eq!(db.cancel_measurement(other, "other_user").await.unwrap(), 0);
-        assert!(
-            !db.get_measurement_status(other, user_hash)
-                .await
-                .unwrap()
-                .unwrap()
-                .measurement_complete
-        );
-    }
-
-    #[tokio::test]
-    async fn test_per_agent_completion_tracking() {
-        let db = Database::new_mock();
-        db.initialize().await.unwrap();
-
-        let measurement_id = Uuid::new_v4();
-        let user_hash = "test_user_hash";
-        let agent1_id = "fast_agent";
-        let agent2_id = "slow_agent";
-        let agent3_id = "failing_agent";
-
-        // Create tracking for 3 agents
-        for agent_id in [agent1_id, agent2_id, agent3_id] {
-            db.create_measurement_tracking(user_hash, measurement_id, agent_id, 50)
-                .await
-                .unwrap();
-        }
-
-        // Agent1 completes quickly
-        db.update_measurement_probe_count(measurement_id, user_hash, agent1_id, 50, true)
-            .await
-            .unwrap();
-
-        // Agent2 makes partial progress
-        db.update_measurement_probe_count(measurement_id, user_hash, agent2_id, 25, false)
-            .await
-            .unwrap();
-
-        // Agent3 fails (0 probes sent)
-        db.update_measurement_probe_count(measurement_id, user_hash, agent3_id, 0, true)
-            .await
-            .unwrap();
-
-        // Check status shows per-agent completion
-        let status = db
-            .get_measurement_status(measurement_id, user_hash)
-            .await
-            .unwrap()
-            .unwrap();
-
-        assert_eq!(status.total_agents, 3);
-        assert_eq!(status.completed_agents, 2); // agent1 and agent3 marked complete
-        assert_eq!(status.total_expected_probes, 150); // 3 agents * 50 each
-        assert_eq!(status.total_sent_probes, 75); // 50 + 25 + 0
-        assert!(!status.measurement_complete); // agent2 not complete yet
-
-        // Get detailed tracking to verify per-agent status
-        let tracking_details = db
-            .get_measurement_tracking(measurement_id, user_hash)
-            .await
-            .unwrap();
-
-        let agent1_tracking = tracking_details
-            .iter()
-            .find(|t| t.agent_id == agent1_id)
-            .unwrap();
-        let agent2_tracking = tracking_details
-            .iter()
-            .find(|t| t.agent_id == agent2_id)
-            .unwrap();
-        let agent3_tracking = tracking_details
-            .iter()
-            .find(|t| t.agent_id == agent3_id)
-            .unwrap();
-
-        assert_eq!(agent1_tracking.sent_probes, 50);
-        assert!(agent1_tracking.is_complete);
-
-        assert_eq!(agent2_tracking.sent_probes, 25);
-        assert!(!agent2_tracking.is_complete);
-
-        assert_eq!(agent3_tracking.sent_probes, 0);
-        assert!(agent3_tracking.is_complete); // Marked complete even with 0 probes (failure case)
-
-        // Agent2 finishes
-        db.update_measurement_probe_count(measurement_id, user_hash, agent2_id, 50, true)
-            .await
-            .unwrap();
-
-        // Verify measurement is now complete
-        let final_status = db
-            .get_measurement_status(measurement_id, user_hash)
-            .await
-            .unwrap()
-            .unwrap();
-
-        assert!(final_status.measurement_complete);
-        assert_eq!(final_status.completed_agents, 3);
-        assert_eq!(final_status.total_sent_probes, 100); // 50 + 50 + 0
-    }
-
-    #[tokio::test]
-    async fn test_database_integration() {
-        // Use mock database instead of real database to avoid connection issues
-        let db = Database::new_mock();
-
-        // Initialize the database (run migrations)
-        db.initialize().await.unwrap();
-
-        let user_id = "integrate_test_user";
-        let measurement_id = Uuid::new_v4();
-
-        // Test setting and getting user limit
-        let limit = db.set_user_limit(user_id, 500).await.unwrap();
-        assert_eq!(limit.probe_limit, 500);
-
-        let retrieved_limit = db.get_user_limit(user_id).await.unwrap().unwrap();
-        assert_eq!(retrieved_limit.probe_limit, 500);
-
-        // Test measurement tracking
-        let agent_id = "test_agent";
-        let user_hash = &hash_user_identifier(user_id);
-        let tracking = db
-            .create_measurement_tracking(user_hash, measurement_id, agent_id, 50)
-            .await
-            .unwrap();
-        assert_eq!(tracking.expected_probes, 50);
-        assert_eq!(tracking.sent_probes, 0);
-        assert!(!tracking.is_complete);
-
-        // Update and complete the measurement tracking
-        let updated_tracking = db
-            .update_measurement_probe_count(measurement_id, user_hash, agent_id, 50, true)
-            .await
-            .unwrap();
-        assert_eq!(updated_tracking.sent_probes, 50);
-        assert!(updated_tracking.is_complete);
-
-        // Check measurement status
-        let status = db
-            .get_measurement_status(measurement_id, user_hash)
-            .await
-            .unwrap()
-            .unwrap();
-        assert_eq!(status.total_agents, 1);
-        assert_eq!(status.completed_agents, 1);
-        assert_eq!(status.total_expected_probes, 50);
-        assert_eq!(status.total_sent_probes, 50);
-        assert!(status.measurement_complete);
-
-        // Test user usage stats
-        let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
-        assert_eq!(stats.total_probes, 50);
-        assert_eq!(stats.limit, 500);
-        assert_eq!(stats.submission_count, 1);
-    }
 }
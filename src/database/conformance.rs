@@ -0,0 +1,2156 @@
+//! Conformance suite, run against every storage backend so that the in-memory
+//! storage the other tests use answers like the SQL ones. The PostgreSQL run
+//! needs a server: set `TEST_DATABASE_URL` (each test gets its own schema in
+//! that database), otherwise its tests pass without running.
+
+use super::postgres::PostgresStorage;
+use super::*;
+use crate::quota::ChargingMode;
+use chrono::SubsecRound;
+use sqlx::PgPool;
+
+fn memory() -> Database {
+    Database::new_mock()
+}
+
+async fn sqlite() -> Database {
+    let db = Database::new(&DatabaseConfig::new("sqlite::memory:".to_string()))
+        .await
+        .unwrap();
+    db.initialize().await.unwrap();
+    db
+}
+
+async fn postgres() -> Option<Database> {
+    let url = std::env::var("TEST_DATABASE_URL").ok()?;
+    let options = connect_options(&url).unwrap();
+    let schema = format!("conformance_{}", Uuid::new_v4().simple());
+
+    let pool = PgPool::connect_with(options.clone()).await.unwrap();
+    sqlx::query(sqlx::AssertSqlSafe(format!("CREATE SCHEMA {schema}")))
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+
+    let pool = PgPool::connect_with(options.options([("search_path", schema.as_str())]))
+        .await
+        .unwrap();
+    let db = Database::with_storage(PostgresStorage::new(pool));
+    db.initialize().await.unwrap();
+    Some(db)
+}
+
+macro_rules! conformance {
+    ($($test:ident),* $(,)?) => {
+        mod memory {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test(super::memory()).await;
+                }
+            )*
+        }
+
+        mod sqlite {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test(super::sqlite().await).await;
+                }
+            )*
+        }
+
+        mod postgres {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    if let Some(db) = super::postgres().await {
+                        super::$test(db).await;
+                    }
+                }
+            )*
+        }
+    };
+}
+
+conformance!(
+    usage_tracking,
+    user_limits,
+    rate_limiting,
+    default_limit_behavior,
+    daily_rate_limiting,
+    measurement_tracking,
+    list_user_measurements,
+    list_user_measurements_filters,
+    cancel_measurement,
+    measurement_cancellations,
+    measurement_filtered_probes,
+    multi_round_measurement,
+    cancelled_multi_round_measurement,
+    outbox_dispatch,
+    partially_delivered_dispatch,
+    delete_measurement_tracking,
+    per_agent_completion_tracking,
+    database_integration,
+    idempotency_keys,
+    advance_schedule_is_claimed_once,
+    usage_and_user_id_mappings,
+    measurement_status_and_cancellation,
+    idempotency_and_schedules,
+    target_lists_archives_and_rounds,
+    outbox_claims,
+    retention_purge,
+    usage_since_and_quotas,
+    quota_reservations,
+    concurrent_quota_reservations,
+    quota_refunds,
+    measurement_progress_moves_forward,
+);
+
+async fn usage_tracking(db: Database) {
+    // Initialize mock database
+    db.initialize().await.unwrap();
+
+    // Test recording probe usage via measurement tracking
+    let user_id = "test-user";
+    let probe_count = 5;
+    let measurement_id = Uuid::new_v4();
+    let agent_id = "test-agent";
+
+    // Create measurement tracking entry
+    let _tracking = db
+        .create_measurement_tracking(&hash_user_identifier(user_id), measurement_id, agent_id, 5)
+        .await
+        .unwrap();
+
+    // Update measurement tracking with probe count
+    let _updated = db
+        .update_measurement_probe_count(
+            measurement_id,
+            &hash_user_identifier(user_id),
+            agent_id,
+            probe_count,
+            true,
+        )
+        .await
+        .unwrap();
+
+    // Test getting user stats
+    let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
+    assert_eq!(stats.submission_count, 1);
+    assert_eq!(stats.total_probes, 5);
+    assert!(
+        stats.last_submitted.is_some(),
+        "last_submitted should be set"
+    );
+
+    // Test getting recent usage
+    let recent = db.get_recent_usage(Some(10)).await.unwrap();
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0].probe_count, probe_count);
+}
+
+async fn user_limits(db: Database) {
+    db.initialize().await.unwrap();
+
+    let user_id = "test-user";
+
+    // Test default limit (no limit set)
+    let limit = db.get_user_limit(user_id).await.unwrap();
+    assert!(limit.is_none());
+
+    // Test setting a user limit
+    let new_limit = db.set_user_limit(user_id, 5000).await.unwrap();
+    assert_eq!(new_limit.probe_limit, 5000);
+    assert_eq!(new_limit.user_hash, hash_user_identifier(user_id));
+
+    // Test getting the user limit
+    let retrieved_limit = db.get_user_limit(user_id).await.unwrap();
+    assert!(retrieved_limit.is_some());
+    let retrieved_limit = retrieved_limit.unwrap();
+    assert_eq!(retrieved_limit.probe_limit, 5000);
+    assert_eq!(retrieved_limit.user_hash, hash_user_identifier(user_id));
+
+    // Test updating the user limit
+    let updated_limit = db.set_user_limit(user_id, 8000).await.unwrap();
+    assert_eq!(updated_limit.probe_limit, 8000);
+    assert!(updated_limit.updated_at >= updated_limit.created_at);
+
+    // Test that stats include the custom limit
+    let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
+    assert_eq!(stats.limit, 8000);
+}
+
+async fn rate_limiting(db: Database) {
+    db.initialize().await.unwrap();
+
+    let user_id = "test-user";
+
+    // Set a low limit for testing
+    db.set_user_limit(user_id, 100).await.unwrap();
+
+    // Test that user can submit probes within limit
+    let can_submit = db.can_user_submit_probes(user_id, 50, None).await.unwrap();
+    assert!(can_submit);
+
+    // Record some usage via measurement tracking
+    let measurement_id = Uuid::new_v4();
+    let agent_id = "test-agent";
+    let _tracking = db
+        .create_measurement_tracking(&hash_user_identifier(user_id), measurement_id, agent_id, 60)
+        .await
+        .unwrap();
+    let _updated = db
+        .update_measurement_probe_count(
+            measurement_id,
+            &hash_user_identifier(user_id),
+            agent_id,
+            60,
+            true,
+        )
+        .await
+        .unwrap();
+
+    // Test that user can still submit more probes within limit
+    let can_submit = db.can_user_submit_probes(user_id, 30, None).await.unwrap();
+    assert!(can_submit);
+
+    // Test that user cannot exceed limit
+    let can_submit = db.can_user_submit_probes(user_id, 50, None).await.unwrap();
+    assert!(!can_submit);
+
+    // Test with exactly at limit
+    let can_submit = db.can_user_submit_probes(user_id, 40, None).await.unwrap();
+    assert!(can_submit);
+
+    // Test with one over limit
+    let can_submit = db.can_user_submit_probes(user_id, 41, None).await.unwrap();
+    assert!(!can_submit);
+}
+
+async fn default_limit_behavior(db: Database) {
+    db.initialize().await.unwrap();
+
+    let user_id = "test-user";
+
+    // Test that default limit is used when no limit is set
+    let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
+    assert_eq!(stats.limit, DEFAULT_PROBE_LIMIT); // Default limit
+
+    // Test rate limiting with default limit
+    let can_submit = db
+        .can_user_submit_probes(user_id, 5000, None)
+        .await
+        .unwrap();
+    assert!(can_submit);
+
+    let can_submit = db
+        .can_user_submit_probes(user_id, 15000, None)
+        .await
+        .unwrap();
+    assert!(!can_submit);
+}
+
+async fn daily_rate_limiting(db: Database) {
+    db.initialize().await.unwrap();
+
+    let user_id = "test-user";
+
+    // Set a limit for testing
+    db.set_user_limit(user_id, 100).await.unwrap();
+
+    // Test daily usage calculation
+    let today_start = Utc::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+
+    // Get today's stats (should be zero initially)
+    let stats = db
+        .get_user_usage_stats(user_id, Some(today_start), None)
+        .await
+        .unwrap();
+    assert_eq!(stats.total_probes, 0);
+    assert_eq!(stats.limit, 100);
+
+    // Record some usage today via measurement tracking
+    let measurement_id = Uuid::new_v4();
+    let agent_id = "test-agent";
+    let _tracking = db
+        .create_measurement_tracking(&hash_user_identifier(user_id), measurement_id, agent_id, 50)
+        .await
+        .unwrap();
+    let _updated = db
+        .update_measurement_probe_count(
+            measurement_id,
+            &hash_user_identifier(user_id),
+            agent_id,
+            50,
+            true,
+        )
+        .await
+        .unwrap();
+
+    // Test that today's usage is calculated correctly (use None for end_time to get current time)
+    let stats = db
+        .get_user_usage_stats(user_id, Some(today_start), None)
+        .await
+        .unwrap();
+    assert_eq!(stats.total_probes, 50);
+
+    // Test daily rate limiting
+    let can_submit = db.can_user_submit_probes(user_id, 30, None).await.unwrap();
+    assert!(can_submit); // 50 + 30 = 80 <= 100
+
+    let can_submit = db.can_user_submit_probes(user_id, 60, None).await.unwrap();
+    assert!(!can_submit); // 50 + 60 = 110 > 100
+
+    // Test default behavior (today's usage)
+    let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
+    assert_eq!(stats.total_probes, 50); // Should be today's usage
+}
+
+async fn measurement_tracking(db: Database) {
+    db.initialize().await.unwrap();
+
+    let measurement_id = Uuid::new_v4();
+    let user_hash = "test_user_hash";
+    let agent1_id = "agent1";
+    let agent2_id = "agent2";
+
+    // Test creating measurement tracking for multiple agents
+    let tracking1 = db
+        .create_measurement_tracking(user_hash, measurement_id, agent1_id, 100)
+        .await
+        .unwrap();
+
+    let _tracking2 = db
+        .create_measurement_tracking(user_hash, measurement_id, agent2_id, 100)
+        .await
+        .unwrap();
+
+    assert_eq!(tracking1.measurement_id, measurement_id);
+    assert_eq!(tracking1.agent_id, agent1_id);
+    assert_eq!(tracking1.expected_probes, 100);
+    assert_eq!(tracking1.sent_probes, 0);
+    assert!(!tracking1.is_complete);
+
+    // Test updating probe count for agent1 (partial completion)
+    let updated_tracking1 = db
+        .update_measurement_probe_count(measurement_id, user_hash, agent1_id, 50, false)
+        .await
+        .unwrap();
+
+    assert_eq!(updated_tracking1.sent_probes, 50);
+    assert!(!updated_tracking1.is_complete);
+
+    // Test completing agent1
+    let completed_tracking1 = db
+        .update_measurement_probe_count(measurement_id, user_hash, agent1_id, 100, true)
+        .await
+        .unwrap();
+
+    assert_eq!(completed_tracking1.sent_probes, 100);
+    assert!(completed_tracking1.is_complete);
+
+    // Test getting measurement status (should show 1 of 2 agents complete)
+    let status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(status.total_agents, 2);
+    assert_eq!(status.completed_agents, 1);
+    assert_eq!(status.total_expected_probes, 200); // 2 agents * 100 each
+    assert_eq!(status.total_sent_probes, 100); // Only agent1 has sent probes
+    assert!(!status.measurement_complete); // Not all agents complete
+
+    // Complete agent2
+    db.update_measurement_probe_count(measurement_id, user_hash, agent2_id, 100, true)
+        .await
+        .unwrap();
+
+    // Test measurement is now complete
+    let final_status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(final_status.completed_agents, 2);
+    assert_eq!(final_status.total_sent_probes, 200);
+    assert!(final_status.measurement_complete);
+
+    // Test getting detailed tracking
+    let tracking_details = db
+        .get_measurement_tracking(measurement_id, user_hash)
+        .await
+        .unwrap();
+
+    assert_eq!(tracking_details.len(), 2);
+    for tracking in &tracking_details {
+        assert_eq!(tracking.sent_probes, 100);
+        assert!(tracking.is_complete);
+    }
+}
+
+async fn list_user_measurements(db: Database) {
+    db.initialize().await.unwrap();
+
+    let user_hash = "test_user_hash";
+    let other_user_hash = "other_user_hash";
+
+    // First measurement: two agents, one complete.
+    let m1 = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, m1, "agent1", 100)
+        .await
+        .unwrap();
+    db.create_measurement_tracking(user_hash, m1, "agent2", 100)
+        .await
+        .unwrap();
+    db.update_measurement_probe_count(m1, user_hash, "agent1", 100, true)
+        .await
+        .unwrap();
+
+    // The Mock derives `last_updated` from `Utc::now()`, so a real (small)
+    // delay is needed to guarantee m2 sorts strictly before m1 — two
+    // consecutive in-memory ops can otherwise tie on the same instant and
+    // make the order of the pair non-deterministic.
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+    // Second measurement (touched later, so it should sort first).
+    let m2 = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, m2, "agent1", 10)
+        .await
+        .unwrap();
+    db.update_measurement_probe_count(m2, user_hash, "agent1", 10, true)
+        .await
+        .unwrap();
+
+    // A different user's measurement must not leak into the list.
+    let m3 = Uuid::new_v4();
+    db.create_measurement_tracking(other_user_hash, m3, "agent1", 5)
+        .await
+        .unwrap();
+
+    let measurements = db
+        .list_user_measurements(user_hash, &MeasurementListFilter::with_limit(20))
+        .await
+        .unwrap();
+
+    // Only this user's two measurements, newest (m2) first.
+    assert_eq!(measurements.len(), 2);
+    assert_eq!(measurements[0].measurement_id, m2);
+    assert!(measurements[0].measurement_complete);
+    assert_eq!(measurements[1].measurement_id, m1);
+    assert_eq!(measurements[1].total_agents, 2);
+    assert_eq!(measurements[1].completed_agents, 1);
+    assert!(!measurements[1].measurement_complete);
+
+    // The limit caps the number of returned measurements.
+    let limited = db
+        .list_user_measurements(user_hash, &MeasurementListFilter::with_limit(1))
+        .await
+        .unwrap();
+    assert_eq!(limited.len(), 1);
+    assert_eq!(limited[0].measurement_id, m2);
+}
+
+async fn list_user_measurements_filters(db: Database) {
+    use std::collections::HashSet;
+    db.initialize().await.unwrap();
+    let user = "u";
+
+    // complete, on agentA (created/updated first)
+    let m_complete = Uuid::new_v4();
+    db.create_measurement_tracking(user, m_complete, "agentA", 10)
+        .await
+        .unwrap();
+    db.update_measurement_probe_count(m_complete, user, "agentA", 10, true)
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+    // in-progress, on agentB
+    let m_inprogress = Uuid::new_v4();
+    db.create_measurement_tracking(user, m_inprogress, "agentB", 10)
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+    // cancelled, on agentB (touched last)
+    let m_cancelled = Uuid::new_v4();
+    db.create_measurement_tracking(user, m_cancelled, "agentB", 10)
+        .await
+        .unwrap();
+    db.cancel_measurement(m_cancelled, user).await.unwrap();
+
+    let base = MeasurementListFilter::with_limit(100);
+    let ids = |ms: Vec<MeasurementStatus>| -> HashSet<Uuid> {
+        ms.into_iter().map(|m| m.measurement_id).collect()
+    };
+    let with_status = |status: Vec<MeasurementState>| MeasurementListFilter {
+        status,
+        ..base.clone()
+    };
+
+    // single-state filters
+    let r = db
+        .list_user_measurements(user, &with_status(vec![MeasurementState::Complete]))
+        .await
+        .unwrap();
+    assert_eq!(ids(r), HashSet::from([m_complete]));
+    let r = db
+        .list_user_measurements(user, &with_status(vec![MeasurementState::InProgress]))
+        .await
+        .unwrap();
+    assert_eq!(ids(r), HashSet::from([m_inprogress]));
+    let r = db
+        .list_user_measurements(user, &with_status(vec![MeasurementState::Cancelled]))
+        .await
+        .unwrap();
+    assert_eq!(ids(r), HashSet::from([m_cancelled]));
+
+    // OR of multiple states
+    let r = db
+        .list_user_measurements(
+            user,
+            &with_status(vec![
+                MeasurementState::Complete,
+                MeasurementState::Cancelled,
+            ]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(r), HashSet::from([m_complete, m_cancelled]));
+
+    // agent filter
+    let r = db
+        .list_user_measurements(
+            user,
+            &MeasurementListFilter {
+                agent: Some("agentA".to_string()),
+                ..base.clone()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(r), HashSet::from([m_complete]));
+
+    // default order = newest last_updated first (m_cancelled touched last)
+    let r = db.list_user_measurements(user, &base).await.unwrap();
+    assert_eq!(r.first().unwrap().measurement_id, m_cancelled);
+
+    // sort by started ascending -> earliest started first (m_complete)
+    let by_started = db
+        .list_user_measurements(
+            user,
+            &MeasurementListFilter {
+                sort: MeasurementSort::Started,
+                reverse: true,
+                ..base.clone()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(by_started[0].measurement_id, m_complete);
+
+    // started-at window (timestamps taken from the started-ascending order:
+    // [0] m_complete, [1] m_inprogress, [2] m_cancelled)
+    let t_inprogress = by_started[1].started_at;
+    let t_cancelled = by_started[2].started_at;
+
+    // since excludes anything started earlier
+    let r = db
+        .list_user_measurements(
+            user,
+            &MeasurementListFilter {
+                since: Some(t_inprogress),
+                ..base.clone()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(r), HashSet::from([m_inprogress, m_cancelled]));
+
+    // until excludes anything started later
+    let r = db
+        .list_user_measurements(
+            user,
+            &MeasurementListFilter {
+                until: Some(t_inprogress),
+                ..base.clone()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(r), HashSet::from([m_complete, m_inprogress]));
+
+    // inclusive boundary: since == until matches exactly that instant
+    let r = db
+        .list_user_measurements(
+            user,
+            &MeasurementListFilter {
+                since: Some(t_inprogress),
+                until: Some(t_inprogress),
+                ..base.clone()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids(r), HashSet::from([m_inprogress]));
+
+    // since > until yields nothing
+    let r = db
+        .list_user_measurements(
+            user,
+            &MeasurementListFilter {
+                since: Some(t_cancelled),
+                until: Some(t_inprogress),
+                ..base.clone()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(r.is_empty());
+}
+
+async fn cancel_measurement(db: Database) {
+    db.initialize().await.unwrap();
+    let user_hash = "test_user_hash";
+
+    // A stuck measurement: one agent that never completed.
+    let m = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, m, "agent1", 3000)
+        .await
+        .unwrap();
+
+    let before = db
+        .get_measurement_status(m, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!before.measurement_complete);
+    assert!(!before.measurement_cancelled);
+
+    // Cancel marks the unfinished agent row -> 1 row changed.
+    assert_eq!(db.cancel_measurement(m, user_hash).await.unwrap(), 1);
+
+    let after = db
+        .get_measurement_status(m, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(after.measurement_complete); // terminal now
+    assert!(after.measurement_cancelled);
+    assert_eq!(after.completed_agents, 0); // cancelled is not "completed"
+
+    // Idempotent: a second cancel changes nothing.
+    assert_eq!(db.cancel_measurement(m, user_hash).await.unwrap(), 0);
+
+    // A different user cannot cancel someone else's measurement.
+    let other = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, other, "agent1", 10)
+        .await
+        .unwrap();
+    assert_eq!(db.cancel_measurement(other, "other_user").await.unwrap(), 0);
+    assert!(
+        !db.get_measurement_status(other, user_hash)
+            .await
+            .unwrap()
+            .unwrap()
+            .measurement_complete
+    );
+}
+
+async fn measurement_cancellations(db: Database) {
+    let user_hash = "test_user_hash";
+
+    let m1 = Uuid::new_v4();
+    let m2 = Uuid::new_v4();
+    for (m, agent) in [(m1, "agent1"), (m1, "agent2"), (m2, "agent1")] {
+        db.create_measurement_tracking(user_hash, m, agent, 10)
+            .await
+            .unwrap();
+    }
+    // A finished agent has nothing to stop
+    db.update_measurement_probe_count(m1, user_hash, "agent2", 10, true)
+        .await
+        .unwrap();
+
+    db.cancel_measurement(m1, user_hash).await.unwrap();
+    db.cancel_measurement(m2, user_hash).await.unwrap();
+
+    let feed = db.get_agent_cancellations("agent1", 0, 10).await.unwrap();
+    assert_eq!(
+        feed.iter().map(|c| c.measurement_id).collect::<Vec<_>>(),
+        vec![m1, m2]
+    );
+    let after = db
+        .get_agent_cancellations("agent1", feed[0].id, 10)
+        .await
+        .unwrap();
+    assert_eq!(after.len(), 1);
+    assert_eq!(after[0].measurement_id, m2);
+    assert!(
+        db.get_agent_cancellations("agent2", 0, 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    let acknowledged = db
+        .acknowledge_cancellation(m1, "agent1")
+        .await
+        .unwrap()
+        .unwrap();
+    let first = acknowledged.acknowledged_at.unwrap();
+    let again = db
+        .acknowledge_cancellation(m1, "agent1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(again.acknowledged_at, Some(first));
+    assert!(
+        db.acknowledge_cancellation(m1, "agent2")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    let cancellations = db.get_measurement_cancellations(m1).await.unwrap();
+    assert_eq!(cancellations.len(), 1);
+    assert!(cancellations[0].acknowledged_at.is_some());
+}
+
+async fn measurement_filtered_probes(db: Database) {
+    db.initialize().await.unwrap();
+    let user_hash = "test_user_hash";
+
+    let m = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, m, "agent1", 10)
+        .await
+        .unwrap();
+    db.create_measurement_tracking(user_hash, m, "agent2", 10)
+        .await
+        .unwrap();
+
+    // Another user's write is ignored
+    db.add_measurement_filtered_probes(m, "other_user", 7)
+        .await
+        .unwrap();
+    let status = db
+        .get_measurement_status(m, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.filtered_probes, 0);
+
+    db.add_measurement_filtered_probes(m, user_hash, 3)
+        .await
+        .unwrap();
+    let status = db
+        .get_measurement_status(m, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    // Recorded per agent row, but reported once per measurement
+    assert_eq!(status.filtered_probes, 3);
+}
+
+async fn multi_round_measurement(db: Database) {
+    let user_hash = "test_user_hash";
+    let m = Uuid::new_v4();
+
+    db.create_multi_round_measurement(m, user_hash, &["agent1".to_string()], "[]")
+        .await
+        .unwrap();
+    assert!(
+        db.get_multi_round_measurement(m, "other_user")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    for round in 1..=2 {
+        assert!(
+            db.add_measurement_expected_probes(m, user_hash, "agent1", 5)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            db.record_measurement_round(m, user_hash).await.unwrap(),
+            Some(round)
+        );
+    }
+    assert!(
+        !db.add_measurement_expected_probes(m, user_hash, "agent2", 5)
+            .await
+            .unwrap()
+    );
+    let status = db
+        .get_measurement_status(m, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_expected_probes, 10);
+
+    assert!(
+        db.close_multi_round_measurement(m, user_hash)
+            .await
+            .unwrap()
+    );
+    assert!(
+        !db.close_multi_round_measurement(m, user_hash)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        db.record_measurement_round(m, user_hash).await.unwrap(),
+        None
+    );
+
+    let measurement = db
+        .get_multi_round_measurement(m, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(measurement.rounds, 2);
+    assert!(measurement.closed_at.is_some());
+}
+
+async fn cancelled_multi_round_measurement(db: Database) {
+    let user_hash = "test_user_hash";
+    let m = Uuid::new_v4();
+    let round = DispatchTracking {
+        measurement_id: m,
+        user_hash: user_hash.to_string(),
+        agent_ids: vec!["agent1".to_string(), "agent2".to_string()],
+        probes: 5,
+        new_measurement: false,
+        filtered: 0,
+    };
+
+    db.create_multi_round_measurement(m, user_hash, &round.agent_ids, "[]")
+        .await
+        .unwrap();
+    db.record_dispatch(&round, &[]).await.unwrap();
+
+    // Cancelling closes the measurement for rounds
+    assert_eq!(db.cancel_measurement(m, user_hash).await.unwrap(), 2);
+    let measurement = db
+        .get_multi_round_measurement(m, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(measurement.closed_at.is_some());
+
+    // A round racing the cancellation is not recorded
+    assert!(matches!(
+        db.record_dispatch(&round, &[]).await,
+        Err(sqlx::Error::RowNotFound)
+    ));
+    // Nor a round for an agent without tracking, even partly
+    let other = Uuid::new_v4();
+    db.record_dispatch(
+        &DispatchTracking {
+            measurement_id: other,
+            agent_ids: vec!["agent1".to_string()],
+            probes: 0,
+            new_measurement: true,
+            ..round.clone()
+        },
+        &[],
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        db.record_dispatch(
+            &DispatchTracking {
+                measurement_id: other,
+                ..round.clone()
+            },
+            &[]
+        )
+        .await,
+        Err(sqlx::Error::RowNotFound)
+    ));
+    let status = db
+        .get_measurement_status(other, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_expected_probes, 0);
+}
+
+async fn outbox_dispatch(db: Database) {
+    let user_hash = "test_user_hash";
+    let message = |payload: &[u8]| OutboundMessage {
+        key: "key".to_string(),
+        payload: payload.to_vec(),
+        headers: vec![("agent1".to_string(), "{}".to_string())],
+        topic: Some("probes-agent1".to_string()),
+        partition: None,
+    };
+
+    let m1 = Uuid::new_v4();
+    let mut tracking = DispatchTracking {
+        measurement_id: m1,
+        user_hash: user_hash.to_string(),
+        agent_ids: vec!["agent1".to_string(), "agent2".to_string()],
+        probes: 5,
+        new_measurement: true,
+        filtered: 1,
+    };
+    db.record_dispatch(&tracking, &[message(b"a"), message(b"b")])
+        .await
+        .unwrap();
+    let status = db
+        .get_measurement_status(m1, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_expected_probes, 10);
+    assert_eq!(status.filtered_probes, 1);
+    assert_eq!(status.queued_batches, 2);
+
+    // A failed round is reverted
+    tracking.new_measurement = false;
+    db.record_dispatch(&tracking, &[]).await.unwrap();
+    db.revert_dispatch(&tracking).await.unwrap();
+    let status = db
+        .get_measurement_status(m1, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_expected_probes, 10);
+    assert_eq!(status.filtered_probes, 1);
+
+    let m2 = Uuid::new_v4();
+    db.record_dispatch(
+        &DispatchTracking {
+            measurement_id: m2,
+            agent_ids: vec!["agent1".to_string()],
+            new_measurement: true,
+            ..tracking.clone()
+        },
+        &[message(b"c")],
+    )
+    .await
+    .unwrap();
+
+    // One measurement per claim, oldest first, in queue order
+    let now = Utc::now();
+    let lease = now + chrono::Duration::seconds(60);
+    let claimed = db.claim_outbox_messages(now, lease, 1).await.unwrap();
+    assert_eq!(
+        claimed
+            .iter()
+            .map(|m| m.payload.clone())
+            .collect::<Vec<_>>(),
+        vec![b"a".to_vec(), b"b".to_vec()]
+    );
+    let claimed_ids: Vec<i64> = claimed.iter().map(|m| m.id).collect();
+
+    // Held messages are not claimed again until their lease expires
+    let claimed = db.claim_outbox_messages(now, lease, 10).await.unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert_eq!(claimed[0].measurement_id, m2);
+    db.record_outbox_failure(&[claimed[0].id], "broker down")
+        .await
+        .unwrap();
+
+    db.mark_outbox_sent(&claimed_ids).await.unwrap();
+    let status = db
+        .get_measurement_status(m1, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.queued_batches, 0);
+
+    let retried = db.claim_outbox_messages(now, lease, 10).await.unwrap();
+    assert_eq!(retried.len(), 1);
+    assert_eq!(retried[0].attempts, 1);
+    assert_eq!(retried[0].last_error.as_deref(), Some("broker down"));
+    assert_eq!(retried[0].kafka_topic.as_deref(), Some("probes-agent1"));
+}
+
+async fn partially_delivered_dispatch(db: Database) {
+    let user_hash = "test_user_hash";
+    let measurement_id = Uuid::new_v4();
+    let mut tracking = DispatchTracking {
+        measurement_id,
+        user_hash: user_hash.to_string(),
+        agent_ids: vec!["agent1".to_string(), "agent2".to_string()],
+        probes: 5,
+        new_measurement: true,
+        filtered: 1,
+    };
+
+    // Only the first agent got some of its probes
+    db.record_dispatch(&tracking, &[]).await.unwrap();
+    db.revert_undelivered(&tracking, &[3, 0]).await.unwrap();
+    let rows = db
+        .get_measurement_tracking(measurement_id, user_hash)
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].agent_id, "agent1");
+    assert_eq!(rows[0].expected_probes, 3);
+    assert_eq!(rows[0].filtered_probes, 1);
+
+    // A round keeps the rows of agents that got nothing
+    tracking.agent_ids = vec!["agent1".to_string()];
+    tracking.new_measurement = false;
+    db.record_dispatch(&tracking, &[]).await.unwrap();
+    db.revert_undelivered(&tracking, &[2]).await.unwrap();
+    let status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_expected_probes, 5);
+    assert_eq!(status.filtered_probes, 2);
+
+    db.record_dispatch(&tracking, &[]).await.unwrap();
+    db.revert_undelivered(&tracking, &[0]).await.unwrap();
+    let status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_expected_probes, 5);
+    assert_eq!(status.filtered_probes, 2);
+}
+
+async fn delete_measurement_tracking(db: Database) {
+    let m = Uuid::new_v4();
+    db.create_measurement_tracking("user", m, "agent1", 1)
+        .await
+        .unwrap();
+    assert_eq!(db.delete_measurement_tracking(m, "other").await.unwrap(), 0);
+    assert_eq!(db.delete_measurement_tracking(m, "user").await.unwrap(), 1);
+    assert!(
+        db.get_measurement_status(m, "user")
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+async fn per_agent_completion_tracking(db: Database) {
+    db.initialize().await.unwrap();
+
+    let measurement_id = Uuid::new_v4();
+    let user_hash = "test_user_hash";
+    let agent1_id = "fast_agent";
+    let agent2_id = "slow_agent";
+    let agent3_id = "failing_agent";
+
+    // Create tracking for 3 agents
+    for agent_id in [agent1_id, agent2_id, agent3_id] {
+        db.create_measurement_tracking(user_hash, measurement_id, agent_id, 50)
+            .await
+            .unwrap();
+    }
+
+    // Agent1 completes quickly
+    db.update_measurement_probe_count(measurement_id, user_hash, agent1_id, 50, true)
+        .await
+        .unwrap();
+
+    // Agent2 makes partial progress
+    db.update_measurement_probe_count(measurement_id, user_hash, agent2_id, 25, false)
+        .await
+        .unwrap();
+
+    // Agent3 fails (0 probes sent)
+    db.update_measurement_probe_count(measurement_id, user_hash, agent3_id, 0, true)
+        .await
+        .unwrap();
+
+    // Check status shows per-agent completion
+    let status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(status.total_agents, 3);
+    assert_eq!(status.completed_agents, 2); // agent1 and agent3 marked complete
+    assert_eq!(status.total_expected_probes, 150); // 3 agents * 50 each
+    assert_eq!(status.total_sent_probes, 75); // 50 + 25 + 0
+    assert!(!status.measurement_complete); // agent2 not complete yet
+
+    // Get detailed tracking to verify per-agent status
+    let tracking_details = db
+        .get_measurement_tracking(measurement_id, user_hash)
+        .await
+        .unwrap();
+
+    let agent1_tracking = tracking_details
+        .iter()
+        .find(|t| t.agent_id == agent1_id)
+        .unwrap();
+    let agent2_tracking = tracking_details
+        .iter()
+        .find(|t| t.agent_id == agent2_id)
+        .unwrap();
+    let agent3_tracking = tracking_details
+        .iter()
+        .find(|t| t.agent_id == agent3_id)
+        .unwrap();
+
+    assert_eq!(agent1_tracking.sent_probes, 50);
+    assert!(agent1_tracking.is_complete);
+
+    assert_eq!(agent2_tracking.sent_probes, 25);
+    assert!(!agent2_tracking.is_complete);
+
+    assert_eq!(agent3_tracking.sent_probes, 0);
+    assert!(agent3_tracking.is_complete); // Marked complete even with 0 probes (failure case)
+
+    // Agent2 finishes
+    db.update_measurement_probe_count(measurement_id, user_hash, agent2_id, 50, true)
+        .await
+        .unwrap();
+
+    // Verify measurement is now complete
+    let final_status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(final_status.measurement_complete);
+    assert_eq!(final_status.completed_agents, 3);
+    assert_eq!(final_status.total_sent_probes, 100); // 50 + 50 + 0
+}
+
+async fn database_integration(db: Database) {
+    // Initialize the database (run migrations)
+    db.initialize().await.unwrap();
+
+    let user_id = "integrate_test_user";
+    let measurement_id = Uuid::new_v4();
+
+    // Test setting and getting user limit
+    let limit = db.set_user_limit(user_id, 500).await.unwrap();
+    assert_eq!(limit.probe_limit, 500);
+
+    let retrieved_limit = db.get_user_limit(user_id).await.unwrap().unwrap();
+    assert_eq!(retrieved_limit.probe_limit, 500);
+
+    // Test measurement tracking
+    let agent_id = "test_agent";
+    let user_hash = &hash_user_identifier(user_id);
+    let tracking = db
+        .create_measurement_tracking(user_hash, measurement_id, agent_id, 50)
+        .await
+        .unwrap();
+    assert_eq!(tracking.expected_probes, 50);
+    assert_eq!(tracking.sent_probes, 0);
+    assert!(!tracking.is_complete);
+
+    // Update and complete the measurement tracking
+    let updated_tracking = db
+        .update_measurement_probe_count(measurement_id, user_hash, agent_id, 50, true)
+        .await
+        .unwrap();
+    assert_eq!(updated_tracking.sent_probes, 50);
+    assert!(updated_tracking.is_complete);
+
+    // Check measurement status
+    let status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_agents, 1);
+    assert_eq!(status.completed_agents, 1);
+    assert_eq!(status.total_expected_probes, 50);
+    assert_eq!(status.total_sent_probes, 50);
+    assert!(status.measurement_complete);
+
+    // Test user usage stats
+    let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
+    assert_eq!(stats.total_probes, 50);
+    assert_eq!(stats.limit, 500);
+    assert_eq!(stats.submission_count, 1);
+}
+
+async fn idempotency_keys(db: Database) {
+    let user_hash = hash_user_identifier("test-user");
+    let far_past = Utc::now() - chrono::Duration::hours(24);
+
+    // First claim owns the key
+    let claimed = db
+        .claim_idempotency_key(&user_hash, "key-1", "hash-a", far_past, far_past)
+        .await
+        .unwrap();
+    assert!(claimed.is_none());
+
+    // A concurrent retry sees the in-flight claim
+    let existing = db
+        .claim_idempotency_key(&user_hash, "key-1", "hash-a", far_past, far_past)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(existing.request_hash, "hash-a");
+    assert!(existing.response.is_none());
+
+    // Releasing an in-flight key lets the next request claim it again
+    db.release_idempotency_key(&user_hash, "key-1")
+        .await
+        .unwrap();
+    let claimed = db
+        .claim_idempotency_key(&user_hash, "key-1", "hash-a", far_past, far_past)
+        .await
+        .unwrap();
+    assert!(claimed.is_none());
+
+    // Completed keys return the stored response and survive a release
+    let measurement_id = Uuid::new_v4();
+    db.complete_idempotency_key(&user_hash, "key-1", measurement_id, "{}")
+        .await
+        .unwrap();
+    db.release_idempotency_key(&user_hash, "key-1")
+        .await
+        .unwrap();
+    let existing = db
+        .claim_idempotency_key(&user_hash, "key-1", "hash-a", far_past, far_past)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(existing.measurement_id, Some(measurement_id));
+    assert_eq!(existing.response.as_deref(), Some("{}"));
+
+    // Keys are scoped per user
+    let other_hash = hash_user_identifier("other-user");
+    let claimed = db
+        .claim_idempotency_key(&other_hash, "key-1", "hash-b", far_past, far_past)
+        .await
+        .unwrap();
+    assert!(claimed.is_none());
+
+    // Once expired, the key can be claimed for a new request
+    let claimed = db
+        .claim_idempotency_key(&user_hash, "key-1", "hash-b", Utc::now(), far_past)
+        .await
+        .unwrap();
+    assert!(claimed.is_none());
+
+    // A claim left in flight past its lease is taken over
+    let lease_expired = Utc::now() + chrono::Duration::seconds(1);
+    let claimed = db
+        .claim_idempotency_key(&user_hash, "key-1", "hash-b", far_past, lease_expired)
+        .await
+        .unwrap();
+    assert!(claimed.is_none());
+    db.complete_idempotency_key(&user_hash, "key-1", measurement_id, "{}")
+        .await
+        .unwrap();
+    let existing = db
+        .claim_idempotency_key(&user_hash, "key-1", "hash-b", far_past, lease_expired)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(existing.response.as_deref(), Some("{}"));
+}
+
+async fn advance_schedule_is_claimed_once(db: Database) {
+    let user_hash = hash_user_identifier("test-user");
+    let run_at = Utc::now();
+
+    let schedule = db
+        .create_schedule(&user_hash, None, "{}", Some(60), run_at)
+        .await
+        .unwrap();
+    let due = db.get_due_schedules(Utc::now(), 10).await.unwrap();
+    assert_eq!(due.len(), 1);
+
+    // Only the first of two racing schedulers moves the schedule forward
+    let next = run_at + chrono::Duration::seconds(60);
+    assert!(
+        db.advance_schedule(schedule.id, run_at, Some(next))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !db.advance_schedule(schedule.id, run_at, Some(next))
+            .await
+            .unwrap()
+    );
+    assert!(
+        db.get_due_schedules(Utc::now(), 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // Deleting the schedule removes its run history
+    db.record_schedule_run(schedule.id, Some(Uuid::new_v4()), None)
+        .await
+        .unwrap();
+    assert!(db.delete_schedule(schedule.id, &user_hash).await.unwrap());
+    assert!(
+        db.list_schedule_runs(schedule.id, 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+async fn usage_and_user_id_mappings(db: Database) {
+    db.ping().await.unwrap();
+
+    let user_id = "test-user";
+    let user_hash = hash_user_identifier(user_id);
+    assert!(db.get_user_limit(user_id).await.unwrap().is_none());
+    assert_eq!(
+        db.set_user_limit(user_id, 100).await.unwrap().probe_limit,
+        100
+    );
+    let updated = db.set_user_limit(user_id, 80).await.unwrap();
+    assert_eq!(updated.probe_limit, 80);
+    assert!(updated.updated_at >= updated.created_at);
+
+    let measurement_id = Uuid::new_v4();
+    db.create_measurement_tracking(&user_hash, measurement_id, "agent1", 50)
+        .await
+        .unwrap();
+    let tracking = db
+        .update_measurement_probe_count(measurement_id, &user_hash, "agent1", 50, true)
+        .await
+        .unwrap();
+    assert!(tracking.is_complete);
+
+    let stats = db.get_user_usage_stats(user_id, None, None).await.unwrap();
+    assert_eq!(stats.submission_count, 1);
+    assert_eq!(stats.total_probes, 50);
+    assert_eq!(stats.limit, 80);
+    assert!(stats.last_submitted.is_some());
+    assert!(db.can_user_submit_probes(user_id, 30, None).await.unwrap());
+    assert!(!db.can_user_submit_probes(user_id, 31, None).await.unwrap());
+
+    let recent = db.get_recent_usage(Some(10)).await.unwrap();
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0].id, measurement_id);
+    assert_eq!(recent[0].probe_count, 50);
+
+    // Unique constraint violations surface as AlreadyExists
+    db.create_user_id_mapping(&user_hash, 7).await.unwrap();
+    assert_eq!(db.get_user_id_by_hash(&user_hash).await.unwrap(), Some(7));
+    for (hash, id) in [(user_hash.as_str(), 8), ("other", 7)] {
+        match db.create_user_id_mapping(hash, id).await {
+            Err(sqlx::Error::Io(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists)
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+}
+
+async fn measurement_status_and_cancellation(db: Database) {
+    let user_hash = "test_user_hash";
+    let measurement_id = Uuid::new_v4();
+
+    for agent_id in ["agent1", "agent2", "agent3"] {
+        db.create_measurement_tracking(user_hash, measurement_id, agent_id, 10)
+            .await
+            .unwrap();
+    }
+    db.add_measurement_filtered_probes(measurement_id, user_hash, 2)
+        .await
+        .unwrap();
+    db.update_measurement_probe_count(measurement_id, user_hash, "agent1", 10, true)
+        .await
+        .unwrap();
+
+    let status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_agents, 3);
+    assert_eq!(status.total_expected_probes, 30);
+    assert_eq!(status.total_sent_probes, 10);
+    assert_eq!(status.completed_agents, 1);
+    assert_eq!(status.filtered_probes, 2);
+    assert!(!status.measurement_complete);
+    assert!(!status.measurement_cancelled);
+
+    let in_progress = db
+        .list_user_measurements(
+            user_hash,
+            &MeasurementListFilter {
+                status: vec![MeasurementState::InProgress],
+                agent: Some("agent2".to_string()),
+                ..MeasurementListFilter::with_limit(10)
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(in_progress.len(), 1);
+
+    // Only the agents that had not finished are cancelled and notified
+    assert_eq!(
+        db.cancel_measurement(measurement_id, user_hash)
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        db.cancel_measurement(measurement_id, user_hash)
+            .await
+            .unwrap(),
+        0
+    );
+    let status = db
+        .get_measurement_status(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(status.measurement_complete);
+    assert!(status.measurement_cancelled);
+
+    let cancellations = db.get_agent_cancellations("agent2", 0, 10).await.unwrap();
+    assert_eq!(cancellations.len(), 1);
+    assert!(
+        db.get_agent_cancellations("agent2", cancellations[0].id, 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    let acknowledged = db
+        .acknowledge_cancellation(measurement_id, "agent2")
+        .await
+        .unwrap()
+        .unwrap();
+    let again = db
+        .acknowledge_cancellation(measurement_id, "agent2")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(again.acknowledged_at, acknowledged.acknowledged_at);
+    assert!(
+        db.acknowledge_cancellation(measurement_id, "agent1")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(
+        db.get_measurement_cancellations(measurement_id)
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+
+    let cancelled = db
+        .list_user_measurements(
+            user_hash,
+            &MeasurementListFilter {
+                status: vec![MeasurementState::Cancelled],
+                ..MeasurementListFilter::with_limit(10)
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(cancelled.len(), 1);
+    assert_eq!(
+        db.delete_measurement_tracking(measurement_id, user_hash)
+            .await
+            .unwrap(),
+        3
+    );
+}
+
+async fn idempotency_and_schedules(db: Database) {
+    let user_hash = hash_user_identifier("test-user");
+    let far_past = Utc::now() - chrono::Duration::hours(24);
+
+    let claim = |hash: &'static str, expired_before| {
+        let db = db.clone();
+        let user_hash = user_hash.clone();
+        async move {
+            db.claim_idempotency_key(&user_hash, "key-1", hash, expired_before, far_past)
+                .await
+                .unwrap()
+        }
+    };
+    assert!(claim("hash-a", far_past).await.is_none());
+    assert!(claim("hash-a", far_past).await.unwrap().response.is_none());
+
+    let measurement_id = Uuid::new_v4();
+    db.complete_idempotency_key(&user_hash, "key-1", measurement_id, "{}")
+        .await
+        .unwrap();
+    db.release_idempotency_key(&user_hash, "key-1")
+        .await
+        .unwrap();
+    let existing = claim("hash-a", far_past).await.unwrap();
+    assert_eq!(existing.measurement_id, Some(measurement_id));
+    assert_eq!(existing.response.as_deref(), Some("{}"));
+    // Once expired, the key is taken over
+    assert!(claim("hash-b", Utc::now()).await.is_none());
+
+    // PostgreSQL keeps microseconds
+    let run_at = Utc::now().trunc_subsecs(6);
+    let schedule = db
+        .create_schedule(&user_hash, Some("daily"), "{}", Some(60), run_at)
+        .await
+        .unwrap();
+    assert_eq!(schedule.next_run_at, Some(run_at));
+    assert_eq!(db.get_due_schedules(Utc::now(), 10).await.unwrap().len(), 1);
+
+    let next = run_at + chrono::Duration::seconds(60);
+    assert!(
+        db.advance_schedule(schedule.id, run_at, Some(next))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !db.advance_schedule(schedule.id, run_at, Some(next))
+            .await
+            .unwrap()
+    );
+    assert!(
+        db.get_due_schedules(Utc::now(), 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    db.record_schedule_run(schedule.id, None, Some("no agents"))
+        .await
+        .unwrap();
+    assert_eq!(
+        db.list_schedule_runs(schedule.id, 10).await.unwrap().len(),
+        1
+    );
+    assert_eq!(db.list_user_schedules(&user_hash).await.unwrap().len(), 1);
+
+    // Deleting the schedule removes its run history
+    assert!(db.delete_schedule(schedule.id, &user_hash).await.unwrap());
+    assert!(
+        db.get_schedule(schedule.id, &user_hash)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        db.list_schedule_runs(schedule.id, 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+async fn target_lists_archives_and_rounds(db: Database) {
+    let user_hash = "test_user_hash";
+
+    let targets = vec!["192.0.2.1".to_string(), "2001:db8::1".to_string()];
+    let list = db
+        .create_target_list(user_hash, "list", &targets)
+        .await
+        .unwrap();
+    assert_eq!(list.target_count, 2);
+    assert_eq!(
+        db.get_target_list_targets(list.id, user_hash)
+            .await
+            .unwrap(),
+        Some(targets)
+    );
+    let renamed = db
+        .update_target_list(list.id, user_hash, Some("renamed"), None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(renamed.name, "renamed");
+    assert_eq!(renamed.target_count, 2);
+    assert_eq!(db.list_user_target_lists(user_hash).await.unwrap().len(), 1);
+    assert!(db.delete_target_list(list.id, user_hash).await.unwrap());
+    assert!(
+        db.get_target_list(list.id, user_hash)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    let measurement_id = Uuid::new_v4();
+    let archive = db
+        .append_measurement_archive(measurement_id, user_hash, "blob", "[]", 3, 42)
+        .await
+        .unwrap();
+    assert_eq!(archive.segments, 1);
+    let archive = db
+        .get_measurement_archive(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(archive.size_bytes, 42);
+    assert_eq!(archive.segments, 1);
+
+    // A round adds a segment
+    let archive = db
+        .append_measurement_archive(measurement_id, user_hash, "blob", "[]", 2, 8)
+        .await
+        .unwrap();
+    assert_eq!(archive.blob_key, "blob");
+    assert_eq!(archive.probe_count, 5);
+    assert_eq!(archive.size_bytes, 50);
+    assert_eq!(archive.segments, 2);
+    // Not to another user's measurement
+    assert!(
+        db.append_measurement_archive(measurement_id, "other_user", "blob", "[]", 1, 1)
+            .await
+            .is_err()
+    );
+
+    db.create_multi_round_measurement(measurement_id, user_hash, &[], "[]")
+        .await
+        .unwrap();
+    assert_eq!(
+        db.record_measurement_round(measurement_id, user_hash)
+            .await
+            .unwrap(),
+        Some(1)
+    );
+    assert!(
+        db.close_multi_round_measurement(measurement_id, user_hash)
+            .await
+            .unwrap()
+    );
+    assert!(
+        db.record_measurement_round(measurement_id, user_hash)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    let measurement = db
+        .get_multi_round_measurement(measurement_id, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(measurement.rounds, 1);
+    assert!(measurement.closed_at.is_some());
+}
+
+async fn outbox_claims(db: Database) {
+    let user_hash = "test_user_hash";
+    let message = |payload: &[u8]| OutboundMessage {
+        key: "key".to_string(),
+        payload: payload.to_vec(),
+        headers: vec![("agent1".to_string(), "{}".to_string())],
+        topic: Some("probes-agent1".to_string()),
+        partition: Some(3),
+    };
+
+    let m1 = Uuid::new_v4();
+    let mut tracking = DispatchTracking {
+        measurement_id: m1,
+        user_hash: user_hash.to_string(),
+        agent_ids: vec!["agent1".to_string(), "agent2".to_string()],
+        probes: 5,
+        new_measurement: true,
+        filtered: 1,
+    };
+    db.record_dispatch(&tracking, &[message(b"a"), message(b"b")])
+        .await
+        .unwrap();
+
+    // A failed round is reverted
+    tracking.new_measurement = false;
+    db.record_dispatch(&tracking, &[]).await.unwrap();
+    db.revert_dispatch(&tracking).await.unwrap();
+    let status = db
+        .get_measurement_status(m1, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_expected_probes, 10);
+    assert_eq!(status.filtered_probes, 1);
+    assert_eq!(status.queued_batches, 2);
+
+    let m2 = Uuid::new_v4();
+    db.record_dispatch(
+        &DispatchTracking {
+            measurement_id: m2,
+            agent_ids: vec!["agent1".to_string()],
+            new_measurement: true,
+            ..tracking.clone()
+        },
+        &[message(b"c")],
+    )
+    .await
+    .unwrap();
+
+    // One measurement per claim, oldest first, in queue order
+    let now = Utc::now();
+    let lease = now + chrono::Duration::seconds(60);
+    let claimed = db.claim_outbox_messages(now, lease, 1).await.unwrap();
+    assert_eq!(
+        claimed
+            .iter()
+            .map(|m| m.payload.clone())
+            .collect::<Vec<_>>(),
+        vec![b"a".to_vec(), b"b".to_vec()]
+    );
+    assert_eq!(claimed[0].kafka_partition, Some(3));
+    let claimed_ids: Vec<i64> = claimed.iter().map(|m| m.id).collect();
+
+    // Held messages are not claimed again until their lease expires
+    let claimed = db.claim_outbox_messages(now, lease, 10).await.unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert_eq!(claimed[0].measurement_id, m2);
+    db.record_outbox_failure(&[claimed[0].id], "broker down")
+        .await
+        .unwrap();
+
+    db.mark_outbox_sent(&claimed_ids).await.unwrap();
+    let status = db
+        .get_measurement_status(m1, user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.queued_batches, 0);
+
+    let retried = db.claim_outbox_messages(now, lease, 10).await.unwrap();
+    assert_eq!(retried.len(), 1);
+    assert_eq!(retried[0].attempts, 1);
+    assert_eq!(retried[0].last_error.as_deref(), Some("broker down"));
+}
+
+async fn retention_purge(db: Database) {
+    let user_hash = "retention_user";
+    let idle_hash = "idle_user";
+    db.create_user_id_mapping(user_hash, 1).await.unwrap();
+    db.create_user_id_mapping(idle_hash, 2).await.unwrap();
+
+    let complete = Uuid::new_v4();
+    for (agent, expected, sent) in [("agent1", 100, 100), ("agent2", 50, 40)] {
+        db.create_measurement_tracking(user_hash, complete, agent, expected)
+            .await
+            .unwrap();
+        db.update_measurement_probe_count(complete, user_hash, agent, sent, true)
+            .await
+            .unwrap();
+    }
+    let cancelled = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, cancelled, "agent1", 30)
+        .await
+        .unwrap();
+    db.cancel_measurement(cancelled, user_hash).await.unwrap();
+    // Kept: still running, and open for more rounds
+    let running = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, running, "agent1", 10)
+        .await
+        .unwrap();
+    let open = Uuid::new_v4();
+    db.create_multi_round_measurement(open, user_hash, &[], "{}")
+        .await
+        .unwrap();
+    db.create_measurement_tracking(user_hash, open, "agent1", 20)
+        .await
+        .unwrap();
+    db.update_measurement_probe_count(open, user_hash, "agent1", 20, true)
+        .await
+        .unwrap();
+
+    let far_past = Utc::now() - chrono::Duration::days(365);
+    let nothing = PurgedMeasurements::default();
+    assert_eq!(
+        db.count_expired_measurements(far_past).await.unwrap(),
+        nothing
+    );
+    assert_eq!(
+        db.purge_expired_measurements(far_past, 10).await.unwrap(),
+        nothing
+    );
+
+    let before = Utc::now() + chrono::Duration::minutes(1);
+    let expired = PurgedMeasurements {
+        measurements: 2,
+        rows: 3,
+    };
+    assert_eq!(
+        db.count_expired_measurements(before).await.unwrap(),
+        expired
+    );
+
+    // In chunks of one measurement
+    let first = db.purge_expired_measurements(before, 1).await.unwrap();
+    assert_eq!(first.measurements, 1);
+    let second = db.purge_expired_measurements(before, 1).await.unwrap();
+    assert_eq!(second.measurements, 1);
+    assert_eq!(first.rows + second.rows, 3);
+    assert_eq!(
+        db.purge_expired_measurements(before, 1).await.unwrap(),
+        nothing
+    );
+
+    for purged in [complete, cancelled] {
+        assert!(
+            db.get_measurement_status(purged, user_hash)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+    assert!(
+        db.get_measurement_cancellations(cancelled)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    for kept in [running, open] {
+        assert!(
+            db.get_measurement_status(kept, user_hash)
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    let today = Utc::now().date_naive();
+    let usage = db
+        .get_daily_usage(user_hash, today - chrono::Duration::days(1))
+        .await
+        .unwrap();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].day, today);
+    assert_eq!(usage[0].measurements, 2);
+    assert_eq!(usage[0].expected_probes, 180);
+    assert_eq!(usage[0].sent_probes, 140);
+
+    // User IDs, and the prefixes derived from them, are never reallocated
+    assert_eq!(db.get_user_id_by_hash(idle_hash).await.unwrap(), Some(2));
+    assert_eq!(db.get_user_id_by_hash(user_hash).await.unwrap(), Some(1));
+}
+
+async fn usage_since_and_quotas(db: Database) {
+    let user_hash = "quota_user";
+    let start = Utc::now() - chrono::Duration::minutes(1);
+    assert_eq!(
+        db.get_usage_since(user_hash, start, ChargingMode::Expected)
+            .await
+            .unwrap(),
+        UsageSince::default()
+    );
+
+    let complete = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, complete, "agent1", 100)
+        .await
+        .unwrap();
+    db.update_measurement_probe_count(complete, user_hash, "agent1", 100, true)
+        .await
+        .unwrap();
+    let running = Uuid::new_v4();
+    for agent in ["agent1", "agent2"] {
+        db.create_measurement_tracking(user_hash, running, agent, 25)
+            .await
+            .unwrap();
+    }
+    db.create_measurement_tracking("other_user", Uuid::new_v4(), "agent1", 1000)
+        .await
+        .unwrap();
+
+    let usage = db
+        .get_usage_since(user_hash, start, ChargingMode::Expected)
+        .await
+        .unwrap();
+    assert_eq!(usage.probes, 150);
+    let oldest = usage.oldest.unwrap();
+    assert!(oldest >= start && oldest <= Utc::now());
+    assert_eq!(
+        db.get_usage_since(
+            user_hash,
+            Utc::now() + chrono::Duration::minutes(1),
+            ChargingMode::Expected
+        )
+        .await
+        .unwrap(),
+        UsageSince::default()
+    );
+
+    // Purged measurements still count through the rollup of their day, for
+    // windows starting at midnight
+    let purged = db
+        .purge_expired_measurements(Utc::now() + chrono::Duration::minutes(1), 10)
+        .await
+        .unwrap();
+    assert_eq!(purged.measurements, 1);
+    let midnight = Utc::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    assert_eq!(
+        db.get_usage_since(user_hash, midnight, ChargingMode::Expected)
+            .await
+            .unwrap()
+            .probes,
+        150
+    );
+    assert_eq!(
+        db.get_usage_since(user_hash, start, ChargingMode::Expected)
+            .await
+            .unwrap()
+            .probes,
+        50
+    );
+
+    assert!(db.get_user_quota(user_hash).await.unwrap().is_none());
+    let quota = db
+        .set_user_quota(user_hash, r#"[{"period":"1d","limit":10,"burst":0}]"#)
+        .await
+        .unwrap();
+    assert_eq!(quota.user_hash, user_hash);
+    let windows = r#"[{"period":"month","limit":500,"burst":50}]"#;
+    let updated = db.set_user_quota(user_hash, windows).await.unwrap();
+    assert_eq!(updated.created_at, quota.created_at);
+    let stored = db.get_user_quota(user_hash).await.unwrap().unwrap();
+    assert_eq!(stored.windows, windows);
+
+    assert!(db.delete_user_quota(user_hash).await.unwrap());
+    assert!(!db.delete_user_quota(user_hash).await.unwrap());
+    assert!(db.get_user_quota(user_hash).await.unwrap().is_none());
+}
+
+async fn quota_reservations(db: Database) {
+    let user_hash = "reserving_user";
+    let since = Utc::now() - chrono::Duration::minutes(1);
+    db.create_measurement_tracking(user_hash, Uuid::new_v4(), "agent1", 4)
+        .await
+        .unwrap();
+
+    let under_10 = |usage: &[UsageSince]| usage.iter().all(|u| u.probes + 5 <= 10);
+    let first = db
+        .reserve_quota(user_hash, 5, &[since], ChargingMode::Expected, &under_10)
+        .await
+        .unwrap();
+    assert_eq!(first.usage.len(), 1);
+    assert_eq!(first.usage[0].probes, 4);
+    let reservation = first.reservation.unwrap();
+    // Reserved probes count as usage
+    assert_eq!(
+        db.get_usage_since(user_hash, since, ChargingMode::Expected)
+            .await
+            .unwrap()
+            .probes,
+        9
+    );
+
+    let second = db
+        .reserve_quota(user_hash, 5, &[since], ChargingMode::Expected, &under_10)
+        .await
+        .unwrap();
+    assert_eq!(second.reservation, None);
+    assert_eq!(second.usage[0].probes, 9);
+
+    assert!(db.release_quota_reservation(reservation).await.unwrap());
+    assert!(!db.release_quota_reservation(reservation).await.unwrap());
+    assert_eq!(
+        db.get_usage_since(user_hash, since, ChargingMode::Expected)
+            .await
+            .unwrap()
+            .probes,
+        4
+    );
+
+    // A reservation left over by an interrupted dispatch is dropped once stale
+    let hour_ago = Utc::now() - chrono::Duration::hours(1);
+    let stale = QuotaReservation {
+        id: Uuid::new_v4(),
+        user_hash: user_hash.to_string(),
+        probes: 100,
+        created_at: (Utc::now() - chrono::Duration::minutes(30)).trunc_subsecs(6),
+    };
+    let admit_all = |_: &[UsageSince]| true;
+    db.storage
+        .reserve_quota(
+            &stale,
+            &[UsageWindowStart::new(hour_ago)],
+            ChargingMode::Expected,
+            hour_ago,
+            &admit_all,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_usage_since(user_hash, hour_ago, ChargingMode::Expected)
+            .await
+            .unwrap()
+            .probes,
+        104
+    );
+    let fresh = db
+        .reserve_quota(user_hash, 5, &[hour_ago], ChargingMode::Expected, &under_10)
+        .await
+        .unwrap();
+    assert!(fresh.reservation.is_some());
+    assert_eq!(fresh.usage[0].probes, 4);
+}
+
+async fn concurrent_quota_reservations(db: Database) {
+    let user_hash = "racing_user";
+    let since = Utc::now() - chrono::Duration::minutes(1);
+
+    let racers: Vec<_> = (0..8)
+        .map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let fits = |usage: &[UsageSince]| usage.iter().all(|u| u.probes + 3 <= 10);
+                db.reserve_quota(user_hash, 3, &[since], ChargingMode::Expected, &fits)
+                    .await
+                    .unwrap()
+                    .reservation
+            })
+        })
+        .collect();
+    let mut reserved = 0;
+    for racer in racers {
+        if racer.await.unwrap().is_some() {
+            reserved += 1;
+        }
+    }
+
+    assert_eq!(reserved, 3);
+    assert_eq!(
+        db.get_usage_since(user_hash, since, ChargingMode::Expected)
+            .await
+            .unwrap()
+            .probes,
+        9
+    );
+}
+
+async fn quota_refunds(db: Database) {
+    let user_hash = "refunded_user";
+    let since = Utc::now() - chrono::Duration::minutes(1);
+    let charged = |charging| {
+        let db = db.clone();
+        async move {
+            db.get_usage_since(user_hash, since, charging)
+                .await
+                .unwrap()
+        }
+    };
+
+    // Cancelled after 10 of agent1's 100 probes, before any of agent2's 50
+    let cancelled = Uuid::new_v4();
+    for (agent, expected) in [("agent1", 100), ("agent2", 50)] {
+        db.create_measurement_tracking(user_hash, cancelled, agent, expected)
+            .await
+            .unwrap();
+    }
+    db.update_measurement_probe_count(cancelled, user_hash, "agent1", 10, false)
+        .await
+        .unwrap();
+    assert_eq!(
+        db.cancel_measurement(cancelled, user_hash).await.unwrap(),
+        2
+    );
+    let usage = charged(ChargingMode::Refunded).await;
+    assert_eq!(usage.probes, 10);
+    assert_eq!(usage.refunded, 140);
+    assert_eq!(charged(ChargingMode::Sent).await.probes, 10);
+    let expected = charged(ChargingMode::Expected).await;
+    assert_eq!(expected.probes, 150);
+    assert_eq!(expected.refunded, 140);
+
+    // Probes already in flight when the cancellation arrived are charged
+    db.update_measurement_probe_count(cancelled, user_hash, "agent1", 30, false)
+        .await
+        .unwrap();
+    assert_eq!(charged(ChargingMode::Refunded).await.probes, 30);
+
+    // A lost agent is refunded the probes it had not sent, once
+    let running = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, running, "agent3", 40)
+        .await
+        .unwrap();
+    db.update_measurement_probe_count(running, user_hash, "agent3", 15, false)
+        .await
+        .unwrap();
+    let reported = Utc::now() - chrono::Duration::minutes(10);
+    assert_eq!(db.refund_lost_measurements(reported).await.unwrap(), 0);
+    let lost = Utc::now() + chrono::Duration::seconds(1);
+    assert_eq!(db.refund_lost_measurements(lost).await.unwrap(), 1);
+    assert_eq!(db.refund_lost_measurements(lost).await.unwrap(), 0);
+    assert_eq!(charged(ChargingMode::Refunded).await.probes, 45);
+    assert_eq!(charged(ChargingMode::Sent).await.probes, 45);
+    assert_eq!(charged(ChargingMode::Expected).await.probes, 190);
+
+    // Refunds survive the rollup of the cancelled measurement
+    let purged = db
+        .purge_expired_measurements(Utc::now() + chrono::Duration::minutes(1), 10)
+        .await
+        .unwrap();
+    assert_eq!(purged.measurements, 1);
+    let usage = db
+        .get_daily_usage(user_hash, Utc::now().date_naive())
+        .await
+        .unwrap();
+    assert_eq!(usage[0].refunded_probes, 120);
+    let midnight = Utc::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    for (charging, probes) in [
+        (ChargingMode::Expected, 190),
+        (ChargingMode::Sent, 45),
+        (ChargingMode::Refunded, 45),
+    ] {
+        let usage = db
+            .get_usage_since(user_hash, midnight, charging)
+            .await
+            .unwrap();
+        assert_eq!(usage.probes, probes, "{charging}");
+        assert_eq!(usage.refunded, 145);
+    }
+}
+
+async fn measurement_progress_moves_forward(db: Database) {
+    let user_hash = "progress_user";
+    let measurement_id = Uuid::new_v4();
+    db.create_measurement_tracking(user_hash, measurement_id, "agent1", 10)
+        .await
+        .unwrap();
+
+    for (sent_probes, is_complete, moves) in [
+        (4, false, true),
+        (4, false, false),
+        (2, true, false),
+        (10, true, true),
+        (10, true, false),
+        // A completed measurement is never reopened
+        (12, false, true),
+    ] {
+        assert_eq!(
+            db.advance_measurement_progress(measurement_id, "agent1", sent_probes, is_complete)
+                .await
+                .unwrap(),
+            moves,
+            "{sent_probes} {is_complete}"
+        );
+    }
+    let tracking = db
+        .get_measurement_tracking_by_agent(measurement_id, "agent1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(tracking.sent_probes, 12);
+    assert!(tracking.is_complete);
+
+    assert!(
+        !db.advance_measurement_progress(measurement_id, "agent2", 1, false)
+            .await
+            .unwrap()
+    );
+}
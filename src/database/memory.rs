@@ -0,0 +1,1520 @@
+//! In-memory storage. It answers like the SQL backends (checked by the
+//! conformance suite) without a database, for tests and development.
+
+use super::{
+    DEFAULT_PROBE_LIMIT, DailyUsage, DispatchTracking, IdempotencyRecord, MeasurementArchive,
+    MeasurementCancellation, MeasurementListFilter, MeasurementSchedule, MeasurementSort,
+    MeasurementState, MeasurementStatus, MeasurementTracking, MultiRoundMeasurement, OutboxMessage,
+    ProbeUsageRecord, PurgedMeasurements, QuotaCheck, QuotaReservation, ScheduleRun, Storage,
+    TargetList, UsageSince, UsageWindowStart, UserLimit, UserQuota, UserUsageStats,
+};
+use crate::kafka::OutboundMessage;
+use crate::quota::ChargingMode;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Does a measurement match one of the requested states?
+fn measurement_in_state(m: &MeasurementStatus, state: MeasurementState) -> bool {
+    match state {
+        MeasurementState::Complete => m.measurement_complete && !m.measurement_cancelled,
+        MeasurementState::InProgress => !m.measurement_complete,
+        MeasurementState::Cancelled => m.measurement_cancelled,
+        MeasurementState::Queued => m.queued_batches > 0,
+    }
+}
+
+/// Storage kept in memory, for tests and development
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryStorage {
+    user_limits: Arc<Mutex<Vec<UserLimit>>>,
+    user_id_mappings: Arc<Mutex<HashMap<String, u32>>>,
+    measurement_tracking: Arc<Mutex<Vec<MeasurementTracking>>>,
+    idempotency_keys: Arc<Mutex<HashMap<(String, String), IdempotencyRecord>>>,
+    schedules: Arc<Mutex<Vec<MeasurementSchedule>>>,
+    schedule_runs: Arc<Mutex<Vec<ScheduleRun>>>,
+    target_lists: Arc<Mutex<Vec<StoredTargetList>>>,
+    measurement_archives: Arc<Mutex<Vec<MeasurementArchive>>>,
+    multi_round_measurements: Arc<Mutex<Vec<MultiRoundMeasurement>>>,
+    outbox: Arc<Mutex<Vec<OutboxMessage>>>,
+    cancellations: Arc<Mutex<Vec<MeasurementCancellation>>>,
+    daily_usage: Arc<Mutex<Vec<DailyUsage>>>,
+    user_quotas: Arc<Mutex<HashMap<String, UserQuota>>>,
+    quota_reservations: Arc<Mutex<Vec<QuotaReservation>>>,
+}
+
+// A target list with its destinations, as kept in memory
+type StoredTargetList = (TargetList, Vec<String>);
+
+impl MemoryStorage {
+    pub(crate) fn new() -> Self {
+        Self {
+            user_limits: Arc::new(Mutex::new(Vec::new())),
+            user_id_mappings: Arc::new(Mutex::new(HashMap::new())),
+            measurement_tracking: Arc::new(Mutex::new(Vec::new())),
+            idempotency_keys: Arc::new(Mutex::new(HashMap::new())),
+            schedules: Arc::new(Mutex::new(Vec::new())),
+            schedule_runs: Arc::new(Mutex::new(Vec::new())),
+            target_lists: Arc::new(Mutex::new(Vec::new())),
+            measurement_archives: Arc::new(Mutex::new(Vec::new())),
+            multi_round_measurements: Arc::new(Mutex::new(Vec::new())),
+            outbox: Arc::new(Mutex::new(Vec::new())),
+            cancellations: Arc::new(Mutex::new(Vec::new())),
+            daily_usage: Arc::new(Mutex::new(Vec::new())),
+            user_quotas: Arc::new(Mutex::new(HashMap::new())),
+            quota_reservations: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Probes charged to a user since `since`, with the reservations (whose
+    /// lock the caller holds, and always takes before the others)
+    fn usage_since(
+        &self,
+        reservations: &[QuotaReservation],
+        user_hash: &str,
+        since: DateTime<Utc>,
+        rollup_since: NaiveDate,
+        charging: ChargingMode,
+    ) -> UsageSince {
+        let mut usage = UsageSince::default();
+        let mut charged = Vec::new();
+        for t in self
+            .measurement_tracking
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| t.user_hash == user_hash && t.created_at >= since)
+        {
+            let probes = match charging {
+                ChargingMode::Expected => t.expected_probes,
+                ChargingMode::Sent if t.is_complete || t.cancelled => t.sent_probes,
+                ChargingMode::Sent | ChargingMode::Refunded => {
+                    t.expected_probes - t.refunded_probes
+                }
+            };
+            usage.refunded += t.refunded_probes as i64;
+            charged.push((probes as i64, t.created_at));
+        }
+        charged.extend(
+            reservations
+                .iter()
+                .filter(|r| r.user_hash == user_hash && r.created_at >= since)
+                .map(|r| (r.probes, r.created_at)),
+        );
+        for u in self
+            .daily_usage
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|u| u.user_hash == user_hash && u.day >= rollup_since)
+        {
+            usage.probes += match charging {
+                ChargingMode::Expected => u.expected_probes,
+                ChargingMode::Sent => u.sent_probes,
+                ChargingMode::Refunded => u.expected_probes - u.refunded_probes,
+            };
+            usage.refunded += u.refunded_probes;
+        }
+
+        usage.probes += charged.iter().map(|(probes, _)| probes).sum::<i64>();
+        usage.oldest = charged.iter().map(|(_, created_at)| *created_at).min();
+        usage
+    }
+
+    fn queued_batches(&self, measurement_id: Uuid) -> i64 {
+        let outbox = self.outbox.lock().unwrap();
+        outbox
+            .iter()
+            .filter(|m| m.measurement_id == measurement_id && m.sent_at.is_none())
+            .count() as i64
+    }
+
+    /// Measurements a retention purge removes, oldest last update first, with
+    /// their number of agent rows (mirrors the SQL backends)
+    fn expired_measurements(&self, before: DateTime<Utc>) -> Vec<(Uuid, u64)> {
+        let open: Vec<Uuid> = self
+            .multi_round_measurements
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.closed_at.is_none())
+            .map(|m| m.measurement_id)
+            .collect();
+
+        let tracking = self.measurement_tracking.lock().unwrap();
+        let mut by_measurement: HashMap<Uuid, Vec<&MeasurementTracking>> = HashMap::new();
+        for t in tracking.iter() {
+            by_measurement.entry(t.measurement_id).or_default().push(t);
+        }
+
+        let mut expired: Vec<(Uuid, u64, DateTime<Utc>)> = by_measurement
+            .into_iter()
+            .filter(|(measurement_id, records)| {
+                records.iter().all(|r| r.is_complete || r.cancelled)
+                    && !open.contains(measurement_id)
+                    && self.queued_batches(*measurement_id) == 0
+            })
+            .map(|(measurement_id, records)| {
+                let last_updated = records.iter().map(|r| r.updated_at).max().unwrap();
+                (measurement_id, records.len() as u64, last_updated)
+            })
+            .filter(|(_, _, last_updated)| *last_updated < before)
+            .collect();
+        expired.sort_by_key(|(_, _, last_updated)| *last_updated);
+        expired
+            .into_iter()
+            .map(|(measurement_id, rows, _)| (measurement_id, rows))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn migrate(&self) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    async fn get_user_usage_stats(
+        &self,
+        user_hash: &str,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<UserUsageStats, sqlx::Error> {
+        // Get stats from measurement tracking instead of probe records
+        let tracking = self.measurement_tracking.lock().unwrap();
+        let filtered_tracking: Vec<_> = tracking
+            .iter()
+            .filter(|t| {
+                t.user_hash == user_hash && t.created_at >= start_time && t.created_at <= end_time
+            })
+            .collect();
+
+        // Group by measurement_id to count unique measurements
+        let mut measurements = std::collections::HashSet::new();
+        let mut total_probes = 0;
+        let mut last_submitted: Option<DateTime<Utc>> = None;
+
+        for tracking_entry in &filtered_tracking {
+            measurements.insert(&tracking_entry.measurement_id);
+            total_probes += tracking_entry.expected_probes as u32;
+            let updated_at = tracking_entry.updated_at;
+            last_submitted = Some(match last_submitted {
+                Some(existing) => existing.max(updated_at),
+                None => updated_at,
+            });
+        }
+
+        // Get the user limit
+        let user_limits = self.user_limits.lock().unwrap();
+        let limit = user_limits
+            .iter()
+            .find(|ul| ul.user_hash == user_hash)
+            .map(|ul| ul.probe_limit)
+            .unwrap_or(DEFAULT_PROBE_LIMIT);
+
+        Ok(UserUsageStats {
+            user_hash: user_hash.to_string(),
+            submission_count: measurements.len() as u32,
+            total_probes,
+            limit,
+            last_submitted,
+        })
+    }
+
+    async fn get_recent_usage(&self, limit: i32) -> Result<Vec<ProbeUsageRecord>, sqlx::Error> {
+        // Get recent measurements from tracking data
+        let tracking = self.measurement_tracking.lock().unwrap();
+
+        // Group by measurement_id and sum probe counts
+        let mut measurement_map: std::collections::HashMap<(Uuid, String), (i32, DateTime<Utc>)> =
+            std::collections::HashMap::new();
+        for entry in tracking.iter() {
+            let key = (entry.measurement_id, entry.user_hash.clone());
+            measurement_map
+                .entry(key)
+                .and_modify(|(probe_count, timestamp)| {
+                    *probe_count += entry.sent_probes;
+                    *timestamp = (*timestamp).max(entry.updated_at);
+                })
+                .or_insert((entry.sent_probes, entry.updated_at));
+        }
+
+        // Convert to ProbeUsageRecord format
+        let mut records: Vec<_> = measurement_map
+            .into_iter()
+            .map(
+                |((measurement_id, user_hash), (probe_count, timestamp))| ProbeUsageRecord {
+                    id: measurement_id,
+                    user_hash,
+                    probe_count,
+                    timestamp,
+                },
+            )
+            .collect();
+
+        records.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        Ok(records.into_iter().take(limit as usize).collect())
+    }
+
+    async fn set_user_limit(
+        &self,
+        user_hash: &str,
+        probe_limit: u32,
+    ) -> Result<UserLimit, sqlx::Error> {
+        let mut user_limits = self.user_limits.lock().unwrap();
+        let now = Utc::now();
+
+        // Check if user limit already exists
+        if let Some(existing) = user_limits.iter_mut().find(|ul| ul.user_hash == user_hash) {
+            existing.probe_limit = probe_limit;
+            existing.updated_at = now;
+            Ok(existing.clone())
+        } else {
+            // Create new user limit
+            let new_limit = UserLimit {
+                id: Uuid::new_v4(),
+                user_hash: user_hash.to_string(),
+                probe_limit,
+                created_at: now,
+                updated_at: now,
+            };
+            user_limits.push(new_limit.clone());
+            Ok(new_limit)
+        }
+    }
+
+    async fn get_user_limit(&self, user_hash: &str) -> Result<Option<UserLimit>, sqlx::Error> {
+        let user_limits = self.user_limits.lock().unwrap();
+        Ok(user_limits
+            .iter()
+            .find(|ul| ul.user_hash == user_hash)
+            .cloned())
+    }
+
+    async fn get_user_id_by_hash(&self, user_hash: &str) -> Result<Option<u32>, sqlx::Error> {
+        let mappings = self.user_id_mappings.lock().unwrap();
+        Ok(mappings.get(user_hash).copied())
+    }
+
+    async fn create_user_id_mapping(
+        &self,
+        user_hash: &str,
+        user_id: u32,
+    ) -> Result<(), sqlx::Error> {
+        let mut mappings = self.user_id_mappings.lock().unwrap();
+        if mappings.contains_key(user_hash) {
+            // Simulate user_hash primary key violation
+            return Err(sqlx::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "UNIQUE constraint failed: user_hash already exists",
+            )));
+        }
+        if mappings.values().any(|&v| v == user_id) {
+            // Simulate user_id unique constraint violation
+            return Err(sqlx::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "UNIQUE constraint failed: user_id already exists",
+            )));
+        }
+        mappings.insert(user_hash.to_string(), user_id);
+        Ok(())
+    }
+
+    async fn create_measurement_tracking(
+        &self,
+        user_hash: &str,
+        measurement_id: Uuid,
+        agent_id: &str,
+        expected_probes: i32,
+    ) -> Result<MeasurementTracking, sqlx::Error> {
+        let now = Utc::now();
+
+        let record = MeasurementTracking {
+            id: Uuid::new_v4(),
+            user_hash: user_hash.to_string(),
+            measurement_id,
+            agent_id: agent_id.to_string(),
+            expected_probes,
+            sent_probes: 0,
+            is_complete: false,
+            cancelled: false,
+            filtered_probes: 0,
+            refunded_probes: 0,
+            created_at: now,
+            updated_at: now,
+        };
+
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        tracking.push(record.clone());
+        Ok(record)
+    }
+
+    async fn update_measurement_probe_count(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_id: &str,
+        sent_probes: i32,
+        is_complete: bool,
+    ) -> Result<MeasurementTracking, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+
+        if let Some(record) = tracking.iter_mut().find(|t| {
+            t.measurement_id == measurement_id && t.user_hash == user_hash && t.agent_id == agent_id
+        }) {
+            record.sent_probes = sent_probes;
+            record.is_complete = is_complete;
+            record.updated_at = now;
+            record.refunded_probes = record
+                .refunded_probes
+                .min((record.expected_probes - sent_probes).max(0));
+            Ok(record.clone())
+        } else {
+            Err(sqlx::Error::RowNotFound)
+        }
+    }
+
+    async fn advance_measurement_progress(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+        sent_probes: i32,
+        is_complete: bool,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        let Some(record) = tracking.iter_mut().find(|t| {
+            t.measurement_id == measurement_id
+                && t.agent_id == agent_id
+                && (t.sent_probes < sent_probes
+                    || (t.sent_probes == sent_probes && !t.is_complete && is_complete))
+        }) else {
+            return Ok(false);
+        };
+        record.sent_probes = sent_probes;
+        record.is_complete |= is_complete;
+        record.updated_at = now;
+        record.refunded_probes = record
+            .refunded_probes
+            .min((record.expected_probes - sent_probes).max(0));
+        Ok(true)
+    }
+
+    async fn add_measurement_expected_probes(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_id: &str,
+        probes: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        match tracking.iter_mut().find(|t| {
+            t.measurement_id == measurement_id && t.user_hash == user_hash && t.agent_id == agent_id
+        }) {
+            Some(record) => {
+                record.expected_probes += probes;
+                record.updated_at = now;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn add_measurement_filtered_probes(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        filtered_probes: i32,
+    ) -> Result<(), sqlx::Error> {
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        for t in tracking
+            .iter_mut()
+            .filter(|t| t.measurement_id == measurement_id && t.user_hash == user_hash)
+        {
+            t.filtered_probes += filtered_probes;
+        }
+        Ok(())
+    }
+
+    async fn get_measurement_status(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementStatus>, sqlx::Error> {
+        let tracking = self.measurement_tracking.lock().unwrap();
+        let records: Vec<_> = tracking
+            .iter()
+            .filter(|t| t.measurement_id == measurement_id && t.user_hash == user_hash)
+            .collect();
+
+        if records.is_empty() {
+            return Ok(None);
+        }
+
+        let total_agents = records.len() as i64;
+        let total_expected_probes: i64 = records.iter().map(|r| r.expected_probes as i64).sum();
+        let total_sent_probes: i64 = records.iter().map(|r| r.sent_probes as i64).sum();
+        let completed_agents = records.iter().filter(|r| r.is_complete).count() as i64;
+        // Terminal when every agent is complete or cancelled (mirrors the view).
+        let terminal_agents = records
+            .iter()
+            .filter(|r| r.is_complete || r.cancelled)
+            .count() as i64;
+        let measurement_complete = terminal_agents == total_agents;
+        let measurement_cancelled = records.iter().any(|r| r.cancelled);
+        let started_at = records.iter().map(|r| r.created_at).min().unwrap();
+        let last_updated = records.iter().map(|r| r.updated_at).max().unwrap();
+        let filtered_probes = records
+            .iter()
+            .map(|r| r.filtered_probes as i64)
+            .max()
+            .unwrap();
+        let queued_batches = self.queued_batches(measurement_id);
+
+        Ok(Some(MeasurementStatus {
+            measurement_id,
+            user_hash: user_hash.to_string(),
+            total_agents,
+            total_expected_probes,
+            total_sent_probes,
+            completed_agents,
+            measurement_complete,
+            measurement_cancelled,
+            started_at,
+            last_updated,
+            filtered_probes,
+            queued_batches,
+        }))
+    }
+
+    async fn list_user_measurements(
+        &self,
+        user_hash: &str,
+        filter: &MeasurementListFilter,
+    ) -> Result<Vec<MeasurementStatus>, sqlx::Error> {
+        let tracking = self.measurement_tracking.lock().unwrap();
+
+        // Aggregate the per-agent tracking rows into one status per
+        // measurement, mirroring the `measurement_status` SQL view.
+        let mut by_measurement: std::collections::HashMap<Uuid, Vec<&MeasurementTracking>> =
+            std::collections::HashMap::new();
+        for t in tracking.iter().filter(|t| t.user_hash == user_hash) {
+            by_measurement.entry(t.measurement_id).or_default().push(t);
+        }
+
+        let mut measurements: Vec<MeasurementStatus> = by_measurement
+            .into_iter()
+            // agent filter: keep only measurements that involve the agent
+            .filter(|(_, records)| match &filter.agent {
+                Some(a) => records.iter().any(|r| &r.agent_id == a),
+                None => true,
+            })
+            .map(|(measurement_id, records)| {
+                let total_agents = records.len() as i64;
+                let completed_agents = records.iter().filter(|r| r.is_complete).count() as i64;
+                let terminal_agents = records
+                    .iter()
+                    .filter(|r| r.is_complete || r.cancelled)
+                    .count() as i64;
+                MeasurementStatus {
+                    measurement_id,
+                    user_hash: user_hash.to_string(),
+                    total_agents,
+                    total_expected_probes: records.iter().map(|r| r.expected_probes as i64).sum(),
+                    total_sent_probes: records.iter().map(|r| r.sent_probes as i64).sum(),
+                    completed_agents,
+                    measurement_complete: terminal_agents == total_agents,
+                    measurement_cancelled: records.iter().any(|r| r.cancelled),
+                    started_at: records.iter().map(|r| r.created_at).min().unwrap(),
+                    last_updated: records.iter().map(|r| r.updated_at).max().unwrap(),
+                    filtered_probes: records
+                        .iter()
+                        .map(|r| r.filtered_probes as i64)
+                        .max()
+                        .unwrap(),
+                    queued_batches: self.queued_batches(measurement_id),
+                }
+            })
+            .collect();
+
+        // status filter (OR of requested states)
+        if !filter.status.is_empty() {
+            measurements.retain(|m| filter.status.iter().any(|s| measurement_in_state(m, *s)));
+        }
+        // started-at window
+        if let Some(since) = filter.since {
+            measurements.retain(|m| m.started_at >= since);
+        }
+        if let Some(until) = filter.until {
+            measurements.retain(|m| m.started_at <= until);
+        }
+
+        measurements.sort_by(|a, b| {
+            let ord = match filter.sort {
+                MeasurementSort::Started => a.started_at.cmp(&b.started_at),
+                MeasurementSort::Updated => a.last_updated.cmp(&b.last_updated),
+            };
+            if filter.reverse { ord } else { ord.reverse() }
+        });
+        measurements.truncate(filter.limit as usize);
+        Ok(measurements)
+    }
+
+    async fn cancel_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let now = Utc::now();
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        let mut cancellations = self.cancellations.lock().unwrap();
+        let mut affected = 0u64;
+        for record in tracking.iter_mut().filter(|t| {
+            t.measurement_id == measurement_id
+                && t.user_hash == user_hash
+                && !t.is_complete
+                && !t.cancelled
+        }) {
+            record.cancelled = true;
+            record.updated_at = now;
+            record.refunded_probes = (record.expected_probes - record.sent_probes).max(0);
+            affected += 1;
+
+            let id = cancellations.len() as i64 + 1;
+            cancellations.push(MeasurementCancellation {
+                id,
+                measurement_id,
+                agent_id: record.agent_id.clone(),
+                requested_at: now,
+                acknowledged_at: None,
+            });
+        }
+
+        self.outbox
+            .lock()
+            .unwrap()
+            .retain(|m| m.measurement_id != measurement_id || m.sent_at.is_some());
+
+        // A cancelled multi-round measurement takes no more rounds
+        if let Some(measurement) = self
+            .multi_round_measurements
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|m| {
+                m.measurement_id == measurement_id
+                    && m.user_hash == user_hash
+                    && m.closed_at.is_none()
+            })
+        {
+            measurement.closed_at = Some(now);
+        }
+        Ok(affected)
+    }
+
+    async fn get_agent_cancellations(
+        &self,
+        agent_id: &str,
+        after: i64,
+        limit: i32,
+    ) -> Result<Vec<MeasurementCancellation>, sqlx::Error> {
+        let cancellations = self.cancellations.lock().unwrap();
+        Ok(cancellations
+            .iter()
+            .filter(|c| c.agent_id == agent_id && c.id > after)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_measurement_cancellations(
+        &self,
+        measurement_id: Uuid,
+    ) -> Result<Vec<MeasurementCancellation>, sqlx::Error> {
+        let cancellations = self.cancellations.lock().unwrap();
+        Ok(cancellations
+            .iter()
+            .filter(|c| c.measurement_id == measurement_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn acknowledge_cancellation(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+    ) -> Result<Option<MeasurementCancellation>, sqlx::Error> {
+        let now = Utc::now();
+        let mut cancellations = self.cancellations.lock().unwrap();
+        Ok(cancellations
+            .iter_mut()
+            .find(|c| c.measurement_id == measurement_id && c.agent_id == agent_id)
+            .map(|c| {
+                c.acknowledged_at.get_or_insert(now);
+                c.clone()
+            }))
+    }
+
+    async fn delete_measurement_tracking(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        let before = tracking.len();
+        tracking.retain(|t| !(t.measurement_id == measurement_id && t.user_hash == user_hash));
+        Ok((before - tracking.len()) as u64)
+    }
+
+    async fn get_measurement_tracking(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Vec<MeasurementTracking>, sqlx::Error> {
+        let tracking = self.measurement_tracking.lock().unwrap();
+        let mut records: Vec<_> = tracking
+            .iter()
+            .filter(|t| t.measurement_id == measurement_id && t.user_hash == user_hash)
+            .cloned()
+            .collect();
+
+        records.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        Ok(records)
+    }
+
+    async fn get_measurement_tracking_by_agent(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+    ) -> Result<Option<MeasurementTracking>, sqlx::Error> {
+        let tracking = self.measurement_tracking.lock().unwrap();
+        let record = tracking
+            .iter()
+            .find(|t| t.measurement_id == measurement_id && t.agent_id == agent_id)
+            .cloned();
+
+        Ok(record)
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+        request_hash: &str,
+        expired_before: DateTime<Utc>,
+        abandoned_before: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut keys = self.idempotency_keys.lock().unwrap();
+        let map_key = (user_hash.to_string(), idempotency_key.to_string());
+
+        if let Some(existing) = keys.get(&map_key)
+            && existing.created_at >= expired_before
+            && (existing.response.is_some() || existing.created_at >= abandoned_before)
+        {
+            return Ok(Some(existing.clone()));
+        }
+
+        keys.insert(
+            map_key,
+            IdempotencyRecord {
+                user_hash: user_hash.to_string(),
+                idempotency_key: idempotency_key.to_string(),
+                request_hash: request_hash.to_string(),
+                measurement_id: None,
+                response: None,
+                created_at: now,
+            },
+        );
+        Ok(None)
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+        measurement_id: Uuid,
+        response: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut keys = self.idempotency_keys.lock().unwrap();
+        if let Some(record) = keys.get_mut(&(user_hash.to_string(), idempotency_key.to_string())) {
+            record.measurement_id = Some(measurement_id);
+            record.response = Some(response.to_string());
+        }
+        Ok(())
+    }
+
+    async fn release_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut keys = self.idempotency_keys.lock().unwrap();
+        let map_key = (user_hash.to_string(), idempotency_key.to_string());
+        if keys.get(&map_key).is_some_and(|r| r.response.is_none()) {
+            keys.remove(&map_key);
+        }
+        Ok(())
+    }
+
+    async fn create_schedule(
+        &self,
+        user_hash: &str,
+        name: Option<&str>,
+        spec: &str,
+        interval_seconds: Option<i64>,
+        next_run_at: DateTime<Utc>,
+    ) -> Result<MeasurementSchedule, sqlx::Error> {
+        let now = Utc::now();
+        let id = Uuid::new_v4();
+
+        let schedule = MeasurementSchedule {
+            id,
+            user_hash: user_hash.to_string(),
+            name: name.map(str::to_string),
+            spec: spec.to_string(),
+            interval_seconds,
+            next_run_at: Some(next_run_at),
+            created_at: now,
+            updated_at: now,
+        };
+        self.schedules.lock().unwrap().push(schedule.clone());
+        Ok(schedule)
+    }
+
+    async fn list_user_schedules(
+        &self,
+        user_hash: &str,
+    ) -> Result<Vec<MeasurementSchedule>, sqlx::Error> {
+        let schedules = self.schedules.lock().unwrap();
+        let mut result: Vec<_> = schedules
+            .iter()
+            .filter(|s| s.user_hash == user_hash)
+            .cloned()
+            .collect();
+        result.sort_by_key(|s| std::cmp::Reverse(s.created_at));
+        Ok(result)
+    }
+
+    async fn get_schedule(
+        &self,
+        schedule_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementSchedule>, sqlx::Error> {
+        let schedules = self.schedules.lock().unwrap();
+        Ok(schedules
+            .iter()
+            .find(|s| s.id == schedule_id && s.user_hash == user_hash)
+            .cloned())
+    }
+
+    async fn delete_schedule(
+        &self,
+        schedule_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let mut schedules = self.schedules.lock().unwrap();
+        let before = schedules.len();
+        schedules.retain(|s| !(s.id == schedule_id && s.user_hash == user_hash));
+        let deleted = schedules.len() < before;
+        if deleted {
+            let mut runs = self.schedule_runs.lock().unwrap();
+            runs.retain(|r| r.schedule_id != schedule_id);
+        }
+        Ok(deleted)
+    }
+
+    async fn get_due_schedules(
+        &self,
+        now: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<Vec<MeasurementSchedule>, sqlx::Error> {
+        let schedules = self.schedules.lock().unwrap();
+        let mut due: Vec<_> = schedules
+            .iter()
+            .filter(|s| s.next_run_at.is_some_and(|t| t <= now))
+            .cloned()
+            .collect();
+        due.sort_by_key(|s| s.next_run_at);
+        due.truncate(limit as usize);
+        Ok(due)
+    }
+
+    async fn advance_schedule(
+        &self,
+        schedule_id: Uuid,
+        expected_run_at: DateTime<Utc>,
+        next_run_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut schedules = self.schedules.lock().unwrap();
+        match schedules
+            .iter_mut()
+            .find(|s| s.id == schedule_id && s.next_run_at == Some(expected_run_at))
+        {
+            Some(schedule) => {
+                schedule.next_run_at = next_run_at;
+                schedule.updated_at = now;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn record_schedule_run(
+        &self,
+        schedule_id: Uuid,
+        measurement_id: Option<Uuid>,
+        error: Option<&str>,
+    ) -> Result<ScheduleRun, sqlx::Error> {
+        let now = Utc::now();
+
+        let run = ScheduleRun {
+            id: Uuid::new_v4(),
+            schedule_id,
+            measurement_id,
+            error: error.map(str::to_string),
+            started_at: now,
+        };
+        self.schedule_runs.lock().unwrap().push(run.clone());
+        Ok(run)
+    }
+
+    async fn list_schedule_runs(
+        &self,
+        schedule_id: Uuid,
+        limit: i32,
+    ) -> Result<Vec<ScheduleRun>, sqlx::Error> {
+        let runs = self.schedule_runs.lock().unwrap();
+        let mut result: Vec<_> = runs
+            .iter()
+            .filter(|r| r.schedule_id == schedule_id)
+            .cloned()
+            .collect();
+        result.sort_by_key(|r| std::cmp::Reverse(r.started_at));
+        result.truncate(limit as usize);
+        Ok(result)
+    }
+
+    async fn create_target_list(
+        &self,
+        user_hash: &str,
+        name: &str,
+        targets: &[String],
+    ) -> Result<TargetList, sqlx::Error> {
+        let now = Utc::now();
+        let id = Uuid::new_v4();
+
+        let list = TargetList {
+            id,
+            user_hash: user_hash.to_string(),
+            name: name.to_string(),
+            target_count: targets.len() as i32,
+            created_at: now,
+            updated_at: now,
+        };
+        let mut lists = self.target_lists.lock().unwrap();
+        lists.push((list.clone(), targets.to_vec()));
+        Ok(list)
+    }
+
+    async fn list_user_target_lists(
+        &self,
+        user_hash: &str,
+    ) -> Result<Vec<TargetList>, sqlx::Error> {
+        let lists = self.target_lists.lock().unwrap();
+        let mut result: Vec<_> = lists
+            .iter()
+            .filter(|(l, _)| l.user_hash == user_hash)
+            .map(|(l, _)| l.clone())
+            .collect();
+        result.sort_by_key(|l| std::cmp::Reverse(l.created_at));
+        Ok(result)
+    }
+
+    async fn get_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<TargetList>, sqlx::Error> {
+        let lists = self.target_lists.lock().unwrap();
+        Ok(lists
+            .iter()
+            .find(|(l, _)| l.id == list_id && l.user_hash == user_hash)
+            .map(|(l, _)| l.clone()))
+    }
+
+    async fn get_target_list_targets(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<Vec<String>>, sqlx::Error> {
+        let lists = self.target_lists.lock().unwrap();
+        Ok(lists
+            .iter()
+            .find(|(l, _)| l.id == list_id && l.user_hash == user_hash)
+            .map(|(_, targets)| targets.clone()))
+    }
+
+    async fn update_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+        name: Option<&str>,
+        targets: Option<&[String]>,
+    ) -> Result<Option<TargetList>, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut lists = self.target_lists.lock().unwrap();
+        let Some((list, stored)) = lists
+            .iter_mut()
+            .find(|(l, _)| l.id == list_id && l.user_hash == user_hash)
+        else {
+            return Ok(None);
+        };
+
+        if let Some(name) = name {
+            list.name = name.to_string();
+        }
+        if let Some(targets) = targets {
+            *stored = targets.to_vec();
+            list.target_count = targets.len() as i32;
+        }
+        list.updated_at = now;
+        Ok(Some(list.clone()))
+    }
+
+    async fn delete_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let mut lists = self.target_lists.lock().unwrap();
+        let before = lists.len();
+        lists.retain(|(l, _)| !(l.id == list_id && l.user_hash == user_hash));
+        Ok(lists.len() < before)
+    }
+
+    async fn append_measurement_archive(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        blob_key: &str,
+        metadata: &str,
+        probe_count: i32,
+        size_bytes: i64,
+    ) -> Result<MeasurementArchive, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut archives = self.measurement_archives.lock().unwrap();
+        if let Some(archive) = archives
+            .iter_mut()
+            .find(|a| a.measurement_id == measurement_id)
+        {
+            if archive.user_hash != user_hash {
+                return Err(sqlx::Error::RowNotFound);
+            }
+            archive.probe_count += probe_count;
+            archive.size_bytes += size_bytes;
+            archive.segments += 1;
+            return Ok(archive.clone());
+        }
+
+        let archive = MeasurementArchive {
+            measurement_id,
+            user_hash: user_hash.to_string(),
+            blob_key: blob_key.to_string(),
+            metadata: metadata.to_string(),
+            probe_count,
+            size_bytes,
+            segments: 1,
+            created_at: now,
+        };
+        archives.push(archive.clone());
+        Ok(archive)
+    }
+
+    async fn get_measurement_archive(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementArchive>, sqlx::Error> {
+        let archives = self.measurement_archives.lock().unwrap();
+        Ok(archives
+            .iter()
+            .find(|a| a.measurement_id == measurement_id && a.user_hash == user_hash)
+            .cloned())
+    }
+
+    async fn create_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_ids: &[String],
+        metadata: &str,
+    ) -> Result<MultiRoundMeasurement, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        tracking.extend(agent_ids.iter().map(|agent_id| MeasurementTracking {
+            id: Uuid::new_v4(),
+            user_hash: user_hash.to_string(),
+            measurement_id,
+            agent_id: agent_id.clone(),
+            expected_probes: 0,
+            sent_probes: 0,
+            is_complete: false,
+            cancelled: false,
+            filtered_probes: 0,
+            refunded_probes: 0,
+            created_at: now,
+            updated_at: now,
+        }));
+
+        let measurement = MultiRoundMeasurement {
+            measurement_id,
+            user_hash: user_hash.to_string(),
+            metadata: metadata.to_string(),
+            rounds: 0,
+            created_at: now,
+            closed_at: None,
+        };
+        let mut measurements = self.multi_round_measurements.lock().unwrap();
+        measurements.push(measurement.clone());
+        Ok(measurement)
+    }
+
+    async fn get_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MultiRoundMeasurement>, sqlx::Error> {
+        let measurements = self.multi_round_measurements.lock().unwrap();
+        Ok(measurements
+            .iter()
+            .find(|m| m.measurement_id == measurement_id && m.user_hash == user_hash)
+            .cloned())
+    }
+
+    async fn record_measurement_round(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<i32>, sqlx::Error> {
+        let mut measurements = self.multi_round_measurements.lock().unwrap();
+        Ok(measurements
+            .iter_mut()
+            .find(|m| {
+                m.measurement_id == measurement_id
+                    && m.user_hash == user_hash
+                    && m.closed_at.is_none()
+            })
+            .map(|m| {
+                m.rounds += 1;
+                m.rounds
+            }))
+    }
+
+    async fn close_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+
+        let mut measurements = self.multi_round_measurements.lock().unwrap();
+        match measurements.iter_mut().find(|m| {
+            m.measurement_id == measurement_id && m.user_hash == user_hash && m.closed_at.is_none()
+        }) {
+            Some(measurement) => {
+                measurement.closed_at = Some(now);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn record_dispatch(
+        &self,
+        tracking: &DispatchTracking,
+        messages: &[OutboundMessage],
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+
+        let mut records = self.measurement_tracking.lock().unwrap();
+        // A round needs every agent's row, still running
+        let running = |agent_id: &String| {
+            records.iter().any(|t| {
+                t.measurement_id == tracking.measurement_id
+                    && t.user_hash == tracking.user_hash
+                    && &t.agent_id == agent_id
+                    && !t.cancelled
+            })
+        };
+        if !tracking.new_measurement && !tracking.agent_ids.iter().all(running) {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        for agent_id in &tracking.agent_ids {
+            if tracking.new_measurement {
+                records.push(MeasurementTracking {
+                    id: Uuid::new_v4(),
+                    user_hash: tracking.user_hash.clone(),
+                    measurement_id: tracking.measurement_id,
+                    agent_id: agent_id.clone(),
+                    expected_probes: tracking.probes,
+                    sent_probes: 0,
+                    is_complete: false,
+                    cancelled: false,
+                    filtered_probes: 0,
+                    refunded_probes: 0,
+                    created_at: now,
+                    updated_at: now,
+                });
+            } else if let Some(record) = records.iter_mut().find(|t| {
+                t.measurement_id == tracking.measurement_id
+                    && t.user_hash == tracking.user_hash
+                    && &t.agent_id == agent_id
+            }) {
+                record.expected_probes += tracking.probes;
+                record.updated_at = now;
+            }
+        }
+        for record in records.iter_mut().filter(|t| {
+            t.measurement_id == tracking.measurement_id && t.user_hash == tracking.user_hash
+        }) {
+            record.filtered_probes += tracking.filtered;
+        }
+
+        let mut outbox = self.outbox.lock().unwrap();
+        for message in messages {
+            let id = outbox.len() as i64 + 1;
+            outbox.push(OutboxMessage {
+                id,
+                measurement_id: tracking.measurement_id,
+                message_key: message.key.clone(),
+                payload: message.payload.clone(),
+                headers: serde_json::to_string(&message.headers).unwrap_or_default(),
+                kafka_topic: message.topic.clone(),
+                kafka_partition: message.partition,
+                attempts: 0,
+                last_error: None,
+                created_at: now,
+                claimed_until: None,
+                sent_at: None,
+            });
+        }
+        Ok(())
+    }
+
+    async fn revert_dispatch(
+        &self,
+        tracking: &DispatchTracking,
+        delivered: &[i32],
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+
+        let mut records = self.measurement_tracking.lock().unwrap();
+        for (agent_id, &delivered) in tracking.agent_ids.iter().zip(delivered) {
+            let row = |t: &MeasurementTracking| {
+                t.measurement_id == tracking.measurement_id
+                    && t.user_hash == tracking.user_hash
+                    && &t.agent_id == agent_id
+            };
+            // A new measurement's agent that got nothing has nothing to follow
+            if tracking.new_measurement && delivered == 0 {
+                records.retain(|t| !row(t));
+            } else if let Some(record) = records.iter_mut().find(|t| row(t)) {
+                record.expected_probes -= tracking.probes - delivered;
+                record.updated_at = now;
+            }
+        }
+
+        // A round that reached no agent leaves its filtered probes out too
+        if !tracking.new_measurement && delivered.iter().all(|&d| d == 0) {
+            for record in records.iter_mut().filter(|t| {
+                t.measurement_id == tracking.measurement_id && t.user_hash == tracking.user_hash
+            }) {
+                record.filtered_probes -= tracking.filtered;
+            }
+        }
+        Ok(())
+    }
+
+    async fn claim_outbox_messages(
+        &self,
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<Vec<OutboxMessage>, sqlx::Error> {
+        let mut outbox = self.outbox.lock().unwrap();
+        let pending = |m: &OutboxMessage| m.sent_at.is_none();
+        let held: std::collections::HashSet<Uuid> = outbox
+            .iter()
+            .filter(|m| pending(m) && m.claimed_until.is_some_and(|until| until >= now))
+            .map(|m| m.measurement_id)
+            .collect();
+
+        let mut measurements = Vec::new();
+        for m in outbox.iter().filter(|m| pending(m)) {
+            if !held.contains(&m.measurement_id)
+                && !measurements.contains(&m.measurement_id)
+                && measurements.len() < limit as usize
+            {
+                measurements.push(m.measurement_id);
+            }
+        }
+
+        Ok(outbox
+            .iter_mut()
+            .filter(|m| pending(m) && measurements.contains(&m.measurement_id))
+            .map(|m| {
+                m.claimed_until = Some(lease_until);
+                m.clone()
+            })
+            .collect())
+    }
+
+    async fn mark_outbox_sent(&self, ids: &[i64]) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+
+        let mut outbox = self.outbox.lock().unwrap();
+        for m in outbox.iter_mut().filter(|m| ids.contains(&m.id)) {
+            m.sent_at = Some(now);
+            m.claimed_until = None;
+        }
+        Ok(())
+    }
+
+    async fn record_outbox_failure(&self, ids: &[i64], error: &str) -> Result<(), sqlx::Error> {
+        let mut outbox = self.outbox.lock().unwrap();
+        for m in outbox.iter_mut().filter(|m| ids.contains(&m.id)) {
+            m.attempts += 1;
+            m.last_error = Some(error.to_string());
+            m.claimed_until = None;
+        }
+        Ok(())
+    }
+
+    async fn get_usage_since(
+        &self,
+        user_hash: &str,
+        since: DateTime<Utc>,
+        rollup_since: NaiveDate,
+        charging: ChargingMode,
+    ) -> Result<UsageSince, sqlx::Error> {
+        let reservations = self.quota_reservations.lock().unwrap();
+        Ok(self.usage_since(&reservations, user_hash, since, rollup_since, charging))
+    }
+
+    async fn reserve_quota(
+        &self,
+        reservation: &QuotaReservation,
+        windows: &[UsageWindowStart],
+        charging: ChargingMode,
+        stale_before: DateTime<Utc>,
+        admit: &(dyn for<'u> Fn(&'u [UsageSince]) -> bool + Send + Sync),
+    ) -> Result<QuotaCheck, sqlx::Error> {
+        // Holding the reservations lock throughout serializes reservations
+        let mut reservations = self.quota_reservations.lock().unwrap();
+        reservations.retain(|r| r.created_at >= stale_before);
+
+        let usage: Vec<_> = windows
+            .iter()
+            .map(|window| {
+                self.usage_since(
+                    &reservations,
+                    &reservation.user_hash,
+                    window.since,
+                    window.rollup_since,
+                    charging,
+                )
+            })
+            .collect();
+        if !admit(&usage) {
+            return Ok(QuotaCheck {
+                reservation: None,
+                usage,
+            });
+        }
+
+        reservations.push(reservation.clone());
+        Ok(QuotaCheck {
+            reservation: Some(reservation.id),
+            usage,
+        })
+    }
+
+    async fn release_quota_reservation(&self, reservation_id: Uuid) -> Result<bool, sqlx::Error> {
+        let mut reservations = self.quota_reservations.lock().unwrap();
+        let before = reservations.len();
+        reservations.retain(|r| r.id != reservation_id);
+        Ok(reservations.len() < before)
+    }
+
+    async fn refund_lost_measurements(
+        &self,
+        idle_before: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let now = Utc::now();
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        let active: HashSet<String> = tracking
+            .iter()
+            .filter(|t| t.updated_at >= idle_before)
+            .map(|t| t.agent_id.clone())
+            .collect();
+        let mut refunded = 0;
+        for record in tracking.iter_mut().filter(|t| {
+            !active.contains(&t.agent_id)
+                && !t.is_complete
+                && !t.cancelled
+                && t.refunded_probes < t.expected_probes - t.sent_probes
+        }) {
+            record.refunded_probes = record.expected_probes - record.sent_probes;
+            record.updated_at = now;
+            refunded += 1;
+        }
+        Ok(refunded)
+    }
+
+    async fn get_user_quota(&self, user_hash: &str) -> Result<Option<UserQuota>, sqlx::Error> {
+        Ok(self.user_quotas.lock().unwrap().get(user_hash).cloned())
+    }
+
+    async fn set_user_quota(
+        &self,
+        user_hash: &str,
+        windows: &str,
+    ) -> Result<UserQuota, sqlx::Error> {
+        let now = Utc::now();
+        let mut quotas = self.user_quotas.lock().unwrap();
+        let quota = quotas
+            .entry(user_hash.to_string())
+            .and_modify(|quota| {
+                quota.windows = windows.to_string();
+                quota.updated_at = now;
+            })
+            .or_insert_with(|| UserQuota {
+                user_hash: user_hash.to_string(),
+                windows: windows.to_string(),
+                created_at: now,
+                updated_at: now,
+            });
+        Ok(quota.clone())
+    }
+
+    async fn delete_user_quota(&self, user_hash: &str) -> Result<bool, sqlx::Error> {
+        Ok(self.user_quotas.lock().unwrap().remove(user_hash).is_some())
+    }
+
+    async fn count_expired_measurements(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<PurgedMeasurements, sqlx::Error> {
+        let expired = self.expired_measurements(before);
+        Ok(PurgedMeasurements {
+            measurements: expired.len() as u64,
+            rows: expired.iter().map(|(_, rows)| rows).sum(),
+        })
+    }
+
+    async fn purge_expired_measurements(
+        &self,
+        before: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<PurgedMeasurements, sqlx::Error> {
+        let mut expired = self.expired_measurements(before);
+        expired.truncate(limit.max(0) as usize);
+
+        let mut tracking = self.measurement_tracking.lock().unwrap();
+        let mut daily_usage = self.daily_usage.lock().unwrap();
+        let mut purged = PurgedMeasurements::default();
+        for (measurement_id, _) in &expired {
+            let (records, kept): (Vec<_>, Vec<_>) = tracking
+                .drain(..)
+                .partition(|t| t.measurement_id == *measurement_id);
+            *tracking = kept;
+
+            let user_hash = records[0].user_hash.clone();
+            let day = records
+                .iter()
+                .map(|r| r.created_at)
+                .min()
+                .unwrap()
+                .date_naive();
+            let expected_probes: i64 = records.iter().map(|r| r.expected_probes as i64).sum();
+            let sent_probes: i64 = records.iter().map(|r| r.sent_probes as i64).sum();
+            let refunded_probes: i64 = records.iter().map(|r| r.refunded_probes as i64).sum();
+            match daily_usage
+                .iter_mut()
+                .find(|u| u.user_hash == user_hash && u.day == day)
+            {
+                Some(usage) => {
+                    usage.measurements += 1;
+                    usage.expected_probes += expected_probes;
+                    usage.sent_probes += sent_probes;
+                    usage.refunded_probes += refunded_probes;
+                }
+                None => daily_usage.push(DailyUsage {
+                    user_hash,
+                    day,
+                    measurements: 1,
+                    expected_probes,
+                    sent_probes,
+                    refunded_probes,
+                }),
+            }
+
+            purged.measurements += 1;
+            purged.rows += records.len() as u64;
+        }
+
+        self.cancellations
+            .lock()
+            .unwrap()
+            .retain(|c| !expired.iter().any(|(id, _)| *id == c.measurement_id));
+        Ok(purged)
+    }
+
+    async fn get_daily_usage(
+        &self,
+        user_hash: &str,
+        since: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, sqlx::Error> {
+        let mut usage: Vec<DailyUsage> = self
+            .daily_usage
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|u| u.user_hash == user_hash && u.day >= since)
+            .cloned()
+            .collect();
+        usage.sort_by_key(|u| u.day);
+        Ok(usage)
+    }
+
+    // Usage is not recorded in a probe_usage table in memory
+    async fn count_expired_probe_usage(&self, _before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        Ok(0)
+    }
+
+    async fn purge_expired_probe_usage(
+        &self,
+        _before: DateTime<Utc>,
+        _limit: i32,
+    ) -> Result<u64, sqlx::Error> {
+        Ok(0)
+    }
+}
@@ -0,0 +1,1595 @@
+//! SQLite storage, for single-node deployments that run without PostgreSQL.
+//! Queries follow the PostgreSQL ones in `postgres.rs`; UUIDs and
+//! timestamps are always generated here rather than by database defaults
+//! (see `migrations/sqlite`).
+
+use super::{
+    DEFAULT_PROBE_LIMIT, DailyUsage, DispatchTracking, IdempotencyRecord, MeasurementArchive,
+    MeasurementCancellation, MeasurementListFilter, MeasurementSchedule, MeasurementSort,
+    MeasurementState, MeasurementStatus, MeasurementTracking, MultiRoundMeasurement, OutboxMessage,
+    ProbeUsageRecord, PurgedMeasurements, QuotaCheck, QuotaReservation, ScheduleRun, Storage,
+    TargetList, UsageSince, UsageWindowStart, UserLimit, UserQuota, UserUsageStats,
+    user_id_mapping_conflict,
+};
+use crate::kafka::OutboundMessage;
+use crate::quota::ChargingMode;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use sqlx::{Encode, QueryBuilder, Row, Sqlite, SqliteExecutor, Type};
+use uuid::Uuid;
+
+/// Is the database URL one of a SQLite database (`sqlite:` scheme)?
+pub fn is_sqlite_url(database_url: &str) -> bool {
+    database_url.starts_with("sqlite:")
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Open the database, creating the file if needed. An in-memory database
+    /// only lives as long as its connection, so its pool keeps a single one.
+    pub(crate) async fn connect(database_url: &str) -> Result<Self, sqlx::Error> {
+        let options: SqliteConnectOptions = database_url.parse()?;
+        let options = options
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .foreign_keys(true);
+
+        let pool = if database_url.contains(":memory:") || database_url.contains("mode=memory") {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect_with(options)
+                .await?
+        } else {
+            SqlitePool::connect_with(options).await?
+        };
+        Ok(Self { pool })
+    }
+}
+
+/// What a tracking row and a daily rollup charge in `charging` mode
+fn charge_columns(charging: ChargingMode) -> (&'static str, &'static str) {
+    match charging {
+        ChargingMode::Expected => ("expected_probes", "expected_probes"),
+        ChargingMode::Sent => (
+            "CASE WHEN is_complete OR cancelled THEN sent_probes
+                  ELSE expected_probes - refunded_probes END",
+            "sent_probes",
+        ),
+        ChargingMode::Refunded => (
+            "expected_probes - refunded_probes",
+            "expected_probes - refunded_probes",
+        ),
+    }
+}
+
+/// Probes charged to a user since `since`: tracked measurements, usage
+/// rolled up from `rollup_since` on, and reservations
+async fn usage_since(
+    executor: impl SqliteExecutor<'_>,
+    user_hash: &str,
+    since: DateTime<Utc>,
+    rollup_since: NaiveDate,
+    charging: ChargingMode,
+) -> Result<UsageSince, sqlx::Error> {
+    let (tracked_charge, rolled_up_charge) = charge_columns(charging);
+    let row = sqlx::query(sqlx::AssertSqlSafe(format!(
+        r#"WITH tracked AS (
+               SELECT COALESCE(SUM({tracked_charge}), 0) AS probes,
+                      COALESCE(SUM(refunded_probes), 0) AS refunded,
+                      MIN(created_at) AS oldest
+               FROM measurement_tracking
+               WHERE user_hash = $1 AND created_at >= $2),
+           rolled_up AS (
+               SELECT COALESCE(SUM({rolled_up_charge}), 0) AS probes,
+                      COALESCE(SUM(refunded_probes), 0) AS refunded
+               FROM daily_usage_rollups
+               WHERE user_hash = $1 AND day >= $3),
+           reserved AS (
+               SELECT COALESCE(SUM(probes), 0) AS probes, MIN(created_at) AS oldest
+               FROM quota_reservations
+               WHERE user_hash = $1 AND created_at >= $2)
+           SELECT tracked.probes + rolled_up.probes + reserved.probes AS probes,
+                  tracked.refunded + rolled_up.refunded AS refunded,
+                  COALESCE(MIN(tracked.oldest, reserved.oldest), tracked.oldest, reserved.oldest)
+                      AS oldest
+           FROM tracked, rolled_up, reserved"#
+    )))
+    .bind(user_hash)
+    .bind(since)
+    .bind(rollup_since)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(UsageSince {
+        probes: row.get("probes"),
+        refunded: row.get("refunded"),
+        oldest: row.get("oldest"),
+    })
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> Result<(), sqlx::Error> {
+        sqlx::migrate!("./migrations/sqlite")
+            .run(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn get_user_usage_stats(
+        &self,
+        user_hash: &str,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<UserUsageStats, sqlx::Error> {
+        let limit = self
+            .get_user_limit(user_hash)
+            .await?
+            .map(|limit| limit.probe_limit)
+            .unwrap_or(DEFAULT_PROBE_LIMIT);
+
+        let row = sqlx::query(
+            r#"SELECT
+                   COUNT(DISTINCT measurement_id) as submission_count,
+                   COALESCE(SUM(expected_probes), 0) as total_probes,
+                   MAX(updated_at) as last_submitted
+               FROM measurement_tracking
+               WHERE user_hash = $1
+               AND created_at >= $2
+               AND created_at <= $3"#,
+        )
+        .bind(user_hash)
+        .bind(start_time)
+        .bind(end_time)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(UserUsageStats {
+            user_hash: user_hash.to_string(),
+            submission_count: row.get::<i64, _>("submission_count") as u32,
+            total_probes: row.get::<i64, _>("total_probes") as u32,
+            limit,
+            last_submitted: row.get::<Option<DateTime<Utc>>, _>("last_submitted"),
+        })
+    }
+
+    async fn get_recent_usage(&self, limit: i32) -> Result<Vec<ProbeUsageRecord>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"SELECT
+                   measurement_id as id,
+                   user_hash,
+                   SUM(sent_probes) as probe_count,
+                   MAX(updated_at) as timestamp
+               FROM measurement_tracking
+               GROUP BY measurement_id, user_hash
+               ORDER BY timestamp DESC
+               LIMIT $1"#,
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ProbeUsageRecord {
+                id: row.get("id"),
+                user_hash: row.get("user_hash"),
+                probe_count: row.get::<i64, _>("probe_count") as i32,
+                timestamp: row.get("timestamp"),
+            })
+            .collect())
+    }
+
+    async fn set_user_limit(
+        &self,
+        user_hash: &str,
+        probe_limit: u32,
+    ) -> Result<UserLimit, sqlx::Error> {
+        let row = sqlx::query(
+            r#"INSERT INTO user_limits (id, user_hash, probe_limit, created_at, updated_at)
+               VALUES ($1, $2, $3, $4, $4)
+               ON CONFLICT (user_hash)
+               DO UPDATE SET
+                   probe_limit = excluded.probe_limit,
+                   updated_at = excluded.updated_at
+               RETURNING id, user_hash, probe_limit, created_at, updated_at"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_hash)
+        .bind(probe_limit as i32)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(UserLimit {
+            id: row.get("id"),
+            user_hash: row.get("user_hash"),
+            probe_limit: row.get::<i32, _>("probe_limit") as u32,
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        })
+    }
+
+    async fn get_user_limit(&self, user_hash: &str) -> Result<Option<UserLimit>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"SELECT id, user_hash, probe_limit, created_at, updated_at
+               FROM user_limits
+               WHERE user_hash = $1"#,
+        )
+        .bind(user_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| UserLimit {
+            id: r.get("id"),
+            user_hash: r.get("user_hash"),
+            probe_limit: r.get::<i32, _>("probe_limit") as u32,
+            created_at: r.get("created_at"),
+            updated_at: r.get("updated_at"),
+        }))
+    }
+
+    async fn get_user_id_by_hash(&self, user_hash: &str) -> Result<Option<u32>, sqlx::Error> {
+        let row = sqlx::query("SELECT user_id FROM user_id_mappings WHERE user_hash = $1")
+            .bind(user_hash)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.get::<i32, _>("user_id") as u32))
+    }
+
+    async fn create_user_id_mapping(
+        &self,
+        user_hash: &str,
+        user_id: u32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO user_id_mappings (user_hash, user_id, created_at) VALUES ($1, $2, $3)",
+        )
+        .bind(user_hash)
+        .bind(user_id as i32)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await
+        .map_err(user_id_mapping_conflict)?;
+        Ok(())
+    }
+
+    async fn create_measurement_tracking(
+        &self,
+        user_hash: &str,
+        measurement_id: Uuid,
+        agent_id: &str,
+        expected_probes: i32,
+    ) -> Result<MeasurementTracking, sqlx::Error> {
+        let now = Utc::now();
+        sqlx::query_as::<_, MeasurementTracking>(
+            r#"INSERT INTO measurement_tracking
+               (id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, created_at, updated_at)
+               VALUES ($1, $2, $3, $4, $5, 0, false, $6, $6)
+               RETURNING id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, cancelled, filtered_probes, refunded_probes, created_at, updated_at"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_hash)
+        .bind(measurement_id)
+        .bind(agent_id)
+        .bind(expected_probes)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn update_measurement_probe_count(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_id: &str,
+        sent_probes: i32,
+        is_complete: bool,
+    ) -> Result<MeasurementTracking, sqlx::Error> {
+        let now = Utc::now();
+        sqlx::query_as::<_, MeasurementTracking>(
+            r#"UPDATE measurement_tracking
+               SET sent_probes = $4, is_complete = $5, updated_at = $6,
+                   refunded_probes = MIN(refunded_probes, MAX(expected_probes - $4, 0))
+               WHERE measurement_id = $1 AND user_hash = $2 AND agent_id = $3
+               RETURNING id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, cancelled, filtered_probes, refunded_probes, created_at, updated_at"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(agent_id)
+        .bind(sent_probes)
+        .bind(is_complete)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn advance_measurement_progress(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+        sent_probes: i32,
+        is_complete: bool,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+        let result = sqlx::query(
+            r#"UPDATE measurement_tracking
+               SET sent_probes = $3, is_complete = is_complete OR $4, updated_at = $5,
+                   refunded_probes = MIN(refunded_probes, MAX(expected_probes - $3, 0))
+               WHERE measurement_id = $1 AND agent_id = $2
+                 AND (sent_probes < $3 OR (sent_probes = $3 AND NOT is_complete AND $4))"#,
+        )
+        .bind(measurement_id)
+        .bind(agent_id)
+        .bind(sent_probes)
+        .bind(is_complete)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn add_measurement_expected_probes(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_id: &str,
+        probes: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+        let result = sqlx::query(
+            r#"UPDATE measurement_tracking
+               SET expected_probes = expected_probes + $4, updated_at = $5
+               WHERE measurement_id = $1 AND user_hash = $2 AND agent_id = $3"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(agent_id)
+        .bind(probes)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn add_measurement_filtered_probes(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        filtered_probes: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"UPDATE measurement_tracking
+               SET filtered_probes = filtered_probes + $3
+               WHERE measurement_id = $1 AND user_hash = $2"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(filtered_probes)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_measurement_status(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementStatus>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementStatus>(
+            r#"SELECT
+               measurement_id,
+               user_hash,
+               total_agents,
+               total_expected_probes,
+               total_sent_probes,
+               completed_agents,
+               measurement_complete,
+               measurement_cancelled,
+               started_at,
+               last_updated,
+               filtered_probes,
+               queued_batches
+               FROM measurement_status
+               WHERE measurement_id = $1 AND user_hash = $2"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn list_user_measurements(
+        &self,
+        user_hash: &str,
+        filter: &MeasurementListFilter,
+    ) -> Result<Vec<MeasurementStatus>, sqlx::Error> {
+        let mut qb = QueryBuilder::<Sqlite>::new(
+            "SELECT measurement_id, user_hash, total_agents, total_expected_probes, \
+             total_sent_probes, completed_agents, measurement_complete, \
+             measurement_cancelled, started_at, last_updated, filtered_probes, \
+             queued_batches FROM measurement_status WHERE user_hash = ",
+        );
+        qb.push_bind(user_hash);
+
+        // status: OR of the requested states (constant predicates, no binds)
+        if !filter.status.is_empty() {
+            qb.push(" AND (");
+            {
+                let mut sep = qb.separated(" OR ");
+                for state in &filter.status {
+                    match state {
+                        MeasurementState::Complete => {
+                            sep.push("(measurement_complete AND NOT measurement_cancelled)");
+                        }
+                        MeasurementState::InProgress => {
+                            sep.push("(NOT measurement_complete)");
+                        }
+                        MeasurementState::Cancelled => {
+                            sep.push("measurement_cancelled");
+                        }
+                        MeasurementState::Queued => {
+                            sep.push("(queued_batches > 0)");
+                        }
+                    };
+                }
+            }
+            qb.push(")");
+        }
+
+        if let Some(since) = filter.since {
+            qb.push(" AND started_at >= ").push_bind(since);
+        }
+        if let Some(until) = filter.until {
+            qb.push(" AND started_at <= ").push_bind(until);
+        }
+        if let Some(agent) = &filter.agent {
+            qb.push(
+                " AND EXISTS (SELECT 1 FROM measurement_tracking t \
+                 WHERE t.measurement_id = measurement_status.measurement_id \
+                 AND t.user_hash = ",
+            )
+            .push_bind(user_hash)
+            .push(" AND t.agent_id = ")
+            .push_bind(agent)
+            .push(")");
+        }
+
+        // sort column is a fixed identifier from an enum, never raw input
+        let sort_col = match filter.sort {
+            MeasurementSort::Started => "started_at",
+            MeasurementSort::Updated => "last_updated",
+        };
+        qb.push(" ORDER BY ").push(sort_col);
+        qb.push(if filter.reverse { " ASC" } else { " DESC" });
+        qb.push(" LIMIT ").push_bind(filter.limit);
+
+        qb.build_query_as::<MeasurementStatus>()
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn cancel_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        // SQLite has no data-modifying CTEs: publish the cancellation to the
+        // agents about to be cancelled, then cancel them
+        sqlx::query(
+            r#"INSERT INTO measurement_cancellations (measurement_id, agent_id, requested_at)
+               SELECT measurement_id, agent_id, $3 FROM measurement_tracking
+               WHERE measurement_id = $1 AND user_hash = $2
+                 AND is_complete = FALSE AND cancelled = FALSE
+               ON CONFLICT (measurement_id, agent_id) DO NOTHING"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query(
+            r#"UPDATE measurement_tracking
+               SET cancelled = TRUE, updated_at = $3,
+                   refunded_probes = MAX(expected_probes - sent_probes, 0)
+               WHERE measurement_id = $1 AND user_hash = $2
+                 AND is_complete = FALSE AND cancelled = FALSE"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        // Batches still queued in the outbox are never sent
+        sqlx::query(
+            r#"DELETE FROM probe_outbox
+               WHERE measurement_id = $1 AND sent_at IS NULL"#,
+        )
+        .bind(measurement_id)
+        .execute(&mut *tx)
+        .await?;
+
+        // A cancelled multi-round measurement takes no more rounds
+        sqlx::query(
+            r#"UPDATE multi_round_measurements
+               SET closed_at = $3
+               WHERE measurement_id = $1 AND user_hash = $2 AND closed_at IS NULL"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_agent_cancellations(
+        &self,
+        agent_id: &str,
+        after: i64,
+        limit: i32,
+    ) -> Result<Vec<MeasurementCancellation>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementCancellation>(
+            r#"SELECT id, measurement_id, agent_id, requested_at, acknowledged_at
+               FROM measurement_cancellations
+               WHERE agent_id = $1 AND id > $2
+               ORDER BY id
+               LIMIT $3"#,
+        )
+        .bind(agent_id)
+        .bind(after)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_measurement_cancellations(
+        &self,
+        measurement_id: Uuid,
+    ) -> Result<Vec<MeasurementCancellation>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementCancellation>(
+            r#"SELECT id, measurement_id, agent_id, requested_at, acknowledged_at
+               FROM measurement_cancellations
+               WHERE measurement_id = $1
+               ORDER BY id"#,
+        )
+        .bind(measurement_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn acknowledge_cancellation(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+    ) -> Result<Option<MeasurementCancellation>, sqlx::Error> {
+        let now = Utc::now();
+        sqlx::query_as::<_, MeasurementCancellation>(
+            r#"UPDATE measurement_cancellations
+               SET acknowledged_at = COALESCE(acknowledged_at, $3)
+               WHERE measurement_id = $1 AND agent_id = $2
+               RETURNING id, measurement_id, agent_id, requested_at, acknowledged_at"#,
+        )
+        .bind(measurement_id)
+        .bind(agent_id)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn delete_measurement_tracking(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            r#"DELETE FROM measurement_tracking
+               WHERE measurement_id = $1 AND user_hash = $2"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_measurement_tracking(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Vec<MeasurementTracking>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementTracking>(
+            r#"SELECT id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, cancelled, filtered_probes, refunded_probes, created_at, updated_at
+               FROM measurement_tracking
+               WHERE measurement_id = $1 AND user_hash = $2
+               ORDER BY created_at"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_measurement_tracking_by_agent(
+        &self,
+        measurement_id: Uuid,
+        agent_id: &str,
+    ) -> Result<Option<MeasurementTracking>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementTracking>(
+            r#"SELECT id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, cancelled, filtered_probes, refunded_probes, created_at, updated_at
+               FROM measurement_tracking
+               WHERE measurement_id = $1 AND agent_id = $2"#,
+        )
+        .bind(measurement_id)
+        .bind(agent_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+        request_hash: &str,
+        expired_before: DateTime<Utc>,
+        abandoned_before: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>, sqlx::Error> {
+        let now = Utc::now();
+        // Insert, or take over a key whose previous use has expired or was
+        // abandoned in flight. Nothing is returned when the conditional DO
+        // UPDATE does not apply.
+        let claimed = sqlx::query(
+            r#"INSERT INTO idempotency_keys (user_hash, idempotency_key, request_hash, created_at)
+               VALUES ($1, $2, $3, $4)
+               ON CONFLICT (user_hash, idempotency_key) DO UPDATE
+               SET request_hash = excluded.request_hash,
+                   measurement_id = NULL,
+                   response = NULL,
+                   created_at = excluded.created_at
+               WHERE idempotency_keys.created_at < $5
+                  OR (idempotency_keys.response IS NULL AND idempotency_keys.created_at < $6)
+               RETURNING user_hash"#,
+        )
+        .bind(user_hash)
+        .bind(idempotency_key)
+        .bind(request_hash)
+        .bind(now)
+        .bind(expired_before)
+        .bind(abandoned_before)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if claimed.is_some() {
+            return Ok(None);
+        }
+
+        sqlx::query_as::<_, IdempotencyRecord>(
+            r#"SELECT user_hash, idempotency_key, request_hash, measurement_id, response, created_at
+               FROM idempotency_keys
+               WHERE user_hash = $1 AND idempotency_key = $2"#,
+        )
+        .bind(user_hash)
+        .bind(idempotency_key)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+        measurement_id: Uuid,
+        response: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"UPDATE idempotency_keys
+               SET measurement_id = $3, response = $4
+               WHERE user_hash = $1 AND idempotency_key = $2"#,
+        )
+        .bind(user_hash)
+        .bind(idempotency_key)
+        .bind(measurement_id)
+        .bind(response)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn release_idempotency_key(
+        &self,
+        user_hash: &str,
+        idempotency_key: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"DELETE FROM idempotency_keys
+               WHERE user_hash = $1 AND idempotency_key = $2 AND response IS NULL"#,
+        )
+        .bind(user_hash)
+        .bind(idempotency_key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_schedule(
+        &self,
+        user_hash: &str,
+        name: Option<&str>,
+        spec: &str,
+        interval_seconds: Option<i64>,
+        next_run_at: DateTime<Utc>,
+    ) -> Result<MeasurementSchedule, sqlx::Error> {
+        let now = Utc::now();
+        let id = Uuid::new_v4();
+        sqlx::query_as::<_, MeasurementSchedule>(
+            r#"INSERT INTO measurement_schedules
+               (id, user_hash, name, spec, interval_seconds, next_run_at, created_at, updated_at)
+               VALUES ($1, $2, $3, $4, $5, $6, $7, $7)
+               RETURNING id, user_hash, name, spec, interval_seconds, next_run_at, created_at, updated_at"#,
+        )
+        .bind(id)
+        .bind(user_hash)
+        .bind(name)
+        .bind(spec)
+        .bind(interval_seconds)
+        .bind(next_run_at)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn list_user_schedules(
+        &self,
+        user_hash: &str,
+    ) -> Result<Vec<MeasurementSchedule>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementSchedule>(
+            r#"SELECT id, user_hash, name, spec, interval_seconds, next_run_at, created_at, updated_at
+               FROM measurement_schedules
+               WHERE user_hash = $1
+               ORDER BY created_at DESC"#,
+        )
+        .bind(user_hash)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_schedule(
+        &self,
+        schedule_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementSchedule>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementSchedule>(
+            r#"SELECT id, user_hash, name, spec, interval_seconds, next_run_at, created_at, updated_at
+               FROM measurement_schedules
+               WHERE id = $1 AND user_hash = $2"#,
+        )
+        .bind(schedule_id)
+        .bind(user_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn delete_schedule(
+        &self,
+        schedule_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        // Runs are deleted by ON DELETE CASCADE (foreign keys are enabled on connect)
+        let result =
+            sqlx::query(r#"DELETE FROM measurement_schedules WHERE id = $1 AND user_hash = $2"#)
+                .bind(schedule_id)
+                .bind(user_hash)
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_due_schedules(
+        &self,
+        now: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<Vec<MeasurementSchedule>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementSchedule>(
+            r#"SELECT id, user_hash, name, spec, interval_seconds, next_run_at, created_at, updated_at
+               FROM measurement_schedules
+               WHERE next_run_at IS NOT NULL AND next_run_at <= $1
+               ORDER BY next_run_at ASC
+               LIMIT $2"#,
+        )
+        .bind(now)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn advance_schedule(
+        &self,
+        schedule_id: Uuid,
+        expected_run_at: DateTime<Utc>,
+        next_run_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+        let result = sqlx::query(
+            r#"UPDATE measurement_schedules
+               SET next_run_at = $3, updated_at = $4
+               WHERE id = $1 AND next_run_at = $2"#,
+        )
+        .bind(schedule_id)
+        .bind(expected_run_at)
+        .bind(next_run_at)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn record_schedule_run(
+        &self,
+        schedule_id: Uuid,
+        measurement_id: Option<Uuid>,
+        error: Option<&str>,
+    ) -> Result<ScheduleRun, sqlx::Error> {
+        let now = Utc::now();
+        sqlx::query_as::<_, ScheduleRun>(
+            r#"INSERT INTO measurement_schedule_runs (id, schedule_id, measurement_id, error, started_at)
+               VALUES ($1, $2, $3, $4, $5)
+               RETURNING id, schedule_id, measurement_id, error, started_at"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(schedule_id)
+        .bind(measurement_id)
+        .bind(error)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn list_schedule_runs(
+        &self,
+        schedule_id: Uuid,
+        limit: i32,
+    ) -> Result<Vec<ScheduleRun>, sqlx::Error> {
+        sqlx::query_as::<_, ScheduleRun>(
+            r#"SELECT id, schedule_id, measurement_id, error, started_at
+               FROM measurement_schedule_runs
+               WHERE schedule_id = $1
+               ORDER BY started_at DESC
+               LIMIT $2"#,
+        )
+        .bind(schedule_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn create_target_list(
+        &self,
+        user_hash: &str,
+        name: &str,
+        targets: &[String],
+    ) -> Result<TargetList, sqlx::Error> {
+        let now = Utc::now();
+        let id = Uuid::new_v4();
+        sqlx::query_as::<_, TargetList>(
+            r#"INSERT INTO target_lists (id, user_hash, name, targets, target_count, created_at, updated_at)
+               VALUES ($1, $2, $3, $4, $5, $6, $6)
+               RETURNING id, user_hash, name, target_count, created_at, updated_at"#,
+        )
+        .bind(id)
+        .bind(user_hash)
+        .bind(name)
+        .bind(targets.join("\n"))
+        .bind(targets.len() as i32)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn list_user_target_lists(
+        &self,
+        user_hash: &str,
+    ) -> Result<Vec<TargetList>, sqlx::Error> {
+        sqlx::query_as::<_, TargetList>(
+            r#"SELECT id, user_hash, name, target_count, created_at, updated_at
+               FROM target_lists
+               WHERE user_hash = $1
+               ORDER BY created_at DESC"#,
+        )
+        .bind(user_hash)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<TargetList>, sqlx::Error> {
+        sqlx::query_as::<_, TargetList>(
+            r#"SELECT id, user_hash, name, target_count, created_at, updated_at
+               FROM target_lists
+               WHERE id = $1 AND user_hash = $2"#,
+        )
+        .bind(list_id)
+        .bind(user_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn get_target_list_targets(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<Vec<String>>, sqlx::Error> {
+        let row =
+            sqlx::query(r#"SELECT targets FROM target_lists WHERE id = $1 AND user_hash = $2"#)
+                .bind(list_id)
+                .bind(user_hash)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.map(|row| {
+            let targets: String = row.get("targets");
+            targets
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect()
+        }))
+    }
+
+    async fn update_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+        name: Option<&str>,
+        targets: Option<&[String]>,
+    ) -> Result<Option<TargetList>, sqlx::Error> {
+        let now = Utc::now();
+        sqlx::query_as::<_, TargetList>(
+            r#"UPDATE target_lists
+               SET name = COALESCE($3, name),
+                   targets = COALESCE($4, targets),
+                   target_count = COALESCE($5, target_count),
+                   updated_at = $6
+               WHERE id = $1 AND user_hash = $2
+               RETURNING id, user_hash, name, target_count, created_at, updated_at"#,
+        )
+        .bind(list_id)
+        .bind(user_hash)
+        .bind(name)
+        .bind(targets.map(|t| t.join("\n")))
+        .bind(targets.map(|t| t.len() as i32))
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn delete_target_list(
+        &self,
+        list_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(r#"DELETE FROM target_lists WHERE id = $1 AND user_hash = $2"#)
+            .bind(list_id)
+            .bind(user_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn append_measurement_archive(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        blob_key: &str,
+        metadata: &str,
+        probe_count: i32,
+        size_bytes: i64,
+    ) -> Result<MeasurementArchive, sqlx::Error> {
+        let now = Utc::now();
+        sqlx::query_as::<_, MeasurementArchive>(
+            r#"INSERT INTO measurement_archives
+               (measurement_id, user_hash, blob_key, metadata, probe_count, size_bytes, segments, created_at)
+               VALUES ($1, $2, $3, $4, $5, $6, 1, $7)
+               ON CONFLICT (measurement_id) DO UPDATE
+               SET probe_count = measurement_archives.probe_count + excluded.probe_count,
+                   size_bytes = measurement_archives.size_bytes + excluded.size_bytes,
+                   segments = measurement_archives.segments + 1
+               WHERE measurement_archives.user_hash = excluded.user_hash
+               RETURNING measurement_id, user_hash, blob_key, metadata, probe_count, size_bytes, segments, created_at"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(blob_key)
+        .bind(metadata)
+        .bind(probe_count)
+        .bind(size_bytes)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn get_measurement_archive(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MeasurementArchive>, sqlx::Error> {
+        sqlx::query_as::<_, MeasurementArchive>(
+            r#"SELECT measurement_id, user_hash, blob_key, metadata, probe_count, size_bytes, segments, created_at
+               FROM measurement_archives
+               WHERE measurement_id = $1 AND user_hash = $2"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn create_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+        agent_ids: &[String],
+        metadata: &str,
+    ) -> Result<MultiRoundMeasurement, sqlx::Error> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        for agent_id in agent_ids {
+            sqlx::query(
+                r#"INSERT INTO measurement_tracking
+                   (id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, created_at, updated_at)
+                   VALUES ($1, $2, $3, $4, 0, 0, false, $5, $5)"#,
+            )
+            .bind(Uuid::new_v4())
+            .bind(user_hash)
+            .bind(measurement_id)
+            .bind(agent_id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let measurement = sqlx::query_as::<_, MultiRoundMeasurement>(
+            r#"INSERT INTO multi_round_measurements
+               (measurement_id, user_hash, metadata, rounds, created_at)
+               VALUES ($1, $2, $3, 0, $4)
+               RETURNING measurement_id, user_hash, metadata, rounds, created_at, closed_at"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(metadata)
+        .bind(now)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(measurement)
+    }
+
+    async fn get_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<MultiRoundMeasurement>, sqlx::Error> {
+        sqlx::query_as::<_, MultiRoundMeasurement>(
+            r#"SELECT measurement_id, user_hash, metadata, rounds, created_at, closed_at
+               FROM multi_round_measurements
+               WHERE measurement_id = $1 AND user_hash = $2"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn record_measurement_round(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<Option<i32>, sqlx::Error> {
+        let round: Option<(i32,)> = sqlx::query_as(
+            r#"UPDATE multi_round_measurements
+               SET rounds = rounds + 1
+               WHERE measurement_id = $1 AND user_hash = $2 AND closed_at IS NULL
+               RETURNING rounds"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(round.map(|(round,)| round))
+    }
+
+    async fn close_multi_round_measurement(
+        &self,
+        measurement_id: Uuid,
+        user_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+        let result = sqlx::query(
+            r#"UPDATE multi_round_measurements
+               SET closed_at = $3
+               WHERE measurement_id = $1 AND user_hash = $2 AND closed_at IS NULL"#,
+        )
+        .bind(measurement_id)
+        .bind(user_hash)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn record_dispatch(
+        &self,
+        tracking: &DispatchTracking,
+        messages: &[OutboundMessage],
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        for agent_id in &tracking.agent_ids {
+            let query = if tracking.new_measurement {
+                sqlx::query(
+                    r#"INSERT INTO measurement_tracking
+                       (id, user_hash, measurement_id, agent_id, expected_probes, sent_probes, is_complete, created_at, updated_at)
+                       VALUES ($1, $2, $3, $4, $5, 0, false, $6, $6)"#,
+                )
+                .bind(Uuid::new_v4())
+            } else {
+                sqlx::query(
+                    r#"UPDATE measurement_tracking
+                       SET expected_probes = expected_probes + $4, updated_at = $5
+                       WHERE user_hash = $1 AND measurement_id = $2 AND agent_id = $3
+                         AND NOT cancelled"#,
+                )
+            };
+            let result = query
+                .bind(&tracking.user_hash)
+                .bind(tracking.measurement_id)
+                .bind(agent_id)
+                .bind(tracking.probes)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+            // A round needs every agent's row, still running
+            if result.rows_affected() == 0 {
+                return Err(sqlx::Error::RowNotFound);
+            }
+        }
+
+        if tracking.filtered != 0 {
+            sqlx::query(
+                r#"UPDATE measurement_tracking
+                   SET filtered_probes = filtered_probes + $3
+                   WHERE measurement_id = $1 AND user_hash = $2"#,
+            )
+            .bind(tracking.measurement_id)
+            .bind(&tracking.user_hash)
+            .bind(tracking.filtered)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        for message in messages {
+            sqlx::query(
+                r#"INSERT INTO probe_outbox
+                   (measurement_id, message_key, payload, headers, kafka_topic,
+                    kafka_partition, created_at)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+            )
+            .bind(tracking.measurement_id)
+            .bind(&message.key)
+            .bind(&message.payload)
+            .bind(serde_json::to_string(&message.headers).unwrap_or_default())
+            .bind(&message.topic)
+            .bind(message.partition)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn revert_dispatch(
+        &self,
+        tracking: &DispatchTracking,
+        delivered: &[i32],
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        for (agent_id, &delivered) in tracking.agent_ids.iter().zip(delivered) {
+            // A new measurement's agent that got nothing has nothing to follow
+            let remove = tracking.new_measurement && delivered == 0;
+            let query = sqlx::query(if remove {
+                r#"DELETE FROM measurement_tracking
+                   WHERE user_hash = $1 AND measurement_id = $2 AND agent_id = $3"#
+            } else {
+                r#"UPDATE measurement_tracking
+                   SET expected_probes = expected_probes - $4, updated_at = $5
+                   WHERE user_hash = $1 AND measurement_id = $2 AND agent_id = $3"#
+            })
+            .bind(&tracking.user_hash)
+            .bind(tracking.measurement_id)
+            .bind(agent_id);
+            let query = if remove {
+                query
+            } else {
+                query.bind(tracking.probes - delivered).bind(now)
+            };
+            query.execute(&mut *tx).await?;
+        }
+
+        // A round that reached no agent leaves its filtered probes out too
+        if !tracking.new_measurement && tracking.filtered != 0 && delivered.iter().all(|&d| d == 0)
+        {
+            sqlx::query(
+                r#"UPDATE measurement_tracking
+                   SET filtered_probes = filtered_probes - $3
+                   WHERE measurement_id = $1 AND user_hash = $2"#,
+            )
+            .bind(tracking.measurement_id)
+            .bind(&tracking.user_hash)
+            .bind(tracking.filtered)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn claim_outbox_messages(
+        &self,
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<Vec<OutboxMessage>, sqlx::Error> {
+        // Timestamps are RFC 3339 text, so an empty string sorts before any of them
+        let mut messages = sqlx::query_as::<_, OutboxMessage>(
+            r#"UPDATE probe_outbox
+               SET claimed_until = $2
+               WHERE sent_at IS NULL
+                 AND (claimed_until IS NULL OR claimed_until < $1)
+                 AND measurement_id IN (
+                     SELECT measurement_id FROM probe_outbox
+                     WHERE sent_at IS NULL
+                     GROUP BY measurement_id
+                     HAVING COALESCE(MAX(claimed_until), '') < $1
+                     ORDER BY MIN(id)
+                     LIMIT $3)
+               RETURNING id, measurement_id, message_key, payload, headers, kafka_topic,
+                         kafka_partition, attempts, last_error, created_at,
+                         claimed_until, sent_at"#,
+        )
+        .bind(now)
+        .bind(lease_until)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        messages.sort_by_key(|m| m.id);
+        Ok(messages)
+    }
+
+    async fn mark_outbox_sent(&self, ids: &[i64]) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        let mut qb = QueryBuilder::<Sqlite>::new("UPDATE probe_outbox SET sent_at = ");
+        qb.push_bind(now)
+            .push(", claimed_until = NULL WHERE id IN (");
+        push_ids(&mut qb, ids);
+        qb.push(")");
+        qb.build().execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn record_outbox_failure(&self, ids: &[i64], error: &str) -> Result<(), sqlx::Error> {
+        let mut qb = QueryBuilder::<Sqlite>::new(
+            "UPDATE probe_outbox SET attempts = attempts + 1, last_error = ",
+        );
+        qb.push_bind(error)
+            .push(", claimed_until = NULL WHERE id IN (");
+        push_ids(&mut qb, ids);
+        qb.push(")");
+        qb.build().execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn get_usage_since(
+        &self,
+        user_hash: &str,
+        since: DateTime<Utc>,
+        rollup_since: NaiveDate,
+        charging: ChargingMode,
+    ) -> Result<UsageSince, sqlx::Error> {
+        usage_since(&self.pool, user_hash, since, rollup_since, charging).await
+    }
+
+    async fn reserve_quota(
+        &self,
+        reservation: &QuotaReservation,
+        windows: &[UsageWindowStart],
+        charging: ChargingMode,
+        stale_before: DateTime<Utc>,
+        admit: &(dyn for<'u> Fn(&'u [UsageSince]) -> bool + Send + Sync),
+    ) -> Result<QuotaCheck, sqlx::Error> {
+        // SQLite has no row locks: take the database write lock up front, so
+        // that reservations wait for each other
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+
+        sqlx::query("DELETE FROM quota_reservations WHERE created_at < $1")
+            .bind(stale_before)
+            .execute(&mut *tx)
+            .await?;
+
+        let mut usage = Vec::with_capacity(windows.len());
+        for window in windows {
+            usage.push(
+                usage_since(
+                    &mut *tx,
+                    &reservation.user_hash,
+                    window.since,
+                    window.rollup_since,
+                    charging,
+                )
+                .await?,
+            );
+        }
+
+        if !admit(&usage) {
+            tx.rollback().await?;
+            return Ok(QuotaCheck {
+                reservation: None,
+                usage,
+            });
+        }
+
+        sqlx::query(
+            r#"INSERT INTO quota_reservations (id, user_hash, probes, created_at)
+               VALUES ($1, $2, $3, $4)"#,
+        )
+        .bind(reservation.id)
+        .bind(&reservation.user_hash)
+        .bind(reservation.probes)
+        .bind(reservation.created_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(QuotaCheck {
+            reservation: Some(reservation.id),
+            usage,
+        })
+    }
+
+    async fn release_quota_reservation(&self, reservation_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM quota_reservations WHERE id = $1")
+            .bind(reservation_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn refund_lost_measurements(
+        &self,
+        idle_before: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let now = Utc::now();
+
+        let result = sqlx::query(
+            r#"UPDATE measurement_tracking
+               SET refunded_probes = expected_probes - sent_probes, updated_at = $2
+               WHERE is_complete = FALSE AND cancelled = FALSE
+                 AND refunded_probes < expected_probes - sent_probes
+                 AND agent_id NOT IN (
+                     SELECT agent_id FROM measurement_tracking WHERE updated_at >= $1)"#,
+        )
+        .bind(idle_before)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn get_user_quota(&self, user_hash: &str) -> Result<Option<UserQuota>, sqlx::Error> {
+        sqlx::query_as::<_, UserQuota>(
+            r#"SELECT user_hash, windows, created_at, updated_at
+               FROM user_quotas
+               WHERE user_hash = $1"#,
+        )
+        .bind(user_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn set_user_quota(
+        &self,
+        user_hash: &str,
+        windows: &str,
+    ) -> Result<UserQuota, sqlx::Error> {
+        let now = Utc::now();
+        sqlx::query_as::<_, UserQuota>(
+            r#"INSERT INTO user_quotas (user_hash, windows, created_at, updated_at)
+               VALUES ($1, $2, $3, $3)
+               ON CONFLICT (user_hash)
+               DO UPDATE SET windows = excluded.windows, updated_at = excluded.updated_at
+               RETURNING user_hash, windows, created_at, updated_at"#,
+        )
+        .bind(user_hash)
+        .bind(windows)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn delete_user_quota(&self, user_hash: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM user_quotas WHERE user_hash = $1")
+            .bind(user_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn count_expired_measurements(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<PurgedMeasurements, sqlx::Error> {
+        let row = sqlx::query(
+            r#"SELECT COUNT(*) AS measurements,
+                      COALESCE(SUM(total_agents), 0) AS agent_rows
+               FROM measurement_status s
+               WHERE measurement_complete AND last_updated < $1 AND queued_batches = 0
+                 AND NOT EXISTS (
+                     SELECT 1 FROM multi_round_measurements m
+                     WHERE m.measurement_id = s.measurement_id AND m.closed_at IS NULL)"#,
+        )
+        .bind(before)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(PurgedMeasurements {
+            measurements: row.get::<i64, _>("measurements") as u64,
+            rows: row.get::<i64, _>("agent_rows") as u64,
+        })
+    }
+
+    async fn purge_expired_measurements(
+        &self,
+        before: DateTime<Utc>,
+        limit: i32,
+    ) -> Result<PurgedMeasurements, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let expired: Vec<Uuid> = sqlx::query_scalar(
+            r#"SELECT measurement_id
+               FROM measurement_status s
+               WHERE measurement_complete AND last_updated < $1 AND queued_batches = 0
+                 AND NOT EXISTS (
+                     SELECT 1 FROM multi_round_measurements m
+                     WHERE m.measurement_id = s.measurement_id AND m.closed_at IS NULL)
+               ORDER BY last_updated
+               LIMIT $2"#,
+        )
+        .bind(before)
+        .bind(limit as i64)
+        .fetch_all(&mut *tx)
+        .await?;
+        if expired.is_empty() {
+            return Ok(PurgedMeasurements::default());
+        }
+
+        // Timestamps are RFC 3339 UTC: the day is their first 10 characters
+        let mut qb = QueryBuilder::<Sqlite>::new(
+            r#"INSERT INTO daily_usage_rollups
+                   (user_hash, day, measurements, expected_probes, sent_probes, refunded_probes)
+               SELECT user_hash, day, COUNT(*), SUM(expected_probes), SUM(sent_probes),
+                      SUM(refunded_probes)
+               FROM (
+                   SELECT user_hash, substr(MIN(created_at), 1, 10) AS day,
+                          SUM(expected_probes) AS expected_probes,
+                          SUM(sent_probes) AS sent_probes,
+                          SUM(refunded_probes) AS refunded_probes
+                   FROM measurement_tracking
+                   WHERE measurement_id IN ("#,
+        );
+        push_ids(&mut qb, &expired);
+        qb.push(
+            r#")
+                   GROUP BY measurement_id, user_hash)
+               WHERE TRUE
+               GROUP BY user_hash, day
+               ON CONFLICT (user_hash, day) DO UPDATE
+               SET measurements = measurements + excluded.measurements,
+                   expected_probes = expected_probes + excluded.expected_probes,
+                   sent_probes = sent_probes + excluded.sent_probes,
+                   refunded_probes = refunded_probes + excluded.refunded_probes"#,
+        );
+        qb.build().execute(&mut *tx).await?;
+
+        let mut qb = QueryBuilder::<Sqlite>::new(
+            "DELETE FROM measurement_tracking WHERE measurement_id IN (",
+        );
+        push_ids(&mut qb, &expired);
+        qb.push(")");
+        let rows = qb.build().execute(&mut *tx).await?.rows_affected();
+
+        let mut qb = QueryBuilder::<Sqlite>::new(
+            "DELETE FROM measurement_cancellations WHERE measurement_id IN (",
+        );
+        push_ids(&mut qb, &expired);
+        qb.push(")");
+        qb.build().execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(PurgedMeasurements {
+            measurements: expired.len() as u64,
+            rows,
+        })
+    }
+
+    async fn get_daily_usage(
+        &self,
+        user_hash: &str,
+        since: NaiveDate,
+    ) -> Result<Vec<DailyUsage>, sqlx::Error> {
+        sqlx::query_as::<_, DailyUsage>(
+            r#"SELECT user_hash, day, measurements, expected_probes, sent_probes,
+                      refunded_probes
+               FROM daily_usage_rollups
+               WHERE user_hash = $1 AND day >= $2
+               ORDER BY day"#,
+        )
+        .bind(user_hash)
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    // The legacy probe_usage table is not part of the SQLite schema
+    async fn count_expired_probe_usage(&self, _before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        Ok(0)
+    }
+
+    async fn purge_expired_probe_usage(
+        &self,
+        _before: DateTime<Utc>,
+        _limit: i32,
+    ) -> Result<u64, sqlx::Error> {
+        Ok(0)
+    }
+}
+
+// SQLite cannot bind arrays: list the ids as separate parameters
+fn push_ids<T>(qb: &mut QueryBuilder<Sqlite>, ids: &[T])
+where
+    T: Copy + for<'t> Encode<'t, Sqlite> + Type<Sqlite>,
+{
+    let mut separated = qb.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+}
@@ -186,6 +186,14 @@ impl JwtValidator {
         }
     }
 
+    /// Check that tokens can be validated: the keys are cached and fresh,
+    /// fetching them if needed. Returns the age of the cached keys.
+    pub async fn check(state: &AppState) -> Result<Duration, AuthorizationError> {
+        Self::get_or_create(state).await?;
+        let last_refresh = LAST_JWKS_REFRESH.read().await;
+        Ok(last_refresh.map(|time| time.elapsed()).unwrap_or_default())
+    }
+
     async fn fetch_jwks(
         state: &AppState,
     ) -> Result<HashMap<String, DecodingKey>, AuthorizationError> {
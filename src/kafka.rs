@@ -1,9 +1,23 @@
+use async_trait::async_trait;
+use metrics::{counter, gauge, histogram};
+use rdkafka::ClientContext;
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
 use rdkafka::config::ClientConfig;
-use rdkafka::message::OwnedHeaders;
-use rdkafka::producer::FutureProducer;
+use rdkafka::consumer::StreamConsumer;
+use rdkafka::error::RDKafkaErrorCode;
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, Producer};
+use rdkafka::statistics::Statistics;
 use rdkafka::{error::KafkaError, producer::FutureRecord};
-use std::time::Duration;
-use tracing::{debug, error};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{debug, error, info, warn};
+
+use crate::sink::{PartialDelivery, ProbeSink};
 
 /// SASL authentication configuration
 #[derive(Clone)]
@@ -13,19 +27,171 @@ pub struct SaslAuth {
     pub mechanism: String,
 }
 
+/// TLS configuration. Paths are PEM files; without a CA location the system
+/// trust store is used.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub ca_location: Option<String>,
+    /// Client certificate, for mutual TLS (requires `key_location`)
+    pub certificate_location: Option<String>,
+    pub key_location: Option<String>,
+    pub key_password: Option<String>,
+    /// Check that the broker certificate matches its hostname
+    pub verify_hostname: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            ca_location: None,
+            certificate_location: None,
+            key_location: None,
+            key_password: None,
+            verify_hostname: true,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Check that a client certificate comes with its key, and vice versa
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.certificate_location, &self.key_location) {
+            (Some(_), None) => Err("A client certificate requires its private key".to_string()),
+            (None, Some(_)) => Err("A private key requires its client certificate".to_string()),
+            _ if self.key_password.is_some() && self.key_location.is_none() => {
+                Err("A key password requires a private key".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn apply(&self, client_config: &mut ClientConfig) {
+        if let Some(ca_location) = &self.ca_location {
+            client_config.set("ssl.ca.location", ca_location);
+        }
+        if let Some(certificate_location) = &self.certificate_location {
+            client_config.set("ssl.certificate.location", certificate_location);
+        }
+        if let Some(key_location) = &self.key_location {
+            client_config.set("ssl.key.location", key_location);
+        }
+        if let Some(key_password) = &self.key_password {
+            client_config.set("ssl.key.password", key_password);
+        }
+        client_config.set(
+            "ssl.endpoint.identification.algorithm",
+            if self.verify_hostname {
+                "https"
+            } else {
+                "none"
+            },
+        );
+    }
+}
+
 /// Kafka authentication options
 #[derive(Clone)]
 pub enum KafkaAuth {
     SaslPlainText(SaslAuth),
+    /// SASL over TLS
+    SaslSsl(SaslAuth, TlsConfig),
+    /// TLS, authenticated by a client certificate when one is configured
+    Ssl(TlsConfig),
     PlainText,
 }
 
+impl SaslAuth {
+    fn apply(&self, client_config: &mut ClientConfig) {
+        client_config
+            .set("sasl.username", &self.username)
+            .set("sasl.password", &self.password)
+            .set("sasl.mechanisms", &self.mechanism);
+    }
+}
+
+/// Encoding of the probe batches sent to agents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchFormat {
+    /// Concatenated `Probe` messages, routed by one JSON header per agent
+    Legacy,
+    /// A versioned `ProbeBatch` envelope carrying the routing information
+    Envelope,
+}
+
+impl FromStr for BatchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "legacy" => Ok(BatchFormat::Legacy),
+            "envelope" => Ok(BatchFormat::Envelope),
+            _ => Err(format!("Invalid batch format: {}", s)),
+        }
+    }
+}
+
+/// Header marking a message as a `ProbeBatch` envelope; its value is the envelope version
+pub const PROBE_BATCH_HEADER: &str = "saimiris-probe-batch";
+
+/// Default name of per-agent topics
+pub const DEFAULT_AGENT_TOPIC_TEMPLATE: &str = "{topic}-{agent}";
+
+/// How probe batches are spread over Kafka
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicRouting {
+    /// Each batch is produced once to the probes topic, for all its agents
+    Shared,
+    /// Each agent's copy of a batch goes to its own topic, named from a
+    /// template where `{topic}` is the probes topic and `{agent}` the agent ID
+    AgentTopic {
+        template: String,
+        /// Create missing agent topics, with the broker's defaults
+        create: bool,
+    },
+    /// Each agent's copy of a batch goes to the probes topic partition derived
+    /// from its ID (see `agent_partition`)
+    AgentPartition { partitions: i32 },
+}
+
+/// Name of an agent's topic. Characters Kafka does not allow in topic names
+/// are replaced by `_`.
+pub fn agent_topic(template: &str, topic: &str, agent_id: &str) -> String {
+    let agent: String = agent_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    template
+        .replace("{topic}", topic)
+        .replace("{agent}", &agent)
+}
+
+/// Partition of an agent's batches: the first four bytes of the SHA-256 of
+/// its ID, as a big-endian integer, modulo the number of partitions
+pub fn agent_partition(agent_id: &str, partitions: i32) -> i32 {
+    let digest = Sha256::digest(agent_id.as_bytes());
+    let hash = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    (hash % partitions.max(1) as u32) as i32
+}
+
 /// Represents a Kafka configuration
 #[derive(Clone)]
 pub struct KafkaConfig {
     pub brokers: String,
     pub topic: String,
     pub auth: KafkaAuth,
+    pub batch_format: BatchFormat,
+    /// When set, the producer is transactional and a measurement's batches
+    /// are committed all together or not at all
+    pub transactional_id: Option<String>,
+    pub routing: TopicRouting,
+    /// Extra librdkafka producer properties, applied over the defaults
+    pub producer_properties: Vec<(String, String)>,
 }
 
 impl Default for KafkaConfig {
@@ -34,48 +200,506 @@ impl Default for KafkaConfig {
             brokers: "localhost:9092".into(),
             topic: "probes".into(),
             auth: KafkaAuth::PlainText,
+            batch_format: BatchFormat::Legacy,
+            transactional_id: None,
+            routing: TopicRouting::Shared,
+            producer_properties: Vec::new(),
         }
     }
 }
 
-/// Creates a new Kafka producer
-pub fn create_producer(config: &KafkaConfig) -> Result<FutureProducer, KafkaError> {
+// librdkafka's default message.max.bytes
+const DEFAULT_MESSAGE_MAX_BYTES: usize = 1_000_000;
+
+// Room left in a message for its key, headers, batch envelope and record
+// framing, next to the probes
+const MESSAGE_OVERHEAD_BYTES: usize = 16 * 1024;
+
+// Properties set through dedicated options, which producer properties may not
+// override
+const MANAGED_PROPERTY_PREFIXES: [&str; 6] = [
+    "bootstrap.servers",
+    "security.protocol",
+    "sasl.",
+    "ssl.",
+    "transactional.id",
+    "enable.idempotence",
+];
+
+impl KafkaConfig {
+    /// Largest message the producer accepts (`message.max.bytes`)
+    pub fn message_max_bytes(&self) -> usize {
+        self.producer_properties
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "message.max.bytes")
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(DEFAULT_MESSAGE_MAX_BYTES)
+    }
+
+    /// Size ceiling of a probe batch, so that its message stays within
+    /// `message.max.bytes`
+    pub fn max_batch_size(&self) -> usize {
+        self.message_max_bytes()
+            .saturating_sub(MESSAGE_OVERHEAD_BYTES)
+            .max(1)
+    }
+}
+
+/// Parse a `key=value` producer property
+pub fn parse_property(property: &str) -> Result<(String, String), String> {
+    let (key, value) = property.split_once('=').ok_or_else(|| {
+        format!(
+            "Invalid producer property '{}': expected key=value",
+            property
+        )
+    })?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!(
+            "Invalid producer property '{}': empty key",
+            property
+        ));
+    }
+    Ok((key.to_string(), value.trim().to_string()))
+}
+
+/// Parse a producer properties file: one `key=value` per line, blank lines and
+/// lines starting with `#` ignored
+pub fn parse_properties_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            parse_property(line).map_err(|err| format!("Line {}: {}", index + 1, err))
+        })
+        .collect()
+}
+
+/// Check producer properties that the gateway depends on. Unknown properties
+/// and invalid values are reported by librdkafka when the producer is created.
+pub fn validate_producer_properties(properties: &[(String, String)]) -> Result<(), String> {
+    for (key, value) in properties {
+        if MANAGED_PROPERTY_PREFIXES
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+        {
+            return Err(format!(
+                "Producer property {} is set by the gateway's Kafka options",
+                key
+            ));
+        }
+        if key == "message.max.bytes" {
+            match value.parse::<usize>() {
+                Ok(bytes) if bytes > MESSAGE_OVERHEAD_BYTES => {}
+                _ => {
+                    return Err(format!(
+                        "message.max.bytes must be an integer greater than {}",
+                        MESSAGE_OVERHEAD_BYTES
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Timeout for committing or aborting a transaction
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Timeout for fetching topic metadata
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The librdkafka configuration shared by the producer and consumers: brokers
+/// and authentication
+pub fn connection_config(config: &KafkaConfig) -> ClientConfig {
     let mut client_config = ClientConfig::new();
-    client_config
-        .set("bootstrap.servers", &config.brokers)
-        .set("message.timeout.ms", "5000");
+    client_config.set("bootstrap.servers", &config.brokers);
 
     match &config.auth {
         KafkaAuth::PlainText => {
             // No additional configuration needed for plaintext
         }
         KafkaAuth::SaslPlainText(sasl_auth) => {
-            client_config
-                .set("sasl.username", &sasl_auth.username)
-                .set("sasl.password", &sasl_auth.password)
-                .set("sasl.mechanisms", &sasl_auth.mechanism)
-                .set("security.protocol", "SASL_PLAINTEXT");
+            sasl_auth.apply(&mut client_config);
+            client_config.set("security.protocol", "SASL_PLAINTEXT");
+        }
+        KafkaAuth::SaslSsl(sasl_auth, tls) => {
+            sasl_auth.apply(&mut client_config);
+            tls.apply(&mut client_config);
+            client_config.set("security.protocol", "SASL_SSL");
+        }
+        KafkaAuth::Ssl(tls) => {
+            tls.apply(&mut client_config);
+            client_config.set("security.protocol", "SSL");
         }
     }
 
-    client_config.create()
+    client_config
+}
+
+/// The librdkafka configuration of the producer
+pub fn client_config(config: &KafkaConfig) -> ClientConfig {
+    let mut client_config = connection_config(config);
+    client_config
+        .set("message.timeout.ms", "5000")
+        .set("statistics.interval.ms", "10000");
+    for (key, value) in &config.producer_properties {
+        client_config.set(key, value);
+    }
+
+    if let Some(transactional_id) = &config.transactional_id {
+        client_config
+            .set("transactional.id", transactional_id)
+            .set("enable.idempotence", "true");
+    }
+
+    client_config
+}
+
+/// Creates a consumer in `group_id` that only reads committed messages and
+/// leaves storing offsets to the caller, once a message is handled
+pub fn create_consumer(config: &KafkaConfig, group_id: &str) -> Result<StreamConsumer, KafkaError> {
+    connection_config(config)
+        .set("group.id", group_id)
+        .set("isolation.level", "read_committed")
+        .set("enable.auto.commit", "true")
+        .set("enable.auto.offset.store", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()
+}
+
+/// Producer client context exporting librdkafka statistics as metrics
+pub struct StatsContext;
+
+impl ClientContext for StatsContext {
+    fn stats(&self, statistics: Statistics) {
+        record_statistics(&statistics);
+    }
+}
+
+/// The gateway's Kafka producer
+pub type GatewayProducer = FutureProducer<StatsContext>;
+
+// Bytes and messages produced to a topic, over its partitions (librdkafka's
+// internal unassigned partition, -1, excluded)
+fn topic_totals(topic: &rdkafka::statistics::Topic) -> (u64, u64) {
+    topic
+        .partitions
+        .values()
+        .filter(|partition| partition.partition >= 0)
+        .fold((0, 0), |(bytes, messages), partition| {
+            (bytes + partition.txbytes, messages + partition.txmsgs)
+        })
+}
+
+/// Record the producer statistics, emitted every `statistics.interval.ms`
+pub fn record_statistics(statistics: &Statistics) {
+    gauge!("saimiris_gateway_kafka_queue_messages").set(statistics.msg_cnt as f64);
+    gauge!("saimiris_gateway_kafka_queue_bytes").set(statistics.msg_size as f64);
+    counter!("saimiris_gateway_kafka_produced_messages_total").absolute(statistics.txmsgs as u64);
+    counter!("saimiris_gateway_kafka_produced_bytes_total").absolute(statistics.txmsg_bytes as u64);
+
+    for broker in statistics.brokers.values() {
+        let name = broker.name.clone();
+        gauge!("saimiris_gateway_kafka_broker_inflight_requests", "broker" => name.clone())
+            .set(broker.waitresp_cnt as f64);
+        gauge!("saimiris_gateway_kafka_broker_outbuf_requests", "broker" => name.clone())
+            .set(broker.outbuf_cnt as f64);
+        counter!("saimiris_gateway_kafka_broker_tx_errors_total", "broker" => name.clone())
+            .absolute(broker.txerrs);
+        // Windows are in microseconds, and empty until the broker is used
+        if let Some(rtt) = broker.rtt.as_ref().filter(|rtt| rtt.cnt > 0) {
+            gauge!("saimiris_gateway_kafka_broker_rtt_avg_seconds", "broker" => name.clone())
+                .set(rtt.avg as f64 / 1e6);
+            gauge!("saimiris_gateway_kafka_broker_rtt_p99_seconds", "broker" => name)
+                .set(rtt.p99 as f64 / 1e6);
+        }
+    }
+
+    for topic in statistics.topics.values() {
+        let (bytes, messages) = topic_totals(topic);
+        counter!("saimiris_gateway_kafka_topic_produced_bytes_total", "topic" => topic.topic.clone())
+            .absolute(bytes);
+        counter!("saimiris_gateway_kafka_topic_produced_messages_total", "topic" => topic.topic.clone())
+            .absolute(messages);
+    }
+}
+
+/// Creates a new Kafka producer
+pub fn create_producer(config: &KafkaConfig) -> Result<GatewayProducer, KafkaError> {
+    let producer: GatewayProducer = client_config(config).create_with_context(StatsContext)?;
+    if config.transactional_id.is_some() {
+        producer.init_transactions(TRANSACTION_TIMEOUT)?;
+    }
+    Ok(producer)
+}
+
+/// Number of partitions of a topic. Fails when the topic does not exist.
+pub async fn topic_partitions(producer: &GatewayProducer, topic: &str) -> Result<i32, KafkaError> {
+    let producer = producer.clone();
+    let name = topic.to_string();
+    let partitions = tokio::task::spawn_blocking(move || {
+        let metadata = producer
+            .client()
+            .fetch_metadata(Some(&name), METADATA_TIMEOUT)?;
+        match metadata.topics().first() {
+            Some(topic) if topic.error().is_none() && !topic.partitions().is_empty() => {
+                Ok(topic.partitions().len() as i32)
+            }
+            _ => Err(KafkaError::MetadataFetch(
+                RDKafkaErrorCode::UnknownTopicOrPartition,
+            )),
+        }
+    })
+    .await
+    .unwrap_or(Err(KafkaError::Canceled));
+
+    if let Err(err) = &partitions {
+        warn!("Kafka topic '{}' is not available: {}", topic, err);
+    }
+    partitions
+}
+
+/// An open Kafka transaction. Messages sent while it is open are only visible
+/// to consumers once it is committed; dropping it without committing leaves
+/// the transaction to time out on the broker, so call `abort` instead.
+pub struct Transaction<'a> {
+    producer: &'a GatewayProducer,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Begin a transaction on a transactional producer. A producer runs one
+    /// transaction at a time, so concurrent callers sharing `lock` take turns.
+    pub async fn begin(
+        producer: &'a GatewayProducer,
+        lock: &'a Mutex<()>,
+    ) -> Result<Self, KafkaError> {
+        let guard = lock.lock().await;
+        producer.begin_transaction()?;
+        Ok(Self {
+            producer,
+            _guard: guard,
+        })
+    }
+
+    /// Commit the messages sent during the transaction. A failed commit is
+    /// aborted, so none of them are delivered.
+    pub async fn commit(self) -> Result<(), KafkaError> {
+        let producer = self.producer.clone();
+        let committed =
+            tokio::task::spawn_blocking(move || producer.commit_transaction(TRANSACTION_TIMEOUT))
+                .await
+                .unwrap_or(Err(KafkaError::Canceled));
+        if let Err(err) = committed {
+            error!("Failed to commit Kafka transaction: {}", err);
+            self.abort().await;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Abort the transaction, discarding the messages sent during it
+    pub async fn abort(self) {
+        let producer = self.producer.clone();
+        let aborted =
+            tokio::task::spawn_blocking(move || producer.abort_transaction(TRANSACTION_TIMEOUT))
+                .await
+                .unwrap_or(Err(KafkaError::Canceled));
+        if let Err(err) = aborted {
+            warn!("Failed to abort Kafka transaction: {}", err);
+        }
+    }
+}
+
+/// A message ready to be produced to Kafka. It is plain data so it can be kept
+/// in the outbox until it is relayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundMessage {
+    pub key: String,
+    pub payload: Vec<u8>,
+    pub headers: Vec<(String, String)>,
+    /// Destination topic, when not the probes topic
+    pub topic: Option<String>,
+    /// Destination partition, when not chosen from the key
+    pub partition: Option<i32>,
+}
+
+impl OutboundMessage {
+    fn owned_headers(&self) -> OwnedHeaders {
+        self.headers
+            .iter()
+            .fold(OwnedHeaders::new(), |headers, (key, value)| {
+                headers.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            })
+    }
+}
+
+/// Probe sink producing to the Kafka probes topic, or to the agents' topics
+pub struct KafkaSink {
+    producer: GatewayProducer,
+    config: KafkaConfig,
+    transaction_lock: Mutex<()>,
+    // Agent topics known to exist
+    agent_topics: Mutex<HashSet<String>>,
+}
+
+impl KafkaSink {
+    pub fn new(producer: GatewayProducer, config: KafkaConfig) -> Self {
+        Self {
+            producer,
+            config,
+            transaction_lock: Mutex::new(()),
+            agent_topics: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Check that the agent topics the messages go to exist, creating them
+    /// when configured to
+    async fn ensure_agent_topics(&self, messages: &[OutboundMessage]) -> Result<(), KafkaError> {
+        let TopicRouting::AgentTopic { create, .. } = &self.config.routing else {
+            return Ok(());
+        };
+
+        let mut known = self.agent_topics.lock().await;
+        for topic in messages.iter().filter_map(|m| m.topic.as_deref()) {
+            if known.contains(topic) {
+                continue;
+            }
+            if topic_partitions(&self.producer, topic).await.is_err() {
+                if !create {
+                    error!(
+                        "Kafka topic '{}' does not exist and topic creation is disabled",
+                        topic
+                    );
+                    return Err(KafkaError::MetadataFetch(
+                        RDKafkaErrorCode::UnknownTopicOrPartition,
+                    ));
+                }
+                self.create_topic(topic).await?;
+            }
+            known.insert(topic.to_string());
+        }
+        Ok(())
+    }
+
+    async fn create_topic(&self, topic: &str) -> Result<(), KafkaError> {
+        let admin: AdminClient<DefaultClientContext> = connection_config(&self.config).create()?;
+        // -1 leaves the number of partitions and replicas to the broker
+        let new_topic = NewTopic::new(topic, -1, TopicReplication::Fixed(-1));
+        let results = admin
+            .create_topics(&[new_topic], &AdminOptions::new())
+            .await?;
+        for result in results {
+            match result {
+                Ok(_) | Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+                Err((name, code)) => {
+                    error!("Failed to create Kafka topic '{}': {}", name, code);
+                    return Err(KafkaError::AdminOp(code));
+                }
+            }
+        }
+        info!("Created Kafka topic '{}'", topic);
+        Ok(())
+    }
+
+    /// Sends messages to Kafka in order. With a transactional producer they
+    /// are delivered all together or not at all; otherwise a failure after
+    /// the first message has a `PartialDelivery` context.
+    pub async fn send_messages(&self, messages: &[OutboundMessage]) -> anyhow::Result<()> {
+        self.ensure_agent_topics(messages).await?;
+
+        let transaction = match self.config.transactional_id {
+            Some(_) => Some(Transaction::begin(&self.producer, &self.transaction_lock).await?),
+            None => None,
+        };
+
+        for (index, message) in messages.iter().enumerate() {
+            let sent = send_to_kafka(
+                &self.producer,
+                message.topic.as_deref().unwrap_or(&self.config.topic),
+                message.partition,
+                &message.key,
+                &message.payload,
+                Some(message.owned_headers()),
+            )
+            .await;
+            if let Err(err) = sent {
+                let err = anyhow::Error::new(err);
+                return Err(match transaction {
+                    Some(transaction) => {
+                        transaction.abort().await;
+                        err
+                    }
+                    None if index > 0 => err.context(PartialDelivery { delivered: index }),
+                    None => err,
+                });
+            }
+        }
+
+        if let Some(transaction) = transaction {
+            transaction.commit().await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProbeSink for KafkaSink {
+    async fn send(&self, messages: &[OutboundMessage]) -> anyhow::Result<()> {
+        self.send_messages(messages).await
+    }
+
+    /// The brokers answer metadata requests and, unless batches go to agent
+    /// topics, the probes topic exists
+    async fn check(&self) -> anyhow::Result<()> {
+        match self.config.routing {
+            TopicRouting::AgentTopic { .. } => {
+                let producer = self.producer.clone();
+                tokio::task::spawn_blocking(move || {
+                    producer
+                        .client()
+                        .fetch_metadata(None, METADATA_TIMEOUT)
+                        .map(|_| ())
+                })
+                .await??;
+            }
+            _ => {
+                topic_partitions(&self.producer, &self.config.topic).await?;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Sends a message to Kafka
 pub async fn send_to_kafka(
-    producer: &FutureProducer,
+    producer: &GatewayProducer,
     topic: &str,
+    partition: Option<i32>,
     key: &str,
     payload: &[u8],
     headers: Option<OwnedHeaders>,
 ) -> Result<(), KafkaError> {
     let mut record = FutureRecord::to(topic).payload(payload).key(key);
 
+    if let Some(partition) = partition {
+        record = record.partition(partition);
+    }
+
     if let Some(headers) = headers {
         record = record.headers(headers);
     }
 
+    let started = Instant::now();
     let delivery_status = producer.send(record, Duration::from_secs(0)).await;
+    histogram!("saimiris_gateway_kafka_delivery_seconds").record(started.elapsed().as_secs_f64());
 
     match delivery_status {
         Ok(delivery) => {
@@ -91,3 +715,198 @@ pub async fn send_to_kafka(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sasl() -> SaslAuth {
+        SaslAuth {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+            mechanism: "SCRAM-SHA-512".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sasl_ssl_client_config() {
+        let config = KafkaConfig {
+            auth: KafkaAuth::SaslSsl(
+                sasl(),
+                TlsConfig {
+                    ca_location: Some("/etc/kafka/ca.pem".to_string()),
+                    ..Default::default()
+                },
+            ),
+            ..Default::default()
+        };
+        let client_config = client_config(&config);
+        assert_eq!(client_config.get("security.protocol"), Some("SASL_SSL"));
+        assert_eq!(client_config.get("sasl.username"), Some("user"));
+        assert_eq!(
+            client_config.get("ssl.ca.location"),
+            Some("/etc/kafka/ca.pem")
+        );
+        assert_eq!(
+            client_config.get("ssl.endpoint.identification.algorithm"),
+            Some("https")
+        );
+        assert_eq!(client_config.get("ssl.certificate.location"), None);
+    }
+
+    #[test]
+    fn test_mutual_tls_client_config() {
+        let tls = TlsConfig {
+            certificate_location: Some("client.pem".to_string()),
+            key_location: Some("client.key".to_string()),
+            verify_hostname: false,
+            ..Default::default()
+        };
+        assert!(tls.validate().is_ok());
+
+        let config = KafkaConfig {
+            auth: KafkaAuth::Ssl(tls),
+            ..Default::default()
+        };
+        let client_config = client_config(&config);
+        assert_eq!(client_config.get("security.protocol"), Some("SSL"));
+        assert_eq!(
+            client_config.get("ssl.certificate.location"),
+            Some("client.pem")
+        );
+        assert_eq!(client_config.get("ssl.key.location"), Some("client.key"));
+        assert_eq!(
+            client_config.get("ssl.endpoint.identification.algorithm"),
+            Some("none")
+        );
+        assert_eq!(client_config.get("sasl.username"), None);
+    }
+
+    #[test]
+    fn test_producer_properties() {
+        let properties = parse_properties_file(
+            "# Large probe batches\nlinger.ms=50\n\ncompression.type = zstd\nmessage.max.bytes=4194304\n",
+        )
+        .unwrap();
+        assert_eq!(
+            properties,
+            vec![
+                ("linger.ms".to_string(), "50".to_string()),
+                ("compression.type".to_string(), "zstd".to_string()),
+                ("message.max.bytes".to_string(), "4194304".to_string()),
+            ]
+        );
+        assert!(validate_producer_properties(&properties).is_ok());
+        assert!(parse_properties_file("linger.ms").is_err());
+        assert!(parse_property("=1").is_err());
+
+        let config = KafkaConfig {
+            producer_properties: properties,
+            ..Default::default()
+        };
+        let client_config = client_config(&config);
+        assert_eq!(client_config.get("compression.type"), Some("zstd"));
+        assert_eq!(client_config.get("message.timeout.ms"), Some("5000"));
+        assert_eq!(config.message_max_bytes(), 4_194_304);
+        assert_eq!(config.max_batch_size(), 4_194_304 - MESSAGE_OVERHEAD_BYTES);
+        assert_eq!(
+            KafkaConfig::default().max_batch_size(),
+            DEFAULT_MESSAGE_MAX_BYTES - MESSAGE_OVERHEAD_BYTES
+        );
+
+        for property in [
+            "security.protocol=SSL",
+            "sasl.password=x",
+            "message.max.bytes=big",
+        ] {
+            let property = parse_property(property).unwrap();
+            assert!(validate_producer_properties(&[property]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_topic_totals() {
+        let statistics: Statistics = serde_json::from_value(serde_json::json!({
+            "name": "producer", "client_id": "gateway", "type": "producer",
+            "ts": 0, "time": 0, "age": 0, "replyq": 0, "msg_cnt": 2, "msg_size": 10,
+            "msg_max": 100, "msg_size_max": 1000, "tx": 0, "tx_bytes": 0, "rx": 0,
+            "rx_bytes": 0, "txmsgs": 3, "txmsg_bytes": 30, "rxmsgs": 0, "rxmsg_bytes": 0,
+            "simple_cnt": 0, "metadata_cache_cnt": 1, "brokers": {},
+            "topics": {
+                "probes": {
+                    "topic": "probes", "age": 0, "metadata_age": 0,
+                    "batchsize": window(), "batchcnt": window(),
+                    "partitions": {
+                        "0": partition(0, 20, 2),
+                        "1": partition(1, 10, 1),
+                        "-1": partition(-1, 5, 1)
+                    }
+                }
+            }
+        }))
+        .unwrap();
+        assert_eq!(topic_totals(&statistics.topics["probes"]), (30, 3));
+        // Without an installed recorder the metrics are dropped
+        record_statistics(&statistics);
+    }
+
+    fn window() -> serde_json::Value {
+        serde_json::json!({
+            "min": 0, "max": 0, "avg": 0, "sum": 0, "cnt": 0, "stddev": 0,
+            "hdrsize": 0, "p50": 0, "p75": 0, "p90": 0, "p95": 0, "p99": 0,
+            "p99_99": 0, "outofrange": 0
+        })
+    }
+
+    fn partition(id: i32, txbytes: u64, txmsgs: u64) -> serde_json::Value {
+        serde_json::json!({
+            "partition": id, "broker": 1, "leader": 1, "desired": false, "unknown": false,
+            "msgq_cnt": 0, "msgq_bytes": 0, "xmit_msgq_cnt": 0, "xmit_msgq_bytes": 0,
+            "fetchq_cnt": 0, "fetchq_size": 0, "fetch_state": "none", "query_offset": 0,
+            "next_offset": 0, "app_offset": 0, "stored_offset": 0, "committed_offset": 0,
+            "eof_offset": 0, "lo_offset": 0, "hi_offset": 0, "ls_offset": 0,
+            "consumer_lag": 0, "consumer_lag_stored": 0, "txmsgs": txmsgs, "txbytes": txbytes,
+            "rxmsgs": 0, "rxbytes": 0, "msgs": 0, "rx_ver_drops": 0, "msgs_inflight": 0,
+            "next_ack_seq": 0, "next_err_seq": 0, "acked_msgid": 0
+        })
+    }
+
+    #[test]
+    fn test_agent_routing() {
+        assert_eq!(
+            agent_topic(DEFAULT_AGENT_TOPIC_TEMPLATE, "probes", "vltcdg01"),
+            "probes-vltcdg01"
+        );
+        assert_eq!(
+            agent_topic("agent.{agent}", "probes", "paris/01 b"),
+            "agent.paris_01_b"
+        );
+
+        // Stable across calls and within range
+        let partition = agent_partition("vltcdg01", 12);
+        assert_eq!(partition, agent_partition("vltcdg01", 12));
+        assert!((0..12).contains(&partition));
+        assert_eq!(agent_partition("vltcdg01", 1), 0);
+    }
+
+    #[test]
+    fn test_tls_config_requires_certificate_and_key() {
+        let certificate_only = TlsConfig {
+            certificate_location: Some("client.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(certificate_only.validate().is_err());
+
+        let key_only = TlsConfig {
+            key_location: Some("client.key".to_string()),
+            ..Default::default()
+        };
+        assert!(key_only.validate().is_err());
+
+        let password_only = TlsConfig {
+            key_password: Some("secret".to_string()),
+            ..Default::default()
+        };
+        assert!(password_only.validate().is_err());
+    }
+}
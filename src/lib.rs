@@ -1,30 +1,41 @@
 pub mod agent;
+pub mod archive;
+pub mod blocklist;
 pub mod database;
 pub mod jwt;
 pub mod kafka;
+pub mod outbox;
 pub mod probe;
 pub mod probe_capnp;
+pub mod progress;
+pub mod quota;
+pub mod retention;
+pub mod scheduler;
+pub mod sink;
 
 use axum::{
     Router,
     extract::{Extension, Path, Query, Request, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode, header},
     middleware::Next,
     response::Json,
-    response::Response,
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
 use hex;
 use ipnet::Ipv6Net;
 use metrics::{counter, gauge};
-use rdkafka::message::{Header, OwnedHeaders};
 use sha2::{Digest, Sha256};
 use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
 use agent::{Agent, AgentConfig, AgentStore, HealthStatus};
-use database::{Database, MeasurementListFilter, MeasurementSort, MeasurementState};
+use database::{
+    Database, DispatchTracking, MeasurementArchive, MeasurementListFilter, MeasurementSchedule,
+    MeasurementSort, MeasurementState, MultiRoundMeasurement, TargetList,
+};
 use probe::{SubmitProbesRequest, SubmitProbesResponse};
 use uuid::Uuid;
 
@@ -33,11 +44,19 @@ pub struct AppState {
     pub agent_store: AgentStore,
     pub agent_key: String,
     pub kafka_config: kafka::KafkaConfig,
-    pub kafka_producer: rdkafka::producer::FutureProducer,
+    pub probe_sink: Arc<dyn sink::ProbeSink>,
     pub auth0_jwks_uri: Option<String>,
     pub auth0_issuer: Option<String>,
     pub bypass_jwt_validation: bool,
     pub database: Database,
+    pub probe_archive: Option<archive::ProbeArchive>,
+    pub admin_key: Option<String>,
+    pub blocklist: blocklist::Blocklist,
+    pub special_purpose: probe::SpecialPurposeFilter,
+    pub outbox: bool,
+    pub retention: retention::RetentionPolicy,
+    pub quota: quota::QuotaPolicy,
+    pub quota_charging: quota::ChargingMode,
 }
 
 // Client-facing API
@@ -46,15 +65,40 @@ pub fn create_client_app(state: AppState) -> Router {
     let protected_routes = Router::new()
         .route("/user/me", get(get_user_info))
         .route("/user/prefixes", get(get_user_prefixes))
-        .route("/measurements", get(list_measurements_handler))
+        .route(
+            "/measurements",
+            get(list_measurements_handler).post(open_measurement_handler),
+        )
         .route("/probes", post(submit_probes))
         .route(
             "/measurement/{id}/status",
             get(get_measurement_status_handler),
         )
         .route(
-            "/measurement/{id}/cancel",
-            post(cancel_measurement_handler),
+            "/measurement/{id}/probes",
+            get(download_measurement_probes_handler),
+        )
+        .route("/measurement/{id}/replay", post(replay_measurement_handler))
+        .route("/measurement/{id}/cancel", post(cancel_measurement_handler))
+        .route("/measurement/{id}/rounds", post(append_round_handler))
+        .route("/measurement/{id}/close", post(close_measurement_handler))
+        .route(
+            "/schedules",
+            get(list_schedules_handler).post(create_schedule_handler),
+        )
+        .route(
+            "/schedule/{id}",
+            get(get_schedule_handler).delete(delete_schedule_handler),
+        )
+        .route(
+            "/target-lists",
+            get(list_target_lists_handler).post(create_target_list_handler),
+        )
+        .route(
+            "/target-list/{id}",
+            get(get_target_list_handler)
+                .put(update_target_list_handler)
+                .delete(delete_target_list_handler),
         )
         // .route("/admin/user-limit", post(set_user_limit))
         // .route("/admin/user-limit/:user_id", get(get_user_limit))
@@ -83,6 +127,11 @@ pub fn create_agent_app(state: AppState) -> Router {
             "/agent/{id}/measurement/{measurement_id}/status",
             post(update_measurement_status),
         )
+        .route("/agent/{id}/cancellations", get(list_agent_cancellations))
+        .route(
+            "/agent/{id}/measurement/{measurement_id}/cancellation/ack",
+            post(acknowledge_cancellation),
+        )
         .with_state(state.clone())
         .layer(axum::middleware::from_fn_with_state(
             state,
@@ -91,14 +140,51 @@ pub fn create_agent_app(state: AppState) -> Router {
         .layer(TraceLayer::new_for_http())
 }
 
-// Combined app with both client and agent endpoints
+// Operator-facing API (admin key required)
+pub fn create_admin_app(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/blocklist",
+            get(get_blocklist)
+                .post(add_blocklist_prefixes)
+                .delete(remove_blocklist_prefixes),
+        )
+        .route("/blocklist/reload", post(reload_blocklist))
+        .route("/retention", get(preview_retention))
+        .route(
+            "/user/{user_id}/quota",
+            get(get_user_quota)
+                .put(set_user_quota)
+                .delete(delete_user_quota),
+        )
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            state,
+            validate_admin_key,
+        ))
+        .layer(TraceLayer::new_for_http())
+}
+
+// Liveness and readiness probes for orchestrators (no authentication)
+pub fn create_health_app(state: AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(state)
+}
+
+// Combined app with client, agent and admin endpoints
 pub fn create_app(state: AppState) -> Router {
     let client_router = create_client_app(state.clone());
-    let agent_router = create_agent_app(state);
+    let agent_router = create_agent_app(state.clone());
+    let admin_router = create_admin_app(state.clone());
+    let health_router = create_health_app(state);
 
     Router::new()
         .nest("/api", client_router)
         .nest("/agent-api", agent_router)
+        .nest("/admin-api", admin_router)
+        .merge(health_router)
 }
 
 // API key validation middleware
@@ -122,6 +208,27 @@ async fn validate_agent_key(
     }
 }
 
+// Admin key validation middleware. The admin API is disabled when no key is configured.
+async fn validate_admin_key(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let auth_header = request
+        .headers()
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "));
+
+    match (auth_header, &state.admin_key) {
+        (Some(key), Some(admin_key)) if key == admin_key => Ok(next.run(request).await),
+        _ => {
+            warn!("Unauthorized access attempt to admin API");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
 // Client-facing handlers (regular REST API)
 async fn list_agents(State(state): State<AppState>) -> Json<Vec<Agent>> {
     // Only return agents that have sent a health check in the last 10 minutes
@@ -161,17 +268,34 @@ async fn get_user_info(
         }
     };
 
-    match state
-        .database
-        .get_user_usage_stats(user_identifier, None, None)
-        .await
-    {
-        Ok(stats) => Ok(Json(serde_json::json!({
+    let usage = async {
+        let stats = state
+            .database
+            .get_user_usage_stats(user_identifier, None, None)
+            .await?;
+        let user_hash = crate::hash_user_identifier(user_identifier);
+        let policy = quota::user_policy(&state.database, &state.quota, &user_hash).await?;
+        let windows = quota::policy_usage(
+            &state.database,
+            &policy,
+            state.quota_charging,
+            &user_hash,
+            chrono::Utc::now(),
+        )
+        .await?;
+        Ok::<_, sqlx::Error>((stats, windows))
+    };
+
+    match usage.await {
+        // `used` and `limit` are those of the first quota window
+        Ok((stats, windows)) => Ok(Json(serde_json::json!({
             "user_id": user_id,
             "submission_count": stats.submission_count,
             "last_submitted": stats.last_submitted,
-            "used": stats.total_probes,
-            "limit": stats.limit
+            "used": windows.first().map(|usage| usage.used),
+            "limit": windows.first().map(|usage| usage.window.limit),
+            "charging": state.quota_charging,
+            "quota": windows
         }))),
         Err(err) => {
             error!("Failed to get user usage stats: {}", err);
@@ -349,15 +473,204 @@ async fn update_agent_health(
     Ok(Json(health))
 }
 
+// Header clients can set on `POST /api/probes` to make retries safe
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+// How long a stored idempotent response is replayed before the key can be reused
+const IDEMPOTENCY_KEY_RETENTION_HOURS: i64 = 24;
+// How long a key stays claimed by a request that never completed it, e.g.
+// after a restart, before a retry can take it over
+const IDEMPOTENCY_CLAIM_LEASE_SECONDS: i64 = 300;
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+
 // Handler for submitting probes
 async fn submit_probes(
     Extension(auth_info): Extension<jwt::AuthInfo>,
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<SubmitProbesRequest>,
 ) -> Result<Json<SubmitProbesResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        None => None,
+        Some(value) => match value.to_str() {
+            Ok(key)
+                if !key.is_empty()
+                    && key.len() <= IDEMPOTENCY_KEY_MAX_LEN
+                    && key.bytes().all(|b| b.is_ascii_graphic()) =>
+            {
+                Some(key.to_string())
+            }
+            _ => {
+                return Err(bad_request(format!(
+                    "Idempotency-Key must be 1 to {} visible ASCII characters",
+                    IDEMPOTENCY_KEY_MAX_LEN
+                )));
+            }
+        },
+    };
+
+    let Some(idempotency_key) = idempotency_key else {
+        return dispatch_probes(&state, &auth_info.sub, &request)
+            .await
+            .map(Json);
+    };
+
+    // Claim the key before dispatching so that concurrent retries cannot both
+    // create a measurement
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+    let request_hash = request.fingerprint();
+    let now = chrono::Utc::now();
+    let expired_before = now - chrono::Duration::hours(IDEMPOTENCY_KEY_RETENTION_HOURS);
+    let abandoned_before = now - chrono::Duration::seconds(IDEMPOTENCY_CLAIM_LEASE_SECONDS);
+    let existing = match state
+        .database
+        .claim_idempotency_key(
+            &user_hash,
+            &idempotency_key,
+            &request_hash,
+            expired_before,
+            abandoned_before,
+        )
+        .await
+    {
+        Ok(existing) => existing,
+        Err(err) => {
+            error!("Failed to claim idempotency key: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to check idempotency key"
+                })),
+            ));
+        }
+    };
+
+    if let Some(existing) = existing {
+        if existing.request_hash != request_hash {
+            debug!(
+                "User {} reused idempotency key {} with a different request",
+                auth_info.sub, idempotency_key
+            );
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!({
+                    "error": 422,
+                    "message": "Idempotency-Key was already used with a different request"
+                })),
+            ));
+        }
+
+        return match existing
+            .response
+            .as_deref()
+            .map(serde_json::from_str::<SubmitProbesResponse>)
+        {
+            Some(Ok(response)) => {
+                debug!(
+                    "Replaying measurement {} for idempotency key {}",
+                    response.id, idempotency_key
+                );
+                Ok(Json(response))
+            }
+            Some(Err(err)) => {
+                error!("Failed to decode stored idempotent response: {}", err);
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(serde_json::json!({
+                        "error": 500,
+                        "message": "Failed to load stored response"
+                    })),
+                ))
+            }
+            None => Err((
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "error": 409,
+                    "message": "A request with this Idempotency-Key is still being processed"
+                })),
+            )),
+        };
+    }
+
+    // The claim is settled even if the client goes away meanwhile
+    let dispatch = tokio::spawn(async move {
+        let result = dispatch_probes(&state, &auth_info.sub, &request).await;
+
+        match &result {
+            Ok(response) => {
+                store_idempotent_response(&state, &user_hash, &idempotency_key, response).await?
+            }
+            Err(_) => {
+                // Let the client retry with the same key
+                if let Err(err) = state
+                    .database
+                    .release_idempotency_key(&user_hash, &idempotency_key)
+                    .await
+                {
+                    error!("Failed to release idempotency key: {}", err);
+                }
+            }
+        }
+
+        result.map(Json)
+    });
+
+    dispatch.await.unwrap_or_else(|err| {
+        error!("Idempotent probe submission failed: {}", err);
+        Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": 500,
+                "message": "Failed to submit probes"
+            })),
+        ))
+    })
+}
+
+// Store the response of a dispatched submission against its idempotency key.
+// A response that cannot be replayed fails the request rather than being
+// stored; if only storing fails, the claim's lease lets retries through.
+async fn store_idempotent_response(
+    state: &AppState,
+    user_hash: &str,
+    idempotency_key: &str,
+    response: &SubmitProbesResponse,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let stored = serde_json::to_string(response);
+    let measurement_id = Uuid::parse_str(&response.id);
+    let (Ok(stored), Ok(measurement_id)) = (stored, measurement_id) else {
+        error!(
+            "Failed to encode idempotent response for measurement {}",
+            response.id
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": 500,
+                "message": "Failed to store response"
+            })),
+        ));
+    };
+
+    if let Err(err) = state
+        .database
+        .complete_idempotency_key(user_hash, idempotency_key, measurement_id, &stored)
+        .await
+    {
+        error!("Failed to store idempotent response: {}", err);
+    }
+    Ok(())
+}
+
+// Check the parts of a probe submission that do not depend on the user or on
+// agent state: a non-empty, well-formed probe list and a source IP per agent.
+fn validate_probe_request(
+    user_hash: &str,
+    request: &SubmitProbesRequest,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
     // Additional validation if needed (basic validation happens during deserialization)
-    if request.probes.is_empty() {
-        debug!("User {} submitted an empty probe list", auth_info.sub);
+    if request.probes.is_empty() && request.target_list.is_none() {
+        debug!("User {} submitted an empty probe list", user_hash);
         return Err((
             StatusCode::BAD_REQUEST,
             Json(serde_json::json!({
@@ -379,12 +692,27 @@ async fn submit_probes(
         ));
     }
 
-    // Validate that all agent metadata has IP addresses specified
-    for agent_meta in &request.metadata {
+    // Validate the template used to build probes from a target list
+    if let Some(target_list) = &request.target_list
+        && let Err(validation_error) = target_list.validate()
+    {
+        debug!("Validation error: {}", validation_error);
+        return Err(bad_request(validation_error));
+    }
+
+    validate_agent_metadata(user_hash, &request.metadata)
+}
+
+// Validate that all agent metadata has IP addresses specified
+fn validate_agent_metadata(
+    user_hash: &str,
+    metadata: &[probe::AgentMetadata],
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    for agent_meta in metadata {
         if agent_meta.ip_address.is_none() {
             debug!(
                 "User {} did not provide IP address for agent {}",
-                auth_info.sub, agent_meta.id
+                user_hash, agent_meta.id
             );
             return Err((
                 StatusCode::BAD_REQUEST,
@@ -396,8 +724,242 @@ async fn submit_probes(
         }
     }
 
-    // Validate source IP addresses for user's allocated prefixes
-    let user_id = match get_or_create_user_id(&state.database, &auth_info.sub).await {
+    Ok(())
+}
+
+// Append one probe per destination of the referenced target list to the
+// request's inline probes.
+async fn expand_target_list(
+    state: &AppState,
+    user_hash: &str,
+    request: &SubmitProbesRequest,
+    target_list: &probe::TargetListProbes,
+) -> Result<SubmitProbesRequest, (StatusCode, Json<serde_json::Value>)> {
+    let targets = match state
+        .database
+        .get_target_list_targets(target_list.id, user_hash)
+        .await
+    {
+        Ok(Some(targets)) => targets,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "error": 404,
+                    "message": format!("Target list {} not found", target_list.id)
+                })),
+            ));
+        }
+        Err(err) => {
+            error!("Failed to load target list {}: {}", target_list.id, err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to load target list"
+                })),
+            ));
+        }
+    };
+
+    let mut probes = request.probes.clone();
+    probes.extend(targets.iter().map(|target| target_list.probe_for(target)));
+
+    Ok(SubmitProbesRequest {
+        metadata: request.metadata.clone(),
+        probes,
+        target_list: None,
+    })
+}
+
+// Store the encoded batches of a submission, or of a round, as the next
+// segment of the measurement's archive. Failures don't fail the request, as the
+// probes are already on their way to the agents: the response says whether
+// they were archived. A segment recorded but not stored is skipped on download
+// and replay.
+async fn archive_probes(
+    state: &AppState,
+    archive: &archive::ProbeArchive,
+    measurement_id: Uuid,
+    user_hash: &str,
+    request: &SubmitProbesRequest,
+    probe_batches: &[Vec<u8>],
+) -> bool {
+    let compressed = match archive::ProbeArchive::compress(probe_batches) {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            error!(
+                "Failed to compress probes of measurement {}: {}",
+                measurement_id, err
+            );
+            return false;
+        }
+    };
+
+    let metadata = serde_json::to_string(&request.metadata).unwrap_or_default();
+    let record = match state
+        .database
+        .append_measurement_archive(
+            measurement_id,
+            user_hash,
+            &archive::ProbeArchive::key(measurement_id),
+            &metadata,
+            request.probes.len() as i32,
+            compressed.len() as i64,
+        )
+        .await
+    {
+        Ok(record) => record,
+        Err(err) => {
+            error!(
+                "Failed to record probe archive of measurement {}: {}",
+                measurement_id, err
+            );
+            return false;
+        }
+    };
+
+    let segment = record.segments - 1;
+    if let Err(err) = archive
+        .store_segment(measurement_id, segment, compressed)
+        .await
+    {
+        error!(
+            "Failed to archive probes of measurement {} (segment {}): {}",
+            measurement_id, segment, err
+        );
+        return false;
+    }
+    true
+}
+
+// Check every probe destination against the special-purpose address policy and
+// the blocklist. Depending on the configured policies, a submission is rejected,
+// or returned without the filtered probes. `None` means every probe is kept.
+fn filter_destinations(
+    state: &AppState,
+    user_hash: &str,
+    request: &SubmitProbesRequest,
+) -> Result<Option<SubmitProbesRequest>, (StatusCode, Json<serde_json::Value>)> {
+    let mut kept = Vec::with_capacity(request.probes.len());
+    let mut dropped = 0;
+    let mut first_blocked = None;
+    let mut blocked = 0;
+    for (index, probe) in request.probes.iter().enumerate() {
+        let Some(dst) = probe::probe_destination(probe) else {
+            kept.push(probe.clone());
+            continue;
+        };
+
+        match state.special_purpose.check(dst) {
+            Ok(true) => {}
+            Ok(false) => {
+                dropped += 1;
+                continue;
+            }
+            Err(err) => return Err(bad_request(format!("Probe at index {}: {}", index, err))),
+        }
+
+        if let IpAddr::V6(dst) = dst
+            && let Some(prefix) = state.blocklist.lookup(dst)
+        {
+            first_blocked.get_or_insert((index, prefix));
+            blocked += 1;
+            continue;
+        }
+
+        kept.push(probe.clone());
+    }
+
+    if dropped == 0 && blocked == 0 {
+        return Ok(None);
+    }
+    counter!("saimiris_gateway_probes_special_purpose_dropped_total").increment(dropped as u64);
+    counter!("saimiris_gateway_probes_blocked_total").increment(blocked as u64);
+    debug!(
+        "User {} submitted {} probes towards special-purpose and {} towards blocked destinations",
+        user_hash, dropped, blocked
+    );
+
+    if let Some((index, prefix)) = first_blocked
+        && state.blocklist.action() == blocklist::BlocklistAction::Reject
+    {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "error": 403,
+                "message": format!(
+                    "Probe at index {} targets blocked prefix {} ({} blocked probes in total)",
+                    index, prefix, blocked
+                )
+            })),
+        ));
+    }
+
+    if kept.is_empty() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "error": 403,
+                "message": "All probes target blocked or special-purpose destinations"
+            })),
+        ));
+    }
+
+    Ok(Some(SubmitProbesRequest {
+        metadata: request.metadata.clone(),
+        probes: kept,
+        target_list: None,
+    }))
+}
+
+// Check that every assigned agent supports the per-probe options used by the
+// submission, as advertised in its configuration
+async fn check_agent_probe_options(
+    state: &AppState,
+    assigned_agents: &[probe::AgentMetadata],
+    request: &SubmitProbesRequest,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let requirements = probe::ProbeRequirements::of(&request.probes);
+    if requirements.options.is_empty() {
+        return Ok(());
+    }
+
+    for agent_meta in assigned_agents {
+        let Some(agent) = state.agent_store.get(&agent_meta.id).await else {
+            continue;
+        };
+        if let Some(option) = requirements
+            .options
+            .iter()
+            .find(|option| !agent.supports_probe_option(option))
+        {
+            return Err(bad_request(format!(
+                "Agent {} does not support the {} probe option",
+                agent.id, option
+            )));
+        }
+        if let (Some(requested), Some(max)) =
+            (requirements.max_payload_size, agent.max_payload_size())
+            && requested > max
+        {
+            return Err(bad_request(format!(
+                "Agent {} supports payloads of at most {} bytes, got {}",
+                agent.id, max, requested
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Validate source IP addresses for user's allocated prefixes
+async fn validate_source_addresses(
+    state: &AppState,
+    user_hash: &str,
+    metadata: &[probe::AgentMetadata],
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let user_id = match get_or_create_user_id_by_hash(&state.database, user_hash).await {
         Ok(id) => id,
         Err(e) => {
             return Err((
@@ -409,7 +971,7 @@ async fn submit_probes(
             ));
         }
     };
-    for agent_meta in &request.metadata {
+    for agent_meta in metadata {
         // We already validated that ip_address is present above
         let ip_addr = agent_meta.ip_address.as_ref().unwrap();
 
@@ -430,7 +992,7 @@ async fn submit_probes(
                     if !valid_ip {
                         debug!(
                             "User {} attempted to use IP {} which is not within their allocated prefix",
-                            auth_info.sub, ip_addr
+                            user_hash, ip_addr
                         );
                         return Err((
                             StatusCode::FORBIDDEN,
@@ -446,164 +1008,466 @@ async fn submit_probes(
         // IPv4 addresses are allowed without prefix validation for now
     }
 
-    // Check if user can submit these probes (daily rate limiting)
-    let can_submit = match state
-        .database
-        .can_user_submit_probes(&auth_info.sub, request.probes.len() as u32, None)
-        .await
-    {
-        Ok(can_submit) => can_submit,
-        Err(err) => {
-            error!("Failed to check user probe limit: {}", err);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({
-                    "error": 500,
-                    "message": "Failed to check probe limit"
-                })),
-            ));
-        }
-    };
+    Ok(())
+}
 
-    if !can_submit {
-        debug!(
-            "User {} exceeded daily probe limit, cannot submit {} probes",
-            auth_info.sub,
-            request.probes.len()
-        );
-        return Err((
-            StatusCode::TOO_MANY_REQUESTS,
-            Json(serde_json::json!({
-                "error": 429,
-                "message": "Daily probe limit exceeded"
-            })),
-        ));
-    }
+fn kafka_send_failed(err: anyhow::Error) -> (StatusCode, Json<serde_json::Value>) {
+    counter!("saimiris_gateway_kafka_errors_total").increment(1);
+    error!("Failed to send probe batch to Kafka: {}", err);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(serde_json::json!({
+            "error": 500,
+            "message": "Failed to send probe data to processing queue"
+        })),
+    )
+}
 
-    // Generate a unique measurement ID
-    let measurement_id = Uuid::new_v4();
+// Build the Kafka messages carrying probe batches, routed to the agents in
+// `metadata`. The last batch carries end_of_measurement if
+// `end_of_measurement` is set.
+fn probe_batch_messages(
+    state: &AppState,
+    measurement_id: Uuid,
+    metadata: &[probe::AgentMetadata],
+    probe_batches: &[Vec<u8>],
+    end_of_measurement: bool,
+) -> Result<Vec<kafka::OutboundMessage>, (StatusCode, Json<serde_json::Value>)> {
+    let total_batches = probe_batches.len();
+    let config = &state.kafka_config;
 
-    let mut assigned_agents = Vec::new();
+    // With per-agent routing each agent gets its own copy of every batch,
+    // addressed to it only
+    let routes: Vec<(Option<String>, Option<i32>, &[probe::AgentMetadata])> = match &config.routing
+    {
+        kafka::TopicRouting::Shared => vec![(None, None, metadata)],
+        kafka::TopicRouting::AgentTopic { template, .. } => metadata
+            .iter()
+            .map(|agent| {
+                (
+                    Some(kafka::agent_topic(template, &config.topic, &agent.id)),
+                    None,
+                    std::slice::from_ref(agent),
+                )
+            })
+            .collect(),
+        kafka::TopicRouting::AgentPartition { partitions } => metadata
+            .iter()
+            .map(|agent| {
+                (
+                    None,
+                    Some(kafka::agent_partition(&agent.id, *partitions)),
+                    std::slice::from_ref(agent),
+                )
+            })
+            .collect(),
+    };
 
-    // Validate that requested agents exist
-    for agent_meta in &request.metadata {
-        if let Some(agent) = state.agent_store.get(&agent_meta.id).await {
-            // Only include healthy agents
-            if let Some(health) = &agent.health {
-                if health.healthy {
-                    assigned_agents.push(agent_meta.clone());
+    let mut messages = Vec::with_capacity(total_batches * routes.len());
+    for (topic, partition, agents) in routes {
+        let mut envelope = probe::BatchEnvelope {
+            version: probe::PROBE_BATCH_VERSION,
+            measurement_id,
+            batch_index: 0,
+            batch_count: total_batches as u32,
+            end_of_measurement: false,
+            agents: agents.to_vec(),
+        };
+
+        for (batch_index, batch) in probe_batches.iter().enumerate() {
+            // Construct headers for this specific batch
+            let mut headers = Vec::new();
+            let is_last_batch = end_of_measurement && batch_index == total_batches - 1;
+
+            let payload = match config.batch_format {
+                kafka::BatchFormat::Legacy => {
+                    for agent_meta in agents {
+                        // Create JSON header value to match saimiris agent expectations
+                        let agent_info_json = serde_json::json!({
+                            "src_ip": agent_meta.ip_address,
+                            "measurement_id": measurement_id.to_string(),
+                            "end_of_measurement": is_last_batch,
+                        });
+                        headers.push((agent_meta.id.clone(), agent_info_json.to_string()));
+                    }
+                    batch.clone()
+                }
+                kafka::BatchFormat::Envelope => {
+                    // Routing information travels in the payload
+                    envelope.batch_index = batch_index as u32;
+                    envelope.end_of_measurement = is_last_batch;
+                    headers.push((
+                        kafka::PROBE_BATCH_HEADER.to_string(),
+                        envelope.version.to_string(),
+                    ));
+                    envelope.encode(batch).map_err(|err| {
+                        error!("Failed to encode probe batch envelope: {}", err);
+                        (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Json(serde_json::json!({
+                                "error": 500,
+                                "message": "Failed to process probe data"
+                            })),
+                        )
+                    })?
                 }
+            };
+
+            // Use the measurement ID as the message key
+            messages.push(kafka::OutboundMessage {
+                key: measurement_id.to_string(),
+                payload,
+                headers,
+                topic: topic.clone(),
+                partition,
+            });
+        }
+    }
+
+    Ok(messages)
+}
+
+// Deliver the messages of a measurement, either directly to Kafka or, with
+// the outbox enabled, by queueing them in the same database transaction as
+// the measurement's tracking for the relay to publish.
+async fn deliver_probe_messages(
+    state: &AppState,
+    messages: Vec<kafka::OutboundMessage>,
+    tracking: &DispatchTracking,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if state.outbox {
+        return state
+            .database
+            .record_dispatch(tracking, &messages)
+            .await
+            .map_err(|err| dispatch_tracking_failed(tracking, err, "Failed to queue probe data"));
+    }
+
+    // Agents may report progress as soon as they get the first batch, so the
+    // tracking goes first. Without it nothing is sent: the measurement would
+    // be neither followed nor charged.
+    if let Err(err) = state.database.record_dispatch(tracking, &[]).await {
+        return Err(dispatch_tracking_failed(
+            tracking,
+            err,
+            "Failed to record measurement tracking",
+        ));
+    }
+
+    if let Err(err) = state.probe_sink.send(&messages).await {
+        let Some(partial) = err.downcast_ref::<sink::PartialDelivery>() else {
+            // Nothing was delivered: don't leave tracking behind for probes
+            // the agents will never send
+            if let Err(err) = state.database.revert_dispatch(tracking).await {
+                error!(
+                    "Failed to roll back tracking of measurement {}: {}",
+                    tracking.measurement_id, err
+                );
+            }
+            return Err(kafka_send_failed(err));
+        };
+
+        // Without transactions the first batches may have reached the agents,
+        // which will send them: the tracking keeps those only
+        let reverted = match delivered_agent_probes(tracking, &messages[..partial.delivered]) {
+            Ok(delivered) => state
+                .database
+                .revert_undelivered(tracking, &delivered)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = reverted {
+            error!(
+                "Failed to roll back undelivered probes of measurement {}: {}",
+                tracking.measurement_id, err
+            );
+        }
+        let (status, Json(mut body)) = kafka_send_failed(err);
+        body["message"] = "Probe data was only partly sent to the processing queue".into();
+        body["id"] = tracking.measurement_id.to_string().into();
+        return Err((status, Json(body)));
+    }
+
+    debug!(
+        "Sent {} probe batches for measurement {}",
+        messages.len(),
+        tracking.measurement_id
+    );
+    Ok(())
+}
+
+// Probes each agent of `tracking` got from `messages`, in the order of its
+// agent IDs. The agents of a batch are in its headers, or in its envelope.
+fn delivered_agent_probes(
+    tracking: &DispatchTracking,
+    messages: &[kafka::OutboundMessage],
+) -> anyhow::Result<Vec<i32>> {
+    let mut delivered = vec![0; tracking.agent_ids.len()];
+    for message in messages {
+        let (agents, probes) = if message
+            .headers
+            .iter()
+            .any(|(key, _)| key == kafka::PROBE_BATCH_HEADER)
+        {
+            let (envelope, probes) = probe::BatchEnvelope::decode(&message.payload)?;
+            let agents = envelope.agents.into_iter().map(|agent| agent.id).collect();
+            (agents, probes.len())
+        } else {
+            let agents: Vec<String> = message.headers.iter().map(|(key, _)| key.clone()).collect();
+            (agents, probe::decode_probes_batch(&message.payload)?.len())
+        };
+        for agent in agents {
+            if let Some(index) = tracking.agent_ids.iter().position(|id| *id == agent) {
+                delivered[index] += probes as i32;
             }
         }
     }
+    Ok(delivered)
+}
 
-    // If no valid agents were found, return an error
-    if assigned_agents.is_empty() {
-        debug!("No valid agents found for user {}", auth_info.sub);
-        return Err((
-            StatusCode::BAD_REQUEST,
+// Error response for a dispatch whose tracking could not be recorded. A round
+// is refused when its measurement was cancelled since it was looked up.
+fn dispatch_tracking_failed(
+    tracking: &DispatchTracking,
+    err: sqlx::Error,
+    message: &str,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if !tracking.new_measurement && matches!(err, sqlx::Error::RowNotFound) {
+        return (
+            StatusCode::CONFLICT,
             Json(serde_json::json!({
-                "error": 400,
-                "message": "No healthy agents found for the requested measurement"
+                "error": 409,
+                "message": "Measurement is no longer open"
             })),
-        ));
+        );
     }
 
-    // Directly deserialize and create probe batches (max 1MB per batch)
-    let probe_batches = match probe::deserialize_probes_batch(&request.probes, 1_000_000) {
-        Ok(batches) => batches,
+    error!(
+        "Failed to record tracking of measurement {}: {}",
+        tracking.measurement_id, err
+    );
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(serde_json::json!({
+            "error": 500,
+            "message": message
+        })),
+    )
+}
+
+// Validate a probe submission for `user_identifier`, dispatch it to Kafka and
+// record the measurement.
+pub(crate) async fn dispatch_probes(
+    state: &AppState,
+    user_identifier: &str,
+    request: &SubmitProbesRequest,
+) -> Result<SubmitProbesResponse, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = crate::hash_user_identifier(user_identifier);
+    dispatch_probes_by_hash(state, &user_hash, request).await
+}
+
+// Like `dispatch_probes`, for the user with hash `user_hash`. Everything stored
+// about a submission is keyed on the hash, so the scheduler dispatches runs on
+// the owner's behalf without keeping their identifier.
+pub(crate) async fn dispatch_probes_by_hash(
+    state: &AppState,
+    user_hash: &str,
+    request: &SubmitProbesRequest,
+) -> Result<SubmitProbesResponse, (StatusCode, Json<serde_json::Value>)> {
+    dispatch_measurement_probes(state, user_hash, request, None).await
+}
+
+// Reserve `probes` more probes in every window of the user's quota policy
+async fn reserve_quota(
+    state: &AppState,
+    user_hash: &str,
+    probes: usize,
+) -> Result<Uuid, (StatusCode, Json<serde_json::Value>)> {
+    let reservation = match quota::reserve(
+        &state.database,
+        &state.quota,
+        state.quota_charging,
+        user_hash,
+        probes as u64,
+        chrono::Utc::now(),
+    )
+    .await
+    {
+        Ok(reservation) => reservation,
         Err(err) => {
-            error!("Failed to deserialize probe batch: {}", err);
+            error!("Failed to reserve user quota: {}", err);
             return Err((
-                StatusCode::BAD_REQUEST,
+                StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
-                    "error": 400,
-                    "message": "Failed to process probe data"
+                    "error": 500,
+                    "message": "Failed to check probe quota"
                 })),
             ));
         }
     };
 
-    // Initialize measurement tracking in database
-    let user_identifier = &auth_info.sub;
-    let user_hash = crate::hash_user_identifier(user_identifier);
-    for agent_meta in &assigned_agents {
-        if let Err(err) = state
-            .database
-            .create_measurement_tracking(
-                &user_hash,
-                measurement_id,
-                &agent_meta.id,
-                request.probes.len() as i32,
-            )
-            .await
-        {
-            error!(
-                "Failed to create measurement tracking for agent {}: {}",
-                agent_meta.id, err
+    match reservation {
+        quota::Reservation::Reserved(reservation) => Ok(reservation),
+        quota::Reservation::Exceeded(exceeded) => {
+            debug!(
+                "User {} exceeded the {} quota window, cannot submit {} probes",
+                user_hash, exceeded.window.period, probes
             );
-            // Continue with other agents even if one fails
+            Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(serde_json::json!({
+                    "error": 429,
+                    "message": format!(
+                        "Probe quota exceeded: {} of {} probes used in the {} window, cannot submit {} more",
+                        exceeded.used, exceeded.window.limit, exceeded.window.period, probes
+                    ),
+                    "quota": exceeded
+                })),
+            ))
         }
     }
+}
 
-    // Send each batch to Kafka with proper headers
-    let topic = &state.kafka_config.topic;
-    let total_batches = probe_batches.len();
+// Dispatch probes either as a new single-round measurement, or as a round of
+// the open measurement `open_measurement`, whose agents already have tracking
+// rows and only get end_of_measurement when it is closed.
+async fn dispatch_measurement_probes(
+    state: &AppState,
+    user_hash: &str,
+    request: &SubmitProbesRequest,
+    open_measurement: Option<Uuid>,
+) -> Result<SubmitProbesResponse, (StatusCode, Json<serde_json::Value>)> {
+    validate_probe_request(user_hash, request)?;
+
+    // Expand a referenced target list into regular probes
+    let expanded;
+    let request = match &request.target_list {
+        Some(target_list) => {
+            expanded = expand_target_list(state, user_hash, request, target_list).await?;
+            &expanded
+        }
+        None => request,
+    };
 
-    for (batch_index, batch) in probe_batches.iter().enumerate() {
-        // Construct headers for this specific batch
-        let mut headers = OwnedHeaders::new();
-        let is_last_batch = batch_index == total_batches - 1;
-
-        for agent_meta in &request.metadata {
-            // Create JSON header value to match saimiris agent expectations
-            let agent_info_json = serde_json::json!({
-                "src_ip": agent_meta.ip_address,
-                "measurement_id": measurement_id.to_string(),
-                "end_of_measurement": is_last_batch,
-            });
-            let agent_info_str = agent_info_json.to_string();
+    // Drop or reject probes towards special-purpose and blocked destinations
+    let unblocked;
+    let (request, filtered) = match filter_destinations(state, user_hash, request)? {
+        Some(kept) => {
+            let filtered = request.probes.len() - kept.probes.len();
+            unblocked = kept;
+            (&unblocked, filtered)
+        }
+        None => (request, 0),
+    };
 
-            headers = headers.insert(Header {
-                key: &agent_meta.id,
-                value: Some(&agent_info_str),
-            });
+    validate_source_addresses(state, user_hash, &request.metadata).await?;
+
+    // Generate a unique measurement ID, unless appending a round
+    let measurement_id = open_measurement.unwrap_or_else(Uuid::new_v4);
+
+    let mut assigned_agents = Vec::new();
+
+    // Validate that requested agents exist
+    for agent_meta in &request.metadata {
+        if let Some(agent) = state.agent_store.get(&agent_meta.id).await {
+            // Only include healthy agents
+            if let Some(health) = &agent.health {
+                if health.healthy {
+                    assigned_agents.push(agent_meta.clone());
+                }
+            }
         }
+    }
 
-        // Use the measurement ID as the message key
-        match kafka::send_to_kafka(
-            &state.kafka_producer,
-            topic,
-            &measurement_id.to_string(),
-            batch,
-            Some(headers),
-        )
-        .await
+    // If no valid agents were found, return an error
+    if assigned_agents.is_empty() {
+        debug!("No valid agents found for user {}", user_hash);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": 400,
+                "message": "No healthy agents found for the requested measurement"
+            })),
+        ));
+    }
+
+    check_agent_probe_options(state, &assigned_agents, request).await?;
+
+    // Directly deserialize and create probe batches, each fitting in a Kafka message
+    let probe_batches =
+        match probe::deserialize_probes_batch(&request.probes, state.kafka_config.max_batch_size())
         {
-            Ok(_) => debug!(
-                "Successfully sent probe batch {} of {} for measurement {} to Kafka topic {}",
-                batch_index + 1,
-                total_batches,
-                measurement_id,
-                topic
-            ),
+            Ok(batches) => batches,
             Err(err) => {
-                counter!("saimiris_gateway_kafka_errors_total").increment(1);
-                error!("Failed to send probe batch to Kafka: {}", err);
+                error!("Failed to deserialize probe batch: {}", err);
                 return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
+                    StatusCode::BAD_REQUEST,
                     Json(serde_json::json!({
-                        "error": 500,
-                        "message": "Failed to send probe data to processing queue"
+                        "error": 400,
+                        "message": "Failed to process probe data"
                     })),
                 ));
             }
-        }
+        };
+
+    // Record the measurement tracking and send each batch to Kafka with
+    // proper headers (or queue them in the outbox)
+    let tracking = DispatchTracking {
+        measurement_id,
+        user_hash: user_hash.to_string(),
+        agent_ids: assigned_agents.iter().map(|a| a.id.clone()).collect(),
+        probes: request.probes.len() as i32,
+        new_measurement: open_measurement.is_none(),
+        filtered: filtered as i32,
+    };
+    let messages = probe_batch_messages(
+        state,
+        measurement_id,
+        &assigned_agents,
+        &probe_batches,
+        open_measurement.is_none(),
+    )?;
+
+    // Hold the probes in the user's quota while they are dispatched. Once
+    // delivery is over the tracking counts the probes the agents got: all of
+    // them, none when nothing was sent, or those of the first batches when
+    // sending failed midway. The reservation can go in every case.
+    let reservation = reserve_quota(
+        state,
+        user_hash,
+        request.probes.len() * assigned_agents.len(),
+    )
+    .await?;
+    let delivered = deliver_probe_messages(state, messages, &tracking).await;
+    if let Err(err) = state.database.release_quota_reservation(reservation).await {
+        // Dropped once stale anyway
+        warn!(
+            "Failed to release quota reservation {}: {}",
+            reservation, err
+        );
     }
+    delivered?;
+
+    // Keep the exact probes sent, for audit and replay
+    let archived = match &state.probe_archive {
+        Some(archive) => Some(
+            archive_probes(
+                state,
+                archive,
+                measurement_id,
+                user_hash,
+                request,
+                &probe_batches,
+            )
+            .await,
+        ),
+        None => None,
+    };
 
     debug!(
         "User {} submitted {} probes for measurement {}, assigned to {} agents",
-        auth_info.sub,
+        user_hash,
         request.probes.len(),
         measurement_id,
         assigned_agents.len()
@@ -615,21 +1479,26 @@ async fn submit_probes(
     // Record probe usage in database
     if let Err(err) = state
         .database
-        .record_probe_usage(user_identifier, measurement_id, total_probe_count as i32)
+        .record_probe_usage_by_hash(user_hash, measurement_id, total_probe_count as i32)
         .await
     {
         error!("Failed to record probe usage in database: {}", err);
         // Don't fail the request if database recording fails
     }
 
-    counter!("saimiris_gateway_measurements_created_total").increment(1);
+    if open_measurement.is_none() {
+        counter!("saimiris_gateway_measurements_created_total").increment(1);
+    }
     counter!("saimiris_gateway_probes_submitted_total").increment(total_probe_count as u64);
 
-    Ok(Json(SubmitProbesResponse {
+    Ok(SubmitProbesResponse {
         id: measurement_id.to_string(),
         probes: total_probe_count,
         agents: assigned_agents,
-    }))
+        filtered,
+        round: None,
+        archived,
+    })
 }
 
 // Query parameters for listing a user's measurements.
@@ -690,7 +1559,7 @@ async fn list_measurements_handler(
         },
     };
 
-    // status: comma-separated complete|in-progress|cancelled
+    // status: comma-separated complete|in-progress|cancelled|queued
     let mut status = Vec::new();
     if let Some(raw) = params.status.as_deref() {
         for part in raw.split(',').map(str::trim).filter(|s| !s.is_empty()) {
@@ -698,9 +1567,10 @@ async fn list_measurements_handler(
                 "complete" => MeasurementState::Complete,
                 "in-progress" => MeasurementState::InProgress,
                 "cancelled" => MeasurementState::Cancelled,
+                "queued" => MeasurementState::Queued,
                 other => {
                     return Err(bad_request(format!(
-                        "Invalid 'status' '{other}': expected complete|in-progress|cancelled"
+                        "Invalid 'status' '{other}': expected complete|in-progress|cancelled|queued"
                     )));
                 }
             };
@@ -771,6 +1641,9 @@ async fn list_measurements_handler(
                         "total_sent_probes": m.total_sent_probes,
                         "measurement_complete": m.measurement_complete,
                         "measurement_cancelled": m.measurement_cancelled,
+                        "filtered_probes": m.filtered_probes,
+                        "queued": m.queued_batches > 0,
+                        "queued_batches": m.queued_batches,
                         "started_at": m.started_at,
                         "last_updated": m.last_updated
                     })
@@ -831,20 +1704,46 @@ async fn get_measurement_status_handler(
                 }
             };
 
+            let cancellations = match state
+                .database
+                .get_measurement_cancellations(measurement_uuid)
+                .await
+            {
+                Ok(cancellations) => cancellations,
+                Err(err) => {
+                    error!("Failed to get measurement cancellations: {}", err);
+                    Vec::new()
+                }
+            };
+
             let agents_detail: Vec<_> = tracking
                 .into_iter()
                 .map(|t| {
+                    let cancellation = cancellations
+                        .iter()
+                        .find(|c| c.agent_id == t.agent_id)
+                        .map(cancellation_state);
                     serde_json::json!({
                         "agent_id": t.agent_id,
                         "expected_probes": t.expected_probes,
                         "sent_probes": t.sent_probes,
                         "is_complete": t.is_complete,
                         "cancelled": t.cancelled,
+                        "cancellation": cancellation,
                         "updated_at": t.updated_at
                     })
                 })
                 .collect();
 
+            // Acknowledged once every cancelled agent has stopped
+            let cancellation = if cancellations.is_empty() {
+                None
+            } else if cancellations.iter().all(|c| c.acknowledged_at.is_some()) {
+                Some("acknowledged")
+            } else {
+                Some("requested")
+            };
+
             Ok(Json(serde_json::json!({
                 "measurement_id": status.measurement_id,
                 "total_agents": status.total_agents,
@@ -853,6 +1752,10 @@ async fn get_measurement_status_handler(
                 "total_sent_probes": status.total_sent_probes,
                 "measurement_complete": status.measurement_complete,
                 "measurement_cancelled": status.measurement_cancelled,
+                "cancellation": cancellation,
+                "filtered_probes": status.filtered_probes,
+                "queued": status.queued_batches > 0,
+                "queued_batches": status.queued_batches,
                 "started_at": status.started_at,
                 "last_updated": status.last_updated,
                 "agents": agents_detail
@@ -878,6 +1781,15 @@ async fn get_measurement_status_handler(
     }
 }
 
+// "requested" until the agent acknowledges the cancellation, then "acknowledged"
+fn cancellation_state(cancellation: &database::MeasurementCancellation) -> &'static str {
+    if cancellation.acknowledged_at.is_some() {
+        "acknowledged"
+    } else {
+        "requested"
+    }
+}
+
 // Handler for cancelling a user's stuck/in-progress measurement (client-facing)
 async fn cancel_measurement_handler(
     Extension(auth_info): Extension<jwt::AuthInfo>,
@@ -980,64 +1892,1226 @@ async fn cancel_measurement_handler(
     }
 }
 
-// Handler for agents to update measurement status (agent-facing)
-#[derive(serde::Deserialize)]
-struct UpdateMeasurementStatusRequest {
-    sent_probes: i32,
-    is_complete: bool,
-}
+// Look up the archive record of one of the user's measurements, mapping the
+// missing cases to client errors.
+async fn find_measurement_archive(
+    state: &AppState,
+    user_identifier: &str,
+    measurement_id: &str,
+) -> Result<(archive::ProbeArchive, MeasurementArchive), (StatusCode, Json<serde_json::Value>)> {
+    let measurement_uuid = Uuid::parse_str(measurement_id)
+        .map_err(|_| bad_request("Invalid measurement ID format"))?;
 
-async fn update_measurement_status(
-    State(state): State<AppState>,
-    Path((agent_id, measurement_id)): Path<(String, String)>,
-    Json(request): Json<UpdateMeasurementStatusRequest>,
-) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
-    let measurement_uuid = match Uuid::parse_str(&measurement_id) {
-        Ok(uuid) => uuid,
-        Err(_) => {
-            return Err((
-                StatusCode::BAD_REQUEST,
+    let Some(archive) = state.probe_archive.clone() else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({
+                "error": 503,
+                "message": "Probe archiving is not enabled"
+            })),
+        ));
+    };
+
+    let user_hash = crate::hash_user_identifier(user_identifier);
+    match state
+        .database
+        .get_measurement_archive(measurement_uuid, &user_hash)
+        .await
+    {
+        Ok(Some(record)) => Ok((archive, record)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": 404,
+                "message": "No archived probes for this measurement"
+            })),
+        )),
+        Err(err) => {
+            error!("Failed to get measurement archive: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
-                    "error": 400,
-                    "message": "Invalid measurement ID format"
+                    "error": 500,
+                    "message": "Failed to retrieve archived probes"
                 })),
-            ));
+            ))
         }
+    }
+}
+
+fn archived_blob_missing() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({
+            "error": 404,
+            "message": "Archived probes are no longer available"
+        })),
+    )
+}
+
+fn archive_read_failed(err: anyhow::Error) -> (StatusCode, Json<serde_json::Value>) {
+    error!("Failed to read archived probes: {}", err);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(serde_json::json!({
+            "error": 500,
+            "message": "Failed to read archived probes"
+        })),
+    )
+}
+
+// Handler for downloading the archived probes of a measurement: the gzip-compressed
+// stream of Cap'n Proto probe messages exactly as sent to Kafka (client-facing)
+async fn download_measurement_probes_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(measurement_id): Path<String>,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let (archive, record) =
+        find_measurement_archive(&state, &auth_info.sub, &measurement_id).await?;
+
+    let blob = archive
+        .load(record.measurement_id, record.segments)
+        .await
+        .map_err(archive_read_failed)?
+        .ok_or_else(archived_blob_missing)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/gzip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}.capnp.gz\"",
+                    record.measurement_id
+                ),
+            ),
+        ],
+        blob,
+    )
+        .into_response())
+}
+
+// Handler for re-running a measurement from its archived probes. The replay goes
+// through the regular submission path (quota, prefixes, agent health) and gets
+// a new measurement ID (client-facing)
+async fn replay_measurement_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(measurement_id): Path<String>,
+) -> Result<Json<SubmitProbesResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let (archive, record) =
+        find_measurement_archive(&state, &auth_info.sub, &measurement_id).await?;
+
+    let probes = archive
+        .load_probes(record.measurement_id, record.segments)
+        .await
+        .map_err(archive_read_failed)?
+        .ok_or_else(archived_blob_missing)?;
+    let metadata: Vec<probe::AgentMetadata> =
+        serde_json::from_str(&record.metadata).map_err(|err| archive_read_failed(err.into()))?;
+
+    let request = SubmitProbesRequest {
+        metadata,
+        probes: probes.iter().map(probe::Probe::to_json).collect(),
+        target_list: None,
     };
 
-    // We need to find the user_hash for this measurement by looking up the tracking entry
-    let tracking_entry = match state
+    let response = dispatch_probes(&state, &auth_info.sub, &request).await?;
+    debug!(
+        "User {} replayed measurement {} as {}",
+        auth_info.sub, record.measurement_id, response.id
+    );
+    Ok(Json(response))
+}
+
+// Body for opening a multi-round measurement
+#[derive(serde::Deserialize)]
+struct OpenMeasurementRequest {
+    metadata: Vec<probe::AgentMetadata>,
+}
+
+// Body for appending a round of probes to an open measurement
+#[derive(serde::Deserialize)]
+struct AppendRoundRequest {
+    #[serde(default)]
+    probes: Vec<serde_json::Value>,
+    target_list: Option<probe::TargetListProbes>,
+}
+
+fn multi_round_database_error(
+    err: sqlx::Error,
+    message: &str,
+) -> (StatusCode, Json<serde_json::Value>) {
+    error!("{}: {}", message, err);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(serde_json::json!({
+            "error": 500,
+            "message": message
+        })),
+    )
+}
+
+// Look up one of the user's open multi-round measurements, with the agents
+// its rounds are sent to
+async fn find_open_measurement(
+    state: &AppState,
+    user_hash: &str,
+    measurement_id: &str,
+) -> Result<(MultiRoundMeasurement, Vec<probe::AgentMetadata>), (StatusCode, Json<serde_json::Value>)>
+{
+    let measurement_uuid = Uuid::parse_str(measurement_id)
+        .map_err(|_| bad_request("Invalid measurement ID format"))?;
+
+    let measurement = match state
         .database
-        .get_measurement_tracking_by_agent(measurement_uuid, &agent_id)
+        .get_multi_round_measurement(measurement_uuid, user_hash)
         .await
     {
-        Ok(Some(entry)) => entry,
+        Ok(Some(measurement)) => measurement,
         Ok(None) => {
             return Err((
                 StatusCode::NOT_FOUND,
                 Json(serde_json::json!({
                     "error": 404,
-                    "message": "Measurement tracking entry not found for this agent"
+                    "message": "Multi-round measurement not found"
                 })),
             ));
         }
         Err(err) => {
-            error!("Failed to get measurement tracking: {}", err);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({
-                    "error": 500,
-                    "message": "Failed to retrieve measurement tracking"
-                })),
+            return Err(multi_round_database_error(
+                err,
+                "Failed to retrieve measurement",
             ));
         }
     };
 
-    let user_hash = &tracking_entry.user_hash;
-
-    match state
-        .database
-        .update_measurement_probe_count(
+    if measurement.closed_at.is_some() {
+        // Cancelling a multi-round measurement also closes it
+        let cancelled = state
+            .database
+            .get_measurement_status(measurement_uuid, user_hash)
+            .await
+            .map_err(|err| multi_round_database_error(err, "Failed to retrieve measurement"))?
+            .is_some_and(|status| status.measurement_cancelled);
+        let message = if cancelled {
+            "Measurement was cancelled"
+        } else {
+            "Measurement is already closed"
+        };
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": 409,
+                "message": message
+            })),
+        ));
+    }
+
+    let metadata = serde_json::from_str(&measurement.metadata).map_err(|err| {
+        error!(
+            "Invalid metadata stored for measurement {}: {}",
+            measurement.measurement_id, err
+        );
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": 500,
+                "message": "Failed to retrieve measurement"
+            })),
+        )
+    })?;
+
+    Ok((measurement, metadata))
+}
+
+// Handler for opening a multi-round measurement (client-facing). The healthy
+// agents at open time receive every round; probes are sent with the rounds.
+async fn open_measurement_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Json(request): Json<OpenMeasurementRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    if request.metadata.is_empty() {
+        return Err(bad_request("No agents specified"));
+    }
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+    validate_agent_metadata(&user_hash, &request.metadata)?;
+    validate_source_addresses(&state, &user_hash, &request.metadata).await?;
+
+    let mut assigned_agents = Vec::new();
+    for agent_meta in &request.metadata {
+        if let Some(agent) = state.agent_store.get(&agent_meta.id).await
+            && agent.health.as_ref().is_some_and(|health| health.healthy)
+        {
+            assigned_agents.push(agent_meta.clone());
+        }
+    }
+    if assigned_agents.is_empty() {
+        return Err(bad_request(
+            "No healthy agents found for the requested measurement",
+        ));
+    }
+
+    // Rounds add to the agents' tracking, so it is created with the measurement
+    let measurement_id = Uuid::new_v4();
+    let agent_ids: Vec<String> = assigned_agents.iter().map(|a| a.id.clone()).collect();
+    let metadata = serde_json::to_string(&assigned_agents).unwrap_or_default();
+    state
+        .database
+        .create_multi_round_measurement(measurement_id, &user_hash, &agent_ids, &metadata)
+        .await
+        .map_err(|err| multi_round_database_error(err, "Failed to open measurement"))?;
+
+    counter!("saimiris_gateway_measurements_created_total").increment(1);
+    debug!(
+        "User {} opened multi-round measurement {} on {} agents",
+        auth_info.sub,
+        measurement_id,
+        assigned_agents.len()
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({
+            "id": measurement_id,
+            "agents": assigned_agents,
+            "rounds": 0,
+        })),
+    ))
+}
+
+// Handler for appending a round of probes to an open measurement
+// (client-facing). Each round is validated, filtered and quota-checked like a
+// regular submission, and archived as a segment of the measurement's archive.
+async fn append_round_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(measurement_id): Path<String>,
+    Json(round): Json<AppendRoundRequest>,
+) -> Result<Json<SubmitProbesResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+    let (measurement, metadata) =
+        find_open_measurement(&state, &user_hash, &measurement_id).await?;
+
+    let request = SubmitProbesRequest {
+        metadata,
+        probes: round.probes,
+        target_list: round.target_list,
+    };
+    let mut response = dispatch_measurement_probes(
+        &state,
+        &user_hash,
+        &request,
+        Some(measurement.measurement_id),
+    )
+    .await?;
+
+    response.round = state
+        .database
+        .record_measurement_round(measurement.measurement_id, &user_hash)
+        .await
+        .map_err(|err| multi_round_database_error(err, "Failed to record measurement round"))?;
+
+    debug!(
+        "User {} appended round {:?} to measurement {}",
+        auth_info.sub, response.round, measurement.measurement_id
+    );
+    Ok(Json(response))
+}
+
+// Handler for closing an open measurement (client-facing): its agents are
+// told the measurement ended and no more rounds are accepted
+async fn close_measurement_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(measurement_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+    let (measurement, metadata) =
+        find_open_measurement(&state, &user_hash, &measurement_id).await?;
+
+    // An empty batch carrying end_of_measurement
+    let messages = probe_batch_messages(
+        &state,
+        measurement.measurement_id,
+        &metadata,
+        &[Vec::new()],
+        true,
+    )?;
+    let tracking = DispatchTracking {
+        measurement_id: measurement.measurement_id,
+        user_hash: user_hash.clone(),
+        agent_ids: Vec::new(),
+        probes: 0,
+        new_measurement: false,
+        filtered: 0,
+    };
+    deliver_probe_messages(&state, messages, &tracking).await?;
+
+    let closed = state
+        .database
+        .close_multi_round_measurement(measurement.measurement_id, &user_hash)
+        .await
+        .map_err(|err| multi_round_database_error(err, "Failed to close measurement"))?;
+    if !closed {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": 409,
+                "message": "Measurement is already closed"
+            })),
+        ));
+    }
+
+    debug!(
+        "User {} closed measurement {} after {} rounds",
+        auth_info.sub, measurement.measurement_id, measurement.rounds
+    );
+    Ok(Json(serde_json::json!({
+        "id": measurement.measurement_id,
+        "closed": true,
+        "rounds": measurement.rounds,
+    })))
+}
+
+// Body for creating a measurement schedule. `start_at` defaults to now; without
+// `interval_seconds` the schedule runs once.
+#[derive(serde::Deserialize)]
+struct CreateScheduleRequest {
+    name: Option<String>,
+    metadata: Vec<probe::AgentMetadata>,
+    #[serde(default)]
+    probes: Vec<serde_json::Value>,
+    target_list: Option<probe::TargetListProbes>,
+    start_at: Option<String>,
+    interval_seconds: Option<i64>,
+}
+
+// Render a schedule for the client API (without the owner's identifiers).
+fn schedule_json(schedule: &MeasurementSchedule) -> serde_json::Value {
+    let spec: serde_json::Value = serde_json::from_str(&schedule.spec).unwrap_or_default();
+    serde_json::json!({
+        "id": schedule.id,
+        "name": schedule.name,
+        "metadata": spec["metadata"],
+        "probes": spec["probes"],
+        "target_list": spec["target_list"],
+        "interval_seconds": schedule.interval_seconds,
+        "next_run_at": schedule.next_run_at,
+        "created_at": schedule.created_at,
+        "updated_at": schedule.updated_at
+    })
+}
+
+fn parse_schedule_id(schedule_id: &str) -> Result<Uuid, (StatusCode, Json<serde_json::Value>)> {
+    Uuid::parse_str(schedule_id).map_err(|_| bad_request("Invalid schedule ID format"))
+}
+
+// Handler for creating a scheduled or recurring measurement (client-facing)
+async fn create_schedule_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Json(body): Json<CreateScheduleRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    let request = SubmitProbesRequest {
+        metadata: body.metadata,
+        probes: body.probes,
+        target_list: body.target_list,
+    };
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+    validate_probe_request(&user_hash, &request)?;
+
+    if request.metadata.is_empty() {
+        return Err(bad_request("At least one agent is required"));
+    }
+
+    let name = body.name.filter(|n| !n.is_empty());
+    if name.as_ref().is_some_and(|n| n.len() > 255) {
+        return Err(bad_request("Schedule name must be at most 255 characters"));
+    }
+
+    if let Some(interval) = body.interval_seconds
+        && interval < scheduler::MIN_SCHEDULE_INTERVAL_SECONDS
+    {
+        return Err(bad_request(format!(
+            "Invalid 'interval_seconds': must be at least {}",
+            scheduler::MIN_SCHEDULE_INTERVAL_SECONDS
+        )));
+    }
+
+    let start_at = match body.start_at.as_deref().filter(|s| !s.is_empty()) {
+        Some(raw) => {
+            parse_time(raw).ok_or_else(|| bad_request(format!("Invalid 'start_at' time: {raw}")))?
+        }
+        None => chrono::Utc::now(),
+    };
+
+    // The list is resolved at each run, but must exist when the schedule is created
+    if let Some(target_list) = &request.target_list {
+        match state
+            .database
+            .get_target_list(target_list.id, &user_hash)
+            .await
+        {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    Json(serde_json::json!({
+                        "error": 404,
+                        "message": format!("Target list {} not found", target_list.id)
+                    })),
+                ));
+            }
+            Err(err) => {
+                error!("Failed to get target list: {}", err);
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(serde_json::json!({
+                        "error": 500,
+                        "message": "Failed to create schedule"
+                    })),
+                ));
+            }
+        }
+    }
+
+    match state.database.list_user_schedules(&user_hash).await {
+        Ok(existing) if existing.len() >= scheduler::MAX_SCHEDULES_PER_USER => {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(serde_json::json!({
+                    "error": 429,
+                    "message": format!(
+                        "Schedule limit reached ({} per user)",
+                        scheduler::MAX_SCHEDULES_PER_USER
+                    )
+                })),
+            ));
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("Failed to list schedules: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to create schedule"
+                })),
+            ));
+        }
+    }
+
+    let spec = serde_json::to_string(&request).unwrap_or_default();
+    match state
+        .database
+        .create_schedule(
+            &user_hash,
+            name.as_deref(),
+            &spec,
+            body.interval_seconds,
+            start_at,
+        )
+        .await
+    {
+        Ok(schedule) => {
+            debug!(
+                "User {} created schedule {} ({} probes, first run at {})",
+                auth_info.sub,
+                schedule.id,
+                request.probes.len(),
+                start_at
+            );
+            Ok((StatusCode::CREATED, Json(schedule_json(&schedule))))
+        }
+        Err(err) => {
+            error!("Failed to create schedule: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to create schedule"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for listing the authenticated user's schedules (client-facing)
+async fn list_schedules_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+
+    match state.database.list_user_schedules(&user_hash).await {
+        Ok(schedules) => Ok(Json(serde_json::Value::Array(
+            schedules.iter().map(schedule_json).collect(),
+        ))),
+        Err(err) => {
+            error!("Failed to list schedules: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to retrieve schedules"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for getting a schedule and its recent runs (client-facing)
+async fn get_schedule_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(schedule_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let schedule_uuid = parse_schedule_id(&schedule_id)?;
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+
+    let schedule = match state.database.get_schedule(schedule_uuid, &user_hash).await {
+        Ok(Some(schedule)) => schedule,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "error": 404,
+                    "message": "Schedule not found"
+                })),
+            ));
+        }
+        Err(err) => {
+            error!("Failed to get schedule: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to retrieve schedule"
+                })),
+            ));
+        }
+    };
+
+    let runs = match state.database.list_schedule_runs(schedule_uuid, 20).await {
+        Ok(runs) => runs,
+        Err(err) => {
+            error!("Failed to get schedule runs: {}", err);
+            Vec::new()
+        }
+    };
+
+    let mut response = schedule_json(&schedule);
+    response["runs"] = runs
+        .into_iter()
+        .map(|r| {
+            serde_json::json!({
+                "measurement_id": r.measurement_id,
+                "error": r.error,
+                "started_at": r.started_at
+            })
+        })
+        .collect();
+
+    Ok(Json(response))
+}
+
+// Handler for deleting a schedule; measurements it already started are kept (client-facing)
+async fn delete_schedule_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(schedule_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let schedule_uuid = parse_schedule_id(&schedule_id)?;
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+
+    match state
+        .database
+        .delete_schedule(schedule_uuid, &user_hash)
+        .await
+    {
+        Ok(true) => Ok(Json(serde_json::json!({
+            "id": schedule_uuid,
+            "deleted": true
+        }))),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": 404,
+                "message": "Schedule not found"
+            })),
+        )),
+        Err(err) => {
+            error!("Failed to delete schedule: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to delete schedule"
+                })),
+            ))
+        }
+    }
+}
+
+// Maximum number of target lists a single user can have
+const MAX_TARGET_LISTS_PER_USER: usize = 50;
+// Maximum number of destinations in one target list
+const MAX_TARGETS_PER_LIST: usize = 50_000;
+
+// Body for creating a target list
+#[derive(serde::Deserialize)]
+struct CreateTargetListRequest {
+    name: String,
+    targets: Vec<String>,
+}
+
+// Body for updating a target list; omitted fields are left unchanged
+#[derive(serde::Deserialize)]
+struct UpdateTargetListRequest {
+    name: Option<String>,
+    targets: Option<Vec<String>>,
+}
+
+fn target_list_json(list: &TargetList) -> serde_json::Value {
+    serde_json::json!({
+        "id": list.id,
+        "name": list.name,
+        "target_count": list.target_count,
+        "created_at": list.created_at,
+        "updated_at": list.updated_at
+    })
+}
+
+fn parse_target_list_id(list_id: &str) -> Result<Uuid, (StatusCode, Json<serde_json::Value>)> {
+    Uuid::parse_str(list_id).map_err(|_| bad_request("Invalid target list ID format"))
+}
+
+fn validate_target_list_name(name: &str) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if name.trim().is_empty() || name.len() > 255 {
+        return Err(bad_request(
+            "Target list name must be between 1 and 255 characters",
+        ));
+    }
+    Ok(())
+}
+
+// Validate and canonicalize the destinations of a target list
+fn validate_target_list_targets(
+    targets: &[String],
+) -> Result<Vec<String>, (StatusCode, Json<serde_json::Value>)> {
+    if targets.is_empty() {
+        return Err(bad_request("Target list cannot be empty"));
+    }
+    if targets.len() > MAX_TARGETS_PER_LIST {
+        return Err(bad_request(format!(
+            "Target list cannot contain more than {} targets",
+            MAX_TARGETS_PER_LIST
+        )));
+    }
+    probe::validate_targets(targets)
+        .map_err(|err| bad_request(format!("Target validation failed: {}", err)))
+}
+
+fn target_list_not_found() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({
+            "error": 404,
+            "message": "Target list not found"
+        })),
+    )
+}
+
+// Handler for saving a target list (client-facing)
+async fn create_target_list_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Json(body): Json<CreateTargetListRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    validate_target_list_name(&body.name)?;
+    let targets = validate_target_list_targets(&body.targets)?;
+
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+    match state.database.list_user_target_lists(&user_hash).await {
+        Ok(existing) if existing.len() >= MAX_TARGET_LISTS_PER_USER => {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(serde_json::json!({
+                    "error": 429,
+                    "message": format!(
+                        "Target list limit reached ({} per user)",
+                        MAX_TARGET_LISTS_PER_USER
+                    )
+                })),
+            ));
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("Failed to list target lists: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to create target list"
+                })),
+            ));
+        }
+    }
+
+    match state
+        .database
+        .create_target_list(&user_hash, &body.name, &targets)
+        .await
+    {
+        Ok(list) => {
+            debug!(
+                "User {} created target list {} with {} targets",
+                auth_info.sub, list.id, list.target_count
+            );
+            Ok((StatusCode::CREATED, Json(target_list_json(&list))))
+        }
+        Err(err) => {
+            error!("Failed to create target list: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to create target list"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for listing the authenticated user's target lists (client-facing)
+async fn list_target_lists_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+
+    match state.database.list_user_target_lists(&user_hash).await {
+        Ok(lists) => Ok(Json(serde_json::Value::Array(
+            lists.iter().map(target_list_json).collect(),
+        ))),
+        Err(err) => {
+            error!("Failed to list target lists: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to retrieve target lists"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for getting a target list with its destinations (client-facing)
+async fn get_target_list_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(list_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let list_uuid = parse_target_list_id(&list_id)?;
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+
+    let internal_error = |err: sqlx::Error| {
+        error!("Failed to get target list: {}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": 500,
+                "message": "Failed to retrieve target list"
+            })),
+        )
+    };
+
+    let list = state
+        .database
+        .get_target_list(list_uuid, &user_hash)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(target_list_not_found)?;
+    let targets = state
+        .database
+        .get_target_list_targets(list_uuid, &user_hash)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(target_list_not_found)?;
+
+    let mut response = target_list_json(&list);
+    response["targets"] = serde_json::json!(targets);
+    Ok(Json(response))
+}
+
+// Handler for renaming a target list or replacing its destinations (client-facing)
+async fn update_target_list_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(list_id): Path<String>,
+    Json(body): Json<UpdateTargetListRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let list_uuid = parse_target_list_id(&list_id)?;
+
+    if let Some(name) = &body.name {
+        validate_target_list_name(name)?;
+    }
+    let targets = match &body.targets {
+        Some(targets) => Some(validate_target_list_targets(targets)?),
+        None => None,
+    };
+
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+    match state
+        .database
+        .update_target_list(
+            list_uuid,
+            &user_hash,
+            body.name.as_deref(),
+            targets.as_deref(),
+        )
+        .await
+    {
+        Ok(Some(list)) => Ok(Json(target_list_json(&list))),
+        Ok(None) => Err(target_list_not_found()),
+        Err(err) => {
+            error!("Failed to update target list: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to update target list"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for deleting a target list (client-facing)
+async fn delete_target_list_handler(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(list_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let list_uuid = parse_target_list_id(&list_id)?;
+    let user_hash = crate::hash_user_identifier(&auth_info.sub);
+
+    match state
+        .database
+        .delete_target_list(list_uuid, &user_hash)
+        .await
+    {
+        Ok(true) => Ok(Json(serde_json::json!({
+            "id": list_uuid,
+            "deleted": true
+        }))),
+        Ok(false) => Err(target_list_not_found()),
+        Err(err) => {
+            error!("Failed to delete target list: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to delete target list"
+                })),
+            ))
+        }
+    }
+}
+
+// Body for adding or removing blocklist entries
+#[derive(serde::Deserialize)]
+struct BlocklistPrefixesRequest {
+    prefixes: Vec<String>,
+}
+
+fn parse_blocklist_prefixes(
+    prefixes: &[String],
+) -> Result<Vec<Ipv6Net>, (StatusCode, Json<serde_json::Value>)> {
+    if prefixes.is_empty() {
+        return Err(bad_request("Prefix list cannot be empty"));
+    }
+    prefixes
+        .iter()
+        .map(|p| blocklist::parse_prefix(p).map_err(|err| bad_request(err.to_string())))
+        .collect()
+}
+
+fn blocklist_update_failed(err: anyhow::Error) -> (StatusCode, Json<serde_json::Value>) {
+    error!("Failed to update blocklist: {}", err);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(serde_json::json!({
+            "error": 500,
+            "message": format!("Failed to update blocklist: {}", err)
+        })),
+    )
+}
+
+// Handler for listing blocked prefixes (admin)
+async fn get_blocklist(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let prefixes: Vec<String> = state
+        .blocklist
+        .prefixes()
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    let action = match state.blocklist.action() {
+        blocklist::BlocklistAction::Reject => "reject",
+        blocklist::BlocklistAction::Filter => "filter",
+    };
+    Json(serde_json::json!({
+        "action": action,
+        "prefixes": prefixes
+    }))
+}
+
+// Handler for blocking prefixes (admin)
+async fn add_blocklist_prefixes(
+    State(state): State<AppState>,
+    Json(body): Json<BlocklistPrefixesRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let prefixes = parse_blocklist_prefixes(&body.prefixes)?;
+    let added = state
+        .blocklist
+        .add(&prefixes)
+        .await
+        .map_err(blocklist_update_failed)?;
+    info!("Added {} prefixes to the blocklist", added);
+
+    Ok(Json(serde_json::json!({
+        "added": added,
+        "total": state.blocklist.len()
+    })))
+}
+
+// Handler for unblocking prefixes (admin)
+async fn remove_blocklist_prefixes(
+    State(state): State<AppState>,
+    Json(body): Json<BlocklistPrefixesRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let prefixes = parse_blocklist_prefixes(&body.prefixes)?;
+    let removed = state
+        .blocklist
+        .remove(&prefixes)
+        .await
+        .map_err(blocklist_update_failed)?;
+    info!("Removed {} prefixes from the blocklist", removed);
+
+    Ok(Json(serde_json::json!({
+        "removed": removed,
+        "total": state.blocklist.len()
+    })))
+}
+
+// Handler for re-reading the blocklist file (admin)
+async fn reload_blocklist(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let total = state
+        .blocklist
+        .reload()
+        .await
+        .map_err(blocklist_update_failed)?;
+    Ok(Json(serde_json::json!({ "total": total })))
+}
+
+// Handler for getting the quota policy of a user (admin)
+async fn get_user_quota(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = crate::hash_user_identifier(&user_id);
+    let result = async {
+        let custom = state.database.get_user_quota(&user_hash).await?;
+        let policy = quota::user_policy(&state.database, &state.quota, &user_hash).await?;
+        let windows = quota::policy_usage(
+            &state.database,
+            &policy,
+            state.quota_charging,
+            &user_hash,
+            chrono::Utc::now(),
+        )
+        .await?;
+        Ok::<_, sqlx::Error>((custom, windows))
+    };
+    match result.await {
+        Ok((custom, windows)) => Ok(Json(serde_json::json!({
+            "user_hash": user_hash,
+            "is_default": custom.is_none(),
+            "updated_at": custom.map(|quota| quota.updated_at),
+            "quota": windows
+        }))),
+        Err(err) => {
+            error!("Failed to get user quota: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to get user quota"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for setting the quota policy of a user (admin)
+async fn set_user_quota(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+    Json(policy): Json<quota::QuotaPolicy>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if let Err(message) = policy.validate() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": 400,
+                "message": message
+            })),
+        ));
+    }
+
+    let user_hash = crate::hash_user_identifier(&user_id);
+    let windows = serde_json::to_string(&policy.windows).expect("quota windows serialize");
+    match state.database.set_user_quota(&user_hash, &windows).await {
+        Ok(quota) => {
+            info!("Set quota policy of user {} to {}", user_hash, windows);
+            Ok(Json(serde_json::json!({
+                "user_hash": quota.user_hash,
+                "windows": policy.windows,
+                "updated_at": quota.updated_at
+            })))
+        }
+        Err(err) => {
+            error!("Failed to set user quota: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to set user quota"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for removing the quota policy of a user, back to the defaults (admin)
+async fn delete_user_quota(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = crate::hash_user_identifier(&user_id);
+    match state.database.delete_user_quota(&user_hash).await {
+        Ok(true) => {
+            info!("Removed quota policy of user {}", user_hash);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": 404,
+                "message": "User has no quota policy"
+            })),
+        )),
+        Err(err) => {
+            error!("Failed to delete user quota: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to delete user quota"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for previewing what the retention policies would purge now (admin)
+async fn preview_retention(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    match retention::preview_retention(&state.database, &state.retention, chrono::Utc::now()).await
+    {
+        Ok(purgeable) => Ok(Json(serde_json::json!({
+            "enabled": state.retention.is_enabled(),
+            "policy": state.retention,
+            "purgeable": purgeable
+        }))),
+        Err(err) => {
+            error!("Failed to preview retention: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to preview retention"
+                })),
+            ))
+        }
+    }
+}
+
+// Handler for agents to update measurement status (agent-facing)
+#[derive(serde::Deserialize)]
+struct UpdateMeasurementStatusRequest {
+    sent_probes: i32,
+    is_complete: bool,
+}
+
+async fn update_measurement_status(
+    State(state): State<AppState>,
+    Path((agent_id, measurement_id)): Path<(String, String)>,
+    Json(request): Json<UpdateMeasurementStatusRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let measurement_uuid = match Uuid::parse_str(&measurement_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({
+                    "error": 400,
+                    "message": "Invalid measurement ID format"
+                })),
+            ));
+        }
+    };
+
+    // We need to find the user_hash for this measurement by looking up the tracking entry
+    let tracking_entry = match state
+        .database
+        .get_measurement_tracking_by_agent(measurement_uuid, &agent_id)
+        .await
+    {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "error": 404,
+                    "message": "Measurement tracking entry not found for this agent"
+                })),
+            ));
+        }
+        Err(err) => {
+            error!("Failed to get measurement tracking: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to retrieve measurement tracking"
+                })),
+            ));
+        }
+    };
+
+    let user_hash = &tracking_entry.user_hash;
+
+    match state
+        .database
+        .update_measurement_probe_count(
             measurement_uuid,
             user_hash,
             &agent_id,
@@ -1073,6 +3147,198 @@ async fn update_measurement_status(
     }
 }
 
+// Query parameters for polling an agent's cancellations. Parsed in the handler
+// for the standard JSON error shape, like the measurement list.
+#[derive(serde::Deserialize)]
+struct ListCancellationsQuery {
+    after: Option<String>,
+    limit: Option<String>,
+}
+
+// Handler for agents to poll the measurements cancelled since a cursor (agent-facing)
+async fn list_agent_cancellations(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+    Query(params): Query<ListCancellationsQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let after = match params.after.as_deref() {
+        None | Some("") => 0,
+        Some(raw) => match raw.parse::<i64>() {
+            Ok(n) if n >= 0 => n,
+            _ => {
+                return Err(bad_request(
+                    "Invalid 'after' query parameter: expected a cursor returned by this endpoint",
+                ));
+            }
+        },
+    };
+    let limit = match params.limit.as_deref() {
+        None | Some("") => 100,
+        Some(raw) => match raw.parse::<i64>() {
+            Ok(n) => n.clamp(1, 1000) as i32,
+            Err(_) => {
+                return Err(bad_request(
+                    "Invalid 'limit' query parameter: expected an integer between 1 and 1000",
+                ));
+            }
+        },
+    };
+
+    let cancellations = state
+        .database
+        .get_agent_cancellations(&agent_id, after, limit)
+        .await
+        .map_err(|err| {
+            error!("Failed to get agent cancellations: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to retrieve cancellations"
+                })),
+            )
+        })?;
+
+    // Poll again from the returned cursor to get only newer cancellations
+    let cursor = cancellations.last().map_or(after, |c| c.id);
+    let cancellations: Vec<_> = cancellations
+        .iter()
+        .map(|c| {
+            serde_json::json!({
+                "measurement_id": c.measurement_id,
+                "requested_at": c.requested_at,
+                "acknowledged": c.acknowledged_at.is_some()
+            })
+        })
+        .collect();
+
+    Ok(Json(serde_json::json!({
+        "cancellations": cancellations,
+        "cursor": cursor
+    })))
+}
+
+// Handler for agents to acknowledge they stopped a cancelled measurement (agent-facing)
+async fn acknowledge_cancellation(
+    State(state): State<AppState>,
+    Path((agent_id, measurement_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let measurement_uuid = Uuid::parse_str(&measurement_id)
+        .map_err(|_| bad_request("Invalid measurement ID format"))?;
+
+    match state
+        .database
+        .acknowledge_cancellation(measurement_uuid, &agent_id)
+        .await
+    {
+        Ok(Some(cancellation)) => {
+            debug!(
+                "Agent {} acknowledged the cancellation of measurement {}",
+                agent_id, measurement_id
+            );
+            Ok(Json(serde_json::json!({
+                "measurement_id": cancellation.measurement_id,
+                "agent_id": cancellation.agent_id,
+                "requested_at": cancellation.requested_at,
+                "acknowledged_at": cancellation.acknowledged_at
+            })))
+        }
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": 404,
+                "message": "No cancellation of this measurement for this agent"
+            })),
+        )),
+        Err(err) => {
+            error!("Failed to acknowledge cancellation: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to acknowledge cancellation"
+                })),
+            ))
+        }
+    }
+}
+
+// Upper bound on each readiness check, so a hung dependency fails the probe
+// instead of blocking it
+const READINESS_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Handler for the liveness probe: the process is up and serving requests
+async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// Result of a readiness check as JSON, and whether it passed
+fn readiness_check<T, E: std::fmt::Display>(
+    result: Result<Result<T, E>, tokio::time::error::Elapsed>,
+    details: impl FnOnce(T) -> serde_json::Value,
+) -> (bool, serde_json::Value) {
+    match result {
+        Ok(Ok(value)) => {
+            let mut check = details(value);
+            check["status"] = "ok".into();
+            (true, check)
+        }
+        Ok(Err(err)) => (
+            false,
+            serde_json::json!({ "status": "error", "message": err.to_string() }),
+        ),
+        Err(_) => (
+            false,
+            serde_json::json!({ "status": "error", "message": "Timed out" }),
+        ),
+    }
+}
+
+// Handler for the readiness probe: 503 when submissions would fail
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    let (database_ok, database) = readiness_check(
+        tokio::time::timeout(READINESS_CHECK_TIMEOUT, state.database.ping()).await,
+        |()| serde_json::json!({}),
+    );
+
+    // With the outbox, submissions are queued while Kafka is unavailable
+    let (kafka_ok, mut kafka) = readiness_check(
+        tokio::time::timeout(READINESS_CHECK_TIMEOUT, state.probe_sink.check()).await,
+        |()| serde_json::json!({}),
+    );
+    kafka["required"] = (!state.outbox).into();
+
+    let (jwks_ok, jwks) = if state.bypass_jwt_validation {
+        (true, serde_json::json!({ "status": "skipped" }))
+    } else {
+        readiness_check(
+            tokio::time::timeout(READINESS_CHECK_TIMEOUT, jwt::JwtValidator::check(&state)).await,
+            |age| serde_json::json!({ "age_seconds": age.as_secs() }),
+        )
+    };
+
+    let ready = database_ok && (kafka_ok || state.outbox) && jwks_ok;
+    if !ready {
+        warn!("Gateway is not ready: database {database}, kafka {kafka}, jwks {jwks}");
+    }
+
+    (
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(serde_json::json!({
+            "status": if ready { "ready" } else { "unavailable" },
+            "checks": {
+                "database": database,
+                "kafka": kafka,
+                "jwks": jwks
+            }
+        })),
+    )
+}
+
 /// Compute a consistent hash for a user identifier
 /// This is used for database storage and lookup
 pub fn hash_user_identifier(user_id: &str) -> String {
@@ -1088,20 +3354,22 @@ pub async fn get_or_create_user_id(
     database: &Database,
     user_identifier: &str,
 ) -> Result<u32, String> {
-    let user_hash = hash_user_identifier(user_identifier);
+    get_or_create_user_id_by_hash(database, &hash_user_identifier(user_identifier)).await
+}
 
+/// Get or create the 32-bit user ID of the user with hash `user_hash`
+pub async fn get_or_create_user_id_by_hash(
+    database: &Database,
+    user_hash: &str,
+) -> Result<u32, String> {
     // First, try to get existing user ID
-    match database.get_user_id_by_hash(&user_hash).await {
+    match database.get_user_id_by_hash(user_hash).await {
         Ok(Some(user_id)) => {
-            tracing::debug!(
-                "Found existing user ID {} for user {}",
-                user_id,
-                user_identifier
-            );
+            tracing::debug!("Found existing user ID {} for user {}", user_id, user_hash);
             return Ok(user_id);
         }
         Ok(None) => {
-            tracing::debug!("Creating new user ID for user {}", user_identifier);
+            tracing::debug!("Creating new user ID for user {}", user_hash);
         }
         Err(e) => {
             return Err(format!("Database error when fetching user ID: {}", e));
@@ -1113,20 +3381,20 @@ pub async fn get_or_create_user_id(
     for attempt in 0..max_attempts {
         // Start with deterministic generation for consistency, then use random for fallback
         let candidate_id = if attempt == 0 {
-            generate_deterministic_user_id(&user_hash)
+            generate_deterministic_user_id(user_hash)
         } else {
             generate_random_user_id()
         };
 
         match database
-            .create_user_id_mapping(&user_hash, candidate_id)
+            .create_user_id_mapping(user_hash, candidate_id)
             .await
         {
             Ok(()) => {
                 tracing::debug!(
                     "Created new user ID {} for user {} (attempt {})",
                     candidate_id,
-                    user_identifier,
+                    user_hash,
                     attempt + 1
                 );
                 return Ok(candidate_id);
@@ -1139,11 +3407,11 @@ pub async fn get_or_create_user_id(
                     if error_msg.contains("user_hash") {
                         // Another request created this user while we were processing
                         // Try to fetch the existing user ID
-                        match database.get_user_id_by_hash(&user_hash).await {
+                        match database.get_user_id_by_hash(user_hash).await {
                             Ok(Some(existing_user_id)) => {
                                 tracing::debug!(
                                     "User {} was created by another request, using existing ID {}",
-                                    user_identifier,
+                                    user_hash,
                                     existing_user_id
                                 );
                                 return Ok(existing_user_id);
@@ -1152,7 +3420,7 @@ pub async fn get_or_create_user_id(
                                 // This shouldn't happen, but retry if it does
                                 tracing::warn!(
                                     "Race condition detected for user {}, retrying",
-                                    user_identifier
+                                    user_hash
                                 );
                                 continue;
                             }
@@ -1,16 +1,23 @@
 use anyhow::Result;
 use clap_verbosity_flag::{InfoLevel, Verbosity};
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use rdkafka::consumer::Consumer;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tracing::{error, info, warn};
 
 use clap::Parser;
 use saimiris_gateway::{
     AppState,
     agent::AgentStore,
+    archive,
+    blocklist::{Blocklist, BlocklistAction},
     create_app,
     database::{Database, DatabaseConfig, safe_database_target},
-    kafka,
+    kafka, outbox,
+    probe::{self, SpecialPurposeFilter},
+    progress, quota, retention, scheduler,
+    sink::{FileSink, MemorySink, ProbeSink, ProbeSinkKind},
 };
 
 /// Command line arguments for the gateway
@@ -33,15 +40,15 @@ pub struct Cli {
     #[arg(long = "kafka-topic", default_value = "saimiris-probes")]
     pub kafka_topic: String,
 
-    /// Kafka authentication protocol (PLAINTEXT or SASL_PLAINTEXT)
+    /// Kafka authentication protocol (PLAINTEXT, SASL_PLAINTEXT, SASL_SSL or SSL)
     #[arg(long = "kafka-auth-protocol", default_value = "PLAINTEXT")]
     pub kafka_auth_protocol: String,
 
-    /// Kafka SASL username (required for SASL_PLAINTEXT)
+    /// Kafka SASL username (required for SASL_PLAINTEXT and SASL_SSL)
     #[arg(long = "kafka-sasl-username")]
     pub kafka_sasl_username: Option<String>,
 
-    /// Kafka SASL password (required for SASL_PLAINTEXT)
+    /// Kafka SASL password (required for SASL_PLAINTEXT and SASL_SSL)
     #[arg(long = "kafka-sasl-password")]
     pub kafka_sasl_password: Option<String>,
 
@@ -49,6 +56,94 @@ pub struct Cli {
     #[arg(long = "kafka-sasl-mechanism", default_value = "SCRAM-SHA-512")]
     pub kafka_sasl_mechanism: String,
 
+    /// CA certificate (PEM) to verify the Kafka brokers with (SSL and SASL_SSL; default: system trust store)
+    #[arg(long = "kafka-ssl-ca-location")]
+    pub kafka_ssl_ca_location: Option<String>,
+
+    /// Client certificate (PEM) for mutual TLS (SSL and SASL_SSL)
+    #[arg(long = "kafka-ssl-certificate-location")]
+    pub kafka_ssl_certificate_location: Option<String>,
+
+    /// Client private key (PEM) for mutual TLS
+    #[arg(long = "kafka-ssl-key-location")]
+    pub kafka_ssl_key_location: Option<String>,
+
+    /// Password of the client private key
+    #[arg(long = "kafka-ssl-key-password")]
+    pub kafka_ssl_key_password: Option<String>,
+
+    /// Check that the Kafka broker certificates match their hostnames
+    #[arg(long = "kafka-ssl-verify-hostname", default_value_t = true, action = clap::ArgAction::Set)]
+    pub kafka_ssl_verify_hostname: bool,
+
+    /// Encoding of probe batches: legacy (per-agent JSON headers) or envelope (versioned ProbeBatch)
+    #[arg(long = "kafka-batch-format", default_value = "legacy")]
+    pub kafka_batch_format: String,
+
+    /// File of extra librdkafka producer properties, one key=value per line (e.g. linger.ms, compression.type, message.max.bytes, acks)
+    #[arg(long = "kafka-producer-config")]
+    pub kafka_producer_config: Option<String>,
+
+    /// Extra librdkafka producer property as key=value, overriding the config file; can be repeated
+    #[arg(long = "kafka-producer-property", value_parser = kafka::parse_property)]
+    pub kafka_producer_property: Vec<(String, String)>,
+
+    /// How probe batches reach agents: shared (one copy on the probes topic), agent-topic (a copy per agent on its own topic) or agent-partition (a copy per agent on its partition of the probes topic)
+    #[arg(long = "kafka-routing", default_value = "shared")]
+    pub kafka_routing: String,
+
+    /// Name of per-agent topics with agent-topic routing; {topic} is the probes topic and {agent} the agent ID
+    #[arg(long = "kafka-agent-topic-template", default_value = kafka::DEFAULT_AGENT_TOPIC_TEMPLATE)]
+    pub kafka_agent_topic_template: String,
+
+    /// Create missing per-agent topics, with the broker's default settings
+    #[arg(long = "kafka-create-topics", default_value = "false")]
+    pub kafka_create_topics: bool,
+
+    /// Number of partitions of the probes topic with agent-partition routing; read from the broker when unset
+    #[arg(long = "kafka-partitions")]
+    pub kafka_partitions: Option<i32>,
+
+    /// Where probe batches are dispatched: kafka, memory (discarded, for local runs) or file (NDJSON, see --probe-sink-file)
+    #[arg(long = "probe-sink", default_value = "kafka")]
+    pub probe_sink: String,
+
+    /// File the `file` probe sink appends to
+    #[arg(long = "probe-sink-file", default_value = "probes.ndjson")]
+    pub probe_sink_file: String,
+
+    /// Kafka transactional ID, unique per gateway instance. When set, each measurement's batches are delivered all together or not at all
+    #[arg(long = "kafka-transactional-id")]
+    pub kafka_transactional_id: Option<String>,
+
+    /// Kafka topic agents publish their progress to; when set, progress is read from it in addition to the agent status endpoint
+    #[arg(long = "kafka-progress-topic")]
+    pub kafka_progress_topic: Option<String>,
+
+    /// Consumer group of the gateways reading the progress topic
+    #[arg(long = "kafka-progress-group-id", default_value = progress::DEFAULT_PROGRESS_GROUP_ID)]
+    pub kafka_progress_group_id: String,
+
+    /// Queue probe batches in the database outbox and publish them from a background relay, so submissions survive Kafka outages
+    #[arg(long = "outbox", default_value = "false")]
+    pub outbox: bool,
+
+    /// Days completed and cancelled measurements are kept after their last update; their usage is then rolled up per day (kept forever if unset)
+    #[arg(long = "retention-measurement-days", value_parser = clap::value_parser!(u32).range(1..))]
+    pub retention_measurement_days: Option<u32>,
+
+    /// Days rows of the legacy probe_usage table are kept (kept forever if unset)
+    #[arg(long = "retention-probe-usage-days", value_parser = clap::value_parser!(u32).range(1..))]
+    pub retention_probe_usage_days: Option<u32>,
+
+    /// Default quota window as period=limit[+burst], where period is day, month or a rolling duration such as 30m, 24h or 7d (repeatable; users with their own quota policy or probe limit are not affected)
+    #[arg(long = "quota-window", value_parser = quota::parse_window, default_value = "day=10000")]
+    pub quota_windows: Vec<quota::QuotaWindow>,
+
+    /// What measurements charge to quotas: expected (the probes submitted), sent (the probes sent once agents are done) or refunded (the probes submitted, minus those unsent when cancelled or when their agent was lost)
+    #[arg(long = "quota-charging", default_value = "expected")]
+    pub quota_charging: quota::ChargingMode,
+
     /// Auth0 JWKS URI for JWT validation
     #[arg(long = "auth0-jwks-uri")]
     pub auth0_jwks_uri: Option<String>,
@@ -60,13 +155,38 @@ pub struct Cli {
     #[arg(long = "bypass-jwt", default_value = "false")]
     pub bypass_jwt: bool,
 
-    /// PostgreSQL database URL
+    /// Database URL: PostgreSQL, or SQLite for a `sqlite:` URL (e.g. `sqlite:///var/lib/saimiris/gateway.db`)
     #[arg(
         long = "database-url",
         default_value = "postgresql://localhost/saimiris_gateway"
     )]
     pub database_url: String,
 
+    /// Directory where submitted probe sets are archived (archiving is disabled if unset)
+    #[arg(long = "archive-dir")]
+    pub archive_dir: Option<String>,
+
+    /// File of blocked destination prefixes, one per line (edits made through the admin API are saved to it)
+    #[arg(long = "blocklist-file")]
+    pub blocklist_file: Option<String>,
+
+    /// What to do with probes towards blocked destinations (reject or filter)
+    #[arg(long = "blocklist-action", default_value = "filter")]
+    pub blocklist_action: String,
+
+    /// Policy override for a special-purpose destination category, as category=policy
+    /// (e.g. private=drop; policies: reject, drop, allow). Every category is rejected by default
+    #[arg(long = "special-purpose-policy", value_parser = probe::parse_special_purpose_override)]
+    pub special_purpose_policy: Vec<(probe::SpecialPurpose, probe::SpecialPurposePolicy)>,
+
+    /// Development mode: allow documentation and benchmarking prefixes as probe destinations
+    #[arg(long = "dev-mode", default_value = "false")]
+    pub dev_mode: bool,
+
+    /// Admin key for the admin API (the admin API is disabled if unset)
+    #[arg(long = "admin-key")]
+    pub admin_key: Option<String>,
+
     /// Metrics listen address
     #[arg(long = "metrics-address", default_value = "0.0.0.0:9090")]
     pub metrics_address: String,
@@ -91,6 +211,13 @@ fn set_metrics(metrics_address: SocketAddr) {
     let prom_builder = PrometheusBuilder::new();
     prom_builder
         .with_http_listener(metrics_address)
+        .set_buckets_for_metric(
+            Matcher::Full("saimiris_gateway_kafka_delivery_seconds".to_string()),
+            &[
+                0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ],
+        )
+        .expect("Invalid histogram buckets")
         .install()
         .expect("Failed to install Prometheus metrics exporter");
 
@@ -114,10 +241,83 @@ fn set_metrics(metrics_address: SocketAddr) {
         "saimiris_gateway_http_requests_total",
         "Total number of HTTP requests"
     );
+    metrics::describe_counter!(
+        "saimiris_gateway_schedule_runs_total",
+        "Total number of scheduled measurement runs dispatched"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_schedule_run_errors_total",
+        "Total number of scheduled measurement runs that failed"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_progress_events_total",
+        "Total number of agent progress events read from Kafka, by outcome"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_outbox_relayed_total",
+        "Total number of queued probe batches relayed from the outbox to Kafka"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_retention_purged_total",
+        "Total number of rows purged by the retention policies, by table"
+    );
     metrics::describe_gauge!(
         "saimiris_gateway_agents_active",
         "Number of currently active agents"
     );
+
+    // Kafka producer, from the librdkafka statistics
+    metrics::describe_histogram!(
+        "saimiris_gateway_kafka_delivery_seconds",
+        metrics::Unit::Seconds,
+        "Time from producing a probe batch to its delivery report"
+    );
+    metrics::describe_gauge!(
+        "saimiris_gateway_kafka_queue_messages",
+        "Messages waiting in the producer queue"
+    );
+    metrics::describe_gauge!(
+        "saimiris_gateway_kafka_queue_bytes",
+        "Bytes waiting in the producer queue"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_kafka_produced_messages_total",
+        "Total number of messages produced to the brokers"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_kafka_produced_bytes_total",
+        "Total number of message bytes produced to the brokers"
+    );
+    metrics::describe_gauge!(
+        "saimiris_gateway_kafka_broker_inflight_requests",
+        "Requests sent to a broker and awaiting a response"
+    );
+    metrics::describe_gauge!(
+        "saimiris_gateway_kafka_broker_outbuf_requests",
+        "Requests queued for sending to a broker"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_kafka_broker_tx_errors_total",
+        "Total number of request transmission errors to a broker"
+    );
+    metrics::describe_gauge!(
+        "saimiris_gateway_kafka_broker_rtt_avg_seconds",
+        metrics::Unit::Seconds,
+        "Average broker round-trip time over the last statistics interval"
+    );
+    metrics::describe_gauge!(
+        "saimiris_gateway_kafka_broker_rtt_p99_seconds",
+        metrics::Unit::Seconds,
+        "99th percentile broker round-trip time over the last statistics interval"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_kafka_topic_produced_bytes_total",
+        "Total number of bytes produced to a topic"
+    );
+    metrics::describe_counter!(
+        "saimiris_gateway_kafka_topic_produced_messages_total",
+        "Total number of messages produced to a topic"
+    );
 }
 
 #[tokio::main]
@@ -147,45 +347,172 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // Initialize Kafka configuration from CLI parameters
+    let tls = kafka::TlsConfig {
+        ca_location: cli.kafka_ssl_ca_location,
+        certificate_location: cli.kafka_ssl_certificate_location,
+        key_location: cli.kafka_ssl_key_location,
+        key_password: cli.kafka_ssl_key_password,
+        verify_hostname: cli.kafka_ssl_verify_hostname,
+    };
     let kafka_auth = match cli.kafka_auth_protocol.as_str() {
         "PLAINTEXT" => kafka::KafkaAuth::PlainText,
-        "SASL_PLAINTEXT" => {
+        "SASL_PLAINTEXT" | "SASL_SSL" => {
             let username = cli.kafka_sasl_username.ok_or_else(|| {
-                anyhow::anyhow!("kafka-sasl-username is required for SASL_PLAINTEXT")
+                anyhow::anyhow!(
+                    "kafka-sasl-username is required for {}",
+                    cli.kafka_auth_protocol
+                )
             })?;
             let password = cli.kafka_sasl_password.ok_or_else(|| {
-                anyhow::anyhow!("kafka-sasl-password is required for SASL_PLAINTEXT")
+                anyhow::anyhow!(
+                    "kafka-sasl-password is required for {}",
+                    cli.kafka_auth_protocol
+                )
             })?;
-            kafka::KafkaAuth::SaslPlainText(kafka::SaslAuth {
+            let sasl_auth = kafka::SaslAuth {
                 username,
                 password,
                 mechanism: cli.kafka_sasl_mechanism,
-            })
+            };
+            if cli.kafka_auth_protocol == "SASL_SSL" {
+                tls.validate().map_err(|err| anyhow::anyhow!(err))?;
+                kafka::KafkaAuth::SaslSsl(sasl_auth, tls)
+            } else {
+                kafka::KafkaAuth::SaslPlainText(sasl_auth)
+            }
+        }
+        "SSL" => {
+            tls.validate().map_err(|err| anyhow::anyhow!(err))?;
+            kafka::KafkaAuth::Ssl(tls)
         }
         _ => {
             return Err(anyhow::anyhow!(
-                "Invalid Kafka authentication protocol: {}. Use PLAINTEXT or SASL_PLAINTEXT",
+                "Invalid Kafka authentication protocol: {}. Use PLAINTEXT, SASL_PLAINTEXT, SASL_SSL or SSL",
                 cli.kafka_auth_protocol
             ));
         }
     };
 
-    let kafka_config = kafka::KafkaConfig {
+    let routing = match cli.kafka_routing.as_str() {
+        "shared" => kafka::TopicRouting::Shared,
+        "agent-topic" => {
+            if !cli.kafka_agent_topic_template.contains("{agent}") {
+                return Err(anyhow::anyhow!(
+                    "kafka-agent-topic-template must contain {{agent}}"
+                ));
+            }
+            kafka::TopicRouting::AgentTopic {
+                template: cli.kafka_agent_topic_template.clone(),
+                create: cli.kafka_create_topics,
+            }
+        }
+        "agent-partition" => {
+            if cli
+                .kafka_partitions
+                .is_some_and(|partitions| partitions < 1)
+            {
+                return Err(anyhow::anyhow!("kafka-partitions must be at least 1"));
+            }
+            // Resolved from the broker below when unset
+            kafka::TopicRouting::AgentPartition {
+                partitions: cli.kafka_partitions.unwrap_or(1),
+            }
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Unsupported Kafka routing: {}",
+                cli.kafka_routing
+            ));
+        }
+    };
+
+    let mut producer_properties = match &cli.kafka_producer_config {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path).map_err(|err| {
+                anyhow::anyhow!("Failed to read Kafka producer config {}: {}", path, err)
+            })?;
+            kafka::parse_properties_file(&contents)
+                .map_err(|err| anyhow::anyhow!("Invalid Kafka producer config {}: {}", path, err))?
+        }
+        None => Vec::new(),
+    };
+    producer_properties.extend(cli.kafka_producer_property.iter().cloned());
+    kafka::validate_producer_properties(&producer_properties)
+        .map_err(|err| anyhow::anyhow!(err))?;
+
+    let mut kafka_config = kafka::KafkaConfig {
         brokers: cli.kafka_brokers.clone(),
         topic: cli.kafka_topic.clone(),
         auth: kafka_auth,
+        batch_format: cli
+            .kafka_batch_format
+            .parse()
+            .map_err(|err: String| anyhow::anyhow!(err))?,
+        transactional_id: cli.kafka_transactional_id.clone(),
+        routing,
+        producer_properties,
     };
 
-    // Create Kafka producer
-    let kafka_producer = match kafka::create_producer(&kafka_config) {
-        Ok(producer) => {
-            info!("Connected to Kafka brokers: {}", kafka_config.brokers);
-            info!("Using Kafka topic: {}", kafka_config.topic);
-            producer
+    // Create the probe sink
+    let probe_sink_kind: ProbeSinkKind = cli
+        .probe_sink
+        .parse()
+        .map_err(|err: String| anyhow::anyhow!(err))?;
+    let probe_sink: Arc<dyn ProbeSink> = match probe_sink_kind {
+        ProbeSinkKind::Kafka => match kafka::create_producer(&kafka_config) {
+            Ok(producer) => {
+                info!("Connected to Kafka brokers: {}", kafka_config.brokers);
+                info!("Using Kafka topic: {}", kafka_config.topic);
+                for (key, value) in &kafka_config.producer_properties {
+                    info!("Kafka producer property {}={}", key, value);
+                }
+                if let Some(transactional_id) = &kafka_config.transactional_id {
+                    info!("Using Kafka transactions with ID: {}", transactional_id);
+                }
+                match &mut kafka_config.routing {
+                    kafka::TopicRouting::Shared => {}
+                    kafka::TopicRouting::AgentTopic { template, create } => {
+                        info!(
+                            "Routing probe batches to per-agent topics {} (creation {})",
+                            template,
+                            if *create { "enabled" } else { "disabled" }
+                        );
+                    }
+                    kafka::TopicRouting::AgentPartition { partitions } => {
+                        // Agents derive their partition from the same count
+                        let available =
+                            kafka::topic_partitions(&producer, &kafka_config.topic).await?;
+                        match cli.kafka_partitions {
+                            Some(configured) if configured != available => {
+                                return Err(anyhow::anyhow!(
+                                    "kafka-partitions is {} but topic {} has {} partitions",
+                                    configured,
+                                    kafka_config.topic,
+                                    available
+                                ));
+                            }
+                            _ => *partitions = available,
+                        }
+                        info!(
+                            "Routing probe batches to per-agent partitions of {} ({} partitions)",
+                            kafka_config.topic, partitions
+                        );
+                    }
+                }
+                Arc::new(kafka::KafkaSink::new(producer, kafka_config.clone()))
+            }
+            Err(err) => {
+                error!("Failed to create Kafka producer: {}", err);
+                return Err(anyhow::anyhow!("Failed to create Kafka producer: {}", err));
+            }
+        },
+        ProbeSinkKind::Memory => {
+            warn!("⚠️ Probe batches are kept in memory and never reach the agents");
+            Arc::new(MemorySink::new())
         }
-        Err(err) => {
-            error!("Failed to create Kafka producer: {}", err);
-            return Err(anyhow::anyhow!("Failed to create Kafka producer: {}", err));
+        ProbeSinkKind::File => {
+            info!("Writing probe batches to {}", cli.probe_sink_file);
+            Arc::new(FileSink::new(&cli.probe_sink_file))
         }
     };
 
@@ -216,16 +543,89 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
+    // Initialize the probe archive
+    let probe_archive = match &cli.archive_dir {
+        Some(dir) => {
+            info!("Archiving submitted probes to {}", dir);
+            Some(archive::ProbeArchive::filesystem(dir))
+        }
+        None => {
+            info!("Probe archiving is disabled");
+            None
+        }
+    };
+
+    // Load the destination blocklist
+    let blocklist_action: BlocklistAction = cli
+        .blocklist_action
+        .parse()
+        .map_err(|err: String| anyhow::anyhow!(err))?;
+    let blocklist = match &cli.blocklist_file {
+        Some(path) => match Blocklist::from_file(path, blocklist_action) {
+            Ok(blocklist) => blocklist,
+            Err(err) => {
+                error!("Failed to load blocklist from {}: {}", path, err);
+                return Err(anyhow::anyhow!("Failed to load blocklist: {}", err));
+            }
+        },
+        None => Blocklist::new(blocklist_action),
+    };
+    info!(
+        "Blocklist has {} prefixes (action: {})",
+        blocklist.len(),
+        cli.blocklist_action
+    );
+
+    // Special-purpose destination policy
+    let mut special_purpose = if cli.dev_mode {
+        warn!("⚠️ Development mode: documentation and benchmarking destinations are allowed");
+        SpecialPurposeFilter::development()
+    } else {
+        SpecialPurposeFilter::default()
+    };
+    for (category, policy) in &cli.special_purpose_policy {
+        info!("Special-purpose {} destinations: {:?}", category, policy);
+        special_purpose = special_purpose.with_policy(*category, *policy);
+    }
+
+    let retention_policy = retention::RetentionPolicy {
+        measurement_days: cli.retention_measurement_days,
+        probe_usage_days: cli.retention_probe_usage_days,
+    };
+
+    let quota_policy = quota::QuotaPolicy {
+        windows: cli.quota_windows.clone(),
+    };
+    quota_policy
+        .validate()
+        .map_err(|err| anyhow::anyhow!(err))?;
+    info!(
+        "Default quota policy: {:?}, charging {} probes",
+        quota_policy.windows, cli.quota_charging
+    );
+
+    if cli.admin_key.is_none() {
+        info!("Admin API is disabled (no admin key configured)");
+    }
+
     // Create app state with agent key for authentication
     let state = AppState {
         agent_store: agent_store.clone(),
         agent_key: cli.agent_key,
         kafka_config,
-        kafka_producer,
+        probe_sink,
         auth0_jwks_uri: cli.auth0_jwks_uri.clone(),
         auth0_issuer: cli.auth0_issuer.clone(),
         bypass_jwt_validation: cli.bypass_jwt,
         database,
+        probe_archive,
+        admin_key: cli.admin_key.clone(),
+        blocklist,
+        special_purpose,
+        outbox: cli.outbox,
+        retention: retention_policy,
+        quota: quota_policy,
+        quota_charging: cli.quota_charging,
     };
 
     if cli.bypass_jwt {
@@ -234,6 +634,7 @@ async fn main() -> anyhow::Result<()> {
 
     // Spawn background task to clean up stale agents every 5 minutes
     let cleanup_agent_store = agent_store.clone();
+    let cleanup_database = state.database.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(300)); // 5 minutes
         loop {
@@ -243,9 +644,90 @@ async fn main() -> anyhow::Result<()> {
             if !removed.is_empty() {
                 info!("Removed {} stale agents: {:?}", removed.len(), removed);
             }
+            // Agents that stopped reporting progress, as known to every
+            // gateway, won't send the rest of their probes: refund them
+            match cleanup_database
+                .refund_lost_measurements(chrono::Utc::now() - max_age)
+                .await
+            {
+                Ok(0) => {}
+                Ok(refunded) => info!(
+                    "Refunded the unsent probes of {} measurements of lost agents",
+                    refunded
+                ),
+                Err(err) => error!("Failed to refund the measurements of lost agents: {}", err),
+            }
         }
     });
 
+    // Spawn the scheduler that fires due measurement schedules
+    let scheduler_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+            scheduler::SCHEDULER_TICK_SECONDS,
+        ));
+        loop {
+            interval.tick().await;
+            let fired = scheduler::run_due_schedules(&scheduler_state).await;
+            if fired > 0 {
+                info!("Fired {} scheduled measurements", fired);
+            }
+        }
+    });
+
+    // Spawn the relay publishing probe batches queued in the outbox
+    if cli.outbox {
+        info!("Probe batches are queued in the outbox and relayed to Kafka");
+        let relay_state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+                outbox::OUTBOX_RELAY_TICK_SECONDS,
+            ));
+            loop {
+                interval.tick().await;
+                let relayed = outbox::relay_outbox(&relay_state).await;
+                if relayed > 0 {
+                    info!("Relayed {} queued probe batches", relayed);
+                }
+            }
+        });
+    }
+
+    // Spawn the job applying the retention policies
+    if retention_policy.is_enabled() {
+        info!("Applying retention policy {:?}", retention_policy);
+        let retention_database = state.database.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+                retention::RETENTION_TICK_SECONDS,
+            ));
+            loop {
+                interval.tick().await;
+                retention::apply_retention(
+                    &retention_database,
+                    &retention_policy,
+                    chrono::Utc::now(),
+                )
+                .await;
+            }
+        });
+    }
+
+    // Spawn the consumer applying agent progress read from Kafka
+    if let Some(topic) = &cli.kafka_progress_topic {
+        let consumer = kafka::create_consumer(&state.kafka_config, &cli.kafka_progress_group_id)
+            .and_then(|consumer| consumer.subscribe(&[topic]).map(|()| consumer))
+            .map_err(|err| {
+                error!("Failed to create Kafka progress consumer: {}", err);
+                anyhow::anyhow!("Failed to create Kafka progress consumer: {}", err)
+            })?;
+        info!(
+            "Reading agent progress from Kafka topic {} (group {})",
+            topic, cli.kafka_progress_group_id
+        );
+        tokio::spawn(progress::consume_progress(state.database.clone(), consumer));
+    }
+
     let app = create_app(state);
 
     let addr: SocketAddr = cli.address.parse()?;
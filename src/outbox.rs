@@ -0,0 +1,115 @@
+use chrono::{Duration, Utc};
+use metrics::counter;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::database::OutboxMessage;
+use crate::kafka::OutboundMessage;
+
+/// How often the background relay publishes queued probe batches
+pub const OUTBOX_RELAY_TICK_SECONDS: u64 = 5;
+
+/// How long claimed messages are held by a relay before another one may retry
+/// them. Longer than a Kafka transaction can take to commit.
+const CLAIM_LEASE_SECONDS: i64 = 60;
+
+/// Upper bound on the number of measurements relayed in one tick
+const MAX_MEASUREMENTS_PER_TICK: i32 = 50;
+
+fn outbound_message(message: &OutboxMessage) -> Result<OutboundMessage, serde_json::Error> {
+    Ok(OutboundMessage {
+        key: message.message_key.clone(),
+        payload: message.payload.clone(),
+        headers: serde_json::from_str(&message.headers)?,
+        topic: message.kafka_topic.clone(),
+        partition: message.kafka_partition,
+    })
+}
+
+/// Publish the messages waiting in the outbox, one measurement at a time and
+/// in the order they were queued. A measurement whose messages fail to publish
+/// is released and retried on a later tick. Returns the number of messages
+/// published.
+pub async fn relay_outbox(state: &AppState) -> usize {
+    let now = Utc::now();
+    let lease_until = now + Duration::seconds(CLAIM_LEASE_SECONDS);
+
+    let claimed = match state
+        .database
+        .claim_outbox_messages(now, lease_until, MAX_MEASUREMENTS_PER_TICK)
+        .await
+    {
+        Ok(claimed) => claimed,
+        Err(err) => {
+            error!("Failed to claim outbox messages: {}", err);
+            return 0;
+        }
+    };
+
+    // Claimed messages come ordered by ID: group them by measurement,
+    // keeping each measurement's order
+    let mut measurements: Vec<(Uuid, Vec<OutboxMessage>)> = Vec::new();
+    for message in claimed {
+        match measurements
+            .iter_mut()
+            .find(|(id, _)| *id == message.measurement_id)
+        {
+            Some((_, messages)) => messages.push(message),
+            None => measurements.push((message.measurement_id, vec![message])),
+        }
+    }
+
+    let mut published = 0;
+    for (measurement_id, messages) in measurements {
+        let ids: Vec<i64> = messages.iter().map(|m| m.id).collect();
+
+        let sent = match messages
+            .iter()
+            .map(outbound_message)
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(outbound) => state
+                .probe_sink
+                .send(&outbound)
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(format!("Invalid outbox headers: {}", err)),
+        };
+
+        match sent {
+            Ok(()) => {
+                if let Err(err) = state.database.mark_outbox_sent(&ids).await {
+                    // The lease expires and the batches are sent again
+                    error!(
+                        "Failed to mark outbox messages of measurement {} as sent: {}",
+                        measurement_id, err
+                    );
+                    continue;
+                }
+                debug!(
+                    "Relayed {} queued batches of measurement {}",
+                    ids.len(),
+                    measurement_id
+                );
+                counter!("saimiris_gateway_outbox_relayed_total").increment(ids.len() as u64);
+                published += ids.len();
+            }
+            Err(message) => {
+                counter!("saimiris_gateway_kafka_errors_total").increment(1);
+                warn!(
+                    "Failed to relay queued batches of measurement {}: {}",
+                    measurement_id, message
+                );
+                if let Err(err) = state.database.record_outbox_failure(&ids, &message).await {
+                    error!(
+                        "Failed to record outbox failure for measurement {}: {}",
+                        measurement_id, err
+                    );
+                }
+            }
+        }
+    }
+
+    published
+}
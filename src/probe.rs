@@ -3,12 +3,16 @@ use capnp::message::Builder;
 use capnp::serialize;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::net::IpAddr;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use tracing::error;
+use uuid::Uuid;
 
 // Import the Cap'n Proto generated code
-pub use crate::probe_capnp::probe;
+pub use crate::probe_capnp::{probe, probe_batch};
 
 /// Represents the protocol type for a probe
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -60,6 +64,192 @@ impl Protocol {
     }
 }
 
+/// TCP flags set on a TCP probe
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TcpFlags {
+    Syn,
+    Ack,
+}
+
+/// Largest probe payload, in bytes: fills a minimum-MTU IPv6 packet with a UDP header
+pub const MAX_PAYLOAD_SIZE: u16 = 1232;
+
+/// Largest IPv6 flow label (20 bits)
+pub const MAX_FLOW_LABEL: u32 = 0xf_ffff;
+
+/// Optional per-probe settings, set through the JSON object probe form. Agents
+/// advertise the options they support in their configuration.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProbeOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_size: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flow_label: Option<u32>,
+    /// Probe/round identifier for multi-round algorithms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_flags: Option<TcpFlags>,
+}
+
+/// Names of the per-probe options, as used in the JSON object form and agent configs
+pub const PROBE_OPTIONS: [&str; 4] = ["payload_size", "flow_label", "probe_id", "tcp_flags"];
+
+impl ProbeOptions {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Names of the options that are set
+    pub fn names(&self) -> impl Iterator<Item = &'static str> {
+        [
+            self.payload_size.is_some(),
+            self.flow_label.is_some(),
+            self.probe_id.is_some(),
+            self.tcp_flags.is_some(),
+        ]
+        .into_iter()
+        .zip(PROBE_OPTIONS)
+        .filter_map(|(set, name)| set.then_some(name))
+    }
+
+    fn from_json(map: &serde_json::Map<String, Value>) -> Result<Self, String> {
+        let number = |name: &str, max: u32| -> Result<Option<u32>, String> {
+            match map.get(name) {
+                None | Some(Value::Null) => Ok(None),
+                Some(value) => value
+                    .as_u64()
+                    .filter(|n| *n <= u64::from(max))
+                    .map(|n| Some(n as u32))
+                    .ok_or_else(|| format!("Invalid {} (must be 0-{}): {}", name, max, value)),
+            }
+        };
+        let tcp_flags = match map.get("tcp_flags") {
+            None | Some(Value::Null) => None,
+            Some(value) => Some(
+                serde_json::from_value(value.clone())
+                    .map_err(|_| format!("Invalid tcp_flags (must be syn or ack): {}", value))?,
+            ),
+        };
+
+        Ok(ProbeOptions {
+            payload_size: number("payload_size", u32::from(MAX_PAYLOAD_SIZE))?.map(|n| n as u16),
+            flow_label: number("flow_label", MAX_FLOW_LABEL)?,
+            probe_id: number("probe_id", u32::MAX)?,
+            tcp_flags,
+        })
+    }
+
+    /// Check that the options make sense for a probe of `protocol`
+    fn validate(&self, protocol: &str) -> Result<(), String> {
+        if self.flow_label.is_some_and(|n| n > MAX_FLOW_LABEL) {
+            return Err(format!(
+                "Invalid flow_label (must be 0-{}): {}",
+                MAX_FLOW_LABEL,
+                self.flow_label.unwrap_or_default()
+            ));
+        }
+        let is_tcp = protocol.eq_ignore_ascii_case("tcp");
+        if self.tcp_flags.is_some() && !is_tcp {
+            return Err("tcp_flags can only be set on TCP probes".to_string());
+        }
+        if is_tcp && self.tcp_flags.is_none() {
+            return Err("TCP probes must set tcp_flags".to_string());
+        }
+        if let Some(size) = self.payload_size
+            && (size == 0 || size > MAX_PAYLOAD_SIZE)
+        {
+            return Err(format!(
+                "Invalid payload_size (must be 1-{}): {}",
+                MAX_PAYLOAD_SIZE, size
+            ));
+        }
+        Ok(())
+    }
+
+    fn to_capnp(self, mut builder: probe::Builder) {
+        builder.set_payload_size(self.payload_size.unwrap_or(0));
+        builder.set_flow_label(self.flow_label.unwrap_or(0));
+        builder.set_has_flow_label(self.flow_label.is_some());
+        builder.set_probe_id(self.probe_id.unwrap_or(0));
+        builder.set_has_probe_id(self.probe_id.is_some());
+        builder.set_tcp_flags(match self.tcp_flags {
+            None => probe::TcpFlags::Default,
+            Some(TcpFlags::Syn) => probe::TcpFlags::Syn,
+            Some(TcpFlags::Ack) => probe::TcpFlags::Ack,
+        });
+    }
+
+    fn from_capnp(reader: probe::Reader) -> Result<Self> {
+        // Probes written before the presence flags only set non-zero values
+        let present = |n: u32, has: bool| (has || n != 0).then_some(n);
+        Ok(ProbeOptions {
+            payload_size: Some(reader.get_payload_size()).filter(|n| *n != 0),
+            flow_label: present(reader.get_flow_label(), reader.get_has_flow_label()),
+            probe_id: present(reader.get_probe_id(), reader.get_has_probe_id()),
+            tcp_flags: match reader.get_tcp_flags()? {
+                probe::TcpFlags::Default => None,
+                probe::TcpFlags::Syn => Some(TcpFlags::Syn),
+                probe::TcpFlags::Ack => Some(TcpFlags::Ack),
+            },
+        })
+    }
+}
+
+/// Field names of the JSON object probe form, in 5-tuple order
+const PROBE_FIELDS: [&str; 5] = ["dst_addr", "src_port", "dst_port", "ttl", "protocol"];
+
+/// Split a JSON probe into its 5-tuple and options. A probe is either an array
+/// `[dst_addr, src_port, dst_port, ttl, protocol]`, or an object with those
+/// fields plus any of the optional `payload_size`, `flow_label`, `probe_id`
+/// and `tcp_flags`.
+pub fn probe_parts(probe: &Value) -> Result<(Cow<'_, [Value]>, ProbeOptions), String> {
+    match probe {
+        Value::Array(arr) if arr.len() == 5 => Ok((Cow::Borrowed(arr), ProbeOptions::default())),
+        Value::Array(arr) => Err(format!("Expected 5 elements, got {}", arr.len())),
+        Value::Object(map) => {
+            if let Some(key) = map.keys().find(|key| {
+                !PROBE_FIELDS.contains(&key.as_str()) && !PROBE_OPTIONS.contains(&key.as_str())
+            }) {
+                return Err(format!("Unknown probe field: {}", key));
+            }
+            let fields = PROBE_FIELDS
+                .iter()
+                .map(|field| {
+                    map.get(*field)
+                        .cloned()
+                        .ok_or_else(|| format!("Missing probe field: {}", field))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((Cow::Owned(fields), ProbeOptions::from_json(map)?))
+        }
+        _ => Err("Probe must be a JSON array or object".to_string()),
+    }
+}
+
+/// Per-probe options used by a set of JSON probes, and the largest payload
+/// requested, which the target agents must support
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProbeRequirements {
+    pub options: BTreeSet<&'static str>,
+    pub max_payload_size: Option<u16>,
+}
+
+impl ProbeRequirements {
+    pub fn of(probes: &[Value]) -> Self {
+        let mut requirements = Self::default();
+        for probe in probes.iter().filter(|probe| probe.is_object()) {
+            if let Ok((_, options)) = probe_parts(probe) {
+                requirements.options.extend(options.names());
+                requirements.max_payload_size =
+                    requirements.max_payload_size.max(options.payload_size);
+            }
+        }
+        requirements
+    }
+}
+
 /// A probe represents a packet sent to a destination address
 /// using a specific protocol at a given TTL.
 ///
@@ -73,36 +263,32 @@ pub struct Probe {
     pub dst_port: u16,
     pub ttl: u8,
     pub protocol: Protocol,
+    #[serde(default)]
+    pub options: ProbeOptions,
 }
 
 impl Probe {
     /// Create a Probe from a JSON value in the array format
-    /// [dst_addr, src_port, dst_port, ttl, protocol]
+    /// [dst_addr, src_port, dst_port, ttl, protocol], or the object format
     pub fn from_json(value: &Value) -> Result<Self> {
-        if let Value::Array(arr) = value {
-            if arr.len() != 5 {
-                return Err(anyhow!(
-                    "Invalid probe format: expected 5 elements in array"
-                ));
-            }
-
-            // Parse values from the JSON array
-            let dst_addr = parse_ip_from_json(&arr[0])?;
-            let src_port = parse_port_from_json(&arr[1], "Source port")?;
-            let dst_port = parse_port_from_json(&arr[2], "Destination port")?;
-            let ttl = parse_ttl_from_json(&arr[3])?;
-            let protocol = parse_protocol_from_json(&arr[4])?;
-
-            Ok(Probe {
-                dst_addr,
-                src_port,
-                dst_port,
-                ttl,
-                protocol,
-            })
-        } else {
-            Err(anyhow!("Expected JSON array for probe"))
-        }
+        let (arr, options) =
+            probe_parts(value).map_err(|err| anyhow!("Invalid probe format: {}", err))?;
+
+        // Parse values from the JSON array
+        let dst_addr = parse_ip_from_json(&arr[0])?;
+        let src_port = parse_port_from_json(&arr[1], "Source port")?;
+        let dst_port = parse_port_from_json(&arr[2], "Destination port")?;
+        let ttl = parse_ttl_from_json(&arr[3])?;
+        let protocol = parse_protocol_from_json(&arr[4])?;
+
+        Ok(Probe {
+            dst_addr,
+            src_port,
+            dst_port,
+            ttl,
+            protocol,
+            options,
+        })
     }
 
     /// Serialize the probe to a Cap'n Proto binary representation
@@ -125,6 +311,7 @@ impl Probe {
         capnp_probe.set_dst_port(self.dst_port);
         capnp_probe.set_ttl(self.ttl);
         capnp_probe.set_protocol(self.protocol.to_capnp());
+        self.options.to_capnp(capnp_probe);
 
         // Serialize to binary
         let mut buffer = Vec::with_capacity(64); // Preallocate a reasonable buffer size
@@ -134,6 +321,178 @@ impl Probe {
     }
 }
 
+impl Probe {
+    /// Read a probe back from its Cap'n Proto representation
+    pub fn from_capnp(reader: probe::Reader) -> Result<Self> {
+        let bytes: [u8; 16] = reader
+            .get_dst_addr()?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid destination address length"))?;
+        let ipv6 = std::net::Ipv6Addr::from(bytes);
+        // IPv4 destinations are stored IPv6-mapped
+        let dst_addr = match ipv6.to_ipv4_mapped() {
+            Some(ipv4) => IpAddr::V4(ipv4),
+            None => IpAddr::V6(ipv6),
+        };
+
+        let protocol = match reader.get_protocol()? {
+            probe::Protocol::Tcp => Protocol::TCP,
+            probe::Protocol::Udp => Protocol::UDP,
+            probe::Protocol::Icmp => Protocol::ICMP,
+            probe::Protocol::Icmpv6 => Protocol::ICMPV6,
+        };
+
+        Ok(Probe {
+            dst_addr,
+            src_port: reader.get_src_port(),
+            dst_port: reader.get_dst_port(),
+            ttl: reader.get_ttl(),
+            protocol,
+            options: ProbeOptions::from_capnp(reader)?,
+        })
+    }
+
+    /// Convert to the JSON format accepted by the API: the array format
+    /// [dst_addr, src_port, dst_port, ttl, protocol], or the object format if
+    /// any option is set
+    pub fn to_json(&self) -> Value {
+        if self.options.is_empty() {
+            return serde_json::json!([
+                self.dst_addr.to_string(),
+                self.src_port,
+                self.dst_port,
+                self.ttl,
+                self.protocol.to_string()
+            ]);
+        }
+
+        let mut value = serde_json::json!({
+            "dst_addr": self.dst_addr.to_string(),
+            "src_port": self.src_port,
+            "dst_port": self.dst_port,
+            "ttl": self.ttl,
+            "protocol": self.protocol.to_string(),
+        });
+        if let (Value::Object(map), Ok(Value::Object(options))) =
+            (&mut value, serde_json::to_value(self.options))
+        {
+            map.extend(options);
+        }
+        value
+    }
+}
+
+/// Decode a batch of concatenated Cap'n Proto probe messages, as produced by
+/// `deserialize_probes_batch`
+pub fn decode_probes_batch(mut batch: &[u8]) -> Result<Vec<Probe>> {
+    let mut probes = Vec::new();
+    while !batch.is_empty() {
+        let message = serialize::read_message_from_flat_slice(
+            &mut batch,
+            capnp::message::ReaderOptions::new(),
+        )?;
+        probes.push(Probe::from_capnp(message.get_root::<probe::Reader>()?)?);
+    }
+    Ok(probes)
+}
+
+/// Version of the `ProbeBatch` envelope produced by the gateway
+pub const PROBE_BATCH_VERSION: u16 = 1;
+
+/// Routing information carried by a `ProbeBatch` envelope
+#[derive(Debug, Clone)]
+pub struct BatchEnvelope {
+    pub version: u16,
+    pub measurement_id: Uuid,
+    pub batch_index: u32,
+    pub batch_count: u32,
+    pub end_of_measurement: bool,
+    pub agents: Vec<AgentMetadata>,
+}
+
+impl BatchEnvelope {
+    /// Wrap a batch of concatenated `Probe` messages (as produced by
+    /// `deserialize_probes_batch`) in a `ProbeBatch` envelope
+    pub fn encode(&self, batch: &[u8]) -> Result<Vec<u8>> {
+        let mut messages = Vec::new();
+        let mut remaining = batch;
+        while !remaining.is_empty() {
+            messages.push(serialize::read_message_from_flat_slice(
+                &mut remaining,
+                capnp::message::ReaderOptions::new(),
+            )?);
+        }
+
+        let mut message = Builder::new_default();
+        let mut envelope = message.init_root::<probe_batch::Builder>();
+        envelope.set_version(self.version);
+        envelope.set_measurement_id(self.measurement_id.as_bytes());
+        envelope.set_batch_index(self.batch_index);
+        envelope.set_batch_count(self.batch_count);
+        envelope.set_end_of_measurement(self.end_of_measurement);
+
+        let mut agents = envelope.reborrow().init_agents(self.agents.len() as u32);
+        for (i, agent) in self.agents.iter().enumerate() {
+            let mut assignment = agents.reborrow().get(i as u32);
+            assignment.set_agent_id(&agent.id);
+            let src_ip = agent
+                .ip_address
+                .map(|ip| ip.to_string())
+                .unwrap_or_default();
+            assignment.set_src_ip(&src_ip);
+        }
+
+        let mut probes = envelope.init_probes(messages.len() as u32);
+        for (i, message) in messages.iter().enumerate() {
+            probes.set_with_caveats(i as u32, message.get_root::<probe::Reader>()?)?;
+        }
+
+        let mut buffer = Vec::with_capacity(batch.len() + 64);
+        serialize::write_message(&mut buffer, &message)?;
+        Ok(buffer)
+    }
+
+    /// Read a `ProbeBatch` envelope back, with its probes
+    pub fn decode(mut bytes: &[u8]) -> Result<(Self, Vec<Probe>)> {
+        let message = serialize::read_message_from_flat_slice(
+            &mut bytes,
+            capnp::message::ReaderOptions::new(),
+        )?;
+        let reader = message.get_root::<probe_batch::Reader>()?;
+
+        let agents = reader
+            .get_agents()?
+            .iter()
+            .map(|assignment| {
+                let src_ip = assignment.get_src_ip()?.to_str()?;
+                Ok(AgentMetadata {
+                    id: assignment.get_agent_id()?.to_string()?,
+                    ip_address: if src_ip.is_empty() {
+                        None
+                    } else {
+                        Some(src_ip.parse()?)
+                    },
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let probes = reader
+            .get_probes()?
+            .iter()
+            .map(Probe::from_capnp)
+            .collect::<Result<Vec<_>>>()?;
+
+        let envelope = BatchEnvelope {
+            version: reader.get_version(),
+            measurement_id: Uuid::from_slice(reader.get_measurement_id()?)?,
+            batch_index: reader.get_batch_index(),
+            batch_count: reader.get_batch_count(),
+            end_of_measurement: reader.get_end_of_measurement(),
+            agents,
+        };
+        Ok((envelope, probes))
+    }
+}
+
 impl std::fmt::Display for Probe {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -208,19 +567,98 @@ pub struct AgentMetadata {
     // Additional fields can be added as needed
 }
 
+/// Probes generated from a saved target list: one probe per stored destination,
+/// with the other probe fields taken from this template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetListProbes {
+    pub id: Uuid,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub ttl: u8,
+    pub protocol: String,
+    /// Options set on every generated probe
+    #[serde(default, skip_serializing_if = "ProbeOptions::is_empty")]
+    pub options: ProbeOptions,
+}
+
+impl TargetListProbes {
+    /// Build the JSON probe for one destination: the array form, or the object
+    /// form when the template sets options
+    pub fn probe_for(&self, dst_addr: &str) -> Value {
+        let tuple = serde_json::json!([
+            dst_addr,
+            self.src_port,
+            self.dst_port,
+            self.ttl,
+            self.protocol
+        ]);
+        if self.options.is_empty() {
+            return tuple;
+        }
+
+        let mut probe = serde_json::Map::new();
+        for (field, value) in PROBE_FIELDS
+            .iter()
+            .zip(tuple.as_array().into_iter().flatten())
+        {
+            probe.insert(field.to_string(), value.clone());
+        }
+        if let Ok(Value::Object(options)) = serde_json::to_value(self.options) {
+            probe.extend(options);
+        }
+        Value::Object(probe)
+    }
+
+    /// Validate the template fields, independently of the stored destinations
+    pub fn validate(&self) -> Result<(), String> {
+        self.options
+            .validate(&self.protocol)
+            .and_then(|_| {
+                validate_ports_and_ttl(
+                    u64::from(self.src_port),
+                    u64::from(self.dst_port),
+                    u64::from(self.ttl),
+                )
+            })
+            .and_then(|_| validate_protocol(&self.protocol, self.options.tcp_flags.is_some()))
+            .map_err(|err| format!("Invalid target list template: {}", err))
+    }
+}
+
 /// Request structure for submitting probes
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubmitProbesRequest {
     pub metadata: Vec<AgentMetadata>,
+    #[serde(default)]
     pub probes: Vec<serde_json::Value>,
+    /// Probes to generate from a saved target list, in addition to `probes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_list: Option<TargetListProbes>,
+}
+
+impl SubmitProbesRequest {
+    /// SHA-256 of the serialized request, used to detect a reused `Idempotency-Key`
+    pub fn fingerprint(&self) -> String {
+        let bytes = serde_json::to_vec(self).unwrap_or_default();
+        hex::encode(Sha256::digest(&bytes))
+    }
 }
 
 /// Response structure for submitted probes
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubmitProbesResponse {
     pub id: String,
     pub probes: usize,
     pub agents: Vec<AgentMetadata>,
+    /// Submitted probes that were not sent (e.g. blocked destinations)
+    #[serde(default)]
+    pub filtered: usize,
+    /// Round number, for rounds appended to a multi-round measurement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub round: Option<i32>,
+    /// Whether the probes were archived, when probe archiving is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived: Option<bool>,
 }
 
 /// Validate a batch of JSON probes
@@ -239,20 +677,11 @@ pub fn validate_probes(probes: &[Value]) -> Result<(), String> {
     Ok(())
 }
 
-/// Directly deserialize a JSON array into a Cap'n Proto probe message
-/// Format: [dst_addr, src_port, dst_port, ttl, protocol]
+/// Directly deserialize a JSON probe into a Cap'n Proto probe message
+/// Format: [dst_addr, src_port, dst_port, ttl, protocol], or the object format
 pub fn deserialize_json_to_capnp(json_value: &serde_json::Value) -> Result<Vec<u8>> {
-    // JSON array must have exactly 5 elements
-    let arr = match json_value {
-        Value::Array(arr) if arr.len() == 5 => arr,
-        Value::Array(arr) => {
-            return Err(anyhow!(
-                "Invalid probe format: expected 5 elements in array, got {}",
-                arr.len()
-            ));
-        }
-        _ => return Err(anyhow!("Expected JSON array for probe")),
-    };
+    let (arr, options) =
+        probe_parts(json_value).map_err(|err| anyhow!("Invalid probe format: {}", err))?;
 
     // Create a new message builder for a probe
     let mut message = Builder::new_default();
@@ -279,6 +708,9 @@ pub fn deserialize_json_to_capnp(json_value: &serde_json::Value) -> Result<Vec<u
     let protocol = parse_protocol_capnp_from_json(&arr[4])?;
     capnp_probe.set_protocol(protocol);
 
+    // 6. Optional per-probe settings
+    options.to_capnp(capnp_probe);
+
     // Serialize to binary
     let mut buffer = Vec::with_capacity(64); // Preallocate a reasonable buffer size
     serialize::write_message(&mut buffer, &message)?;
@@ -398,79 +830,363 @@ pub fn deserialize_probes_batch(
     Ok(batches)
 }
 
-/// Validate a JSON array probe format
-/// Format: [dst_addr, src_port, dst_port, ttl, protocol]
+/// Validate a probe destination address and return it in canonical form
+pub fn validate_destination(ip_str: &str) -> Result<Ipv6Addr, String> {
+    match IpAddr::from_str(ip_str) {
+        Ok(IpAddr::V6(ipv6)) => Ok(ipv6),
+        // IPv4 addresses are not allowed for now
+        Ok(IpAddr::V4(_)) => Err(format!("IPv4 addresses are not allowed: {}", ip_str)),
+        Err(_) => Err(format!("Invalid IP address: {}", ip_str)),
+    }
+}
+
+/// Validate the destinations of a target list, returning them in canonical form
+pub fn validate_targets(targets: &[String]) -> Result<Vec<String>, String> {
+    targets
+        .iter()
+        .enumerate()
+        .map(|(i, target)| {
+            validate_destination(target.trim())
+                .map(|ip| ip.to_string())
+                .map_err(|err| format!("Target at index {}: {}", i, err))
+        })
+        .collect()
+}
+
+/// Validate a JSON probe, in the array or object form (see `probe_parts`)
 pub fn validate_json_probe(probe: &Value) -> Result<(), String> {
+    let (arr, options) = probe_parts(probe)?;
+    if let Value::String(protocol) = &arr[4] {
+        options.validate(protocol)?;
+    }
+    // TCP probes need explicit flags, so they only reach agents advertising `tcp_flags`
+    validate_probe_fields(&arr, options.tcp_flags.is_some())
+}
+
+/// Validate the 5-tuple of a probe: [dst_addr, src_port, dst_port, ttl, protocol]
+fn validate_probe_fields(arr: &[Value], allow_tcp: bool) -> Result<(), String> {
+    // Validate IP address (must be IPv6 only)
+    if let Value::String(ip_str) = &arr[0] {
+        validate_destination(ip_str)?;
+    } else {
+        return Err("IP address must be a string".to_string());
+    }
+
+    // Validate ports (source and destination) and TTL
+    let mut numbers = [0; 3];
+    for (idx, field_name) in [(1, "Source port"), (2, "Destination port"), (3, "TTL")] {
+        if let Value::Number(n) = &arr[idx] {
+            numbers[idx - 1] = n
+                .as_u64()
+                .ok_or_else(|| format!("{} must be a positive integer", field_name))?;
+        } else {
+            return Err(format!("{} must be a number", field_name));
+        }
+    }
+    validate_ports_and_ttl(numbers[0], numbers[1], numbers[2])?;
+
+    // Validate protocol
+    if let Value::String(p) = &arr[4] {
+        validate_protocol(p, allow_tcp)
+    } else {
+        Err("Protocol must be a string".to_string())
+    }
+}
+
+/// Validate the source and destination ports and the TTL of a probe
+fn validate_ports_and_ttl(src_port: u64, dst_port: u64, ttl: u64) -> Result<(), String> {
+    for (port, field_name) in [(src_port, "source port"), (dst_port, "destination port")] {
+        if port == 0 || port > u16::MAX as u64 {
+            return Err(format!(
+                "Invalid {} (must be 1-65535; for ICMP/ICMPv6 ports are ignored by the probe, use any non-zero value e.g. 1): {}",
+                field_name, port
+            ));
+        }
+    }
+    if ttl == 0 || ttl > u8::MAX as u64 {
+        return Err(format!("Invalid TTL: {}", ttl));
+    }
+    Ok(())
+}
+
+/// Validate the protocol of a probe. TCP is only allowed with explicit flags.
+fn validate_protocol(protocol: &str, allow_tcp: bool) -> Result<(), String> {
+    match protocol.to_lowercase().as_str() {
+        "udp" | "icmpv6" => Ok(()), // Valid protocols (not allowing ICMPv4 for now)
+        "tcp" if allow_tcp => Ok(()),
+        _ => Err(format!("Invalid protocol: {}", protocol)),
+    }
+}
+
+/// Destination address of a JSON probe, if it is a valid IP address
+pub fn probe_destination(probe: &Value) -> Option<IpAddr> {
     match probe {
-        Value::Array(arr) => {
-            // Check array length
-            if arr.len() != 5 {
-                return Err(format!("Expected 5 elements, got {}", arr.len()));
-            }
+        Value::Object(map) => map.get("dst_addr"),
+        _ => probe.get(0),
+    }?
+    .as_str()?
+    .parse()
+    .ok()
+}
 
-            // Validate IP address (must be IPv6 only)
-            if let Value::String(ip_str) = &arr[0] {
-                match IpAddr::from_str(ip_str) {
-                    Ok(IpAddr::V6(_)) => {} // valid IPv6
-                    Ok(IpAddr::V4(_)) => {
-                        // IPv4 addresses are not allowed for now
-                        return Err(format!("IPv4 addresses are not allowed: {}", ip_str));
-                    }
-                    Err(_) => {
-                        return Err(format!("Invalid IP address: {}", ip_str));
-                    }
-                }
-            } else {
-                return Err("IP address must be a string".to_string());
-            }
+/// Categories of the IANA special-purpose address registries (IPv4 and IPv6)
+/// that should not be probed on the public Internet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpecialPurpose {
+    /// `::/128`, `0.0.0.0/8`
+    Unspecified,
+    /// `::1/128`, `127.0.0.0/8`
+    Loopback,
+    /// `fe80::/10`, `169.254.0.0/16`
+    LinkLocal,
+    /// `ff00::/8`, `224.0.0.0/4`
+    Multicast,
+    /// Unique local and private-use space: `fc00::/7`, `10.0.0.0/8`,
+    /// `172.16.0.0/12`, `192.168.0.0/16`, `100.64.0.0/10`
+    Private,
+    /// `2001:db8::/32`, `3fff::/20`, `192.0.2.0/24`, `198.51.100.0/24`, `203.0.113.0/24`
+    Documentation,
+    /// `2001:2::/48`, `198.18.0.0/15`
+    Benchmarking,
+    /// `::ffff:0:0/96`
+    Ipv4Mapped,
+    /// Other non-routable assignments: discard-only, Teredo, ORCHID, local-use
+    /// NAT64, IETF protocol assignments, `240.0.0.0/4` and broadcast
+    Reserved,
+}
 
-            // Validate ports (source and destination)
-            for (idx, field_name) in [(1, "Source port"), (2, "Destination port")] {
-                if let Value::Number(n) = &arr[idx] {
-                    if let Some(port) = n.as_u64() {
-                        if port == 0 || port > u16::MAX as u64 {
-                            return Err(format!(
-                                "Invalid {} (must be 1-65535; for ICMP/ICMPv6 ports are ignored by the probe, use any non-zero value e.g. 1): {}",
-                                field_name.to_lowercase(), port
-                            ));
-                        }
-                    } else {
-                        return Err(format!("{} must be a positive integer", field_name));
-                    }
-                } else {
-                    return Err(format!("{} must be a number", field_name));
-                }
-            }
+impl SpecialPurpose {
+    pub const ALL: [SpecialPurpose; 9] = [
+        SpecialPurpose::Unspecified,
+        SpecialPurpose::Loopback,
+        SpecialPurpose::LinkLocal,
+        SpecialPurpose::Multicast,
+        SpecialPurpose::Private,
+        SpecialPurpose::Documentation,
+        SpecialPurpose::Benchmarking,
+        SpecialPurpose::Ipv4Mapped,
+        SpecialPurpose::Reserved,
+    ];
+
+    /// The special-purpose category of `addr`, if any
+    pub fn of(addr: IpAddr) -> Option<Self> {
+        match addr {
+            IpAddr::V6(addr) => IPV6_SPECIAL_PURPOSE
+                .iter()
+                .find(|(network, len, _)| prefix_matches(addr.to_bits(), network.to_bits(), *len))
+                .map(|(_, _, category)| *category),
+            IpAddr::V4(addr) => IPV4_SPECIAL_PURPOSE
+                .iter()
+                .find(|(network, len, _)| {
+                    prefix_matches(
+                        u128::from(addr.to_bits()) << 96,
+                        u128::from(network.to_bits()) << 96,
+                        *len,
+                    )
+                })
+                .map(|(_, _, category)| *category),
+        }
+    }
+}
 
-            // Validate TTL
-            if let Value::Number(n) = &arr[3] {
-                if let Some(ttl) = n.as_u64() {
-                    if ttl == 0 || ttl > u8::MAX as u64 {
-                        return Err(format!("Invalid TTL: {}", ttl));
-                    }
-                } else {
-                    return Err("TTL must be a positive integer".to_string());
-                }
-            } else {
-                return Err("TTL must be a number".to_string());
-            }
+impl FromStr for SpecialPurpose {
+    type Err = String;
 
-            // Validate protocol
-            if let Value::String(p) = &arr[4] {
-                match p.to_lowercase().as_str() {
-                    "udp" | "icmpv6" => (), // Valid protocols (not allowing ICMPv4 for now)
-                    _ => return Err(format!("Invalid protocol: {}", p)),
-                }
-            } else {
-                return Err("Protocol must be a string".to_string());
-            }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SpecialPurpose::ALL
+            .into_iter()
+            .find(|category| category.to_string() == s.to_lowercase())
+            .ok_or_else(|| format!("Invalid special-purpose category: {}", s))
+    }
+}
+
+impl std::fmt::Display for SpecialPurpose {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpecialPurpose::Unspecified => write!(f, "unspecified"),
+            SpecialPurpose::Loopback => write!(f, "loopback"),
+            SpecialPurpose::LinkLocal => write!(f, "link-local"),
+            SpecialPurpose::Multicast => write!(f, "multicast"),
+            SpecialPurpose::Private => write!(f, "private"),
+            SpecialPurpose::Documentation => write!(f, "documentation"),
+            SpecialPurpose::Benchmarking => write!(f, "benchmarking"),
+            SpecialPurpose::Ipv4Mapped => write!(f, "ipv4-mapped"),
+            SpecialPurpose::Reserved => write!(f, "reserved"),
+        }
+    }
+}
 
-            Ok(())
+fn prefix_matches(addr: u128, network: u128, len: u8) -> bool {
+    len == 0 || (addr ^ network) >> (128 - u32::from(len)) == 0
+}
+
+const IPV6_SPECIAL_PURPOSE: &[(Ipv6Addr, u8, SpecialPurpose)] = &[
+    (Ipv6Addr::UNSPECIFIED, 128, SpecialPurpose::Unspecified),
+    (Ipv6Addr::LOCALHOST, 128, SpecialPurpose::Loopback),
+    (
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0),
+        96,
+        SpecialPurpose::Ipv4Mapped,
+    ),
+    (
+        Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0),
+        48,
+        SpecialPurpose::Reserved,
+    ),
+    (
+        Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 0),
+        64,
+        SpecialPurpose::Reserved,
+    ),
+    // Before the IETF protocol assignments block that contains it
+    (
+        Ipv6Addr::new(0x2001, 0x2, 0, 0, 0, 0, 0, 0),
+        48,
+        SpecialPurpose::Benchmarking,
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0),
+        23,
+        SpecialPurpose::Reserved,
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0),
+        32,
+        SpecialPurpose::Documentation,
+    ),
+    (
+        Ipv6Addr::new(0x3fff, 0, 0, 0, 0, 0, 0, 0),
+        20,
+        SpecialPurpose::Documentation,
+    ),
+    (
+        Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0),
+        7,
+        SpecialPurpose::Private,
+    ),
+    (
+        Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0),
+        10,
+        SpecialPurpose::LinkLocal,
+    ),
+    (
+        Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0),
+        8,
+        SpecialPurpose::Multicast,
+    ),
+];
+
+const IPV4_SPECIAL_PURPOSE: &[(Ipv4Addr, u8, SpecialPurpose)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8, SpecialPurpose::Unspecified),
+    (Ipv4Addr::new(10, 0, 0, 0), 8, SpecialPurpose::Private),
+    (Ipv4Addr::new(100, 64, 0, 0), 10, SpecialPurpose::Private),
+    (Ipv4Addr::new(127, 0, 0, 0), 8, SpecialPurpose::Loopback),
+    (Ipv4Addr::new(169, 254, 0, 0), 16, SpecialPurpose::LinkLocal),
+    (Ipv4Addr::new(172, 16, 0, 0), 12, SpecialPurpose::Private),
+    (Ipv4Addr::new(192, 0, 0, 0), 24, SpecialPurpose::Reserved),
+    (
+        Ipv4Addr::new(192, 0, 2, 0),
+        24,
+        SpecialPurpose::Documentation,
+    ),
+    (Ipv4Addr::new(192, 168, 0, 0), 16, SpecialPurpose::Private),
+    (
+        Ipv4Addr::new(198, 18, 0, 0),
+        15,
+        SpecialPurpose::Benchmarking,
+    ),
+    (
+        Ipv4Addr::new(198, 51, 100, 0),
+        24,
+        SpecialPurpose::Documentation,
+    ),
+    (
+        Ipv4Addr::new(203, 0, 113, 0),
+        24,
+        SpecialPurpose::Documentation,
+    ),
+    (Ipv4Addr::new(224, 0, 0, 0), 4, SpecialPurpose::Multicast),
+    (Ipv4Addr::new(240, 0, 0, 0), 4, SpecialPurpose::Reserved),
+];
+
+/// What to do with probes towards a special-purpose destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialPurposePolicy {
+    /// Refuse the whole submission
+    Reject,
+    /// Drop the probe and send the rest
+    Drop,
+    /// Send the probe like any other
+    Allow,
+}
+
+impl FromStr for SpecialPurposePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(SpecialPurposePolicy::Reject),
+            "drop" => Ok(SpecialPurposePolicy::Drop),
+            "allow" => Ok(SpecialPurposePolicy::Allow),
+            _ => Err(format!("Invalid special-purpose policy: {}", s)),
         }
-        _ => Err("Probe must be a JSON array".to_string()),
     }
 }
 
+/// Per-category policy for special-purpose probe destinations. Every category
+/// is rejected unless configured otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct SpecialPurposeFilter {
+    overrides: HashMap<SpecialPurpose, SpecialPurposePolicy>,
+}
+
+impl SpecialPurposeFilter {
+    /// Development policy: documentation and benchmarking prefixes are allowed,
+    /// so tests and lab setups can use them as destinations
+    pub fn development() -> Self {
+        Self::default()
+            .with_policy(SpecialPurpose::Documentation, SpecialPurposePolicy::Allow)
+            .with_policy(SpecialPurpose::Benchmarking, SpecialPurposePolicy::Allow)
+    }
+
+    pub fn with_policy(mut self, category: SpecialPurpose, policy: SpecialPurposePolicy) -> Self {
+        self.overrides.insert(category, policy);
+        self
+    }
+
+    pub fn policy(&self, category: SpecialPurpose) -> SpecialPurposePolicy {
+        self.overrides
+            .get(&category)
+            .copied()
+            .unwrap_or(SpecialPurposePolicy::Reject)
+    }
+
+    /// Check a probe destination. Returns whether the probe should be sent, or
+    /// an error if the destination is rejected.
+    pub fn check(&self, addr: IpAddr) -> Result<bool, String> {
+        let Some(category) = SpecialPurpose::of(addr) else {
+            return Ok(true);
+        };
+        match self.policy(category) {
+            SpecialPurposePolicy::Allow => Ok(true),
+            SpecialPurposePolicy::Drop => Ok(false),
+            SpecialPurposePolicy::Reject => {
+                Err(format!("Destination {} is a {} address", addr, category))
+            }
+        }
+    }
+}
+
+/// Parse a `category=policy` override, e.g. `private=drop`
+pub fn parse_special_purpose_override(
+    s: &str,
+) -> Result<(SpecialPurpose, SpecialPurposePolicy), String> {
+    let (category, policy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected category=policy, got: {}", s))?;
+    Ok((category.trim().parse()?, policy.trim().parse()?))
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -525,6 +1241,7 @@ mod tests {
             dst_port: 80,
             ttl: 64,
             protocol: Protocol::TCP,
+            options: ProbeOptions::default(),
         };
         assert_eq!(format!("{}", probe), "192.168.1.1,12345,80,64,tcp");
     }
@@ -654,6 +1371,7 @@ mod tests {
             dst_port: 53,
             ttl: 64,
             protocol: Protocol::TCP,
+            options: ProbeOptions::default(),
         };
 
         let serialized = probe
@@ -747,4 +1465,265 @@ mod tests {
         ];
         assert!(deserialize_probes_batch(&invalid_probes, 10000).is_err());
     }
+
+    #[test]
+    fn test_probe_object_form() {
+        let object = json!({
+            "dst_addr": "2001:db8::1",
+            "src_port": 24000,
+            "dst_port": 80,
+            "ttl": 12,
+            "protocol": "tcp",
+            "payload_size": 64,
+            "flow_label": 1234,
+            "probe_id": 7,
+            "tcp_flags": "ack"
+        });
+        assert!(validate_json_probe(&object).is_ok());
+        assert_eq!(
+            ProbeRequirements::of(&[object.clone(), json!(["2001:db8::2", 1, 1, 1, "udp"])]),
+            ProbeRequirements {
+                options: ["flow_label", "payload_size", "probe_id", "tcp_flags"]
+                    .into_iter()
+                    .collect(),
+                max_payload_size: Some(64),
+            }
+        );
+
+        // Options survive the Cap'n Proto encoding
+        let batches = deserialize_probes_batch(std::slice::from_ref(&object), 10000).unwrap();
+        let decoded = decode_probes_batch(&batches[0]).unwrap();
+        assert_eq!(decoded[0].options.tcp_flags, Some(TcpFlags::Ack));
+        assert_eq!(decoded[0].to_json(), object);
+        assert_eq!(Probe::from_json(&object).unwrap().to_json(), object);
+
+        // Zero is a flow label and probe ID of its own, not the agent default
+        let mut zero = object.clone();
+        zero["flow_label"] = json!(0);
+        zero["probe_id"] = json!(0);
+        let batches = deserialize_probes_batch(std::slice::from_ref(&zero), 10000).unwrap();
+        let decoded = decode_probes_batch(&batches[0]).unwrap();
+        assert_eq!(decoded[0].options.flow_label, Some(0));
+        assert_eq!(decoded[0].options.probe_id, Some(0));
+        assert_eq!(decoded[0].to_json(), zero);
+
+        // Without options, the object form is equivalent to the array form
+        let plain = json!({
+            "dst_addr": "2001:db8::1",
+            "src_port": 24000,
+            "dst_port": 80,
+            "ttl": 12,
+            "protocol": "udp"
+        });
+        assert_eq!(
+            Probe::from_json(&plain).unwrap().to_json(),
+            json!(["2001:db8::1", 24000, 80, 12, "udp"])
+        );
+        assert_eq!(
+            probe_destination(&plain),
+            Some("2001:db8::1".parse().unwrap())
+        );
+
+        // TCP is only available with explicit flags
+        let mut tcp = plain.clone();
+        tcp["protocol"] = json!("tcp");
+        assert!(validate_json_probe(&tcp).is_err());
+        assert!(validate_json_probe(&json!(["2001:db8::1", 24000, 80, 12, "tcp"])).is_err());
+    }
+
+    #[test]
+    fn test_batch_envelope_roundtrip() {
+        let probes = vec![
+            json!(["2001:db8::1", 24000, 33434, 1, "udp"]),
+            json!(["2001:db8::2", 24000, 33434, 2, "icmpv6"]),
+        ];
+        let batches = deserialize_probes_batch(&probes, 10000).unwrap();
+        assert_eq!(batches.len(), 1);
+
+        let envelope = BatchEnvelope {
+            version: PROBE_BATCH_VERSION,
+            measurement_id: Uuid::new_v4(),
+            batch_index: 0,
+            batch_count: 1,
+            end_of_measurement: true,
+            agents: vec![
+                AgentMetadata {
+                    id: "agent-1".to_string(),
+                    ip_address: Some("2001:db8::100".parse().unwrap()),
+                },
+                AgentMetadata {
+                    id: "agent-2".to_string(),
+                    ip_address: None,
+                },
+            ],
+        };
+        let encoded = envelope.encode(&batches[0]).unwrap();
+
+        let (decoded, decoded_probes) = BatchEnvelope::decode(&encoded).unwrap();
+        assert_eq!(decoded.version, PROBE_BATCH_VERSION);
+        assert_eq!(decoded.measurement_id, envelope.measurement_id);
+        assert_eq!((decoded.batch_index, decoded.batch_count), (0, 1));
+        assert!(decoded.end_of_measurement);
+        assert_eq!(decoded.agents.len(), 2);
+        assert_eq!(decoded.agents[0].id, "agent-1");
+        assert_eq!(decoded.agents[0].ip_address, envelope.agents[0].ip_address);
+        assert!(decoded.agents[1].ip_address.is_none());
+
+        let decoded_probes: Vec<_> = decoded_probes.iter().map(Probe::to_json).collect();
+        assert_eq!(decoded_probes, probes);
+    }
+
+    #[test]
+    fn test_special_purpose_categories() {
+        let category = |s: &str| SpecialPurpose::of(s.parse().unwrap());
+
+        assert_eq!(category("::"), Some(SpecialPurpose::Unspecified));
+        assert_eq!(category("::1"), Some(SpecialPurpose::Loopback));
+        assert_eq!(category("fe80::1"), Some(SpecialPurpose::LinkLocal));
+        assert_eq!(category("ff02::1"), Some(SpecialPurpose::Multicast));
+        assert_eq!(category("fd12:3456::1"), Some(SpecialPurpose::Private));
+        assert_eq!(category("2001:db8::1"), Some(SpecialPurpose::Documentation));
+        assert_eq!(
+            category("::ffff:192.0.2.1"),
+            Some(SpecialPurpose::Ipv4Mapped)
+        );
+        assert_eq!(category("2001:2::1"), Some(SpecialPurpose::Benchmarking));
+        assert_eq!(category("2001::1"), Some(SpecialPurpose::Reserved));
+        assert_eq!(category("2001:1ff::1"), Some(SpecialPurpose::Reserved));
+        assert_eq!(category("2001:200::1"), None);
+        assert_eq!(category("2001:4860:4860::8888"), None);
+        assert_eq!(category("2606:4700:4700::1111"), None);
+
+        assert_eq!(category("127.0.0.1"), Some(SpecialPurpose::Loopback));
+        assert_eq!(category("172.31.255.255"), Some(SpecialPurpose::Private));
+        assert_eq!(category("203.0.113.7"), Some(SpecialPurpose::Documentation));
+        assert_eq!(category("255.255.255.255"), Some(SpecialPurpose::Reserved));
+        assert_eq!(category("172.32.0.1"), None);
+        assert_eq!(category("8.8.8.8"), None);
+
+        for category in SpecialPurpose::ALL {
+            assert_eq!(category.to_string().parse::<SpecialPurpose>(), Ok(category));
+        }
+    }
+
+    #[test]
+    fn test_special_purpose_filter() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // Everything special-purpose is rejected by default
+        let filter = SpecialPurposeFilter::default();
+        assert_eq!(filter.check(ip("2001:4860:4860::8888")), Ok(true));
+        assert!(
+            filter
+                .check(ip("2001:db8::1"))
+                .unwrap_err()
+                .contains("documentation")
+        );
+        assert!(filter.check(ip("fe80::1")).is_err());
+
+        let filter = SpecialPurposeFilter::development()
+            .with_policy(SpecialPurpose::LinkLocal, SpecialPurposePolicy::Drop);
+        assert_eq!(filter.check(ip("2001:db8::1")), Ok(true));
+        assert_eq!(filter.check(ip("fe80::1")), Ok(false));
+        assert!(filter.check(ip("::1")).is_err());
+
+        assert_eq!(
+            parse_special_purpose_override("private=drop"),
+            Ok((SpecialPurpose::Private, SpecialPurposePolicy::Drop))
+        );
+        assert!(parse_special_purpose_override("private").is_err());
+        assert!(parse_special_purpose_override("public=allow").is_err());
+        assert!(parse_special_purpose_override("private=ignore").is_err());
+    }
+
+    #[test]
+    fn test_target_list_validation() {
+        // Destinations are canonicalized
+        let targets = vec!["2001:DB8:0:0::1".to_string(), " 2001:db8::2 ".to_string()];
+        assert_eq!(
+            validate_targets(&targets).unwrap(),
+            vec!["2001:db8::1".to_string(), "2001:db8::2".to_string()]
+        );
+
+        // IPv4 and malformed destinations are rejected like inline probes
+        let ipv4 = vec!["2001:db8::1".to_string(), "192.0.2.1".to_string()];
+        assert!(validate_targets(&ipv4).unwrap_err().contains("index 1"));
+        let invalid = vec!["not-an-ip".to_string()];
+        assert!(validate_targets(&invalid).is_err());
+
+        // The template is validated with the same rules as an inline probe
+        let template = TargetListProbes {
+            id: Uuid::new_v4(),
+            src_port: 24000,
+            dst_port: 33434,
+            ttl: 64,
+            protocol: "udp".to_string(),
+            options: ProbeOptions::default(),
+        };
+        assert!(template.validate().is_ok());
+        assert_eq!(
+            template.probe_for("2001:db8::1"),
+            json!(["2001:db8::1", 24000, 33434, 64, "udp"])
+        );
+
+        let tcp_template = TargetListProbes {
+            protocol: "tcp".to_string(),
+            ..template.clone()
+        };
+        assert!(tcp_template.validate().is_err());
+
+        // Options of the template are set on every probe
+        let tcp_template = TargetListProbes {
+            options: ProbeOptions {
+                tcp_flags: Some(TcpFlags::Syn),
+                flow_label: Some(0),
+                ..Default::default()
+            },
+            ..tcp_template
+        };
+        assert!(tcp_template.validate().is_ok());
+        let probe = tcp_template.probe_for("2001:db8::1");
+        assert_eq!(
+            probe,
+            json!({
+                "dst_addr": "2001:db8::1", "src_port": 24000, "dst_port": 33434,
+                "ttl": 64, "protocol": "tcp", "flow_label": 0, "tcp_flags": "syn"
+            })
+        );
+        assert!(validate_json_probe(&probe).is_ok());
+
+        for invalid in [
+            TargetListProbes {
+                ttl: 0,
+                ..template.clone()
+            },
+            TargetListProbes {
+                src_port: 0,
+                ..template.clone()
+            },
+            TargetListProbes {
+                options: ProbeOptions {
+                    payload_size: Some(MAX_PAYLOAD_SIZE + 1),
+                    ..Default::default()
+                },
+                ..template.clone()
+            },
+            TargetListProbes {
+                options: ProbeOptions {
+                    flow_label: Some(MAX_FLOW_LABEL + 1),
+                    ..Default::default()
+                },
+                ..template.clone()
+            },
+            TargetListProbes {
+                options: ProbeOptions {
+                    tcp_flags: Some(TcpFlags::Ack),
+                    ..Default::default()
+                },
+                ..template.clone()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
 }
@@ -0,0 +1,190 @@
+use metrics::counter;
+use rdkafka::Message;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::database::Database;
+
+/// Default consumer group of the gateways reading agent progress
+pub const DEFAULT_PROGRESS_GROUP_ID: &str = "saimiris-gateway";
+
+// Delay before retrying an event after a database error, or after a failed
+// poll of the topic
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Progress of an agent on a measurement, as published on the progress topic.
+/// Same fields as the agent status endpoint; `sent_probes` is a running total.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ProgressEvent {
+    pub agent_id: String,
+    pub measurement_id: Uuid,
+    pub sent_probes: i32,
+    pub is_complete: bool,
+}
+
+impl ProgressEvent {
+    /// Parse a JSON progress event. Other messages on the topic (e.g. probe
+    /// replies) are not events.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        serde_json::from_slice(payload).ok()
+    }
+}
+
+/// What became of a progress event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressOutcome {
+    /// The measurement tracking was updated
+    Applied,
+    /// The event was a duplicate or older than the recorded progress
+    Stale,
+    /// No tracking exists for this agent and measurement
+    Unknown,
+}
+
+impl ProgressOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProgressOutcome::Applied => "applied",
+            ProgressOutcome::Stale => "stale",
+            ProgressOutcome::Unknown => "unknown",
+        }
+    }
+}
+
+/// Apply a progress event to the measurement tracking. Events are delivered at
+/// least once and possibly out of order, so progress only moves forward: an
+/// event that does not add sent probes or complete the measurement is ignored.
+/// The check is part of the update, as other gateways and the agent status
+/// endpoint update the same tracking concurrently.
+pub async fn apply_progress_event(
+    database: &Database,
+    event: &ProgressEvent,
+) -> Result<ProgressOutcome, sqlx::Error> {
+    if database
+        .advance_measurement_progress(
+            event.measurement_id,
+            &event.agent_id,
+            event.sent_probes,
+            event.is_complete,
+        )
+        .await?
+    {
+        return Ok(ProgressOutcome::Applied);
+    }
+
+    let tracking = database
+        .get_measurement_tracking_by_agent(event.measurement_id, &event.agent_id)
+        .await?;
+    Ok(match tracking {
+        Some(_) => ProgressOutcome::Stale,
+        None => ProgressOutcome::Unknown,
+    })
+}
+
+/// Read progress events until the gateway stops. A message's offset is stored,
+/// and later committed for the consumer group, once it has been handled.
+pub async fn consume_progress(database: Database, consumer: StreamConsumer) {
+    loop {
+        let message = match consumer.recv().await {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Failed to read agent progress: {}", err);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let outcome = match message.payload().and_then(ProgressEvent::parse) {
+            Some(event) => loop {
+                // Retry until the database is back, so no event is skipped
+                match apply_progress_event(&database, &event).await {
+                    Ok(outcome) => {
+                        debug!(
+                            "Progress of agent {} on measurement {}: {} probes sent, complete: {} ({})",
+                            event.agent_id,
+                            event.measurement_id,
+                            event.sent_probes,
+                            event.is_complete,
+                            outcome.as_str()
+                        );
+                        break outcome.as_str();
+                    }
+                    Err(err) => {
+                        error!("Failed to apply agent progress: {}", err);
+                        tokio::time::sleep(RETRY_DELAY).await;
+                    }
+                }
+            },
+            None => "invalid",
+        };
+        counter!("saimiris_gateway_progress_events_total", "outcome" => outcome).increment(1);
+
+        if let Err(err) = consumer.store_offset_from_message(&message) {
+            warn!("Failed to store agent progress offset: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(sent_probes: i32, is_complete: bool) -> ProgressEvent {
+        ProgressEvent {
+            agent_id: "agent1".to_string(),
+            measurement_id: Uuid::nil(),
+            sent_probes,
+            is_complete,
+        }
+    }
+
+    #[test]
+    fn test_parse_progress_event() {
+        let payload = br#"{"agent_id":"agent1","measurement_id":"00000000-0000-0000-0000-000000000000","sent_probes":5,"is_complete":false}"#;
+        assert_eq!(ProgressEvent::parse(payload), Some(event(5, false)));
+        assert_eq!(ProgressEvent::parse(b"\x00\x01"), None);
+        assert_eq!(ProgressEvent::parse(br#"{"agent_id":"agent1"}"#), None);
+    }
+
+    #[tokio::test]
+    async fn test_progress_only_moves_forward() {
+        let db = Database::new_mock();
+        db.create_measurement_tracking("user", Uuid::nil(), "agent1", 10)
+            .await
+            .unwrap();
+
+        let outcomes = [
+            (event(4, false), ProgressOutcome::Applied),
+            // Duplicate and out-of-order deliveries
+            (event(4, false), ProgressOutcome::Stale),
+            (event(2, false), ProgressOutcome::Stale),
+            (event(10, false), ProgressOutcome::Applied),
+            (event(10, true), ProgressOutcome::Applied),
+            (event(10, true), ProgressOutcome::Stale),
+            (event(10, false), ProgressOutcome::Stale),
+        ];
+        for (event, expected) in outcomes {
+            assert_eq!(apply_progress_event(&db, &event).await.unwrap(), expected);
+        }
+
+        let tracking = db
+            .get_measurement_tracking_by_agent(Uuid::nil(), "agent1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tracking.sent_probes, 10);
+        assert!(tracking.is_complete);
+
+        let unknown = ProgressEvent {
+            agent_id: "agent2".to_string(),
+            ..event(1, false)
+        };
+        assert_eq!(
+            apply_progress_event(&db, &unknown).await.unwrap(),
+            ProgressOutcome::Unknown
+        );
+    }
+}
@@ -0,0 +1,558 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::database::{Database, UsageSince};
+
+/// Probes per rolling 24 hours of the default quota
+pub const DEFAULT_DAILY_PROBE_LIMIT: u64 = 10_000;
+
+/// Maximum number of windows in a quota policy
+pub const MAX_QUOTA_WINDOWS: usize = 8;
+
+/// Longest rolling period, in seconds (366 days)
+pub const MAX_ROLLING_PERIOD: i64 = 366 * 86400;
+
+/// The period a quota window counts usage over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum QuotaPeriod {
+    /// The last given number of seconds, sliding with time
+    Rolling(i64),
+    /// The current UTC calendar day
+    Day,
+    /// The current UTC calendar month
+    Month,
+}
+
+fn month_start(year: i32, month: u32) -> DateTime<Utc> {
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc()
+}
+
+impl QuotaPeriod {
+    /// Check that a rolling period is positive and at most `MAX_ROLLING_PERIOD`
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            QuotaPeriod::Rolling(seconds) if *seconds <= 0 || *seconds > MAX_ROLLING_PERIOD => {
+                Err(format!(
+                    "Invalid quota period: {}s. Rolling periods last at most {}d",
+                    seconds,
+                    MAX_ROLLING_PERIOD / 86400
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Start of the period that contains `now`
+    pub fn start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            QuotaPeriod::Rolling(seconds) => now - Duration::seconds(*seconds),
+            QuotaPeriod::Day => now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc(),
+            QuotaPeriod::Month => month_start(now.year(), now.month()),
+        }
+    }
+
+    /// When usage counted at `now` starts being released: the end of a
+    /// calendar period, or when the oldest usage of a rolling period
+    /// (`oldest`) leaves it
+    pub fn resets_at(
+        &self,
+        now: DateTime<Utc>,
+        oldest: Option<DateTime<Utc>>,
+    ) -> Option<DateTime<Utc>> {
+        match self {
+            QuotaPeriod::Rolling(seconds) => {
+                oldest.map(|oldest| oldest + Duration::seconds(*seconds))
+            }
+            QuotaPeriod::Day => Some(self.start(now) + Duration::days(1)),
+            QuotaPeriod::Month => Some(if now.month() == 12 {
+                month_start(now.year() + 1, 1)
+            } else {
+                month_start(now.year(), now.month() + 1)
+            }),
+        }
+    }
+}
+
+impl FromStr for QuotaPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => return Ok(QuotaPeriod::Day),
+            "month" => return Ok(QuotaPeriod::Month),
+            _ => {}
+        }
+        let invalid = || {
+            format!(
+                "Invalid quota period: {}. Use day, month or a duration such as 30m, 24h or 7d",
+                s
+            )
+        };
+        let unit = match s.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 3600,
+            Some('d') => 86400,
+            _ => return Err(invalid()),
+        };
+        let count: i64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+        if count <= 0 {
+            return Err(invalid());
+        }
+        let period = count
+            .checked_mul(unit)
+            .map(QuotaPeriod::Rolling)
+            .ok_or_else(invalid)?;
+        period.validate()?;
+        Ok(period)
+    }
+}
+
+impl fmt::Display for QuotaPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaPeriod::Day => write!(f, "day"),
+            QuotaPeriod::Month => write!(f, "month"),
+            QuotaPeriod::Rolling(seconds) if seconds % 86400 == 0 => {
+                write!(f, "{}d", seconds / 86400)
+            }
+            QuotaPeriod::Rolling(seconds) if seconds % 3600 == 0 => {
+                write!(f, "{}h", seconds / 3600)
+            }
+            QuotaPeriod::Rolling(seconds) if seconds % 60 == 0 => write!(f, "{}m", seconds / 60),
+            QuotaPeriod::Rolling(seconds) => write!(f, "{}s", seconds),
+        }
+    }
+}
+
+impl TryFrom<String> for QuotaPeriod {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<QuotaPeriod> for String {
+    fn from(period: QuotaPeriod) -> Self {
+        period.to_string()
+    }
+}
+
+/// What a measurement charges to its user's quota, per agent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChargingMode {
+    /// The probes submitted, even those never sent
+    #[default]
+    Expected,
+    /// The probes sent, once the agent completed, was cancelled or was lost;
+    /// the probes submitted until then
+    Sent,
+    /// The probes submitted, minus the refund of those still unsent when
+    /// the measurement was cancelled or the agent was lost
+    Refunded,
+}
+
+impl FromStr for ChargingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "expected" => Ok(ChargingMode::Expected),
+            "sent" => Ok(ChargingMode::Sent),
+            "refunded" => Ok(ChargingMode::Refunded),
+            _ => Err(format!(
+                "Invalid charging mode: {}. Use expected, sent or refunded",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChargingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChargingMode::Expected => write!(f, "expected"),
+            ChargingMode::Sent => write!(f, "sent"),
+            ChargingMode::Refunded => write!(f, "refunded"),
+        }
+    }
+}
+
+/// At most `limit` probes per period. A submission that starts under the
+/// limit may go over it by up to `burst` probes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaWindow {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    #[serde(default)]
+    pub burst: u64,
+}
+
+impl QuotaWindow {
+    /// Can `probes` more probes be charged when `used` are already?
+    pub fn allows(&self, used: u64, probes: u64) -> bool {
+        let after = used.saturating_add(probes);
+        after <= self.limit || (used < self.limit && after <= self.limit.saturating_add(self.burst))
+    }
+}
+
+/// Parse a quota window given as `period=limit[+burst]`, e.g. `24h=10000` or
+/// `month=200000+5000`
+pub fn parse_window(s: &str) -> Result<QuotaWindow, String> {
+    let (period, amounts) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid quota window: {}. Use period=limit[+burst]", s))?;
+    let (limit, burst) = amounts.split_once('+').unwrap_or((amounts, "0"));
+    let parse_amount = |amount: &str| {
+        amount
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid quota window amount: {}", amount))
+    };
+    Ok(QuotaWindow {
+        period: period.trim().parse()?,
+        limit: parse_amount(limit)?,
+        burst: parse_amount(burst)?,
+    })
+}
+
+/// A user's quota: every submission must fit in each of the windows
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaPolicy {
+    pub windows: Vec<QuotaWindow>,
+}
+
+impl Default for QuotaPolicy {
+    fn default() -> Self {
+        QuotaPolicy {
+            windows: vec![QuotaWindow {
+                period: QuotaPeriod::Day,
+                limit: DEFAULT_DAILY_PROBE_LIMIT,
+                burst: 0,
+            }],
+        }
+    }
+}
+
+impl QuotaPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.windows.is_empty() {
+            return Err("A quota policy needs at least one window".to_string());
+        }
+        if self.windows.len() > MAX_QUOTA_WINDOWS {
+            return Err(format!(
+                "A quota policy has at most {} windows",
+                MAX_QUOTA_WINDOWS
+            ));
+        }
+        for (i, window) in self.windows.iter().enumerate() {
+            window.period.validate()?;
+            if self.windows[..i].iter().any(|w| w.period == window.period) {
+                return Err(format!("Duplicate quota window: {}", window.period));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Usage of a quota window
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WindowUsage {
+    #[serde(flatten)]
+    pub window: QuotaWindow,
+    pub used: u64,
+    /// Probes left before the limit (the burst comes on top)
+    pub remaining: u64,
+    /// Probes refunded in the window, deducted from `used` unless charging
+    /// the expected probes
+    pub refunded: u64,
+    pub resets_at: Option<DateTime<Utc>>,
+}
+
+/// The policy of a user: their own, else their legacy probe limit (per UTC
+/// day, as before quota policies), else `defaults`
+pub async fn user_policy(
+    database: &Database,
+    defaults: &QuotaPolicy,
+    user_hash: &str,
+) -> Result<QuotaPolicy, sqlx::Error> {
+    if let Some(quota) = database.get_user_quota(user_hash).await? {
+        let policy = serde_json::from_str::<Vec<QuotaWindow>>(&quota.windows)
+            .map_err(|err| err.to_string())
+            .map(|windows| QuotaPolicy { windows })
+            .and_then(|policy| policy.validate().map(|_| policy));
+        match policy {
+            Ok(policy) => return Ok(policy),
+            Err(err) => warn!(
+                "Ignoring invalid quota policy of user {}: {}",
+                user_hash, err
+            ),
+        }
+    }
+    if let Some(limit) = database.get_user_limit_by_hash(user_hash).await? {
+        return Ok(QuotaPolicy {
+            windows: vec![QuotaWindow {
+                period: QuotaPeriod::Day,
+                limit: limit.probe_limit as u64,
+                burst: 0,
+            }],
+        });
+    }
+    Ok(defaults.clone())
+}
+
+impl WindowUsage {
+    fn new(window: QuotaWindow, usage: UsageSince, now: DateTime<Utc>) -> Self {
+        let used = usage.probes.max(0) as u64;
+        WindowUsage {
+            window,
+            used,
+            remaining: window.limit.saturating_sub(used),
+            refunded: usage.refunded.max(0) as u64,
+            resets_at: window.period.resets_at(now, usage.oldest),
+        }
+    }
+}
+
+/// Usage of each window of `policy` at `now`
+pub async fn policy_usage(
+    database: &Database,
+    policy: &QuotaPolicy,
+    charging: ChargingMode,
+    user_hash: &str,
+    now: DateTime<Utc>,
+) -> Result<Vec<WindowUsage>, sqlx::Error> {
+    let mut usage = Vec::with_capacity(policy.windows.len());
+    for window in &policy.windows {
+        let since = database
+            .get_usage_since(user_hash, window.period.start(now), charging)
+            .await?;
+        usage.push(WindowUsage::new(*window, since, now));
+    }
+    Ok(usage)
+}
+
+/// Outcome of a quota reservation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reservation {
+    /// The probes are held until the reservation is released
+    Reserved(Uuid),
+    /// The probes don't fit in this window
+    Exceeded(WindowUsage),
+}
+
+/// Atomically check that `probes` more probes fit in every window of the
+/// user's policy at `now` and reserve them. The reservation counts as usage
+/// until released, which the caller does once the probes are tracked or
+/// were not dispatched.
+pub async fn reserve(
+    database: &Database,
+    defaults: &QuotaPolicy,
+    charging: ChargingMode,
+    user_hash: &str,
+    probes: u64,
+    now: DateTime<Utc>,
+) -> Result<Reservation, sqlx::Error> {
+    let policy = user_policy(database, defaults, user_hash).await?;
+    let starts: Vec<_> = policy
+        .windows
+        .iter()
+        .map(|window| window.period.start(now))
+        .collect();
+    let admit = |usage: &[UsageSince]| {
+        policy
+            .windows
+            .iter()
+            .zip(usage)
+            .all(|(window, usage)| window.allows(usage.probes.max(0) as u64, probes))
+    };
+
+    let check = database
+        .reserve_quota(user_hash, probes as i64, &starts, charging, &admit)
+        .await?;
+    if let Some(reservation) = check.reservation {
+        return Ok(Reservation::Reserved(reservation));
+    }
+    let exceeded = policy
+        .windows
+        .iter()
+        .zip(check.usage)
+        .map(|(window, usage)| WindowUsage::new(*window, usage, now))
+        .find(|usage| !usage.window.allows(usage.used, probes))
+        .expect("a refused reservation exceeds a window");
+    Ok(Reservation::Exceeded(exceeded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_periods() {
+        assert_eq!("day".parse(), Ok(QuotaPeriod::Day));
+        assert_eq!("month".parse(), Ok(QuotaPeriod::Month));
+        assert_eq!("24h".parse(), Ok(QuotaPeriod::Rolling(86400)));
+        assert_eq!("30m".parse(), Ok(QuotaPeriod::Rolling(1800)));
+        assert_eq!("7d".parse(), Ok(QuotaPeriod::Rolling(7 * 86400)));
+        assert_eq!("366d".parse(), Ok(QuotaPeriod::Rolling(MAX_ROLLING_PERIOD)));
+        for invalid in [
+            "",
+            "h",
+            "0h",
+            "-1h",
+            "24x",
+            "week",
+            "367d",
+            "10000000000000s",
+            "10000000000000000s",
+        ] {
+            assert!(invalid.parse::<QuotaPeriod>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn display_periods_in_largest_unit() {
+        for period in ["day", "month", "7d", "36h", "90m", "45s"] {
+            assert_eq!(period.parse::<QuotaPeriod>().unwrap().to_string(), period);
+        }
+        assert_eq!(QuotaPeriod::Rolling(86400).to_string(), "1d");
+    }
+
+    #[test]
+    fn parse_charging_modes() {
+        for mode in ["expected", "sent", "refunded"] {
+            assert_eq!(mode.parse::<ChargingMode>().unwrap().to_string(), mode);
+        }
+        assert_eq!(ChargingMode::default(), ChargingMode::Expected);
+        assert!("unsent".parse::<ChargingMode>().is_err());
+    }
+
+    #[test]
+    fn parse_windows() {
+        assert_eq!(
+            parse_window("24h=10000"),
+            Ok(QuotaWindow {
+                period: QuotaPeriod::Rolling(86400),
+                limit: 10000,
+                burst: 0,
+            })
+        );
+        assert_eq!(
+            parse_window("month=200000+5000"),
+            Ok(QuotaWindow {
+                period: QuotaPeriod::Month,
+                limit: 200000,
+                burst: 5000,
+            })
+        );
+        assert!(parse_window("24h").is_err());
+        assert!(parse_window("24h=lots").is_err());
+        assert!(parse_window("24h=100+").is_err());
+    }
+
+    #[test]
+    fn calendar_periods_start_and_reset() {
+        let now = Utc.with_ymd_and_hms(2026, 12, 31, 23, 59, 0).unwrap();
+        assert_eq!(
+            QuotaPeriod::Day.start(now),
+            Utc.with_ymd_and_hms(2026, 12, 31, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            QuotaPeriod::Day.resets_at(now, None),
+            Some(Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            QuotaPeriod::Month.start(now),
+            Utc.with_ymd_and_hms(2026, 12, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            QuotaPeriod::Month.resets_at(now, None),
+            Some(Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn rolling_period_resets_when_oldest_usage_leaves() {
+        let now = Utc::now();
+        let period = QuotaPeriod::Rolling(3600);
+        assert_eq!(period.start(now), now - Duration::hours(1));
+        assert_eq!(period.resets_at(now, None), None);
+        let oldest = now - Duration::minutes(50);
+        assert_eq!(
+            period.resets_at(now, Some(oldest)),
+            Some(oldest + Duration::hours(1))
+        );
+    }
+
+    #[test]
+    fn burst_only_from_under_the_limit() {
+        let window = QuotaWindow {
+            period: QuotaPeriod::Day,
+            limit: 100,
+            burst: 20,
+        };
+        assert!(window.allows(0, 100));
+        assert!(window.allows(90, 30));
+        assert!(!window.allows(90, 31));
+        assert!(!window.allows(100, 1));
+
+        let strict = QuotaWindow { burst: 0, ..window };
+        assert!(!strict.allows(90, 11));
+    }
+
+    #[test]
+    fn validate_policies() {
+        assert!(QuotaPolicy::default().validate().is_ok());
+        // Per UTC day, like the limit before quota policies
+        assert_eq!(QuotaPolicy::default().windows[0].period, QuotaPeriod::Day);
+        assert!(QuotaPolicy { windows: vec![] }.validate().is_err());
+
+        let daily = parse_window("24h=10000").unwrap();
+        let duplicate = QuotaPolicy {
+            windows: vec![daily, parse_window("1d=500").unwrap()],
+        };
+        assert_eq!(
+            duplicate.validate(),
+            Err("Duplicate quota window: 1d".to_string())
+        );
+
+        let endless = QuotaPolicy {
+            windows: vec![QuotaWindow {
+                period: QuotaPeriod::Rolling(i64::MAX),
+                ..daily
+            }],
+        };
+        assert!(endless.validate().is_err());
+    }
+
+    #[test]
+    fn windows_serialize_with_period_names() {
+        let windows = vec![
+            parse_window("24h=10000").unwrap(),
+            parse_window("month=200000+5000").unwrap(),
+        ];
+        let json = serde_json::to_value(&windows).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"period": "1d", "limit": 10000, "burst": 0},
+                {"period": "month", "limit": 200000, "burst": 5000}
+            ])
+        );
+        let parsed: Vec<QuotaWindow> =
+            serde_json::from_value(serde_json::json!([{"period": "1h", "limit": 5}])).unwrap();
+        assert_eq!(parsed, vec![parse_window("1h=5").unwrap()]);
+    }
+}
@@ -0,0 +1,189 @@
+use chrono::{DateTime, Duration, Utc};
+use metrics::counter;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{error, info};
+
+use crate::database::Database;
+
+/// How often the background retention job applies the policies
+pub const RETENTION_TICK_SECONDS: u64 = 3600;
+
+/// Rows deleted per transaction. Small enough not to hold locks for long.
+const PURGE_CHUNK_SIZE: i32 = 500;
+
+/// Upper bound on the chunks purged per table in one run; a larger backlog is
+/// worked through over the next runs
+const MAX_CHUNKS_PER_RUN: usize = 100;
+
+/// How long rows are kept, in days. A table without a policy is never purged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RetentionPolicy {
+    /// Completed and cancelled measurements, after their last update. Their
+    /// usage is kept in the daily rollup.
+    pub measurement_days: Option<u32>,
+    /// Rows of the legacy `probe_usage` table
+    pub probe_usage_days: Option<u32>,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.measurement_days.is_some() || self.probe_usage_days.is_some()
+    }
+}
+
+/// Rows purged by a retention run, or that a run would purge
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RetentionReport {
+    pub measurements: u64,
+    pub measurement_rows: u64,
+    pub probe_usage_rows: u64,
+}
+
+fn cutoff(days: Option<u32>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    days.map(|days| now - Duration::days(days as i64))
+}
+
+/// Count what applying the policies at `now` would purge
+pub async fn preview_retention(
+    database: &Database,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> Result<RetentionReport, sqlx::Error> {
+    let mut report = RetentionReport::default();
+    if let Some(before) = cutoff(policy.measurement_days, now) {
+        let expired = database.count_expired_measurements(before).await?;
+        report.measurements = expired.measurements;
+        report.measurement_rows = expired.rows;
+    }
+    if let Some(before) = cutoff(policy.probe_usage_days, now) {
+        report.probe_usage_rows = database.count_expired_probe_usage(before).await?;
+    }
+    Ok(report)
+}
+
+/// Purge in chunks until a chunk comes back short or the run reaches its
+/// chunk budget. Returns the purged count, and the error that stopped it.
+async fn purge_in_chunks<F, Fut>(mut purge_chunk: F) -> (u64, Option<sqlx::Error>)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<u64, sqlx::Error>>,
+{
+    let mut purged = 0;
+    for _ in 0..MAX_CHUNKS_PER_RUN {
+        match purge_chunk().await {
+            Ok(count) => {
+                purged += count;
+                if count < PURGE_CHUNK_SIZE as u64 {
+                    break;
+                }
+            }
+            Err(err) => return (purged, Some(err)),
+        }
+    }
+    (purged, None)
+}
+
+/// Apply the policies at `now`. Errors are logged and the other tables are
+/// still purged. Returns what was purged.
+pub async fn apply_retention(
+    database: &Database,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> RetentionReport {
+    let mut report = RetentionReport::default();
+
+    if let Some(before) = cutoff(policy.measurement_days, now) {
+        let rows = &AtomicU64::new(0);
+        let (measurements, err) = purge_in_chunks(|| async move {
+            let purged = database
+                .purge_expired_measurements(before, PURGE_CHUNK_SIZE)
+                .await?;
+            rows.fetch_add(purged.rows, Ordering::Relaxed);
+            Ok(purged.measurements)
+        })
+        .await;
+        let rows = rows.load(Ordering::Relaxed);
+        if let Some(err) = err {
+            error!("Failed to purge expired measurements: {}", err);
+        }
+        report.measurements = measurements;
+        report.measurement_rows = rows;
+        counter!("saimiris_gateway_retention_purged_total", "table" => "measurement_tracking")
+            .increment(rows);
+    }
+
+    if let Some(before) = cutoff(policy.probe_usage_days, now) {
+        let (rows, err) =
+            purge_in_chunks(|| database.purge_expired_probe_usage(before, PURGE_CHUNK_SIZE)).await;
+        if let Some(err) = err {
+            error!("Failed to purge expired probe usage: {}", err);
+        }
+        report.probe_usage_rows = rows;
+        counter!("saimiris_gateway_retention_purged_total", "table" => "probe_usage")
+            .increment(rows);
+    }
+
+    if report != RetentionReport::default() {
+        info!(
+            "Retention purged {} measurements ({} rows) and {} probe usage rows",
+            report.measurements, report.measurement_rows, report.probe_usage_rows
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_without_days_is_disabled() {
+        assert!(!RetentionPolicy::default().is_enabled());
+        let policy = RetentionPolicy {
+            probe_usage_days: Some(30),
+            ..Default::default()
+        };
+        assert!(policy.is_enabled());
+    }
+
+    #[test]
+    fn cutoff_is_days_before_now() {
+        let now = Utc::now();
+        assert_eq!(cutoff(None, now), None);
+        assert_eq!(cutoff(Some(7), now), Some(now - Duration::days(7)));
+    }
+
+    #[tokio::test]
+    async fn purge_in_chunks_stops_on_short_chunk() {
+        let mut calls = 0;
+        let (purged, err) = purge_in_chunks(|| {
+            calls += 1;
+            let count = if calls < 3 {
+                PURGE_CHUNK_SIZE as u64
+            } else {
+                7
+            };
+            async move { Ok(count) }
+        })
+        .await;
+        assert!(err.is_none());
+        assert_eq!(calls, 3);
+        assert_eq!(purged, 2 * PURGE_CHUNK_SIZE as u64 + 7);
+    }
+
+    #[tokio::test]
+    async fn purge_in_chunks_is_bounded() {
+        let mut calls = 0;
+        let (purged, _) = purge_in_chunks(|| {
+            calls += 1;
+            async { Ok(PURGE_CHUNK_SIZE as u64) }
+        })
+        .await;
+        assert_eq!(calls, MAX_CHUNKS_PER_RUN);
+        assert_eq!(
+            purged,
+            (MAX_CHUNKS_PER_RUN as u64) * PURGE_CHUNK_SIZE as u64
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use chrono::{DateTime, Duration, Utc};
+use metrics::counter;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::database::MeasurementSchedule;
+use crate::probe::SubmitProbesRequest;
+
+/// How often the background scheduler looks for due schedules
+pub const SCHEDULER_TICK_SECONDS: u64 = 10;
+
+/// Shortest allowed interval between runs of a recurring schedule
+pub const MIN_SCHEDULE_INTERVAL_SECONDS: i64 = 60;
+
+/// Maximum number of schedules a single user can have
+pub const MAX_SCHEDULES_PER_USER: usize = 100;
+
+/// Upper bound on the number of schedules fired in one scheduler tick
+const MAX_RUNS_PER_TICK: i32 = 100;
+
+/// Next run of a schedule that was due at `run_at`. One-shot schedules have no
+/// next run. Recurring schedules move to the first slot after `now`, so runs
+/// missed while the gateway was down are skipped rather than fired in a burst.
+pub fn next_run_after(
+    run_at: DateTime<Utc>,
+    interval_seconds: Option<i64>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let interval = interval_seconds.filter(|seconds| *seconds > 0)?;
+    let elapsed = (now - run_at).num_seconds().max(0);
+    let slots = elapsed / interval + 1;
+    Some(run_at + Duration::seconds(slots * interval))
+}
+
+/// Fire every schedule that is due. Each schedule is first advanced to its next
+/// run, so a schedule is only fired by the gateway instance that advanced it.
+/// Returns the number of runs attempted.
+pub async fn run_due_schedules(state: &AppState) -> usize {
+    let now = Utc::now();
+
+    let due = match state
+        .database
+        .get_due_schedules(now, MAX_RUNS_PER_TICK)
+        .await
+    {
+        Ok(due) => due,
+        Err(err) => {
+            error!("Failed to fetch due schedules: {}", err);
+            return 0;
+        }
+    };
+
+    let mut fired = 0;
+    for schedule in due {
+        let Some(run_at) = schedule.next_run_at else {
+            continue;
+        };
+        let next_run_at = next_run_after(run_at, schedule.interval_seconds, now);
+
+        match state
+            .database
+            .advance_schedule(schedule.id, run_at, next_run_at)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Schedule {} was already fired elsewhere", schedule.id);
+                continue;
+            }
+            Err(err) => {
+                error!("Failed to advance schedule {}: {}", schedule.id, err);
+                continue;
+            }
+        }
+
+        run_schedule(state, &schedule).await;
+        fired += 1;
+    }
+
+    fired
+}
+
+/// Dispatch one run of a schedule through the regular submission path and
+/// record its outcome
+async fn run_schedule(state: &AppState, schedule: &MeasurementSchedule) {
+    let result = match serde_json::from_str::<SubmitProbesRequest>(&schedule.spec) {
+        Ok(request) => crate::dispatch_probes_by_hash(state, &schedule.user_hash, &request)
+            .await
+            .map_err(|(_, body)| {
+                body["message"]
+                    .as_str()
+                    .unwrap_or("Failed to dispatch probes")
+                    .to_string()
+            }),
+        Err(err) => Err(format!("Invalid schedule spec: {}", err)),
+    };
+
+    let (measurement_id, run_error) = match &result {
+        Ok(response) => {
+            info!(
+                "Schedule {} started measurement {}",
+                schedule.id, response.id
+            );
+            counter!("saimiris_gateway_schedule_runs_total").increment(1);
+            (Uuid::parse_str(&response.id).ok(), None)
+        }
+        Err(message) => {
+            error!("Schedule {} run failed: {}", schedule.id, message);
+            counter!("saimiris_gateway_schedule_run_errors_total").increment(1);
+            (None, Some(message.as_str()))
+        }
+    };
+
+    if let Err(err) = state
+        .database
+        .record_schedule_run(schedule.id, measurement_id, run_error)
+        .await
+    {
+        error!("Failed to record run of schedule {}: {}", schedule.id, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_run_after_one_shot_is_none() {
+        let now = Utc::now();
+        assert_eq!(next_run_after(now, None, now), None);
+    }
+
+    #[test]
+    fn next_run_after_advances_one_interval() {
+        let run_at = Utc::now();
+        let next = next_run_after(run_at, Some(3600), run_at + Duration::seconds(5));
+        assert_eq!(next, Some(run_at + Duration::seconds(3600)));
+    }
+
+    #[test]
+    fn next_run_after_skips_missed_runs() {
+        let run_at = Utc::now();
+        // Gateway was down for 2.5 intervals: the next run is the first future slot
+        let now = run_at + Duration::seconds(150);
+        let next = next_run_after(run_at, Some(60), now);
+        assert_eq!(next, Some(run_at + Duration::seconds(180)));
+    }
+}
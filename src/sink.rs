@@ -0,0 +1,186 @@
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+
+use crate::kafka::OutboundMessage;
+
+/// Destination of the probe batches dispatched to agents
+#[async_trait]
+pub trait ProbeSink: Send + Sync {
+    /// Deliver a measurement's messages, in order. Sinks that support it
+    /// deliver them all together or not at all. A sink that fails after
+    /// delivering some of them says so with a `PartialDelivery` context, any
+    /// other error means nothing was delivered.
+    async fn send(&self, messages: &[OutboundMessage]) -> Result<()>;
+
+    /// Check that the sink can currently accept messages
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Context of a failed `ProbeSink::send` whose first `delivered` messages
+/// reached the agents anyway
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialDelivery {
+    pub delivered: usize,
+}
+
+impl std::fmt::Display for PartialDelivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed after delivering {} messages", self.delivered)
+    }
+}
+
+/// Which probe sink the gateway dispatches to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeSinkKind {
+    /// Produce to the Kafka probes topic
+    Kafka,
+    /// Keep messages in memory (nothing reaches the agents)
+    Memory,
+    /// Append messages to an NDJSON file
+    File,
+}
+
+impl FromStr for ProbeSinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "kafka" => Ok(ProbeSinkKind::Kafka),
+            "memory" => Ok(ProbeSinkKind::Memory),
+            "file" => Ok(ProbeSinkKind::File),
+            _ => Err(format!("Invalid probe sink: {}", s)),
+        }
+    }
+}
+
+/// Sink keeping every message in memory, e.g. for tests to assert on what was
+/// dispatched
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    messages: Arc<Mutex<Vec<OutboundMessage>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All messages sent so far, in order
+    pub fn messages(&self) -> Vec<OutboundMessage> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    /// Remove and return the messages sent so far
+    pub fn take(&self) -> Vec<OutboundMessage> {
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl ProbeSink for MemorySink {
+    async fn send(&self, messages: &[OutboundMessage]) -> Result<()> {
+        self.messages.lock().unwrap().extend_from_slice(messages);
+        Ok(())
+    }
+}
+
+/// Sink appending each message as a line of JSON to a file, for offline runs.
+/// Payloads are hex-encoded.
+pub struct FileSink {
+    path: PathBuf,
+    // Keeps the lines of concurrent measurements from interleaving
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl FileSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Render a message as one NDJSON line
+    pub fn line(message: &OutboundMessage) -> String {
+        let headers: serde_json::Map<String, serde_json::Value> = message
+            .headers
+            .iter()
+            .map(|(key, value)| (key.clone(), serde_json::Value::String(value.clone())))
+            .collect();
+        let mut line = serde_json::json!({
+            "key": message.key,
+            "headers": headers,
+            "payload": hex::encode(&message.payload),
+        });
+        if let Some(topic) = &message.topic {
+            line["topic"] = topic.clone().into();
+        }
+        if let Some(partition) = message.partition {
+            line["partition"] = partition.into();
+        }
+        let mut line = line.to_string();
+        line.push('\n');
+        line
+    }
+}
+
+#[async_trait]
+impl ProbeSink for FileSink {
+    async fn send(&self, messages: &[OutboundMessage]) -> Result<()> {
+        let contents: String = messages.iter().map(Self::line).collect();
+
+        let _guard = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|err| anyhow!("Failed to open {}: {}", self.path.display(), err))?;
+        // A single write, so a measurement's lines land together
+        file.write_all(contents.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(payload: &[u8]) -> OutboundMessage {
+        OutboundMessage {
+            key: "measurement".to_string(),
+            payload: payload.to_vec(),
+            headers: vec![("agent1".to_string(), "{\"src_ip\":null}".to_string())],
+            topic: None,
+            partition: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_appends_ndjson() {
+        let path = std::env::temp_dir().join(format!("saimiris-sink-{}", uuid::Uuid::new_v4()));
+        let sink = FileSink::new(&path);
+        sink.send(&[message(b"\x01\x02")]).await.unwrap();
+        sink.send(&[message(b"\xff")]).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["key"], "measurement");
+        assert_eq!(lines[0]["payload"], "0102");
+        assert_eq!(lines[0]["headers"]["agent1"], "{\"src_ip\":null}");
+        assert_eq!(lines[1]["payload"], "ff");
+
+        std::fs::remove_file(path).ok();
+    }
+}
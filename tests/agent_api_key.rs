@@ -1,6 +1,14 @@
 use axum::{body::Body, http::Request};
-use rdkafka::config::ClientConfig;
-use saimiris_gateway::{AppState, agent::AgentStore, database::Database, kafka};
+use saimiris_gateway::{
+    AppState,
+    agent::AgentStore,
+    blocklist::{Blocklist, BlocklistAction},
+    database::Database,
+    kafka,
+    probe::SpecialPurposeFilter,
+    sink::MemorySink,
+};
+use std::sync::Arc;
 
 async fn create_mock_database() -> Database {
     // Create a mock database that doesn't require PostgreSQL
@@ -14,22 +22,29 @@ async fn test_agent_key_middleware_with_valid_key() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     let state = AppState {
         agent_store: AgentStore::new(),
         agent_key: "test-key".to_string(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: false,
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
 
     let request = Request::builder()
@@ -55,22 +70,29 @@ async fn test_agent_key_middleware_with_invalid_key() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     let state = AppState {
         agent_store: AgentStore::new(),
         agent_key: "test-key".to_string(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: false,
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
 
     let request = Request::builder()
@@ -95,22 +117,29 @@ async fn test_agent_key_middleware_without_header() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     let _state = AppState {
         agent_store: AgentStore::new(),
         agent_key: "test-key".to_string(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: false,
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
 
     let request = Request::builder().body(Body::empty()).unwrap();
@@ -131,22 +160,29 @@ async fn test_agent_key_middleware_with_malformed_header() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     let _state = AppState {
         agent_store: AgentStore::new(),
         agent_key: "test-key".to_string(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: false,
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
 
     let request = Request::builder()
@@ -173,22 +209,29 @@ async fn test_app_state_creation() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     let state = AppState {
         agent_store: agent_store.clone(),
         agent_key: agent_key.clone(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: false,
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
 
     assert_eq!(state.agent_key, agent_key);
@@ -13,6 +13,9 @@ fn test_agent_metadata_in_response() {
         id: "test-measurement-id".to_string(),
         probes: 5,
         agents: vec![agent_meta],
+        filtered: 0,
+        round: None,
+        archived: None,
     };
 
     // Serialize to JSON to verify the structure
@@ -0,0 +1,157 @@
+use axum_test::TestServer;
+use saimiris_gateway::{
+    AppState, create_app, kafka,
+    probe::{self},
+    sink::MemorySink,
+};
+use serde_json::json;
+use std::sync::Arc;
+
+mod common;
+
+const AGENTS: [&str; 2] = ["agent-a", "agent-b"];
+
+async fn create_test_state(
+    sink: &MemorySink,
+    batch_format: kafka::BatchFormat,
+    routing: kafka::TopicRouting,
+) -> AppState {
+    AppState {
+        kafka_config: kafka::KafkaConfig {
+            batch_format,
+            routing,
+            ..common::test_kafka_config()
+        },
+        probe_sink: Arc::new(sink.clone()),
+        ..common::create_api_test_state(&AGENTS).await
+    }
+}
+
+// IPv4 source addresses skip the user prefix check
+fn request() -> serde_json::Value {
+    json!({
+        "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]],
+        "metadata": [
+            {"id": "agent-a", "ip_address": "192.0.2.1"},
+            {"id": "agent-b", "ip_address": "192.0.2.2"}
+        ]
+    })
+}
+
+async fn submit(state: AppState) {
+    let server = TestServer::new(create_app(state));
+    let response = server.post("/api/probes").json(&request()).await;
+    assert_eq!(response.status_code(), 200);
+}
+
+#[tokio::test]
+async fn test_shared_routing() {
+    let sink = MemorySink::new();
+    submit(
+        create_test_state(
+            &sink,
+            kafka::BatchFormat::Legacy,
+            kafka::TopicRouting::Shared,
+        )
+        .await,
+    )
+    .await;
+
+    // One message for both agents, on the probes topic
+    let messages = sink.messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].topic, None);
+    assert_eq!(messages[0].partition, None);
+    assert_eq!(messages[0].headers.len(), 2);
+}
+
+#[tokio::test]
+async fn test_agent_topic_routing() {
+    let sink = MemorySink::new();
+    submit(
+        create_test_state(
+            &sink,
+            kafka::BatchFormat::Legacy,
+            kafka::TopicRouting::AgentTopic {
+                template: kafka::DEFAULT_AGENT_TOPIC_TEMPLATE.to_string(),
+                create: false,
+            },
+        )
+        .await,
+    )
+    .await;
+
+    let messages = sink.messages();
+    assert_eq!(messages.len(), 2);
+    for (message, agent) in messages.iter().zip(AGENTS) {
+        assert_eq!(
+            message.topic.as_deref(),
+            Some(format!("probes-{}", agent).as_str())
+        );
+        assert_eq!(message.partition, None);
+        // Each copy only carries its agent's header
+        assert_eq!(message.headers.len(), 1);
+        assert_eq!(message.headers[0].0, agent);
+    }
+    assert_eq!(messages[0].payload, messages[1].payload);
+}
+
+#[tokio::test]
+async fn test_agent_partition_routing() {
+    let sink = MemorySink::new();
+    submit(
+        create_test_state(
+            &sink,
+            kafka::BatchFormat::Envelope,
+            kafka::TopicRouting::AgentPartition { partitions: 8 },
+        )
+        .await,
+    )
+    .await;
+
+    let messages = sink.messages();
+    assert_eq!(messages.len(), 2);
+    for (message, agent) in messages.iter().zip(AGENTS) {
+        assert_eq!(message.topic, None);
+        assert_eq!(message.partition, Some(kafka::agent_partition(agent, 8)));
+        let (envelope, decoded) = probe::BatchEnvelope::decode(&message.payload).unwrap();
+        assert_eq!(envelope.agents.len(), 1);
+        assert_eq!(envelope.agents[0].id, agent);
+        assert!(envelope.end_of_measurement);
+        assert_eq!(decoded.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_agents_are_not_routed() {
+    let sink = MemorySink::new();
+    let state = create_test_state(
+        &sink,
+        kafka::BatchFormat::Legacy,
+        kafka::TopicRouting::AgentTopic {
+            template: kafka::DEFAULT_AGENT_TOPIC_TEMPLATE.to_string(),
+            create: false,
+        },
+    )
+    .await;
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]],
+            "metadata": [
+                {"id": "agent-a", "ip_address": "192.0.2.1"},
+                {"id": "agent-unknown", "ip_address": "192.0.2.3"}
+            ]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    // Only the agent the measurement was assigned to gets batches
+    let messages = sink.messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].topic.as_deref(), Some("probes-agent-a"));
+    assert_eq!(messages[0].headers.len(), 1);
+    assert_eq!(messages[0].headers[0].0, "agent-a");
+}
@@ -17,6 +17,8 @@ fn test_agent_config_serialization() {
         packets: 1000,
         probing_rate: 100,
         rate_limiting_method: "None".to_string(),
+        probe_options: Vec::new(),
+        max_payload_size: None,
     };
 
     let serialized = serde_json::to_string(&config).unwrap();
@@ -0,0 +1,190 @@
+use axum_test::TestServer;
+use saimiris_gateway::{AppState, archive::ProbeArchive, create_app, hash_user_identifier, probe};
+use serde_json::json;
+use uuid::Uuid;
+
+mod common;
+
+async fn create_test_state(probe_archive: Option<ProbeArchive>) -> AppState {
+    AppState {
+        probe_archive,
+        ..common::create_api_test_state(&[]).await
+    }
+}
+
+/// Archive a measurement for the bypass user, as `submit_probes` does after
+/// sending its batches to Kafka.
+async fn seed_archive(state: &AppState) -> (Uuid, Vec<u8>) {
+    let probes = vec![
+        json!(["2001:4860:4860::8888", 24000, 33434, 1, "udp"]),
+        json!(["2001:4860:4860::8844", 24000, 33434, 2, "udp"]),
+    ];
+    let batches = probe::deserialize_probes_batch(&probes, 1_000_000).unwrap();
+    let measurement_id = Uuid::new_v4();
+
+    let archive = state.probe_archive.as_ref().unwrap();
+    let compressed = ProbeArchive::compress(&batches).unwrap();
+    let metadata = json!([{"id": "test-agent", "ip_address": "2001:db8::1"}]).to_string();
+    state
+        .database
+        .append_measurement_archive(
+            measurement_id,
+            &hash_user_identifier("test-user-id"),
+            &ProbeArchive::key(measurement_id),
+            &metadata,
+            probes.len() as i32,
+            compressed.len() as i64,
+        )
+        .await
+        .unwrap();
+    archive
+        .store_segment(measurement_id, 0, compressed)
+        .await
+        .unwrap();
+
+    let blob = archive.load(measurement_id, 1).await.unwrap().unwrap();
+    (measurement_id, blob)
+}
+
+fn temp_archive() -> (ProbeArchive, std::path::PathBuf) {
+    let root = std::env::temp_dir().join(format!("saimiris-archive-{}", Uuid::new_v4()));
+    (ProbeArchive::filesystem(&root), root)
+}
+
+#[tokio::test]
+async fn test_download_archived_probes() {
+    let (archive, root) = temp_archive();
+    let state = create_test_state(Some(archive)).await;
+    let (measurement_id, blob) = seed_archive(&state).await;
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .get(&format!("/api/measurement/{}/probes", measurement_id))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.header("content-type"), "application/gzip");
+    assert_eq!(response.as_bytes().to_vec(), blob);
+
+    // Measurements without an archive
+    let response = server
+        .get(&format!("/api/measurement/{}/probes", Uuid::new_v4()))
+        .await;
+    assert_eq!(response.status_code(), 404);
+    let response = server.get("/api/measurement/not-a-uuid/probes").await;
+    assert_eq!(response.status_code(), 400);
+
+    std::fs::remove_dir_all(root).ok();
+}
+
+#[tokio::test]
+async fn test_replay_archived_measurement() {
+    let (archive, root) = temp_archive();
+    let state = create_test_state(Some(archive)).await;
+    let (measurement_id, _) = seed_archive(&state).await;
+    let server = TestServer::new(create_app(state));
+
+    // The archived probes are decoded and go through the regular submission
+    // path, which stops at agent selection since no agent is registered here
+    let response = server
+        .post(&format!("/api/measurement/{}/replay", measurement_id))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let error: serde_json::Value = response.json();
+    assert_eq!(
+        error["message"],
+        "No healthy agents found for the requested measurement"
+    );
+
+    let response = server
+        .post(&format!("/api/measurement/{}/replay", Uuid::new_v4()))
+        .await;
+    assert_eq!(response.status_code(), 404);
+
+    std::fs::remove_dir_all(root).ok();
+}
+
+#[tokio::test]
+async fn test_archive_endpoints_when_disabled() {
+    let server = TestServer::new(create_app(create_test_state(None).await));
+
+    let response = server
+        .get(&format!("/api/measurement/{}/probes", Uuid::new_v4()))
+        .await;
+    assert_eq!(response.status_code(), 503);
+    let error: serde_json::Value = response.json();
+    assert_eq!(error["error"], 503);
+}
+
+#[tokio::test]
+async fn test_rounds_are_archived() {
+    let (archive, root) = temp_archive();
+    let state = AppState {
+        probe_archive: Some(archive.clone()),
+        ..common::create_api_test_state(&["round-agent"]).await
+    };
+    let server = TestServer::new(create_app(state));
+
+    // IPv4 source addresses skip the user prefix check
+    let response = server
+        .post("/api/measurements")
+        .json(&json!({ "metadata": [{"id": "round-agent", "ip_address": "192.0.2.1"}] }))
+        .await;
+    let id = response.json::<serde_json::Value>()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    for ttl in [1, 2] {
+        let response = server
+            .post(&format!("/api/measurement/{}/rounds", id))
+            .json(&json!({ "probes": [["2606:4700:4700::1111", 24000, 33434, ttl, "udp"]] }))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.json::<serde_json::Value>()["archived"], true);
+    }
+
+    let probes = archive
+        .load_probes(Uuid::parse_str(&id).unwrap(), 2)
+        .await
+        .unwrap()
+        .unwrap();
+    let ttls: Vec<_> = probes.iter().map(|probe| probe.ttl).collect();
+    assert_eq!(ttls, vec![1, 2]);
+
+    // The download has a gzip member per round
+    let response = server.get(&format!("/api/measurement/{}/probes", id)).await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(
+        response.as_bytes().to_vec(),
+        archive
+            .load(Uuid::parse_str(&id).unwrap(), 2)
+            .await
+            .unwrap()
+            .unwrap()
+    );
+
+    std::fs::remove_dir_all(root).ok();
+}
+
+#[tokio::test]
+async fn test_failed_archive_is_reported() {
+    // The archive root is a file, so no blob can be stored under it
+    let root = std::env::temp_dir().join(format!("saimiris-archive-{}", Uuid::new_v4()));
+    std::fs::write(&root, b"").unwrap();
+    let state = AppState {
+        probe_archive: Some(ProbeArchive::filesystem(&root)),
+        ..common::create_api_test_state(&["archive-agent"]).await
+    };
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]],
+            "metadata": [{"id": "archive-agent", "ip_address": "192.0.2.1"}]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    assert_eq!(response.json::<serde_json::Value>()["archived"], false);
+
+    std::fs::remove_file(root).ok();
+}
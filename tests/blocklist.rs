@@ -0,0 +1,211 @@
+use axum_test::TestServer;
+use saimiris_gateway::{
+    AppState,
+    blocklist::{Blocklist, BlocklistAction},
+    create_app,
+    probe::{SpecialPurpose, SpecialPurposeFilter, SpecialPurposePolicy},
+};
+use serde_json::json;
+
+mod common;
+
+async fn create_test_state(action: BlocklistAction) -> AppState {
+    AppState {
+        blocklist: Blocklist::new(action),
+        ..common::create_api_test_state(&[]).await
+    }
+}
+
+fn probe_request(destinations: &[&str]) -> serde_json::Value {
+    let probes: Vec<_> = destinations
+        .iter()
+        .map(|dst| json!([dst, 12345, 53, 64, "udp"]))
+        .collect();
+    json!({
+        "probes": probes,
+        "metadata": [{"id": "test-agent", "ip_address": "2001:db8::1"}]
+    })
+}
+
+async fn block(server: &TestServer, prefixes: &[&str]) {
+    let response = server
+        .post("/admin-api/blocklist")
+        .add_header("authorization", "Bearer admin-key")
+        .json(&json!({ "prefixes": prefixes }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+}
+
+#[tokio::test]
+async fn test_admin_api_requires_admin_key() {
+    let mut state = create_test_state(BlocklistAction::Filter).await;
+    let server = TestServer::new(create_app(state.clone()));
+
+    let response = server.get("/admin-api/blocklist").await;
+    assert_eq!(response.status_code(), 401);
+
+    let response = server
+        .get("/admin-api/blocklist")
+        .add_header("authorization", "Bearer wrong-key")
+        .await;
+    assert_eq!(response.status_code(), 401);
+
+    // The agent key does not grant admin access
+    let response = server
+        .get("/admin-api/blocklist")
+        .add_header("authorization", "Bearer test-key")
+        .await;
+    assert_eq!(response.status_code(), 401);
+
+    // Without a configured admin key the admin API is disabled
+    state.admin_key = None;
+    let server = TestServer::new(create_app(state));
+    let response = server
+        .get("/admin-api/blocklist")
+        .add_header("authorization", "Bearer admin-key")
+        .await;
+    assert_eq!(response.status_code(), 401);
+}
+
+#[tokio::test]
+async fn test_blocklist_admin_lifecycle() {
+    let server = TestServer::new(create_app(create_test_state(BlocklistAction::Filter).await));
+
+    let response = server
+        .post("/admin-api/blocklist")
+        .add_header("authorization", "Bearer admin-key")
+        .json(&json!({ "prefixes": ["2001:db8::/32", "2a00::1", "2001:db8::/32"] }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["added"], 2);
+    assert_eq!(body["total"], 2);
+
+    let response = server
+        .get("/admin-api/blocklist")
+        .add_header("authorization", "Bearer admin-key")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["action"], "filter");
+    assert_eq!(body["prefixes"], json!(["2001:db8::/32", "2a00::1/128"]));
+
+    let response = server
+        .delete("/admin-api/blocklist")
+        .add_header("authorization", "Bearer admin-key")
+        .json(&json!({ "prefixes": ["2a00::1/128", "2a01::/16"] }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["removed"], 1);
+    assert_eq!(body["total"], 1);
+
+    // Reloading an in-memory blocklist keeps its contents
+    let response = server
+        .post("/admin-api/blocklist/reload")
+        .add_header("authorization", "Bearer admin-key")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["total"], 1);
+
+    let response = server
+        .post("/admin-api/blocklist")
+        .add_header("authorization", "Bearer admin-key")
+        .json(&json!({ "prefixes": ["192.0.2.0/24"] }))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error"], 400);
+}
+
+#[tokio::test]
+async fn test_reject_mode_refuses_blocked_destinations() {
+    let server = TestServer::new(create_app(create_test_state(BlocklistAction::Reject).await));
+    block(&server, &["2001:db8:dead::/48"]).await;
+
+    let response = server
+        .post("/api/probes")
+        .json(&probe_request(&[
+            "2606:4700:4700::1111",
+            "2001:db8:dead::1",
+        ]))
+        .await;
+    assert_eq!(response.status_code(), 403);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error"], 403);
+    let message = body["message"].as_str().unwrap();
+    assert!(message.contains("index 1"), "{}", message);
+    assert!(message.contains("2001:db8:dead::/48"), "{}", message);
+}
+
+#[tokio::test]
+async fn test_filter_mode_drops_blocked_destinations() {
+    let server = TestServer::new(create_app(create_test_state(BlocklistAction::Filter).await));
+    block(&server, &["2001:db8:dead::/48"]).await;
+
+    // Only blocked destinations: nothing left to send
+    let response = server
+        .post("/api/probes")
+        .json(&probe_request(&["2001:db8:dead::1", "2001:db8:dead::2"]))
+        .await;
+    assert_eq!(response.status_code(), 403);
+    let body: serde_json::Value = response.json();
+    assert_eq!(
+        body["message"],
+        "All probes target blocked or special-purpose destinations"
+    );
+
+    // Some probes survive filtering and the request moves on to agent selection
+    let response = server
+        .post("/api/probes")
+        .json(&probe_request(&[
+            "2606:4700:4700::1111",
+            "2001:db8:dead::1",
+        ]))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let body: serde_json::Value = response.json();
+    assert_eq!(
+        body["message"],
+        "No healthy agents found for the requested measurement"
+    );
+}
+
+#[tokio::test]
+async fn test_special_purpose_destinations() {
+    let mut state = create_test_state(BlocklistAction::Filter).await;
+    state.special_purpose = SpecialPurposeFilter::default()
+        .with_policy(SpecialPurpose::LinkLocal, SpecialPurposePolicy::Drop);
+    let server = TestServer::new(create_app(state));
+
+    // Outside development mode, documentation prefixes are rejected
+    let response = server
+        .post("/api/probes")
+        .json(&probe_request(&["2606:4700:4700::1111", "2001:db8::1"]))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let body: serde_json::Value = response.json();
+    assert_eq!(
+        body["message"],
+        "Probe at index 1: Destination 2001:db8::1 is a documentation address"
+    );
+
+    let response = server
+        .post("/api/probes")
+        .json(&probe_request(&["fe80::1", "fe80::2"]))
+        .await;
+    assert_eq!(response.status_code(), 403);
+
+    // Dropped destinations are filtered out and the rest moves on
+    let response = server
+        .post("/api/probes")
+        .json(&probe_request(&["2606:4700:4700::1111", "fe80::1"]))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let body: serde_json::Value = response.json();
+    assert_eq!(
+        body["message"],
+        "No healthy agents found for the requested measurement"
+    );
+}
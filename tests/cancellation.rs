@@ -0,0 +1,126 @@
+use axum_test::TestServer;
+use saimiris_gateway::create_app;
+use serde_json::json;
+
+mod common;
+
+async fn submit(server: &TestServer) -> String {
+    // IPv4 source addresses skip the user prefix check
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]],
+            "metadata": [{"id": "cancel-agent", "ip_address": "192.0.2.1"}]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    body["id"].as_str().unwrap().to_string()
+}
+
+async fn poll(server: &TestServer, after: i64) -> serde_json::Value {
+    let response = server
+        .get(&format!(
+            "/agent-api/agent/cancel-agent/cancellations?after={}",
+            after
+        ))
+        .add_header("authorization", "Bearer test-key")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    response.json()
+}
+
+async fn status(server: &TestServer, id: &str) -> serde_json::Value {
+    let response = server.get(&format!("/api/measurement/{}/status", id)).await;
+    assert_eq!(response.status_code(), 200);
+    response.json()
+}
+
+#[tokio::test]
+async fn test_cancellation_reaches_agents() {
+    let server = TestServer::new(create_app(
+        common::create_api_test_state(&["cancel-agent"]).await,
+    ));
+    let first = submit(&server).await;
+    let second = submit(&server).await;
+
+    let body = poll(&server, 0).await;
+    assert_eq!(body, json!({ "cancellations": [], "cursor": 0 }));
+    assert_eq!(status(&server, &first).await["cancellation"], json!(null));
+
+    for id in [&first, &second] {
+        let response = server
+            .post(&format!("/api/measurement/{}/cancel", id))
+            .await;
+        assert_eq!(response.status_code(), 200);
+    }
+
+    let body = poll(&server, 0).await;
+    let cancellations = body["cancellations"].as_array().unwrap();
+    assert_eq!(cancellations.len(), 2);
+    assert_eq!(cancellations[0]["measurement_id"], first);
+    assert_eq!(cancellations[0]["acknowledged"], false);
+    assert_eq!(cancellations[1]["measurement_id"], second);
+
+    // Polling from the returned cursor only returns newer cancellations
+    let cursor = body["cursor"].as_i64().unwrap();
+    let body = poll(&server, cursor).await;
+    assert_eq!(body["cancellations"], json!([]));
+    assert_eq!(body["cursor"], cursor);
+
+    let status_body = status(&server, &first).await;
+    assert_eq!(status_body["cancellation"], "requested");
+    assert_eq!(status_body["agents"][0]["cancellation"], "requested");
+
+    let response = server
+        .post(&format!(
+            "/agent-api/agent/cancel-agent/measurement/{}/cancellation/ack",
+            first
+        ))
+        .add_header("authorization", "Bearer test-key")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["agent_id"], "cancel-agent");
+    assert!(body["acknowledged_at"].is_string());
+
+    let status_body = status(&server, &first).await;
+    assert_eq!(status_body["cancellation"], "acknowledged");
+    assert_eq!(status_body["agents"][0]["cancellation"], "acknowledged");
+    assert_eq!(status(&server, &second).await["cancellation"], "requested");
+}
+
+#[tokio::test]
+async fn test_acknowledge_unknown_cancellation() {
+    let server = TestServer::new(create_app(
+        common::create_api_test_state(&["cancel-agent"]).await,
+    ));
+    let id = submit(&server).await;
+
+    // Not cancelled
+    let response = server
+        .post(&format!(
+            "/agent-api/agent/cancel-agent/measurement/{}/cancellation/ack",
+            id
+        ))
+        .add_header("authorization", "Bearer test-key")
+        .await;
+    assert_eq!(response.status_code(), 404);
+
+    let response = server
+        .post("/agent-api/agent/cancel-agent/measurement/not-a-uuid/cancellation/ack")
+        .add_header("authorization", "Bearer test-key")
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    let response = server
+        .get("/agent-api/agent/cancel-agent/cancellations?after=-1")
+        .add_header("authorization", "Bearer test-key")
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    let response = server
+        .get("/agent-api/agent/cancel-agent/cancellations")
+        .await;
+    assert_eq!(response.status_code(), 401);
+}
@@ -1,5 +1,19 @@
-use rdkafka::config::ClientConfig;
-use saimiris_gateway::{AppState, agent::AgentStore, database::Database, kafka};
+// Each test crate only uses some of the helpers
+#![allow(dead_code)]
+
+use saimiris_gateway::{
+    AppState,
+    agent::{AgentStore, HealthStatus},
+    blocklist::{Blocklist, BlocklistAction},
+    database::{Database, DatabaseConfig},
+    kafka,
+    probe::SpecialPurposeFilter,
+    sink::MemorySink,
+};
+use std::sync::Arc;
+
+/// Admin key of the states built by `create_api_test_state`
+pub const ADMIN_KEY: &str = "admin-key";
 
 /// Create a mock database for testing
 /// This creates a test database that won't actually persist data
@@ -8,28 +22,101 @@ pub async fn create_mock_database() -> Database {
     Database::new_mock()
 }
 
-pub async fn create_test_app_state() -> AppState {
-    let kafka_config = kafka::KafkaConfig {
+/// A SQLite database in a new temporary file, and its URL for opening more
+/// connections to it
+pub async fn create_sqlite_database() -> (Database, String) {
+    let path = std::env::temp_dir().join(format!("saimiris-test-{}.db", uuid::Uuid::new_v4()));
+    let url = format!("sqlite://{}", path.display());
+    let database = Database::new(&DatabaseConfig::new(url.clone()))
+        .await
+        .unwrap();
+    database.initialize().await.unwrap();
+    (database, url)
+}
+
+/// Make every insert of a measurement tracking row fail, as during a database
+/// outage
+pub async fn fail_tracking_inserts(database_url: &str) {
+    let pool = sqlx::SqlitePool::connect(database_url).await.unwrap();
+    sqlx::query(
+        "CREATE TRIGGER fail_tracking BEFORE INSERT ON measurement_tracking
+         BEGIN SELECT RAISE(ABORT, 'database unavailable'); END",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool.close().await;
+}
+
+/// Kafka configuration with a single shared topic and the legacy batch format
+pub fn test_kafka_config() -> kafka::KafkaConfig {
+    kafka::KafkaConfig {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
-    };
-
-    let kafka_producer = ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
+    }
+}
 
+pub async fn create_test_app_state() -> AppState {
     // Use mock database instead of real PostgreSQL connection
     let database = create_mock_database().await;
 
     AppState {
         agent_store: AgentStore::new(),
         agent_key: "test-key".to_string(),
-        kafka_config,
-        kafka_producer,
+        kafka_config: test_kafka_config(),
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: false,
         database,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     }
 }
+
+/// State for API tests: JWT validation is bypassed, so every request comes
+/// from the `test-user-id` user, and the admin API takes `ADMIN_KEY`.
+/// Override fields with `AppState { field, ..create_api_test_state(&agents).await }`.
+pub async fn create_api_test_state(healthy_agents: &[&str]) -> AppState {
+    let state = create_test_app_state().await;
+    for agent in healthy_agents {
+        add_healthy_agent(&state.agent_store, agent).await;
+    }
+
+    AppState {
+        auth0_jwks_uri: None,
+        auth0_issuer: None,
+        bypass_jwt_validation: true,
+        admin_key: Some(ADMIN_KEY.to_string()),
+        ..state
+    }
+}
+
+/// Register `agent` and mark it healthy, so probes can be assigned to it
+pub async fn add_healthy_agent(agent_store: &AgentStore, agent: &str) {
+    agent_store
+        .add_agent(agent.to_string(), "secret".to_string())
+        .await
+        .unwrap();
+    agent_store
+        .update_health(
+            agent,
+            HealthStatus {
+                healthy: true,
+                last_check: chrono::Utc::now(),
+                message: None,
+            },
+        )
+        .await;
+}
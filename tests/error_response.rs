@@ -1,6 +1,16 @@
 use axum_test::TestServer;
-use saimiris_gateway::{AppState, agent::AgentStore, create_app, database::Database, kafka};
+use saimiris_gateway::{
+    AppState,
+    agent::AgentStore,
+    blocklist::{Blocklist, BlocklistAction},
+    create_app,
+    database::Database,
+    kafka,
+    probe::SpecialPurposeFilter,
+    sink::MemorySink,
+};
 use serde_json::json;
+use std::sync::Arc;
 
 async fn create_mock_database() -> Database {
     Database::new_mock()
@@ -14,24 +24,31 @@ async fn test_json_error_responses() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = rdkafka::config::ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     // Set up the app state
     let agent_store = AgentStore::new();
     let state = AppState {
         agent_store,
         agent_key: "test-key".to_string(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: true, // Bypass JWT validation for testing
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
     let app = create_app(state.clone());
     let server = TestServer::new(app);
@@ -98,24 +115,31 @@ async fn test_error_structure_consistency() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = rdkafka::config::ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     // Set up the app state
     let agent_store = AgentStore::new();
     let state = AppState {
         agent_store,
         agent_key: "test-key".to_string(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: true,
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
     let app = create_app(state.clone());
     let server = TestServer::new(app);
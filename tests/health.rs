@@ -0,0 +1,80 @@
+use async_trait::async_trait;
+use axum_test::TestServer;
+use saimiris_gateway::{create_app, kafka, sink::ProbeSink};
+use serde_json::json;
+use std::sync::Arc;
+
+mod common;
+
+// A sink whose broker is unreachable
+struct UnreachableSink;
+
+#[async_trait]
+impl ProbeSink for UnreachableSink {
+    async fn send(&self, _messages: &[kafka::OutboundMessage]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Broker unreachable"))
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Broker unreachable"))
+    }
+}
+
+#[tokio::test]
+async fn test_healthz() {
+    let mut state = common::create_api_test_state(&[]).await;
+    state.probe_sink = Arc::new(UnreachableSink);
+    let server = TestServer::new(create_app(state));
+
+    // Liveness does not depend on the gateway's dependencies
+    let response = server.get("/healthz").await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body, json!({ "status": "ok" }));
+}
+
+#[tokio::test]
+async fn test_readyz() {
+    let server = TestServer::new(create_app(common::create_api_test_state(&[]).await));
+
+    let response = server.get("/readyz").await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["checks"]["database"]["status"], "ok");
+    assert_eq!(body["checks"]["kafka"]["status"], "ok");
+    assert_eq!(body["checks"]["jwks"]["status"], "skipped");
+}
+
+#[tokio::test]
+async fn test_readyz_reports_failed_dependencies() {
+    let mut state = common::create_api_test_state(&[]).await;
+    state.probe_sink = Arc::new(UnreachableSink);
+    let server = TestServer::new(create_app(state.clone()));
+
+    let response = server.get("/readyz").await;
+    assert_eq!(response.status_code(), 503);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["status"], "unavailable");
+    assert_eq!(body["checks"]["database"]["status"], "ok");
+    assert_eq!(body["checks"]["kafka"]["status"], "error");
+    assert_eq!(body["checks"]["kafka"]["message"], "Broker unreachable");
+    assert_eq!(body["checks"]["kafka"]["required"], true);
+
+    // With the outbox, submissions are queued while Kafka is down
+    state.outbox = true;
+    let server = TestServer::new(create_app(state.clone()));
+    let response = server.get("/readyz").await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["checks"]["kafka"]["status"], "error");
+    assert_eq!(body["checks"]["kafka"]["required"], false);
+
+    // JWT validation cannot work without keys
+    state.bypass_jwt_validation = false;
+    let server = TestServer::new(create_app(state));
+    let response = server.get("/readyz").await;
+    assert_eq!(response.status_code(), 503);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["checks"]["jwks"]["status"], "error");
+}
@@ -0,0 +1,194 @@
+use axum_test::TestServer;
+use saimiris_gateway::{AppState, create_app, hash_user_identifier, probe::SubmitProbesRequest};
+use serde_json::json;
+use uuid::Uuid;
+
+mod common;
+
+fn probe_request(dst: &str) -> serde_json::Value {
+    json!({
+        "probes": [[dst, 12345, 53, 64, "udp"]],
+        "metadata": [{"id": "test-agent", "ip_address": "2001:db8::1"}]
+    })
+}
+
+/// Seed a completed idempotency key for the bypass user, as if the original
+/// request had already been dispatched.
+async fn seed_completed_key(
+    state: &AppState,
+    key: &str,
+    request: &serde_json::Value,
+) -> serde_json::Value {
+    let user_hash = hash_user_identifier("test-user-id");
+    let request: SubmitProbesRequest = serde_json::from_value(request.clone()).unwrap();
+    let measurement_id = Uuid::new_v4();
+    let response = json!({
+        "id": measurement_id.to_string(),
+        "probes": 1,
+        "agents": [{"id": "test-agent", "ip_address": "2001:db8::1"}],
+        "filtered": 0
+    });
+
+    let claimed = state
+        .database
+        .claim_idempotency_key(
+            &user_hash,
+            key,
+            &request.fingerprint(),
+            chrono::Utc::now() - chrono::Duration::hours(24),
+            chrono::Utc::now() - chrono::Duration::hours(24),
+        )
+        .await
+        .unwrap();
+    assert!(claimed.is_none());
+    state
+        .database
+        .complete_idempotency_key(&user_hash, key, measurement_id, &response.to_string())
+        .await
+        .unwrap();
+
+    response
+}
+
+#[tokio::test]
+async fn test_idempotent_retry_replays_response() {
+    let state = common::create_api_test_state(&[]).await;
+    let request = probe_request("2001:4860:4860::8888");
+    let stored = seed_completed_key(&state, "retry-1", &request).await;
+    let server = TestServer::new(create_app(state));
+
+    // No agents are registered, so a fresh dispatch would fail: a 200 proves
+    // the stored response was replayed instead.
+    let response = server
+        .post("/api/probes")
+        .add_header("idempotency-key", "retry-1")
+        .json(&request)
+        .await;
+
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body, stored);
+}
+
+#[tokio::test]
+async fn test_idempotency_key_reused_with_different_request() {
+    let state = common::create_api_test_state(&[]).await;
+    seed_completed_key(&state, "retry-2", &probe_request("2001:4860:4860::8888")).await;
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .post("/api/probes")
+        .add_header("idempotency-key", "retry-2")
+        .json(&probe_request("2001:4860:4860::8844"))
+        .await;
+
+    assert_eq!(response.status_code(), 422);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error"], 422);
+}
+
+#[tokio::test]
+async fn test_idempotency_key_in_flight() {
+    let state = common::create_api_test_state(&[]).await;
+    let request = probe_request("2001:4860:4860::8888");
+    let parsed: SubmitProbesRequest = serde_json::from_value(request.clone()).unwrap();
+    state
+        .database
+        .claim_idempotency_key(
+            &hash_user_identifier("test-user-id"),
+            "retry-3",
+            &parsed.fingerprint(),
+            chrono::Utc::now() - chrono::Duration::hours(24),
+            chrono::Utc::now() - chrono::Duration::hours(24),
+        )
+        .await
+        .unwrap();
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .post("/api/probes")
+        .add_header("idempotency-key", "retry-3")
+        .json(&request)
+        .await;
+
+    assert_eq!(response.status_code(), 409);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error"], 409);
+}
+
+#[tokio::test]
+async fn test_failed_request_releases_idempotency_key() {
+    let state = common::create_api_test_state(&[]).await;
+    let server = TestServer::new(create_app(state.clone()));
+    let request = probe_request("2001:4860:4860::8888");
+
+    // No healthy agents: the request fails and must not leave the key claimed
+    for _ in 0..2 {
+        let response = server
+            .post("/api/probes")
+            .add_header("idempotency-key", "retry-4")
+            .json(&request)
+            .await;
+        assert_eq!(response.status_code(), 400);
+    }
+}
+
+#[tokio::test]
+async fn test_invalid_idempotency_key() {
+    let server = TestServer::new(create_app(common::create_api_test_state(&[]).await));
+    let long_key = "k".repeat(256);
+
+    for key in ["", "has space", long_key.as_str()] {
+        let response = server
+            .post("/api/probes")
+            .add_header("idempotency-key", key)
+            .json(&probe_request("2001:4860:4860::8888"))
+            .await;
+
+        assert_eq!(response.status_code(), 400, "key {:?}", key);
+        let body: serde_json::Value = response.json();
+        assert_eq!(body["error"], 400);
+    }
+}
+
+#[tokio::test]
+async fn test_abandoned_idempotency_key_is_taken_over() {
+    let (database, url) = common::create_sqlite_database().await;
+    let state = AppState {
+        database,
+        ..common::create_api_test_state(&[]).await
+    };
+    let request = probe_request("2001:4860:4860::8888");
+    let parsed: SubmitProbesRequest = serde_json::from_value(request.clone()).unwrap();
+    let far_past = chrono::Utc::now() - chrono::Duration::hours(24);
+    state
+        .database
+        .claim_idempotency_key(
+            &hash_user_identifier("test-user-id"),
+            "retry-5",
+            &parsed.fingerprint(),
+            far_past,
+            far_past,
+        )
+        .await
+        .unwrap();
+
+    // The request holding the key went away without completing it
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    sqlx::query("UPDATE idempotency_keys SET created_at = $1")
+        .bind(chrono::Utc::now() - chrono::Duration::minutes(10))
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+
+    // The retry is dispatched, and fails for lack of agents, instead of
+    // waiting for the key to expire
+    let server = TestServer::new(create_app(state));
+    let response = server
+        .post("/api/probes")
+        .add_header("idempotency-key", "retry-5")
+        .json(&request)
+        .await;
+    assert_eq!(response.status_code(), 400);
+}
@@ -0,0 +1,228 @@
+use axum_test::TestServer;
+use saimiris_gateway::{
+    AppState, agent::AgentConfig, calculate_user_prefix, create_app, get_or_create_user_id,
+    hash_user_identifier,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+mod common;
+
+// IPv4 source addresses skip the user prefix check
+fn open_request(agent_id: &str) -> serde_json::Value {
+    json!({ "metadata": [{"id": agent_id, "ip_address": "192.0.2.1"}] })
+}
+
+#[tokio::test]
+async fn test_open_measurement() {
+    let state = common::create_api_test_state(&["round-agent"]).await;
+    let server = TestServer::new(create_app(state.clone()));
+
+    let response = server
+        .post("/api/measurements")
+        .json(&open_request("unknown-agent"))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let body: serde_json::Value = response.json();
+    assert_eq!(
+        body["message"],
+        "No healthy agents found for the requested measurement"
+    );
+
+    let response = server
+        .post("/api/measurements")
+        .json(&json!({ "metadata": [] }))
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    let response = server
+        .post("/api/measurements")
+        .json(&open_request("round-agent"))
+        .await;
+    assert_eq!(response.status_code(), 201);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["rounds"], 0);
+    assert_eq!(body["agents"][0]["id"], "round-agent");
+
+    // The open measurement is tracked per agent, with nothing expected yet
+    let id = Uuid::parse_str(body["id"].as_str().unwrap()).unwrap();
+    let status = state
+        .database
+        .get_measurement_status(id, &hash_user_identifier("test-user-id"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_agents, 1);
+    assert_eq!(status.total_expected_probes, 0);
+}
+
+#[tokio::test]
+async fn test_rounds_require_open_measurement() {
+    let state = common::create_api_test_state(&["round-agent"]).await;
+    let server = TestServer::new(create_app(state.clone()));
+    let round = json!({ "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]] });
+
+    let unknown = Uuid::new_v4();
+    let response = server
+        .post(&format!("/api/measurement/{}/rounds", unknown))
+        .json(&round)
+        .await;
+    assert_eq!(response.status_code(), 404);
+    let response = server
+        .post(&format!("/api/measurement/{}/close", unknown))
+        .await;
+    assert_eq!(response.status_code(), 404);
+
+    let response = server
+        .post("/api/measurement/not-a-uuid/rounds")
+        .json(&round)
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    let response = server
+        .post("/api/measurements")
+        .json(&open_request("round-agent"))
+        .await;
+    let body: serde_json::Value = response.json();
+    let id = body["id"].as_str().unwrap().to_string();
+
+    // Rounds are validated like regular submissions
+    let response = server
+        .post(&format!("/api/measurement/{}/rounds", id))
+        .json(&json!({ "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "sctp"]] }))
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    // Once closed, no more rounds are accepted
+    state
+        .database
+        .close_multi_round_measurement(
+            Uuid::parse_str(&id).unwrap(),
+            &hash_user_identifier("test-user-id"),
+        )
+        .await
+        .unwrap();
+    let response = server
+        .post(&format!("/api/measurement/{}/rounds", id))
+        .json(&round)
+        .await;
+    assert_eq!(response.status_code(), 409);
+    let response = server.post(&format!("/api/measurement/{}/close", id)).await;
+    assert_eq!(response.status_code(), 409);
+}
+
+#[tokio::test]
+async fn test_cancelled_measurement_takes_no_rounds() {
+    let state = common::create_api_test_state(&["round-agent"]).await;
+    let server = TestServer::new(create_app(state.clone()));
+    let round = json!({ "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]] });
+
+    let body: serde_json::Value = server
+        .post("/api/measurements")
+        .json(&open_request("round-agent"))
+        .await
+        .json();
+    let id = body["id"].as_str().unwrap().to_string();
+    let response = server
+        .post(&format!("/api/measurement/{}/rounds", id))
+        .json(&round)
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let response = server
+        .post(&format!("/api/measurement/{}/cancel", id))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let response = server
+        .post(&format!("/api/measurement/{}/rounds", id))
+        .json(&round)
+        .await;
+    assert_eq!(response.status_code(), 409);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["message"], "Measurement was cancelled");
+
+    // The cancelled agents were not sent, nor charged, another round
+    let status = state
+        .database
+        .get_measurement_status(
+            Uuid::parse_str(&id).unwrap(),
+            &hash_user_identifier("test-user-id"),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status.total_expected_probes, 1);
+}
+
+#[tokio::test]
+async fn test_untracked_measurement_is_not_opened() {
+    let (database, url) = common::create_sqlite_database().await;
+    common::fail_tracking_inserts(&url).await;
+    let state = AppState {
+        database,
+        ..common::create_api_test_state(&["round-agent"]).await
+    };
+    let server = TestServer::new(create_app(state));
+
+    // Rounds could be neither followed nor charged
+    let response = server
+        .post("/api/measurements")
+        .json(&open_request("round-agent"))
+        .await;
+    assert_eq!(response.status_code(), 500);
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    let (opened,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM multi_round_measurements")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(opened, 0);
+}
+
+#[tokio::test]
+async fn test_open_measurement_from_allocated_prefix() {
+    let state = common::create_api_test_state(&["round-agent"]).await;
+    state
+        .agent_store
+        .update_config(
+            "round-agent",
+            vec![AgentConfig {
+                src_ipv6_prefix: Some("2001:db8:1234::/48".to_string()),
+                ..Default::default()
+            }],
+        )
+        .await;
+    let server = TestServer::new(create_app(state.clone()));
+
+    let user_id = get_or_create_user_id(&state.database, "test-user-id")
+        .await
+        .unwrap();
+    let prefix: ipnet::Ipv6Net = calculate_user_prefix("2001:db8:1234::/48", user_id)
+        .unwrap()
+        .parse()
+        .unwrap();
+    let source = std::net::Ipv6Addr::from(u128::from(prefix.network()) + 1);
+
+    let response = server
+        .post("/api/measurements")
+        .json(&json!({ "metadata": [{"id": "round-agent", "ip_address": source}] }))
+        .await;
+    assert_eq!(response.status_code(), 201);
+
+    // Outside of it
+    let response = server
+        .post("/api/measurements")
+        .json(&json!({ "metadata": [{"id": "round-agent", "ip_address": "2001:db8:ffff::1"}] }))
+        .await;
+    assert_eq!(response.status_code(), 403);
+
+    // Prefixes are only allocated to hashed identifiers
+    assert!(
+        state
+            .database
+            .get_user_id_by_hash("test-user-id")
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
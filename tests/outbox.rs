@@ -0,0 +1,77 @@
+use axum_test::TestServer;
+use saimiris_gateway::{AppState, create_app, kafka, outbox};
+use serde_json::json;
+use std::sync::Arc;
+
+mod common;
+
+// With the outbox enabled, submissions never touch Kafka. The relay does, and
+// the mock producer is not transactional, so with a transactional ID every
+// relay attempt fails right away.
+async fn create_test_state() -> AppState {
+    let kafka_config = kafka::KafkaConfig {
+        transactional_id: Some("saimiris-gateway-test".to_string()),
+        ..common::test_kafka_config()
+    };
+
+    let kafka_producer = rdkafka::config::ClientConfig::new()
+        .create_with_context(kafka::StatsContext)
+        .expect("Failed to create mock Kafka producer");
+    let probe_sink = Arc::new(kafka::KafkaSink::new(kafka_producer, kafka_config.clone()));
+
+    AppState {
+        kafka_config,
+        probe_sink,
+        outbox: true,
+        ..common::create_api_test_state(&["outbox-agent"]).await
+    }
+}
+
+#[tokio::test]
+async fn test_submission_is_queued_until_relayed() {
+    let state = create_test_state().await;
+    let server = TestServer::new(create_app(state.clone()));
+
+    // IPv4 source addresses skip the user prefix check
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "probes": [
+                ["2606:4700:4700::1111", 24000, 33434, 8, "udp"],
+                ["2606:4700:4700::1001", 24000, 33434, 8, "udp"]
+            ],
+            "metadata": [{"id": "outbox-agent", "ip_address": "192.0.2.1"}]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    let id = body["id"].as_str().unwrap().to_string();
+    assert_eq!(body["probes"], 2);
+
+    let response = server.get(&format!("/api/measurement/{}/status", id)).await;
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["queued"], true);
+    assert_eq!(body["queued_batches"], 1);
+    assert_eq!(body["total_expected_probes"], 2);
+
+    let response = server.get("/api/measurements?status=queued").await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+
+    // Kafka is unavailable: the batch stays queued for a later retry
+    assert_eq!(outbox::relay_outbox(&state).await, 0);
+    let now = chrono::Utc::now();
+    let pending = state
+        .database
+        .claim_outbox_messages(now, now, 10)
+        .await
+        .unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].attempts, 1);
+    assert!(pending[0].last_error.is_some());
+
+    let response = server.get(&format!("/api/measurement/{}/status", id)).await;
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["queued"], true);
+}
@@ -0,0 +1,126 @@
+use axum_test::TestServer;
+use saimiris_gateway::{AppState, agent::AgentConfig, create_app};
+use serde_json::json;
+
+mod common;
+
+async fn create_test_state() -> AppState {
+    // A healthy agent advertising a subset of the per-probe options
+    let state = common::create_api_test_state(&["options-agent"]).await;
+    state
+        .agent_store
+        .update_config(
+            "options-agent",
+            vec![AgentConfig {
+                probe_options: vec!["payload_size".to_string(), "probe_id".to_string()],
+                max_payload_size: Some(512),
+                ..Default::default()
+            }],
+        )
+        .await;
+    state
+}
+
+fn probe_request(probe: serde_json::Value) -> serde_json::Value {
+    // IPv4 source addresses skip the user prefix check
+    json!({
+        "probes": [probe],
+        "metadata": [{"id": "options-agent", "ip_address": "192.0.2.1"}]
+    })
+}
+
+async fn submit(server: &TestServer, probe: serde_json::Value) -> (u16, String) {
+    let response = server.post("/api/probes").json(&probe_request(probe)).await;
+    let status = response.status_code().as_u16();
+    let body: serde_json::Value = response.json();
+    (
+        status,
+        body["message"].as_str().unwrap_or_default().to_string(),
+    )
+}
+
+#[tokio::test]
+async fn test_invalid_probe_options() {
+    let server = TestServer::new(create_app(create_test_state().await));
+    let probe = json!({
+        "dst_addr": "2606:4700:4700::1111",
+        "src_port": 24000,
+        "dst_port": 33434,
+        "ttl": 8,
+        "protocol": "udp"
+    });
+
+    let cases = [
+        (json!({"flow_label": 1_048_576}), "Invalid flow_label"),
+        (json!({"payload_size": 0}), "Invalid payload_size"),
+        (
+            json!({"tcp_flags": "syn"}),
+            "tcp_flags can only be set on TCP probes",
+        ),
+        (json!({"protocol": "tcp"}), "TCP probes must set tcp_flags"),
+        (
+            json!({"protocol": "tcp", "tcp_flags": "fin"}),
+            "Invalid tcp_flags",
+        ),
+        (json!({"payload": 32}), "Unknown probe field: payload"),
+    ];
+    for (fields, expected) in cases {
+        let mut invalid = probe.clone();
+        invalid
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+
+        let (status, message) = submit(&server, invalid).await;
+        assert_eq!(status, 400, "{}", message);
+        assert!(message.contains(expected), "{}", message);
+    }
+
+    let mut missing = probe.clone();
+    missing.as_object_mut().unwrap().remove("ttl");
+    let (status, message) = submit(&server, missing).await;
+    assert_eq!(status, 400);
+    assert!(message.contains("Missing probe field: ttl"), "{}", message);
+}
+
+#[tokio::test]
+async fn test_probe_options_checked_against_agent_config() {
+    let server = TestServer::new(create_app(create_test_state().await));
+
+    let (status, message) = submit(
+        &server,
+        json!({
+            "dst_addr": "2606:4700:4700::1111",
+            "src_port": 24000,
+            "dst_port": 33434,
+            "ttl": 8,
+            "protocol": "udp",
+            "probe_id": 3,
+            "flow_label": 42
+        }),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert_eq!(
+        message,
+        "Agent options-agent does not support the flow_label probe option"
+    );
+
+    let (status, message) = submit(
+        &server,
+        json!({
+            "dst_addr": "2606:4700:4700::1111",
+            "src_port": 24000,
+            "dst_port": 33434,
+            "ttl": 8,
+            "protocol": "udp",
+            "payload_size": 1000
+        }),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert_eq!(
+        message,
+        "Agent options-agent supports payloads of at most 512 bytes, got 1000"
+    );
+}
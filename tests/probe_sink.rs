@@ -0,0 +1,145 @@
+use axum_test::TestServer;
+use saimiris_gateway::{
+    AppState, create_app, kafka,
+    probe::{self},
+    sink::MemorySink,
+};
+use serde_json::json;
+use std::sync::Arc;
+
+mod common;
+
+async fn create_test_state(sink: &MemorySink, batch_format: kafka::BatchFormat) -> AppState {
+    AppState {
+        kafka_config: kafka::KafkaConfig {
+            batch_format,
+            ..common::test_kafka_config()
+        },
+        probe_sink: Arc::new(sink.clone()),
+        ..common::create_api_test_state(&["sink-agent"]).await
+    }
+}
+
+fn probes() -> serde_json::Value {
+    json!([
+        ["2606:4700:4700::1111", 24000, 33434, 8, "udp"],
+        ["2606:4700:4700::1001", 24000, 33434, 9, "icmpv6"]
+    ])
+}
+
+// IPv4 source addresses skip the user prefix check
+fn metadata() -> serde_json::Value {
+    json!([{"id": "sink-agent", "ip_address": "192.0.2.1"}])
+}
+
+fn agent_header(message: &kafka::OutboundMessage) -> serde_json::Value {
+    assert_eq!(message.headers.len(), 1);
+    assert_eq!(message.headers[0].0, "sink-agent");
+    serde_json::from_str(&message.headers[0].1).unwrap()
+}
+
+#[tokio::test]
+async fn test_submission_is_dispatched_to_sink() {
+    let sink = MemorySink::new();
+    let server = TestServer::new(create_app(
+        create_test_state(&sink, kafka::BatchFormat::Legacy).await,
+    ));
+
+    let response = server
+        .post("/api/probes")
+        .json(&json!({ "probes": probes(), "metadata": metadata() }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    let id = body["id"].as_str().unwrap();
+
+    let messages = sink.messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].key, id);
+    assert_eq!(
+        agent_header(&messages[0]),
+        json!({
+            "src_ip": "192.0.2.1",
+            "measurement_id": id,
+            "end_of_measurement": true
+        })
+    );
+
+    let decoded = probe::decode_probes_batch(&messages[0].payload).unwrap();
+    let decoded: Vec<_> = decoded.iter().map(probe::Probe::to_json).collect();
+    assert_eq!(json!(decoded), probes());
+}
+
+#[tokio::test]
+async fn test_envelope_batches() {
+    let sink = MemorySink::new();
+    let server = TestServer::new(create_app(
+        create_test_state(&sink, kafka::BatchFormat::Envelope).await,
+    ));
+
+    let response = server
+        .post("/api/probes")
+        .json(&json!({ "probes": probes(), "metadata": metadata() }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let messages = sink.take();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        messages[0].headers,
+        vec![(kafka::PROBE_BATCH_HEADER.to_string(), "1".to_string())]
+    );
+    let (envelope, decoded) = probe::BatchEnvelope::decode(&messages[0].payload).unwrap();
+    assert!(envelope.end_of_measurement);
+    assert_eq!(envelope.batch_count, 1);
+    assert_eq!(envelope.agents[0].id, "sink-agent");
+    assert_eq!(decoded.len(), 2);
+}
+
+#[tokio::test]
+async fn test_multi_round_batches() {
+    let sink = MemorySink::new();
+    let server = TestServer::new(create_app(
+        create_test_state(&sink, kafka::BatchFormat::Legacy).await,
+    ));
+
+    let response = server
+        .post("/api/measurements")
+        .json(&json!({ "metadata": metadata() }))
+        .await;
+    assert_eq!(response.status_code(), 201);
+    let body: serde_json::Value = response.json();
+    let id = body["id"].as_str().unwrap().to_string();
+    assert!(sink.messages().is_empty());
+
+    for round in 1..=2 {
+        let response = server
+            .post(&format!("/api/measurement/{}/rounds", id))
+            .json(&json!({ "probes": probes() }))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let body: serde_json::Value = response.json();
+        assert_eq!(body["id"], id);
+        assert_eq!(body["round"], round);
+    }
+
+    let response = server.get(&format!("/api/measurement/{}/status", id)).await;
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["total_expected_probes"], 4);
+
+    let response = server.post(&format!("/api/measurement/{}/close", id)).await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["closed"], true);
+    assert_eq!(body["rounds"], 2);
+
+    // Only the closing (empty) batch ends the measurement
+    let messages = sink.messages();
+    let ends: Vec<_> = messages
+        .iter()
+        .map(|m| agent_header(m)["end_of_measurement"].clone())
+        .collect();
+    assert_eq!(ends, vec![json!(false), json!(false), json!(true)]);
+    assert!(messages[2].payload.is_empty());
+    assert!(messages.iter().all(|m| m.key == id));
+}
@@ -0,0 +1,356 @@
+use async_trait::async_trait;
+use axum_test::TestServer;
+use saimiris_gateway::{
+    AppState, create_app, kafka,
+    quota::{ChargingMode, QuotaPolicy, parse_window},
+    sink::{MemorySink, PartialDelivery, ProbeSink},
+};
+use serde_json::json;
+use std::sync::Arc;
+
+mod common;
+
+const AGENTS: [&str; 2] = ["agent1", "agent2"];
+
+// A sink whose broker is unreachable
+struct UnreachableSink;
+
+#[async_trait]
+impl ProbeSink for UnreachableSink {
+    async fn send(&self, _messages: &[kafka::OutboundMessage]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Broker unreachable"))
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Broker unreachable"))
+    }
+}
+
+// A sink whose broker goes away after the first message
+struct FlakySink;
+
+#[async_trait]
+impl ProbeSink for FlakySink {
+    async fn send(&self, messages: &[kafka::OutboundMessage]) -> anyhow::Result<()> {
+        match messages.len() {
+            0 | 1 => Ok(()),
+            _ => {
+                Err(anyhow::anyhow!("Broker unreachable").context(PartialDelivery { delivered: 1 }))
+            }
+        }
+    }
+}
+
+fn policy(windows: &[&str]) -> QuotaPolicy {
+    QuotaPolicy {
+        windows: windows.iter().map(|w| parse_window(w).unwrap()).collect(),
+    }
+}
+
+async fn create_test_state(quota: QuotaPolicy) -> AppState {
+    AppState {
+        quota,
+        ..common::create_api_test_state(&AGENTS).await
+    }
+}
+
+/// Submit `probes` probes to both agents, so `2 * probes` are charged
+async fn submit(server: &TestServer, probes: usize) -> (u16, serde_json::Value) {
+    let probes: Vec<_> = (0..probes)
+        .map(|i| json!(["2001:4860:4860::8888", 24000 + i, 53, 64, "udp"]))
+        .collect();
+    // IPv4 source addresses skip the user prefix check
+    let metadata: Vec<_> = AGENTS
+        .iter()
+        .map(|agent| json!({"id": agent, "ip_address": "192.0.2.1"}))
+        .collect();
+    let response = server
+        .post("/api/probes")
+        .json(&json!({"probes": probes, "metadata": metadata}))
+        .await;
+    (response.status_code().as_u16(), response.json())
+}
+
+#[tokio::test]
+async fn test_quota_charges_probes_per_agent() {
+    let server = TestServer::new(create_app(create_test_state(policy(&["24h=5"])).await));
+
+    let (status, body) = submit(&server, 3).await;
+    assert_eq!(status, 429);
+    assert_eq!(
+        body["message"],
+        "Probe quota exceeded: 0 of 5 probes used in the 1d window, cannot submit 6 more"
+    );
+    assert_eq!(body["quota"]["period"], "1d");
+
+    let (status, _) = submit(&server, 2).await;
+    assert_eq!(status, 200);
+
+    let body: serde_json::Value = server.get("/api/user/me").await.json();
+    assert_eq!(body["used"], 4);
+    assert_eq!(body["limit"], 5);
+    assert_eq!(body["quota"][0]["remaining"], 1);
+    assert!(body["quota"][0]["resets_at"].is_string());
+}
+
+#[tokio::test]
+async fn test_quota_burst_only_from_under_the_limit() {
+    let server = TestServer::new(create_app(create_test_state(policy(&["day=5+2"])).await));
+
+    assert_eq!(submit(&server, 2).await.0, 200);
+    // 4 used: 8 would go past the burst
+    assert_eq!(submit(&server, 2).await.0, 429);
+    // 6 is within the burst
+    assert_eq!(submit(&server, 1).await.0, 200);
+    // Over the limit, no more bursts
+    assert_eq!(submit(&server, 1).await.0, 429);
+}
+
+#[tokio::test]
+async fn test_user_info_reports_every_window() {
+    let server = TestServer::new(create_app(
+        create_test_state(policy(&["24h=100", "month=1000+50"])).await,
+    ));
+
+    let body: serde_json::Value = server.get("/api/user/me").await.json();
+    assert_eq!(body["used"], 0);
+    assert_eq!(body["limit"], 100);
+    let quota = body["quota"].as_array().unwrap();
+    assert_eq!(quota.len(), 2);
+    assert_eq!(quota[0]["period"], "1d");
+    // Nothing to release from an unused rolling window
+    assert_eq!(quota[0]["resets_at"], serde_json::Value::Null);
+    assert_eq!(quota[1]["period"], "month");
+    assert_eq!(quota[1]["burst"], 50);
+    assert_eq!(quota[1]["remaining"], 1000);
+    assert!(quota[1]["resets_at"].is_string());
+}
+
+#[tokio::test]
+async fn test_legacy_probe_limit_is_per_calendar_day() {
+    let state = create_test_state(QuotaPolicy::default()).await;
+    state
+        .database
+        .set_user_limit("test-user-id", 3)
+        .await
+        .unwrap();
+    let server = TestServer::new(create_app(state));
+
+    assert_eq!(submit(&server, 2).await.0, 429);
+    let body: serde_json::Value = server.get("/api/user/me").await.json();
+    assert_eq!(body["limit"], 3);
+    assert_eq!(body["quota"][0]["period"], "day");
+}
+
+#[tokio::test]
+async fn test_admin_user_quota() {
+    let server = TestServer::new(create_app(create_test_state(QuotaPolicy::default()).await));
+    let path = "/admin-api/user/test-user-id/quota";
+
+    let response = server.get(path).await;
+    assert_eq!(response.status_code(), 401);
+
+    let body: serde_json::Value = server
+        .get(path)
+        .add_header("authorization", "Bearer admin-key")
+        .await
+        .json();
+    assert_eq!(body["is_default"], true);
+    assert_eq!(body["quota"][0]["limit"], 10000);
+
+    for invalid in [
+        json!({"windows": []}),
+        json!({"windows": [{"period": "day", "limit": 1}, {"period": "day", "limit": 2}]}),
+    ] {
+        let response = server
+            .put(path)
+            .add_header("authorization", "Bearer admin-key")
+            .json(&invalid)
+            .await;
+        assert_eq!(response.status_code(), 400);
+    }
+    // Longer than a rolling period may last
+    let response = server
+        .put(path)
+        .add_header("authorization", "Bearer admin-key")
+        .json(&json!({"windows": [{"period": "10000000000000s", "limit": 1}]}))
+        .await;
+    assert_eq!(response.status_code(), 422);
+    let response = server
+        .put(path)
+        .add_header("authorization", "Bearer admin-key")
+        .json(&json!({"windows": [{"period": "month", "limit": 3}]}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let body: serde_json::Value = server
+        .get(path)
+        .add_header("authorization", "Bearer admin-key")
+        .await
+        .json();
+    assert_eq!(body["is_default"], false);
+    assert_eq!(body["quota"][0]["period"], "month");
+    assert_eq!(submit(&server, 2).await.0, 429);
+
+    let response = server
+        .delete(path)
+        .add_header("authorization", "Bearer admin-key")
+        .await;
+    assert_eq!(response.status_code(), 204);
+    let response = server
+        .delete(path)
+        .add_header("authorization", "Bearer admin-key")
+        .await;
+    assert_eq!(response.status_code(), 404);
+    assert_eq!(submit(&server, 2).await.0, 200);
+}
+
+#[tokio::test]
+async fn test_invalid_stored_policy_falls_back_to_defaults() {
+    let state = create_test_state(policy(&["24h=5"])).await;
+    let user_hash = saimiris_gateway::hash_user_identifier("test-user-id");
+    state
+        .database
+        .set_user_quota(&user_hash, "[]")
+        .await
+        .unwrap();
+    let server = TestServer::new(create_app(state));
+
+    let body: serde_json::Value = server.get("/api/user/me").await.json();
+    assert_eq!(body["limit"], 5);
+    assert_eq!(submit(&server, 3).await.0, 429);
+}
+
+#[tokio::test]
+async fn test_concurrent_submissions_share_the_quota() {
+    let server = TestServer::new(create_app(create_test_state(policy(&["24h=5"])).await));
+
+    let (a, b, c, d) = tokio::join!(
+        submit(&server, 1),
+        submit(&server, 1),
+        submit(&server, 1),
+        submit(&server, 1)
+    );
+    let accepted = [a, b, c, d]
+        .iter()
+        .filter(|(status, _)| *status == 200)
+        .count();
+    assert_eq!(accepted, 2);
+
+    let body: serde_json::Value = server.get("/api/user/me").await.json();
+    assert_eq!(body["used"], 4);
+}
+
+#[tokio::test]
+async fn test_failed_dispatch_releases_reservation() {
+    let mut state = create_test_state(policy(&["24h=5"])).await;
+    state.probe_sink = Arc::new(UnreachableSink);
+    let database = state.database.clone();
+    let server = TestServer::new(create_app(state));
+
+    let (status, _) = submit(&server, 2).await;
+    assert_eq!(status, 500);
+
+    // Neither tracking nor a reservation is left to charge
+    let usage = database
+        .get_usage_since(
+            &saimiris_gateway::hash_user_identifier("test-user-id"),
+            chrono::Utc::now() - chrono::Duration::hours(1),
+            ChargingMode::Expected,
+        )
+        .await
+        .unwrap();
+    assert_eq!(usage.probes, 0);
+}
+
+#[tokio::test]
+async fn test_cancellation_refunds_unsent_probes() {
+    let mut state = create_test_state(policy(&["24h=5"])).await;
+    state.quota_charging = ChargingMode::Refunded;
+    let server = TestServer::new(create_app(state));
+
+    let (status, body) = submit(&server, 2).await;
+    assert_eq!(status, 200);
+    let measurement_id = body["id"].as_str().unwrap().to_string();
+    assert_eq!(submit(&server, 1).await.0, 429);
+
+    let response = server
+        .post(&format!("/api/measurement/{}/cancel", measurement_id))
+        .await;
+    assert_eq!(response.status_code(), 200);
+
+    let body: serde_json::Value = server.get("/api/user/me").await.json();
+    assert_eq!(body["charging"], "refunded");
+    assert_eq!(body["used"], 0);
+    assert_eq!(body["quota"][0]["refunded"], 4);
+    assert_eq!(submit(&server, 2).await.0, 200);
+}
+
+#[tokio::test]
+async fn test_expected_charging_ignores_refunds() {
+    let mut state = create_test_state(policy(&["24h=5"])).await;
+    state.quota_charging = ChargingMode::Expected;
+    let server = TestServer::new(create_app(state));
+
+    let (_, body) = submit(&server, 2).await;
+    let measurement_id = body["id"].as_str().unwrap().to_string();
+    server
+        .post(&format!("/api/measurement/{}/cancel", measurement_id))
+        .await;
+
+    let body: serde_json::Value = server.get("/api/user/me").await.json();
+    assert_eq!(body["charging"], "expected");
+    assert_eq!(body["used"], 4);
+    assert_eq!(body["quota"][0]["refunded"], 4);
+    assert_eq!(submit(&server, 1).await.0, 429);
+}
+
+#[tokio::test]
+async fn test_untracked_dispatch_sends_nothing() {
+    let (database, url) = common::create_sqlite_database().await;
+    common::fail_tracking_inserts(&url).await;
+    let sink = MemorySink::new();
+    let state = AppState {
+        database: database.clone(),
+        probe_sink: Arc::new(sink.clone()),
+        ..create_test_state(policy(&["24h=5"])).await
+    };
+    let server = TestServer::new(create_app(state));
+
+    // Nothing to charge the probes to, so they are not sent
+    let (status, _) = submit(&server, 2).await;
+    assert_eq!(status, 500);
+    assert!(sink.messages().is_empty());
+
+    let usage = database
+        .get_usage_since(
+            &saimiris_gateway::hash_user_identifier("test-user-id"),
+            chrono::Utc::now() - chrono::Duration::hours(1),
+            ChargingMode::Expected,
+        )
+        .await
+        .unwrap();
+    assert_eq!(usage.probes, 0);
+}
+
+#[tokio::test]
+async fn test_partial_dispatch_charges_delivered_probes() {
+    let state = create_test_state(policy(&["24h=5"])).await;
+    let state = AppState {
+        kafka_config: kafka::KafkaConfig {
+            routing: kafka::TopicRouting::AgentPartition { partitions: 4 },
+            ..state.kafka_config
+        },
+        probe_sink: Arc::new(FlakySink),
+        ..state
+    };
+    let server = TestServer::new(create_app(state));
+
+    // Only the first agent's batch went out
+    let (status, _) = submit(&server, 2).await;
+    assert_eq!(status, 500);
+
+    let body: serde_json::Value = server.get("/api/user/me").await.json();
+    assert_eq!(body["used"], 2);
+    assert_eq!(body["quota"][0]["remaining"], 3);
+}
@@ -0,0 +1,126 @@
+use axum_test::TestServer;
+use chrono::{Duration, Utc};
+use saimiris_gateway::{
+    AppState, create_app,
+    database::Database,
+    retention::{RetentionPolicy, RetentionReport, apply_retention, preview_retention},
+};
+use uuid::Uuid;
+
+mod common;
+
+async fn create_test_state(retention: RetentionPolicy) -> AppState {
+    AppState {
+        retention,
+        ..common::create_api_test_state(&[]).await
+    }
+}
+
+const POLICY: RetentionPolicy = RetentionPolicy {
+    measurement_days: Some(30),
+    probe_usage_days: None,
+};
+
+async fn complete_measurement(database: &Database, user_hash: &str) -> Uuid {
+    let measurement_id = Uuid::new_v4();
+    database
+        .create_measurement_tracking(user_hash, measurement_id, "agent1", 100)
+        .await
+        .unwrap();
+    database
+        .update_measurement_probe_count(measurement_id, user_hash, "agent1", 100, true)
+        .await
+        .unwrap();
+    measurement_id
+}
+
+#[tokio::test]
+async fn test_retention_preview_requires_admin_key() {
+    let server = TestServer::new(create_app(create_test_state(POLICY).await));
+
+    let response = server.get("/admin-api/retention").await;
+    assert_eq!(response.status_code(), 401);
+}
+
+#[tokio::test]
+async fn test_retention_preview_reports_policy() {
+    let state = create_test_state(POLICY).await;
+    complete_measurement(&state.database, "user").await;
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .get("/admin-api/retention")
+        .add_header("authorization", "Bearer admin-key")
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["enabled"], true);
+    assert_eq!(body["policy"]["measurement_days"], 30);
+    assert_eq!(body["policy"]["probe_usage_days"], serde_json::Value::Null);
+    // The measurement was just updated
+    assert_eq!(body["purgeable"]["measurements"], 0);
+    assert_eq!(body["purgeable"]["measurement_rows"], 0);
+}
+
+#[tokio::test]
+async fn test_retention_disabled_purges_nothing() {
+    let database = Database::new_mock();
+    complete_measurement(&database, "user").await;
+    let later = Utc::now() + Duration::days(1000);
+
+    let policy = RetentionPolicy::default();
+    assert_eq!(
+        preview_retention(&database, &policy, later).await.unwrap(),
+        RetentionReport::default()
+    );
+    assert_eq!(
+        apply_retention(&database, &policy, later).await,
+        RetentionReport::default()
+    );
+}
+
+#[tokio::test]
+async fn test_apply_retention_purges_what_preview_reports() {
+    let database = Database::new_mock();
+    let user_hash = "user";
+    database
+        .create_user_id_mapping(user_hash, 42)
+        .await
+        .unwrap();
+    let measurement_id = complete_measurement(&database, user_hash).await;
+
+    // Within the retention period
+    let soon = Utc::now() + Duration::days(29);
+    assert_eq!(
+        apply_retention(&database, &POLICY, soon).await,
+        RetentionReport::default()
+    );
+
+    let later = Utc::now() + Duration::days(91);
+    let preview = preview_retention(&database, &POLICY, later).await.unwrap();
+    assert_eq!(preview.measurements, 1);
+    assert_eq!(preview.measurement_rows, 1);
+
+    let report = apply_retention(&database, &POLICY, later).await;
+    assert_eq!(report.measurements, 1);
+    assert_eq!(report.measurement_rows, 1);
+
+    assert!(
+        database
+            .get_measurement_status(measurement_id, user_hash)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    // The user keeps their ID, and so their prefix
+    assert_eq!(
+        database.get_user_id_by_hash(user_hash).await.unwrap(),
+        Some(42)
+    );
+    let usage = database
+        .get_daily_usage(user_hash, Utc::now().date_naive())
+        .await
+        .unwrap();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].expected_probes, 100);
+}
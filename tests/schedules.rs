@@ -0,0 +1,173 @@
+use axum_test::TestServer;
+use saimiris_gateway::{create_app, hash_user_identifier, scheduler};
+use serde_json::json;
+use uuid::Uuid;
+
+mod common;
+
+fn schedule_request() -> serde_json::Value {
+    json!({
+        "name": "hourly topology",
+        "probes": [["2001:4860:4860::8888", 12345, 53, 64, "udp"]],
+        "metadata": [{"id": "test-agent", "ip_address": "2001:db8::1"}],
+        "interval_seconds": 3600
+    })
+}
+
+#[tokio::test]
+async fn test_schedule_lifecycle() {
+    let server = TestServer::new(create_app(common::create_api_test_state(&[]).await));
+
+    let response = server
+        .post("/api/schedules")
+        .json(&schedule_request())
+        .await;
+    assert_eq!(response.status_code(), 201);
+    let created: serde_json::Value = response.json();
+    let id = created["id"].as_str().unwrap().to_string();
+    assert_eq!(created["name"], "hourly topology");
+    assert_eq!(created["interval_seconds"], 3600);
+    assert_eq!(created["probes"].as_array().unwrap().len(), 1);
+    assert!(created["next_run_at"].is_string());
+    assert!(created.get("user_identifier").is_none());
+
+    let response = server.get("/api/schedules").await;
+    assert_eq!(response.status_code(), 200);
+    let list: serde_json::Value = response.json();
+    assert_eq!(list.as_array().unwrap().len(), 1);
+    assert_eq!(list[0]["id"], id.as_str());
+
+    let response = server.get(&format!("/api/schedule/{}", id)).await;
+    assert_eq!(response.status_code(), 200);
+    let detail: serde_json::Value = response.json();
+    assert_eq!(detail["runs"].as_array().unwrap().len(), 0);
+
+    let response = server.delete(&format!("/api/schedule/{}", id)).await;
+    assert_eq!(response.status_code(), 200);
+
+    let response = server.get(&format!("/api/schedule/{}", id)).await;
+    assert_eq!(response.status_code(), 404);
+    let response = server.delete(&format!("/api/schedule/{}", id)).await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_create_schedule_validation() {
+    let server = TestServer::new(create_app(common::create_api_test_state(&[]).await));
+
+    let mut too_frequent = schedule_request();
+    too_frequent["interval_seconds"] = json!(10);
+    let mut empty_probes = schedule_request();
+    empty_probes["probes"] = json!([]);
+    let mut missing_ip = schedule_request();
+    missing_ip["metadata"] = json!([{"id": "test-agent"}]);
+    let mut bad_start = schedule_request();
+    bad_start["start_at"] = json!("tomorrow");
+
+    for body in [too_frequent, empty_probes, missing_ip, bad_start] {
+        let response = server.post("/api/schedules").json(&body).await;
+        assert_eq!(response.status_code(), 400, "body {}", body);
+        let error: serde_json::Value = response.json();
+        assert_eq!(error["error"], 400);
+    }
+
+    let response = server.get("/api/schedule/not-a-uuid").await;
+    assert_eq!(response.status_code(), 400);
+    let response = server
+        .get(&format!("/api/schedule/{}", Uuid::new_v4()))
+        .await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_due_schedules_are_fired_and_recorded() {
+    let state = common::create_api_test_state(&[]).await;
+    let server = TestServer::new(create_app(state.clone()));
+
+    // One-shot schedule in the past, recurring one due now, and one in the future
+    let mut one_shot = schedule_request();
+    one_shot.as_object_mut().unwrap().remove("interval_seconds");
+    one_shot["start_at"] = json!("2020-01-01T00:00:00Z");
+    let mut future = schedule_request();
+    future["start_at"] = json!("2999-01-01");
+
+    let mut ids = Vec::new();
+    for body in [one_shot, schedule_request(), future] {
+        let response = server.post("/api/schedules").json(&body).await;
+        assert_eq!(response.status_code(), 201);
+        let created: serde_json::Value = response.json();
+        ids.push(Uuid::parse_str(created["id"].as_str().unwrap()).unwrap());
+    }
+
+    assert_eq!(scheduler::run_due_schedules(&state).await, 2);
+    // Both due schedules were advanced, so nothing fires twice
+    assert_eq!(scheduler::run_due_schedules(&state).await, 0);
+
+    let user_hash = hash_user_identifier("test-user-id");
+    let one_shot = state
+        .database
+        .get_schedule(ids[0], &user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(one_shot.next_run_at.is_none());
+
+    let recurring = state
+        .database
+        .get_schedule(ids[1], &user_hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(recurring.next_run_at.unwrap() > chrono::Utc::now());
+
+    // No agents are registered, so each run is recorded with its dispatch error
+    let runs = state.database.list_schedule_runs(ids[1], 10).await.unwrap();
+    assert_eq!(runs.len(), 1);
+    assert!(runs[0].measurement_id.is_none());
+    assert_eq!(
+        runs[0].error.as_deref(),
+        Some("No healthy agents found for the requested measurement")
+    );
+
+    let response = server.get(&format!("/api/schedule/{}", ids[0])).await;
+    let detail: serde_json::Value = response.json();
+    assert_eq!(detail["runs"].as_array().unwrap().len(), 1);
+    assert!(detail["next_run_at"].is_null());
+
+    let future_runs = state.database.list_schedule_runs(ids[2], 10).await.unwrap();
+    assert!(future_runs.is_empty());
+}
+
+#[tokio::test]
+async fn test_scheduled_runs_belong_to_the_owner() {
+    let state = common::create_api_test_state(&["test-agent"]).await;
+    let server = TestServer::new(create_app(state.clone()));
+
+    // IPv4 source addresses skip the user prefix check
+    let mut request = schedule_request();
+    request["metadata"] = json!([{"id": "test-agent", "ip_address": "192.0.2.1"}]);
+    let response = server.post("/api/schedules").json(&request).await;
+    assert_eq!(response.status_code(), 201);
+    let created: serde_json::Value = response.json();
+    let id = Uuid::parse_str(created["id"].as_str().unwrap()).unwrap();
+
+    assert_eq!(scheduler::run_due_schedules(&state).await, 1);
+    let runs = state.database.list_schedule_runs(id, 10).await.unwrap();
+    let measurement_id = runs[0].measurement_id.unwrap();
+
+    // The run is the owner's measurement, charged to their quota
+    let response = server
+        .get(&format!("/api/measurement/{}/status", measurement_id))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let usage = state
+        .database
+        .get_usage_since(
+            &hash_user_identifier("test-user-id"),
+            chrono::Utc::now() - chrono::Duration::hours(1),
+            saimiris_gateway::quota::ChargingMode::Expected,
+        )
+        .await
+        .unwrap();
+    assert_eq!(usage.probes, 1);
+}
@@ -0,0 +1,196 @@
+use axum_test::TestServer;
+use saimiris_gateway::{agent::AgentConfig, create_app};
+use serde_json::json;
+use uuid::Uuid;
+
+mod common;
+
+#[tokio::test]
+async fn test_target_list_lifecycle() {
+    let server = TestServer::new(create_app(common::create_api_test_state(&[]).await));
+
+    let response = server
+        .post("/api/target-lists")
+        .json(&json!({
+            "name": "hitlist",
+            "targets": ["2001:4860:4860:0:0:0:0:8888", "2606:4700:4700::1111"]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 201);
+    let created: serde_json::Value = response.json();
+    let id = created["id"].as_str().unwrap().to_string();
+    assert_eq!(created["name"], "hitlist");
+    assert_eq!(created["target_count"], 2);
+
+    let response = server.get("/api/target-lists").await;
+    assert_eq!(response.status_code(), 200);
+    let lists: serde_json::Value = response.json();
+    assert_eq!(lists.as_array().unwrap().len(), 1);
+    assert!(lists[0].get("targets").is_none());
+
+    // Stored destinations are canonicalized
+    let response = server.get(&format!("/api/target-list/{}", id)).await;
+    assert_eq!(response.status_code(), 200);
+    let detail: serde_json::Value = response.json();
+    assert_eq!(
+        detail["targets"],
+        json!(["2001:4860:4860::8888", "2606:4700:4700::1111"])
+    );
+
+    let response = server
+        .put(&format!("/api/target-list/{}", id))
+        .json(&json!({"targets": ["2001:4860:4860::8844"]}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let updated: serde_json::Value = response.json();
+    assert_eq!(updated["name"], "hitlist");
+    assert_eq!(updated["target_count"], 1);
+
+    let response = server
+        .put(&format!("/api/target-list/{}", id))
+        .json(&json!({"name": "renamed"}))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let detail: serde_json::Value = server.get(&format!("/api/target-list/{}", id)).await.json();
+    assert_eq!(detail["name"], "renamed");
+    assert_eq!(detail["targets"], json!(["2001:4860:4860::8844"]));
+
+    let response = server.delete(&format!("/api/target-list/{}", id)).await;
+    assert_eq!(response.status_code(), 200);
+    let response = server.get(&format!("/api/target-list/{}", id)).await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_target_list_validation() {
+    let server = TestServer::new(create_app(common::create_api_test_state(&[]).await));
+
+    for body in [
+        json!({"name": "v4", "targets": ["2001:db8::1", "192.0.2.1"]}),
+        json!({"name": "bad", "targets": ["not-an-ip"]}),
+        json!({"name": "empty", "targets": []}),
+        json!({"name": "", "targets": ["2001:db8::1"]}),
+    ] {
+        let response = server.post("/api/target-lists").json(&body).await;
+        assert_eq!(response.status_code(), 400, "body {}", body);
+        let error: serde_json::Value = response.json();
+        assert_eq!(error["error"], 400);
+    }
+
+    let response = server.get("/api/target-list/not-a-uuid").await;
+    assert_eq!(response.status_code(), 400);
+    let response = server
+        .put(&format!("/api/target-list/{}", Uuid::new_v4()))
+        .json(&json!({"name": "missing"}))
+        .await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_probe_submission_with_target_list() {
+    let server = TestServer::new(create_app(common::create_api_test_state(&[]).await));
+    let metadata = json!([{"id": "test-agent", "ip_address": "2001:db8::1"}]);
+
+    // Unknown target list
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "metadata": metadata,
+            "target_list": {
+                "id": Uuid::new_v4(),
+                "src_port": 24000, "dst_port": 33434, "ttl": 64, "protocol": "udp"
+            }
+        }))
+        .await;
+    assert_eq!(response.status_code(), 404);
+
+    let created: serde_json::Value = server
+        .post("/api/target-lists")
+        .json(&json!({"name": "hitlist", "targets": ["2001:4860:4860::8888"]}))
+        .await
+        .json();
+
+    // The template goes through the same validation as inline probes
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "metadata": metadata,
+            "target_list": {
+                "id": created["id"],
+                "src_port": 24000, "dst_port": 33434, "ttl": 64, "protocol": "tcp"
+            }
+        }))
+        .await;
+    assert_eq!(response.status_code(), 400);
+
+    // A valid reference is expanded and reaches agent selection (none registered here)
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "metadata": metadata,
+            "target_list": {
+                "id": created["id"],
+                "src_port": 24000, "dst_port": 33434, "ttl": 64, "protocol": "udp"
+            }
+        }))
+        .await;
+    assert_eq!(response.status_code(), 400);
+    let error: serde_json::Value = response.json();
+    assert_eq!(
+        error["message"],
+        "No healthy agents found for the requested measurement"
+    );
+}
+
+#[tokio::test]
+async fn test_target_list_template_options() {
+    let state = common::create_api_test_state(&["tcp-agent"]).await;
+    state
+        .agent_store
+        .update_config(
+            "tcp-agent",
+            vec![AgentConfig {
+                probe_options: vec!["tcp_flags".to_string()],
+                ..Default::default()
+            }],
+        )
+        .await;
+    let server = TestServer::new(create_app(state));
+
+    let created: serde_json::Value = server
+        .post("/api/target-lists")
+        .json(&json!({"name": "hitlist", "targets": ["2001:4860:4860::8888"]}))
+        .await
+        .json();
+    let submit = |options: serde_json::Value| {
+        // IPv4 source addresses skip the user prefix check
+        server.post("/api/probes").json(&json!({
+            "metadata": [{"id": "tcp-agent", "ip_address": "192.0.2.1"}],
+            "target_list": {
+                "id": created["id"],
+                "src_port": 24000, "dst_port": 443, "ttl": 64, "protocol": "tcp",
+                "options": options
+            }
+        }))
+    };
+
+    // TCP probes are generated with the template's flags
+    let response = submit(json!({"tcp_flags": "syn"})).await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["probes"], 1);
+
+    // Options the agent does not advertise are refused like on inline probes
+    let response = submit(json!({"tcp_flags": "syn", "payload_size": 32})).await;
+    assert_eq!(response.status_code(), 400);
+
+    let response = submit(json!({"tcp_flags": "syn", "flow_label": 1_048_576})).await;
+    assert_eq!(response.status_code(), 400);
+    let error: serde_json::Value = response.json();
+    assert!(
+        error["message"]
+            .as_str()
+            .unwrap()
+            .contains("Invalid flow_label")
+    );
+}
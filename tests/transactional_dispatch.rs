@@ -0,0 +1,203 @@
+use async_trait::async_trait;
+use axum_test::TestServer;
+use saimiris_gateway::{
+    AppState, create_app, kafka,
+    sink::{MemorySink, PartialDelivery, ProbeSink},
+};
+use serde_json::json;
+use std::sync::Arc;
+
+mod common;
+
+// A non-transactional sink failing after delivering its first `deliver`
+// messages
+struct FailingSink {
+    delivered: MemorySink,
+    deliver: usize,
+}
+
+#[async_trait]
+impl ProbeSink for FailingSink {
+    async fn send(&self, messages: &[kafka::OutboundMessage]) -> anyhow::Result<()> {
+        let delivered = self.deliver.min(messages.len());
+        self.delivered.send(&messages[..delivered]).await?;
+        let err = anyhow::anyhow!("broker unavailable");
+        Err(match delivered {
+            0 => err,
+            delivered => err.context(PartialDelivery { delivered }),
+        })
+    }
+}
+
+// Two agents, each with its own partition, so that each gets its own copy
+// of the batches
+async fn create_partial_state(deliver: usize) -> (AppState, MemorySink) {
+    let sink = MemorySink::new();
+    let state = AppState {
+        kafka_config: kafka::KafkaConfig {
+            routing: kafka::TopicRouting::AgentPartition { partitions: 4 },
+            ..common::test_kafka_config()
+        },
+        probe_sink: Arc::new(FailingSink {
+            delivered: sink.clone(),
+            deliver,
+        }),
+        ..common::create_api_test_state(&["tx-agent-a", "tx-agent-b"]).await
+    };
+    (state, sink)
+}
+
+// The mock producer is not transactional, so with a transactional ID
+// configured every dispatch fails when beginning the transaction
+async fn create_test_state() -> AppState {
+    let kafka_config = kafka::KafkaConfig {
+        transactional_id: Some("saimiris-gateway-test".to_string()),
+        ..common::test_kafka_config()
+    };
+
+    let kafka_producer = rdkafka::config::ClientConfig::new()
+        .create_with_context(kafka::StatsContext)
+        .expect("Failed to create mock Kafka producer");
+    let probe_sink = Arc::new(kafka::KafkaSink::new(kafka_producer, kafka_config.clone()));
+
+    AppState {
+        kafka_config,
+        probe_sink,
+        ..common::create_api_test_state(&["tx-agent"]).await
+    }
+}
+
+#[tokio::test]
+async fn test_failed_dispatch_leaves_no_measurement() {
+    let server = TestServer::new(create_app(create_test_state().await));
+
+    // IPv4 source addresses skip the user prefix check
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]],
+            "metadata": [{"id": "tx-agent", "ip_address": "192.0.2.1"}]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 500);
+    let body: serde_json::Value = response.json();
+    assert_eq!(
+        body["message"],
+        "Failed to send probe data to processing queue"
+    );
+
+    // The tracking rows created before dispatch were rolled back
+    let response = server.get("/api/measurements").await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body, json!([]));
+}
+
+#[tokio::test]
+async fn test_failed_round_is_not_tracked() {
+    let server = TestServer::new(create_app(create_test_state().await));
+
+    let response = server
+        .post("/api/measurements")
+        .json(&json!({ "metadata": [{"id": "tx-agent", "ip_address": "192.0.2.1"}] }))
+        .await;
+    assert_eq!(response.status_code(), 201);
+    let body: serde_json::Value = response.json();
+    let id = body["id"].as_str().unwrap().to_string();
+
+    let response = server
+        .post(&format!("/api/measurement/{}/rounds", id))
+        .json(&json!({ "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]] }))
+        .await;
+    assert_eq!(response.status_code(), 500);
+
+    let response = server.get(&format!("/api/measurement/{}/status", id)).await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["total_expected_probes"], 0);
+}
+
+#[tokio::test]
+async fn test_untracked_measurement_is_not_sent() {
+    let (database, url) = common::create_sqlite_database().await;
+    common::fail_tracking_inserts(&url).await;
+    let sink = MemorySink::new();
+    let state = AppState {
+        database,
+        probe_sink: Arc::new(sink.clone()),
+        ..common::create_api_test_state(&["tx-agent"]).await
+    };
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]],
+            "metadata": [{"id": "tx-agent", "ip_address": "192.0.2.1"}]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 500);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["message"], "Failed to record measurement tracking");
+    assert!(sink.messages().is_empty());
+}
+
+#[tokio::test]
+async fn test_partial_dispatch_keeps_delivered_probes() {
+    let (state, sink) = create_partial_state(1).await;
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "probes": [
+                ["2606:4700:4700::1111", 24000, 33434, 8, "udp"],
+                ["2606:4700:4700::1001", 24000, 33434, 8, "udp"]
+            ],
+            "metadata": [
+                {"id": "tx-agent-a", "ip_address": "192.0.2.1"},
+                {"id": "tx-agent-b", "ip_address": "192.0.2.2"}
+            ]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 500);
+    let body: serde_json::Value = response.json();
+    assert_eq!(
+        body["message"],
+        "Probe data was only partly sent to the processing queue"
+    );
+    assert_eq!(sink.messages().len(), 1);
+
+    // The first agent got its batch and is still followed, the second got
+    // nothing and is not
+    let id = body["id"].as_str().unwrap();
+    let response = server.get(&format!("/api/measurement/{}/status", id)).await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["total_agents"], 1);
+    assert_eq!(body["total_expected_probes"], 2);
+}
+
+#[tokio::test]
+async fn test_undelivered_dispatch_leaves_no_measurement() {
+    let (state, sink) = create_partial_state(0).await;
+    let server = TestServer::new(create_app(state));
+
+    let response = server
+        .post("/api/probes")
+        .json(&json!({
+            "probes": [["2606:4700:4700::1111", 24000, 33434, 8, "udp"]],
+            "metadata": [
+                {"id": "tx-agent-a", "ip_address": "192.0.2.1"},
+                {"id": "tx-agent-b", "ip_address": "192.0.2.2"}
+            ]
+        }))
+        .await;
+    assert_eq!(response.status_code(), 500);
+    assert!(sink.messages().is_empty());
+
+    let response = server.get("/api/measurements").await;
+    assert_eq!(response.status_code(), 200);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body, json!([]));
+}
@@ -2,10 +2,14 @@ use axum_test::TestServer;
 use saimiris_gateway::{
     AppState,
     agent::{AgentConfig, AgentStore},
+    blocklist::{Blocklist, BlocklistAction},
     create_app,
     database::Database,
     kafka,
+    probe::SpecialPurposeFilter,
+    sink::MemorySink,
 };
+use std::sync::Arc;
 
 async fn create_mock_database() -> Database {
     Database::new_mock()
@@ -19,24 +23,31 @@ async fn test_user_prefixes_endpoint() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = rdkafka::config::ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     // Set up the app state
     let agent_store = AgentStore::new();
     let state = AppState {
         agent_store: agent_store.clone(),
         agent_key: "test-key".to_string(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: true,
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
 
     // Add a test agent with IPv6 prefix configuration
@@ -138,24 +149,31 @@ async fn test_multiple_agents_and_prefixes() {
         brokers: "localhost:9092".to_string(),
         topic: "probes".to_string(),
         auth: kafka::KafkaAuth::PlainText,
+        batch_format: kafka::BatchFormat::Legacy,
+        transactional_id: None,
+        routing: kafka::TopicRouting::Shared,
+        producer_properties: Vec::new(),
     };
 
-    // Create a mock Kafka producer
-    let kafka_producer = rdkafka::config::ClientConfig::new()
-        .create()
-        .expect("Failed to create mock Kafka producer");
-
     // Set up the app state
     let agent_store = AgentStore::new();
     let state = AppState {
         agent_store: agent_store.clone(),
         agent_key: "test-key".to_string(),
         kafka_config,
-        kafka_producer,
+        probe_sink: Arc::new(MemorySink::new()),
         auth0_jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
         auth0_issuer: Some("https://test.auth0.com/".to_string()),
         bypass_jwt_validation: true,
         database: create_mock_database().await,
+        probe_archive: None,
+        admin_key: None,
+        blocklist: Blocklist::new(BlocklistAction::Filter),
+        special_purpose: SpecialPurposeFilter::development(),
+        outbox: false,
+        retention: Default::default(),
+        quota: Default::default(),
+        quota_charging: Default::default(),
     };
 
     // Add multiple agents with different prefix configurations